@@ -1,6 +1,15 @@
-use crate::{value::Value, ValueArray};
+use std::cell::{Cell, OnceCell};
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::{
+    interner::StringId,
+    value::{Obj, ObjType, Value},
+    ValueArray,
+};
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
     // return from the current function
     OP_RETURN = 0,
@@ -21,14 +30,262 @@ pub enum OpCode {
     OP_LESS = 13,
     OP_PRINT = 14,
     OP_POP = 15,
-    OP_DEFINE_GLOBAL = 16,
-    OP_GET_GLOBAL = 17,
-    OP_SET_GLOBAL = 18,
+    // globals by the slot the compiler gave their name, a two byte
+    // operand, high byte first. See globals.rs
+    OP_DEFINE_GLOBAL_SLOT = 16,
+    OP_GET_GLOBAL_SLOT = 17,
+    OP_SET_GLOBAL_SLOT = 18,
     OP_GET_LOCAL = 19,
     OP_SET_LOCAL = 20,
     OP_JUMP_IF_FALSE = 21,
     OP_JUMP = 22,
     OP_LOOP = 23,
+    OP_CALL = 24,
+    OP_CLASS = 25,
+    OP_GET_PROPERTY = 26,
+    OP_SET_PROPERTY = 27,
+    OP_METHOD = 28,
+    // operand is the number of items on the stack that make up the list
+    OP_BUILD_LIST = 29,
+    OP_INDEX_GET = 30,
+    OP_INDEX_SET = 31,
+    // pops the two bounds and pushes start..end or start..=end
+    OP_RANGE = 32,
+    OP_RANGE_INCLUSIVE = 33,
+    // one step of a for-in loop. Operands are the slot of the collection
+    // and a two byte jump taken when it has no items left
+    OP_FOR_ITER = 34,
+    // bitwise operators, on the operands truncated to 64-bit integers
+    OP_BIT_AND = 35,
+    OP_BIT_OR = 36,
+    OP_BIT_XOR = 37,
+    OP_BIT_NOT = 38,
+    OP_SHIFT_LEFT = 39,
+    OP_SHIFT_RIGHT = 40,
+    // OP_TRY registers an exception handler whose catch block starts the
+    // jump offset after it. OP_END_TRY drops it again once the try block
+    // finishes, OP_THROW unwinds to the innermost one
+    OP_TRY = 41,
+    OP_END_TRY = 42,
+    OP_THROW = 43,
+    // a failed assert, with its message on top of the stack
+    OP_ASSERT_FAILED = 44,
+    // a call whose arguments are the items of the lists on the stack,
+    // see Compiler::argument_list. The operand is how many lists
+    OP_SPREAD_CALL = 45,
+    // like OP_BUILD_LIST, for a tuple
+    OP_BUILD_TUPLE = 46,
+    // like OP_JUMP_IF_FALSE, but jumps when the value isn't nil
+    OP_JUMP_IF_NOT_NIL = 47,
+    // a map from the key/value pairs on the stack. The operand is how
+    // many pairs
+    OP_BUILD_MAP = 48,
+    // OP_GET_LOCAL and OP_SET_LOCAL for slots past 255, with a two byte
+    // slot operand, high byte first
+    OP_GET_LOCAL_LONG = 49,
+    OP_SET_LOCAL_LONG = 50,
+    // like OP_JUMP_IF_FALSE, but jumps when the value is truthy. `or`
+    // compiles to it
+    OP_JUMP_IF_TRUE = 51,
+    // push a copy of the top value, and exchange the top two. Nothing
+    // compiles to them yet, they are for constructs that need a value
+    // twice or in the other order without a temporary local
+    OP_DUP = 52,
+    OP_SWAP = 53,
+    // OP_CONSTANT with a two byte index, for chunks with more than 256
+    // constants that can't be split, see Compiler::emit_constant
+    OP_CONSTANT_LONG = 54,
+}
+
+// the one place bytes become opcodes. A byte that isn't one comes back
+// as the error
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<OpCode, u8> {
+        use OpCode::*;
+        Ok(match byte {
+            0 => OP_RETURN,
+            1 => OP_CONSTANT,
+            2 => OP_NEGATE,
+            3 => OP_ADD,
+            4 => OP_SUBTRACT,
+            5 => OP_MULTIPLY,
+            6 => OP_DIVIDE,
+            7 => OP_NIL,
+            8 => OP_TRUE,
+            9 => OP_FALSE,
+            10 => OP_NOT,
+            11 => OP_EQUAL,
+            12 => OP_GREATER,
+            13 => OP_LESS,
+            14 => OP_PRINT,
+            15 => OP_POP,
+            16 => OP_DEFINE_GLOBAL_SLOT,
+            17 => OP_GET_GLOBAL_SLOT,
+            18 => OP_SET_GLOBAL_SLOT,
+            19 => OP_GET_LOCAL,
+            20 => OP_SET_LOCAL,
+            21 => OP_JUMP_IF_FALSE,
+            22 => OP_JUMP,
+            23 => OP_LOOP,
+            24 => OP_CALL,
+            25 => OP_CLASS,
+            26 => OP_GET_PROPERTY,
+            27 => OP_SET_PROPERTY,
+            28 => OP_METHOD,
+            29 => OP_BUILD_LIST,
+            30 => OP_INDEX_GET,
+            31 => OP_INDEX_SET,
+            32 => OP_RANGE,
+            33 => OP_RANGE_INCLUSIVE,
+            34 => OP_FOR_ITER,
+            35 => OP_BIT_AND,
+            36 => OP_BIT_OR,
+            37 => OP_BIT_XOR,
+            38 => OP_BIT_NOT,
+            39 => OP_SHIFT_LEFT,
+            40 => OP_SHIFT_RIGHT,
+            41 => OP_TRY,
+            42 => OP_END_TRY,
+            43 => OP_THROW,
+            44 => OP_ASSERT_FAILED,
+            45 => OP_SPREAD_CALL,
+            46 => OP_BUILD_TUPLE,
+            47 => OP_JUMP_IF_NOT_NIL,
+            48 => OP_BUILD_MAP,
+            49 => OP_GET_LOCAL_LONG,
+            50 => OP_SET_LOCAL_LONG,
+            51 => OP_JUMP_IF_TRUE,
+            52 => OP_DUP,
+            53 => OP_SWAP,
+            54 => OP_CONSTANT_LONG,
+            _ => return Err(byte),
+        })
+    }
+}
+
+// When the left operand (or the only one, for OP_NEGATE) is an
+// instance, these instructions call a method of its class instead, so
+// `a + b` runs `a.plus(b)`. `a >= b` and `a <= b` compile to a negated
+// OP_LESS and OP_GREATER and so use `lt` and `gt`
+pub const OPERATOR_METHODS: [(u8, &str); 8] = [
+    (OpCode::OP_ADD as u8, "plus"),
+    (OpCode::OP_SUBTRACT as u8, "minus"),
+    (OpCode::OP_MULTIPLY as u8, "times"),
+    (OpCode::OP_DIVIDE as u8, "divide"),
+    (OpCode::OP_NEGATE as u8, "negate"),
+    (OpCode::OP_EQUAL as u8, "eq"),
+    (OpCode::OP_LESS as u8, "lt"),
+    (OpCode::OP_GREATER as u8, "gt"),
+];
+
+pub fn operator_method(op: u8) -> Option<&'static str> {
+    OPERATOR_METHODS
+        .iter()
+        .find(|(code, _)| *code == op)
+        .map(|(_, name)| *name)
+}
+
+// which source file a run of bytecode came from. The code from
+// `start` up to the next entry's start was compiled from `name`
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub start: usize,
+    pub name: String,
+    // the text of the file, so errors can show the line they are on.
+    // Set by the compiler, None for chunks put together by hand
+    pub source: Option<Rc<str>>,
+}
+
+// instructions whose one byte operand is an index into the constant pool
+pub fn has_constant_operand(op: u8) -> bool {
+    matches!(
+        OpCode::try_from(op),
+        Ok(OpCode::OP_CONSTANT
+            | OpCode::OP_CLASS
+            | OpCode::OP_GET_PROPERTY
+            | OpCode::OP_SET_PROPERTY
+            | OpCode::OP_METHOD)
+    )
+}
+
+// how many values each instruction takes off the stack, see
+// Chunk::values_taken. OPERAND_COUNTED ones depend on their operand
+const OPERAND_COUNTED: u8 = u8::MAX;
+const VALUES_TAKEN: [u8; 256] = {
+    let mut taken = [0; 256];
+    let one = [
+        OpCode::OP_NEGATE as u8,
+        OpCode::OP_NOT as u8,
+        OpCode::OP_PRINT as u8,
+        OpCode::OP_POP as u8,
+        OpCode::OP_DEFINE_GLOBAL_SLOT as u8,
+        OpCode::OP_SET_GLOBAL_SLOT as u8,
+        OpCode::OP_SET_LOCAL as u8,
+        OpCode::OP_SET_LOCAL_LONG as u8,
+        OpCode::OP_JUMP_IF_FALSE as u8,
+        OpCode::OP_JUMP_IF_NOT_NIL as u8,
+        OpCode::OP_JUMP_IF_TRUE as u8,
+        OpCode::OP_GET_PROPERTY as u8,
+        OpCode::OP_BIT_NOT as u8,
+        OpCode::OP_THROW as u8,
+        OpCode::OP_ASSERT_FAILED as u8,
+        OpCode::OP_DUP as u8,
+    ];
+    let two = [
+        OpCode::OP_ADD as u8,
+        OpCode::OP_SUBTRACT as u8,
+        OpCode::OP_MULTIPLY as u8,
+        OpCode::OP_DIVIDE as u8,
+        OpCode::OP_EQUAL as u8,
+        OpCode::OP_GREATER as u8,
+        OpCode::OP_LESS as u8,
+        OpCode::OP_SET_PROPERTY as u8,
+        OpCode::OP_METHOD as u8,
+        OpCode::OP_INDEX_GET as u8,
+        OpCode::OP_RANGE as u8,
+        OpCode::OP_RANGE_INCLUSIVE as u8,
+        OpCode::OP_BIT_AND as u8,
+        OpCode::OP_BIT_OR as u8,
+        OpCode::OP_BIT_XOR as u8,
+        OpCode::OP_SHIFT_LEFT as u8,
+        OpCode::OP_SHIFT_RIGHT as u8,
+        OpCode::OP_SWAP as u8,
+    ];
+    let counted = [
+        OpCode::OP_CALL as u8,
+        OpCode::OP_SPREAD_CALL as u8,
+        OpCode::OP_BUILD_LIST as u8,
+        OpCode::OP_BUILD_TUPLE as u8,
+        OpCode::OP_BUILD_MAP as u8,
+    ];
+    let mut i = 0;
+    while i < one.len() {
+        taken[one[i] as usize] = 1;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < two.len() {
+        taken[two[i] as usize] = 2;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < counted.len() {
+        taken[counted[i] as usize] = OPERAND_COUNTED;
+        i += 1;
+    }
+    taken[OpCode::OP_INDEX_SET as usize] = 3;
+    taken
+};
+
+// how big a chunk came out, see Chunk::sizes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkSize {
+    pub name: String,
+    // bytes of bytecode
+    pub code: usize,
+    pub constants: usize,
 }
 
 // array of bytes of instructions
@@ -37,6 +294,28 @@ pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: ValueArray,
     pub lines: Vec<i32>,
+    // the column of the token each byte was compiled from, 0 where
+    // there is none, e.g. in a chunk put together by hand
+    pub columns: Vec<usize>,
+    pub files: Vec<SourceFile>,
+    // inline caches of the property instructions, by the offset of
+    // the instruction. Two accesses of the same name share its interned
+    // handle but not a cache, so objects of different shapes at one
+    // don't make the other miss. Made the first time one is needed, when the code
+    // is final, see Chunk::cache. Cells because the VM updates them in a
+    // chunk it shares with functions
+    caches: OnceCell<Vec<InlineCache>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InlineCache {
+    // the name's handle in the VM's intern pool, looked up the first
+    // time the instruction runs
+    pub name: Cell<Option<StringId>>,
+    // where in the instance's fields the name was found last time. It
+    // is only a guess, the VM checks the key there before using it and
+    // looks the name up on a miss
+    pub position: Cell<usize>,
 }
 // count and capacity can be used with: len(), capacity()
 
@@ -46,6 +325,9 @@ impl Chunk {
             code: vec![],
             constants: ValueArray::init_value_array(),
             lines: vec![],
+            columns: vec![],
+            files: vec![],
+            caches: OnceCell::new(),
         }
     }
     // we don't deal with capacity and count here as rust
@@ -54,8 +336,30 @@ impl Chunk {
     // update count and capacity. We would grow by factor of 2 and min
     // capacity would be 8
     pub fn write_chunk(&mut self, byte: u8, line: i32) {
+        self.write_chunk_at(byte, line, 0);
+    }
+
+    pub fn write_chunk_at(&mut self, byte: u8, line: i32, column: usize) {
         self.code.push(byte);
         self.lines.push(line);
+        self.columns.push(column);
+    }
+
+    // "line:column" of the instruction at `offset` for the disassembly,
+    // or just the line when the column isn't known
+    fn position(&self, offset: usize) -> String {
+        let line = self.lines.get(offset).copied().unwrap_or(0);
+        match self.columns.get(offset) {
+            Some(&column) if column > 0 => format!("{}:{}", line, column),
+            _ => line.to_string(),
+        }
+    }
+
+    // the inline cache of the instruction at `offset`
+    pub fn cache(&self, offset: usize) -> Option<&InlineCache> {
+        self.caches
+            .get_or_init(|| vec![InlineCache::default(); self.code.len()])
+            .get(offset)
     }
 
     pub fn add_constant(&mut self, value: Value) -> usize {
@@ -63,188 +367,514 @@ impl Chunk {
         return self.constants.values.len() - 1;
     }
 
-    pub fn free_chunk(&mut self) {
-        self.code.clear();
-        self.constants.free_value_array();
-        self.lines.clear();
+    // every byte written after this call belongs to `name`, until the
+    // next file begins. Lines stay per byte, so together with this we
+    // can tell both the file and the line of any instruction
+    pub fn begin_file(&mut self, name: &str) {
+        self.files.push(SourceFile {
+            start: self.code.len(),
+            name: name.to_string(),
+            source: None,
+        });
+    }
+
+    // find the file the instruction at `offset` was compiled from
+    pub fn file_at(&self, offset: usize) -> Option<&str> {
+        self.files
+            .iter()
+            .rev()
+            .find(|file| file.start <= offset)
+            .map(|file| file.name.as_str())
+    }
+
+    // the text of the file the instruction at `offset` was compiled from
+    pub fn source_at(&self, offset: usize) -> Option<&Rc<str>> {
+        self.files
+            .iter()
+            .rev()
+            .find(|file| file.start <= offset)?
+            .source
+            .as_ref()
+    }
+
+    // every constant in the pool together with its index
+    pub fn constants(&self) -> impl Iterator<Item = (usize, &Value)> {
+        self.constants.values.iter().enumerate()
+    }
+
+    // number of bytes the instruction at `offset` takes, operands included
+    pub fn instruction_len(&self, offset: usize) -> usize {
+        let op = self.code[offset];
+        if has_constant_operand(op) {
+            return 2;
+        }
+        match OpCode::try_from(op) {
+            Ok(
+                OpCode::OP_GET_LOCAL
+                | OpCode::OP_SET_LOCAL
+                | OpCode::OP_CALL
+                | OpCode::OP_SPREAD_CALL
+                | OpCode::OP_BUILD_LIST
+                | OpCode::OP_BUILD_TUPLE
+                | OpCode::OP_BUILD_MAP,
+            ) => 2,
+            Ok(
+                OpCode::OP_JUMP
+                | OpCode::OP_JUMP_IF_FALSE
+                | OpCode::OP_LOOP
+                | OpCode::OP_TRY
+                | OpCode::OP_JUMP_IF_NOT_NIL
+                | OpCode::OP_JUMP_IF_TRUE
+                | OpCode::OP_GET_LOCAL_LONG
+                | OpCode::OP_SET_LOCAL_LONG
+                | OpCode::OP_CONSTANT_LONG
+                | OpCode::OP_DEFINE_GLOBAL_SLOT
+                | OpCode::OP_GET_GLOBAL_SLOT
+                | OpCode::OP_SET_GLOBAL_SLOT,
+            ) => 3,
+            Ok(OpCode::OP_FOR_ITER) => 4,
+            _ => 1,
+        }
+    }
+
+    // where the jump at `offset` goes, None if it isn't a jump. The
+    // offset operand is the last two bytes of the instruction and counts
+    // from its end, backwards for OP_LOOP
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        let len = self.instruction_len(offset);
+        let distance =
+            || (self.code[offset + len - 2] as usize) << 8 | self.code[offset + len - 1] as usize;
+        match OpCode::try_from(self.code[offset]) {
+            Ok(
+                OpCode::OP_JUMP
+                | OpCode::OP_JUMP_IF_FALSE
+                | OpCode::OP_JUMP_IF_TRUE
+                | OpCode::OP_JUMP_IF_NOT_NIL
+                | OpCode::OP_TRY
+                | OpCode::OP_FOR_ITER,
+            ) => Some(offset + len + distance()),
+            Ok(OpCode::OP_LOOP) => (offset + len).checked_sub(distance()),
+            _ => None,
+        }
+    }
+
+    // how many values the instruction at `offset` takes off the stack,
+    // at least. OP_RETURN takes the result only when it returns from a
+    // call, and locals are read from their slots, so both count as 0
+    #[inline]
+    pub fn values_taken(&self, offset: usize) -> usize {
+        let op = self.code[offset];
+        let taken = VALUES_TAKEN[op as usize];
+        if taken != OPERAND_COUNTED {
+            return taken as usize;
+        }
+        let count = self.code.get(offset + 1).copied().unwrap_or(0) as usize;
+        match OpCode::try_from(op) {
+            // the callee below its arguments, or below the lists of them
+            Ok(OpCode::OP_CALL | OpCode::OP_SPREAD_CALL) => count + 1,
+            Ok(OpCode::OP_BUILD_MAP) => 2 * count,
+            _ => count,
+        }
     }
+
+    // for each constant, the offsets of the instructions that use it
+    pub fn constant_references(&self) -> Vec<Vec<usize>> {
+        let mut references = vec![vec![]; self.constants.values.len()];
+        let mut offset = 0;
+        while offset < self.code.len() {
+            if has_constant_operand(self.code[offset]) {
+                if let Some(refs) = references.get_mut(self.code[offset + 1] as usize) {
+                    refs.push(offset);
+                }
+            }
+            offset += self.instruction_len(offset);
+        }
+        references
+    }
+
+    // list the constant pool: index, type, value and the offsets of
+    // the instructions that load it
+    pub fn dump_constants(&self, name: &str) {
+        println!("== {} constants ==", name);
+        let references = self.constant_references();
+        for (index, constant) in self.constants() {
+            print!("{:04} {:<8} ", index, constant.type_name());
+            constant.print_value();
+            let offsets: Vec<String> = references[index]
+                .iter()
+                .map(|offset| format!("{:04}", offset))
+                .collect();
+            println!("  refs: {}", offsets.join(" "));
+        }
+    }
+
+    // the size of this chunk followed by those of the functions and
+    // methods compiled into it, each one before the functions inside it
+    pub fn sizes(&self, name: &str, sizes: &mut Vec<ChunkSize>) {
+        sizes.push(ChunkSize {
+            name: name.to_string(),
+            code: self.code.len(),
+            constants: self.constants.values.len(),
+        });
+        for constant in &self.constants.values {
+            if let Value::Object(Obj {
+                obj_type: ObjType::ObjFunction(function),
+            }) = constant
+            {
+                function.chunk.sizes(&function.name, sizes);
+            }
+        }
+    }
+
     // disasm all instrcutions in the chunk
     pub fn disassemble_chunk(&self, name: &str) {
-        println!("== {} ==", name);
+        print!("{}", self.disassembly(name));
+    }
+
+    // what disassemble_chunk prints
+    pub fn disassembly(&self, name: &str) -> String {
+        let mut out = format!("== {} ==\n", name);
         let mut i = 0;
         while i < self.code.len() {
-            i = self.disassemble_instruction(&self.code[i], i);
+            i = self.disassemble_instruction(&mut out, i);
         }
+        out
     }
+    // as constant goes right after the opcode, we need to:
+    // - get next value from array of chunks - it will be index
+    // of contant in the constants array
+    // - then we skip next item where constant index was
+    fn constant_instruction(&self, out: &mut String, name: &str, index: usize) -> usize {
+        let constant_index = self.code[index + 1];
+        let _ = write!(
+            out,
+            "{:04} {} {} {:?} '",
+            index,
+            self.position(index),
+            name,
+            constant_index
+        );
+        if let Some(constant) = self.constants.values.get(constant_index as usize) {
+            out.push_str(&constant.format_with(None));
+        }
+        out.push_str("'\n");
+        index + 2
+    }
+
     // disasm a single instruction
-    pub fn disassemble_instruction(&self, instruction: &u8, index: usize) -> usize {
-        match instruction {
-            x if *x == OpCode::OP_RETURN as u8 => {
-                println!("{:04} OP_RETURN", index);
+    pub fn disassemble_instruction(&self, out: &mut String, index: usize) -> usize {
+        // operands cut off by the end of the chunk, which the VM faults
+        // on. Nothing after them can be decoded
+        if index + self.instruction_len(index) > self.code.len() {
+            let _ = writeln!(out, "{:04} <truncated>", index);
+            return self.code.len();
+        }
+        match OpCode::try_from(self.code[index]) {
+            Ok(OpCode::OP_RETURN) => {
+                let _ = writeln!(out, "{:04} OP_RETURN", index);
                 index + 1
             }
-            x if *x == OpCode::OP_NEGATE as u8 => {
-                println!("{:04} OP_NEGATE", index);
+            Ok(OpCode::OP_NEGATE) => {
+                let _ = writeln!(out, "{:04} OP_NEGATE", index);
                 index + 1
             }
-            x if *x == OpCode::OP_ADD as u8 => {
-                println!("{:04} OP_ADD", index);
+            Ok(OpCode::OP_ADD) => {
+                let _ = writeln!(out, "{:04} OP_ADD", index);
                 index + 1
             }
-            x if *x == OpCode::OP_SUBTRACT as u8 => {
-                println!("{:04} OP_SUBTRACT", index);
+            Ok(OpCode::OP_SUBTRACT) => {
+                let _ = writeln!(out, "{:04} OP_SUBTRACT", index);
                 index + 1
             }
-            x if *x == OpCode::OP_MULTIPLY as u8 => {
-                println!("{:04} OP_MULTIPLY", index);
+            Ok(OpCode::OP_MULTIPLY) => {
+                let _ = writeln!(out, "{:04} OP_MULTIPLY", index);
                 index + 1
             }
-            x if *x == OpCode::OP_NEGATE as u8 => {
-                println!("{:04} OP_DIVIDE", index);
+            Ok(OpCode::OP_DIVIDE) => {
+                let _ = writeln!(out, "{:04} OP_DIVIDE", index);
                 index + 1
             }
-            x if *x == OpCode::OP_NIL as u8 => {
-                println!("{:04} OP_NIL", index);
+            Ok(OpCode::OP_NIL) => {
+                let _ = writeln!(out, "{:04} OP_NIL", index);
                 index + 1
             }
-            x if *x == OpCode::OP_TRUE as u8 => {
-                println!("{:04} OP_TRUE", index);
+            Ok(OpCode::OP_TRUE) => {
+                let _ = writeln!(out, "{:04} OP_TRUE", index);
                 index + 1
             }
-            x if *x == OpCode::OP_FALSE as u8 => {
-                println!("{:04} OP_FALSE", index);
+            Ok(OpCode::OP_FALSE) => {
+                let _ = writeln!(out, "{:04} OP_FALSE", index);
                 index + 1
             }
-            x if *x == OpCode::OP_NOT as u8 => {
-                println!("{:04} OP_NOT", index);
+            Ok(OpCode::OP_NOT) => {
+                let _ = writeln!(out, "{:04} OP_NOT", index);
                 index + 1
             }
-            x if *x == OpCode::OP_CONSTANT as u8 => {
-                // as constant goes right after OP_CONSTANT, we need to:
-                // - get next value from array of chunks - it will be index
-                // of contant in the constants array
-                // - then we update current index of chunks array
-                // so we skip next item where constant index was
-                let constant = self
-                    .code
-                    .get(index + 1)
-                    .and_then(|i| self.constants.values.get(*i as usize));
-                let line: Option<&i32> = self.lines.get(index);
-                let constant_index = self.code.get(index + 1);
-
+            Ok(OpCode::OP_CONSTANT) => {
                 // The first two bytes are a constant instruction that loads 1.2 from the chunk’s constant pool.
                 // The first byte is the OP_CONSTANT opcode and the second is the index in the constant pool
-                println!(
-                    "{:04} {:?} OP_CONSTANT {:?} '{:?}'", // 123 OP_CONSTANT 0 1.2
+                self.constant_instruction(out, "OP_CONSTANT", index) // 123 OP_CONSTANT 0 '1.2'
+            }
+            Ok(OpCode::OP_CONSTANT_LONG) => {
+                let constant_index =
+                    (self.code[index + 1] as usize) << 8 | self.code[index + 2] as usize;
+                let _ = write!(
+                    out,
+                    "{:04} {} OP_CONSTANT_LONG {} '",
                     index,
-                    line.unwrap(),
-                    constant_index.unwrap(),
-                    constant.unwrap().print_value()
+                    self.position(index),
+                    constant_index
                 );
-
-                index + 2
+                if let Some(constant) = self.constants.values.get(constant_index) {
+                    out.push_str(&constant.format_with(None));
+                }
+                out.push_str("'\n");
+                index + 3
             }
-            x if *x == OpCode::OP_EQUAL as u8 => {
-                println!("{:04} OP_EQUAL", index);
+            Ok(OpCode::OP_EQUAL) => {
+                let _ = writeln!(out, "{:04} OP_EQUAL", index);
                 index + 1
             }
-            x if *x == OpCode::OP_GREATER as u8 => {
-                println!("{:04} OP_GREATER", index);
+            Ok(OpCode::OP_GREATER) => {
+                let _ = writeln!(out, "{:04} OP_GREATER", index);
                 index + 1
             }
-            x if *x == OpCode::OP_LESS as u8 => {
-                println!("{:04} OP_LESS", index);
+            Ok(OpCode::OP_LESS) => {
+                let _ = writeln!(out, "{:04} OP_LESS", index);
                 index + 1
             }
-            x if *x == OpCode::OP_PRINT as u8 => {
-                println!("{:04} OP_PRINT", index);
+            Ok(OpCode::OP_PRINT) => {
+                let _ = writeln!(out, "{:04} OP_PRINT", index);
                 index + 1
             }
 
-            x if *x == OpCode::OP_POP as u8 => {
-                println!("{:04} OP_POP", index);
+            Ok(OpCode::OP_POP) => {
+                let _ = writeln!(out, "{:04} OP_POP", index);
+                index + 1
+            }
+            Ok(OpCode::OP_DUP) => {
+                let _ = writeln!(out, "{:04} OP_DUP", index);
                 index + 1
             }
-            x if *x == OpCode::OP_DEFINE_GLOBAL as u8 => {
-                println!("{:04} OP_DEFINE_GLOBAL", index);
+            Ok(OpCode::OP_SWAP) => {
+                let _ = writeln!(out, "{:04} OP_SWAP", index);
                 index + 1
             }
-            x if *x == OpCode::OP_GET_GLOBAL as u8 => {
-                let constant = self
-                    .code
-                    .get(index + 1)
-                    .and_then(|i| self.constants.values.get(*i as usize));
-                let line: Option<&i32> = self.lines.get(index);
-                let constant_index = self.code.get(index + 1);
+            Ok(
+                op @ (OpCode::OP_DEFINE_GLOBAL_SLOT
+                | OpCode::OP_GET_GLOBAL_SLOT
+                | OpCode::OP_SET_GLOBAL_SLOT),
+            ) => {
+                let high = *self.code.get(index + 1).unwrap_or(&0) as usize;
+                let low = *self.code.get(index + 2).unwrap_or(&0) as usize;
 
-                println!(
-                    "{:04} {:?} OP_GET_GLOBAL {:?} '{:?}'",
+                let _ = writeln!(
+                    out,
+                    "{:04} {} {:?} {}",
                     index,
-                    line.unwrap(),
-                    constant_index.unwrap(),
-                    constant.unwrap().print_value()
+                    self.position(index),
+                    op,
+                    (high << 8) | low
                 );
 
-                index + 2
+                index + 3
             }
+            Ok(OpCode::OP_CLASS) => self.constant_instruction(out, "OP_CLASS", index),
+            Ok(OpCode::OP_GET_PROPERTY) => self.constant_instruction(out, "OP_GET_PROPERTY", index),
+            Ok(OpCode::OP_SET_PROPERTY) => self.constant_instruction(out, "OP_SET_PROPERTY", index),
+            Ok(OpCode::OP_METHOD) => self.constant_instruction(out, "OP_METHOD", index),
 
-            x if *x == OpCode::OP_SET_GLOBAL as u8 => {
-                let constant = self
-                    .code
-                    .get(index + 1)
-                    .and_then(|i| self.constants.values.get(*i as usize));
-                let line: Option<&i32> = self.lines.get(index);
-                let constant_index = self.code.get(index + 1);
+            Ok(OpCode::OP_GET_LOCAL) => {
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_GET_LOCAL {}",
+                    index,
+                    self.position(index),
+                    self.code[index + 1]
+                );
 
-                println!(
-                    "{:04} {:?} OP_SET_GLOBAL {:?} '{:?}'",
+                index + 2
+            }
+            Ok(OpCode::OP_SET_LOCAL) => {
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_SET_LOCAL {}",
                     index,
-                    line.unwrap(),
-                    constant_index.unwrap(),
-                    constant.unwrap().print_value()
+                    self.position(index),
+                    self.code[index + 1]
                 );
 
                 index + 2
             }
+            Ok(op @ (OpCode::OP_GET_LOCAL_LONG | OpCode::OP_SET_LOCAL_LONG)) => {
+                let name = if op == OpCode::OP_GET_LOCAL_LONG {
+                    "OP_GET_LOCAL_LONG"
+                } else {
+                    "OP_SET_LOCAL_LONG"
+                };
+                let high = *self.code.get(index + 1).unwrap_or(&0) as usize;
+                let low = *self.code.get(index + 2).unwrap_or(&0) as usize;
 
-            x if *x == OpCode::OP_GET_LOCAL as u8 => {
-                let slot = self.code.get(index + 1);
-                let line: Option<&i32> = self.lines.get(index);
+                let _ = writeln!(
+                    out,
+                    "{:04} {} {} {}",
+                    index,
+                    self.position(index),
+                    name,
+                    (high << 8) | low
+                );
 
-                println!(
-                    "{:04} {:?} OP_GET_LOCAL {}",
+                index + 3
+            }
+            Ok(OpCode::OP_CALL) => {
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_CALL {}",
                     index,
-                    line.unwrap(),
-                    slot.unwrap()
+                    self.position(index),
+                    self.code[index + 1]
                 );
 
                 index + 2
             }
-            x if *x == OpCode::OP_SET_LOCAL as u8 => {
-                let slot = self.code.get(index + 1);
-                let line: Option<&i32> = self.lines.get(index);
+            Ok(OpCode::OP_BUILD_LIST) => {
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_BUILD_LIST {}",
+                    index,
+                    self.position(index),
+                    self.code[index + 1]
+                );
 
-                println!(
-                    "{:04} {:?} OP_SET_LOCAL {}",
+                index + 2
+            }
+            Ok(OpCode::OP_BUILD_TUPLE) => {
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_BUILD_TUPLE {}",
                     index,
-                    line.unwrap(),
-                    slot.unwrap()
+                    self.position(index),
+                    self.code[index + 1]
                 );
+                index + 2
+            }
+            Ok(OpCode::OP_BUILD_MAP) => {
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_BUILD_MAP {}",
+                    index,
+                    self.position(index),
+                    self.code[index + 1]
+                );
+                index + 2
+            }
+            Ok(OpCode::OP_INDEX_GET) => {
+                let _ = writeln!(out, "{:04} OP_INDEX_GET", index);
+                index + 1
+            }
+            Ok(OpCode::OP_INDEX_SET) => {
+                let _ = writeln!(out, "{:04} OP_INDEX_SET", index);
+                index + 1
+            }
+            Ok(OpCode::OP_RANGE) => {
+                let _ = writeln!(out, "{:04} OP_RANGE", index);
+                index + 1
+            }
+            Ok(OpCode::OP_RANGE_INCLUSIVE) => {
+                let _ = writeln!(out, "{:04} OP_RANGE_INCLUSIVE", index);
+                index + 1
+            }
+            Ok(OpCode::OP_BIT_AND) => {
+                let _ = writeln!(out, "{:04} OP_BIT_AND", index);
+                index + 1
+            }
+            Ok(OpCode::OP_BIT_OR) => {
+                let _ = writeln!(out, "{:04} OP_BIT_OR", index);
+                index + 1
+            }
+            Ok(OpCode::OP_BIT_XOR) => {
+                let _ = writeln!(out, "{:04} OP_BIT_XOR", index);
+                index + 1
+            }
+            Ok(OpCode::OP_BIT_NOT) => {
+                let _ = writeln!(out, "{:04} OP_BIT_NOT", index);
+                index + 1
+            }
+            Ok(OpCode::OP_SHIFT_LEFT) => {
+                let _ = writeln!(out, "{:04} OP_SHIFT_LEFT", index);
+                index + 1
+            }
+            Ok(OpCode::OP_SHIFT_RIGHT) => {
+                let _ = writeln!(out, "{:04} OP_SHIFT_RIGHT", index);
+                index + 1
+            }
+            Ok(OpCode::OP_TRY) => {
+                let high = *self.code.get(index + 1).unwrap_or(&0) as u16;
+                let low = *self.code.get(index + 2).unwrap_or(&0) as u16;
+                let jump = (high << 8) | low;
 
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_TRY       {} -> {}",
+                    index,
+                    self.position(index),
+                    index,
+                    index + 3 + jump as usize
+                );
+
+                index + 3
+            }
+            Ok(OpCode::OP_END_TRY) => {
+                let _ = writeln!(out, "{:04} OP_END_TRY", index);
+                index + 1
+            }
+            Ok(OpCode::OP_THROW) => {
+                let _ = writeln!(out, "{:04} OP_THROW", index);
+                index + 1
+            }
+            Ok(OpCode::OP_SPREAD_CALL) => {
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_SPREAD_CALL {}",
+                    index,
+                    self.position(index),
+                    self.code[index + 1]
+                );
                 index + 2
             }
-            x if *x == OpCode::OP_JUMP as u8 => {
+            Ok(OpCode::OP_ASSERT_FAILED) => {
+                let _ = writeln!(out, "{:04} OP_ASSERT_FAILED", index);
+                index + 1
+            }
+            Ok(OpCode::OP_FOR_ITER) => {
+                let slot = self.code[index + 1];
+                let high = *self.code.get(index + 2).unwrap_or(&0) as u16;
+                let low = *self.code.get(index + 3).unwrap_or(&0) as u16;
+                let jump = (high << 8) | low;
+
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_FOR_ITER {} {} -> {}",
+                    index,
+                    self.position(index),
+                    slot,
+                    index,
+                    index + 4 + jump as usize
+                );
+
+                index + 4
+            }
+            Ok(OpCode::OP_JUMP) => {
                 // Get the two bytes that make up the jump offset
                 let high = *self.code.get(index + 1).unwrap_or(&0) as u16;
                 let low = *self.code.get(index + 2).unwrap_or(&0) as u16;
                 let jump = (high << 8) | low;
 
-                let line: Option<&i32> = self.lines.get(index);
-
-                println!(
-                    "{:04} {:?} OP_JUMP      {} -> {}",
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_JUMP      {} -> {}",
                     index,
-                    line.unwrap(),
+                    self.position(index),
                     index,
                     index + 3 + jump as usize
                 );
@@ -252,18 +882,49 @@ impl Chunk {
                 index + 3 // Instruction + 2 bytes for jump offset
             }
 
-            x if *x == OpCode::OP_JUMP_IF_FALSE as u8 => {
-                // Get the two bytes that make up the jump offset
+            Ok(OpCode::OP_JUMP_IF_NOT_NIL) => {
+                let high = *self.code.get(index + 1).unwrap_or(&0) as u16;
+                let low = *self.code.get(index + 2).unwrap_or(&0) as u16;
+                let jump = (high << 8) | low;
+
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_JUMP_IF_NOT_NIL {} -> {}",
+                    index,
+                    self.position(index),
+                    index,
+                    index + 3 + jump as usize
+                );
+
+                index + 3
+            }
+            Ok(OpCode::OP_JUMP_IF_TRUE) => {
                 let high = *self.code.get(index + 1).unwrap_or(&0) as u16;
                 let low = *self.code.get(index + 2).unwrap_or(&0) as u16;
                 let jump = (high << 8) | low;
 
-                let line: Option<&i32> = self.lines.get(index);
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_JUMP_IF_TRUE {} -> {}",
+                    index,
+                    self.position(index),
+                    index,
+                    index + 3 + jump as usize
+                );
+
+                index + 3
+            }
+            Ok(OpCode::OP_JUMP_IF_FALSE) => {
+                // Get the two bytes that make up the jump offset
+                let high = *self.code.get(index + 1).unwrap_or(&0) as u16;
+                let low = *self.code.get(index + 2).unwrap_or(&0) as u16;
+                let jump = (high << 8) | low;
 
-                println!(
-                    "{:04} {:?} OP_JUMP_IF_FALSE {} -> {}",
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_JUMP_IF_FALSE {} -> {}",
                     index,
-                    line.unwrap(),
+                    self.position(index),
                     index,
                     index + 3 + jump as usize
                 );
@@ -271,28 +932,101 @@ impl Chunk {
                 index + 3 // Instruction + 2 bytes for jump offset
             }
 
-            x if *x == OpCode::OP_LOOP as u8 => {
+            Ok(OpCode::OP_LOOP) => {
                 // Get the two bytes that make up the jump offset
                 let high = *self.code.get(index + 1).unwrap_or(&0) as u16;
                 let low = *self.code.get(index + 2).unwrap_or(&0) as u16;
                 let jump = (high << 8) | low;
 
-                let line: Option<&i32> = self.lines.get(index);
-
-                println!(
-                    "{:04} {:?} OP_LOOP       {} -> {}",
+                let _ = writeln!(
+                    out,
+                    "{:04} {} OP_LOOP       {} -> {}",
                     index,
-                    line.unwrap(),
+                    self.position(index),
                     index,
                     index + 3 - jump as usize // Note the subtraction for backward jump
                 );
 
                 index + 3
             }
-            _ => {
-                println!("unknown opcode");
+            Err(_) => {
+                let _ = writeln!(out, "unknown opcode");
                 index + 1
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+
+    fn compile(source: &str) -> Chunk {
+        let mut compiler = Compiler::new(source);
+        compiler.compile(Chunk::init_chunk()).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_opcode_from_byte() {
+        for byte in 0..=u8::MAX {
+            match OpCode::try_from(byte) {
+                Ok(op) => assert_eq!(op as u8, byte),
+                Err(rejected) => {
+                    assert_eq!(rejected, byte);
+                    assert!(byte > OpCode::OP_CONSTANT_LONG as u8);
+                }
+            }
+        }
+        assert_eq!(OpCode::try_from(3), Ok(OpCode::OP_ADD));
+    }
+
+    #[test]
+    fn test_constant_references() {
+        let chunk = compile("var a = 1.5; print a + 2; a = 3;");
+        let types: Vec<&str> = chunk
+            .constants()
+            .map(|(_, value)| value.type_name())
+            .collect();
+        // global names take a slot, not a constant
+        assert_eq!(types, ["number", "int", "int"]);
+
+        // var a = 1.5;  -> OP_CONSTANT 0 at 0, OP_DEFINE_GLOBAL_SLOT at 2
+        // print a + 2;  -> OP_GET_GLOBAL_SLOT at 5, OP_CONSTANT 1 at 8
+        // a = 3;        -> OP_CONSTANT 2 at 12, OP_SET_GLOBAL_SLOT at 14
+        let references = chunk.constant_references();
+        assert_eq!(references, [[0], [8], [12]]);
+    }
+
+    #[test]
+    fn test_sizes() {
+        let chunk = compile("fun f() { fun g() { return 1; } return g; } class C { m() {} }");
+        let mut sizes = vec![];
+        chunk.sizes("script", &mut sizes);
+        let names: Vec<&str> = sizes.iter().map(|size| size.name.as_str()).collect();
+        assert_eq!(names, ["script", "f", "g", "m"]);
+        assert_eq!(sizes[0].code, chunk.code.len());
+        // g: OP_CONSTANT 0, OP_RETURN, then the implicit OP_NIL, OP_RETURN
+        assert_eq!((sizes[2].code, sizes[2].constants), (5, 1));
+    }
+
+    #[test]
+    fn test_truncated_disassembly() {
+        for (code, last) in [
+            (vec![OpCode::OP_GET_LOCAL as u8], "0001 <truncated>\n"),
+            (vec![OpCode::OP_CALL as u8], "0001 <truncated>\n"),
+            (vec![OpCode::OP_JUMP as u8, 0], "0001 <truncated>\n"),
+            (vec![OpCode::OP_CONSTANT as u8], "0001 <truncated>\n"),
+        ] {
+            let mut chunk = Chunk::init_chunk();
+            chunk.write_chunk(OpCode::OP_NIL as u8, 1);
+            for byte in code {
+                chunk.write_chunk(byte, 1);
+            }
+            assert_eq!(
+                chunk.disassembly("code"),
+                format!("== code ==\n0000 OP_NIL\n{}", last)
+            );
+        }
+    }
+}
@@ -1,33 +1,199 @@
-use std::{
-    io::{self, Write},
-    string,
-};
-
-use clap::command;
+use std::collections::HashSet;
+use std::num::IntErrorKind;
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use crate::{
-    scanner,
-    value::{Obj, ObjString, ObjType, Value},
+    chunk::{operator_method, SourceFile, OPERATOR_METHODS},
+    diagnostics::Diagnostic,
+    globals::GlobalNames,
+    interner::Interner,
+    peephole,
+    value::{Obj, ObjFunction, ObjString, ObjType, Value},
     Chunk, OpCode, Scanner, Token, TokenType,
 };
 
-const STACK_MAX: usize = 256;
+// most locals a function can have, every slot has to fit in the two
+// byte operand of OP_GET_LOCAL_LONG
+const STACK_MAX: usize = u16::MAX as usize + 1;
 
 #[derive(Debug, Clone)]
 pub struct Local {
     name: Token,
     depth: i32,
+    // declared with `const`, assigning to it is a compile error
+    constant: bool,
+    // read somewhere in its scope. Assigning to it doesn't count
+    used: bool,
+}
+
+// A compile error as data, for hosts that show errors their own way.
+// The compiler only collects these, the VM prints them
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub message: String,
+    pub file: String,
+    pub line: i32,
+    // see Token::column, 0 if unknown
+    pub column: usize,
+    // the byte range of the offending token in the source. Empty at the
+    // end of input and for text the scanner couldn't make a token of
+    pub span: Range<usize>,
+    pub lexeme: String,
+    pub at_end: bool,
+}
+
+impl CompileError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        // the snippet underlines the token, or points just past the end
+        let diagnostic = Diagnostic::error(&self.message)
+            .at(&self.file, self.line)
+            .at_column(self.column)
+            .with_label(self.span.start, self.span.end, "");
+        if self.at_end {
+            diagnostic.with_note("at end of input")
+        } else {
+            diagnostic
+        }
+    }
 }
 
 pub struct Compiler {
     scanner: Scanner,
     parser: Parser,
-    pub compiling_chunk: Chunk,
+    // the chunk code is being emitted into. Owned by the compiler while
+    // it runs, compile() hands the finished ones back
+    compiling_chunk: Chunk,
+    // names declared with a top-level `var`, in source order. The VM
+    // hoists them so reading one before its declaration runs can be
+    // told apart from reading a name that doesn't exist at all
+    pub declared_globals: Vec<ObjType>,
+    // globals declared with `const`, by this program or, passed in by
+    // the VM, by earlier ones. Only assignments compiled after the
+    // declaration are caught, a function compiled before it can still
+    // assign the global when it runs
+    pub constant_globals: HashSet<ObjType>,
+    // globals that exist before this program runs: natives and what
+    // earlier programs defined. Passed in by the VM
+    pub known_globals: HashSet<ObjType>,
+    // the slot of each global name, see globals.rs. The VM lends the
+    // compiler its names so slots agree between programs, and takes
+    // them back with the ones this program added
+    pub global_names: GlobalNames,
+    // the VM's intern pool, lent the same way. String constants come
+    // from it, so a name or literal is one object wherever it's used,
+    // and the VM finds the handles of property names without adding
+    // anything
+    pub strings: Interner,
+    // every global read or assigned (true), with where. Checked
+    // against the globals this program declares once all of it is
+    // compiled, because a function may use one declared further down
+    global_uses: Vec<(ObjType, Token, bool)>,
+    // set by the VM, or by a "use strict"; at the top of the program.
+    // Assigning a global that is never declared is an error rather than
+    // something only found when the assignment runs
+    pub strict: bool,
+    // time spent inside the scanner. Scanning is interleaved with
+    // parsing, so this is what lets --time-passes split the two
+    pub scan_time: Duration,
+    // run optimizations while compiling (-O)
+    pub optimize: bool,
+    // some class has operator methods (plus, eq, ...), so arithmetic and
+    // comparisons may run user code. Set by the VM for classes from
+    // earlier programs and by the compiler for this one
+    pub operator_methods: bool,
+    // the slots of the globals some function assigns, by this program
+    // or, passed in by the VM, by earlier ones. Code a top-level loop
+    // calls can't assign any other global, see hoist_loop_globals
+    pub function_writes: HashSet<usize>,
+    // this program may call functions compiled after it, as with files
+    // the Linker puts together, so function_writes isn't complete yet
+    pub late_functions: bool,
+    // top-level code that didn't fit in one chunk, see
+    // top_level_declaration. compiling_chunk continues where the last
+    // of these stops
+    pub finished_chunks: Vec<Chunk>,
+    // a constant that doesn't fit may start a new chunk instead of
+    // failing, because the declaration can be compiled again from scratch
+    can_split: bool,
+    constant_overflow: bool,
+    locals: Vec<Local>,
+    local_count: usize,
+    scope_depth: i32,
+    function_type: FunctionType,
+    // the loops around the code being compiled, innermost last
+    loops: Vec<Loop>,
+    // try blocks around the code being compiled in this function
+    try_depth: usize,
+    // the functions this one is nested in, innermost last
+    enclosing: Vec<FunctionState>,
+    // every error found, in source order. Only the first of a run of
+    // errors is kept, see error_at
+    pub errors: Vec<CompileError>,
+    // locals nothing reads, in source order. Whether they are shown is
+    // up to the VM
+    pub warnings: Vec<Diagnostic>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionType {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+// the part of the compiler that belongs to the function being compiled.
+// Starting a nested function saves the enclosing one's here and ending
+// it puts them back
+struct FunctionState {
+    chunk: Chunk,
     locals: Vec<Local>,
     local_count: usize,
     scope_depth: i32,
+    function_type: FunctionType,
+    loops: Vec<Loop>,
+    try_depth: usize,
 }
 
+// a loop being compiled. `break` and `continue` pop the locals deeper
+// than scope_depth, the ones declared in the body, and jump to the end
+// or back to `start`
+struct Loop {
+    scope_depth: i32,
+    // try blocks around the loop. Leaving the body for one further out
+    // drops the handlers of the try blocks in between
+    try_depth: usize,
+    // where the next time around begins: the condition, the increment
+    // of a for loop that has one, or OP_FOR_ITER. None in a do-while,
+    // whose condition comes after the body
+    start: Option<usize>,
+    // OP_JUMPs of its breaks, patched once the loop is done
+    breaks: Vec<usize>,
+    // OP_JUMPs of continues with no start to go back to, patched once
+    // the condition is reached
+    continues: Vec<usize>,
+}
+
+// where the compiler was before a top-level declaration, so the
+// declaration can be compiled again
+struct Checkpoint {
+    scanner: (usize, usize, i32, usize),
+    parser: Parser,
+    code_len: usize,
+    constant_count: usize,
+    declared_globals: usize,
+    global_uses: usize,
+    warnings: usize,
+}
+
+// code size after which the next top-level declaration starts a new
+// chunk. Jump targets past 64k bytes don't work, this leaves room for
+// the declaration that crosses the limit
+const CHUNK_CODE_LIMIT: usize = 32 * 1024;
+
+#[derive(Clone)]
 pub struct Parser {
     current: Token,
     previous: Token,
@@ -41,10 +207,17 @@ pub struct Parser {
 pub enum Precedence {
     None,
     Assignment, // =
+    Ternary,    // ?:
+    Coalesce,   // ??
     Or,         // or
     And,        // and
     Equality,   // == !=
     Comparison, // < > <= >=
+    Range,      // .. ..=
+    BitOr,      // |
+    BitXor,     // ^
+    BitAnd,     // &
+    Shift,      // << >>
     Term,       // + -
     Factor,     // * /
     Unary,      // ! -
@@ -56,11 +229,18 @@ impl Precedence {
     pub fn next(&self) -> Precedence {
         match self {
             Precedence::None => Precedence::Assignment,
-            Precedence::Assignment => Precedence::Or,
+            Precedence::Assignment => Precedence::Ternary,
+            Precedence::Ternary => Precedence::Coalesce,
+            Precedence::Coalesce => Precedence::Or,
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
-            Precedence::Comparison => Precedence::Term,
+            Precedence::Comparison => Precedence::Range,
+            Precedence::Range => Precedence::BitOr,
+            Precedence::BitOr => Precedence::BitXor,
+            Precedence::BitXor => Precedence::BitAnd,
+            Precedence::BitAnd => Precedence::Shift,
+            Precedence::Shift => Precedence::Term,
             Precedence::Term => Precedence::Factor,
             Precedence::Factor => Precedence::Unary,
             Precedence::Unary => Precedence::Call,
@@ -80,8 +260,8 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
     // TOKEN_LEFT_PAREN
     ParseRule {
         prefix: Some(Compiler::grouping),
-        infix: None,
-        precedence: Precedence::None,
+        infix: Some(Compiler::call),
+        precedence: Precedence::Call,
     },
     // TOKEN_RIGHT_PAREN
     ParseRule {
@@ -91,7 +271,7 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
     },
     // TOKEN_LEFT_BRACE
     ParseRule {
-        prefix: None,
+        prefix: Some(Compiler::map),
         infix: None,
         precedence: Precedence::None,
     },
@@ -101,18 +281,30 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
         infix: None,
         precedence: Precedence::None,
     },
-    // TOKEN_COMMA
+    // TOKEN_LEFT_BRACKET
+    ParseRule {
+        prefix: Some(Compiler::list),
+        infix: Some(Compiler::index),
+        precedence: Precedence::Call,
+    },
+    // TOKEN_RIGHT_BRACKET
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // TOKEN_DOT
+    // TOKEN_COMMA
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
+    // TOKEN_DOT
+    ParseRule {
+        prefix: None,
+        infix: Some(Compiler::dot),
+        precedence: Precedence::Call,
+    },
     // TOKEN_MINUS
     ParseRule {
         prefix: Some(Compiler::unary),
@@ -143,6 +335,42 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
         infix: Some(Compiler::binary),
         precedence: Precedence::Factor,
     },
+    // TOKEN_QUESTION
+    ParseRule {
+        prefix: None,
+        infix: Some(Compiler::ternary),
+        precedence: Precedence::Ternary,
+    },
+    // TOKEN_COLON
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
+    // TOKEN_AMPERSAND
+    ParseRule {
+        prefix: None,
+        infix: Some(Compiler::binary),
+        precedence: Precedence::BitAnd,
+    },
+    // TOKEN_PIPE
+    ParseRule {
+        prefix: None,
+        infix: Some(Compiler::binary),
+        precedence: Precedence::BitOr,
+    },
+    // TOKEN_CARET
+    ParseRule {
+        prefix: None,
+        infix: Some(Compiler::binary),
+        precedence: Precedence::BitXor,
+    },
+    // TOKEN_TILDE
+    ParseRule {
+        prefix: Some(Compiler::unary),
+        infix: None,
+        precedence: Precedence::None,
+    },
     // TOKEN_BANG
     ParseRule {
         prefix: Some(Compiler::unary),
@@ -179,6 +407,12 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
         infix: Some(Compiler::binary),
         precedence: Precedence::Comparison,
     },
+    // TOKEN_GREATER_GREATER
+    ParseRule {
+        prefix: None,
+        infix: Some(Compiler::binary),
+        precedence: Precedence::Shift,
+    },
     // TOKEN_LESS
     ParseRule {
         prefix: None,
@@ -191,6 +425,36 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
         infix: Some(Compiler::binary),
         precedence: Precedence::Comparison,
     },
+    // TOKEN_LESS_LESS
+    ParseRule {
+        prefix: None,
+        infix: Some(Compiler::binary),
+        precedence: Precedence::Shift,
+    },
+    // TOKEN_DOT_DOT
+    ParseRule {
+        prefix: None,
+        infix: Some(Compiler::range),
+        precedence: Precedence::Range,
+    },
+    // TOKEN_DOT_DOT_EQUAL
+    ParseRule {
+        prefix: None,
+        infix: Some(Compiler::range),
+        precedence: Precedence::Range,
+    },
+    // TOKEN_DOT_DOT_DOT
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
+    // TOKEN_QUESTION_QUESTION
+    ParseRule {
+        prefix: None,
+        infix: Some(Compiler::coalesce),
+        precedence: Precedence::Coalesce,
+    },
     // TOKEN_IDENTIFIER
     ParseRule {
         prefix: Some(Compiler::variable),
@@ -213,6 +477,24 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
     ParseRule {
         prefix: None,
         infix: Some(Compiler::and_),
+        precedence: Precedence::And,
+    },
+    // TOKEN_ASSERT
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
+    // TOKEN_BREAK
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
+    // TOKEN_CATCH
+    ParseRule {
+        prefix: None,
+        infix: None,
         precedence: Precedence::None,
     },
     // TOKEN_CLASS
@@ -221,6 +503,24 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
         infix: None,
         precedence: Precedence::None,
     },
+    // TOKEN_CONST
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
+    // TOKEN_CONTINUE
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
+    // TOKEN_DO
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
     // TOKEN_ELSE
     ParseRule {
         prefix: None,
@@ -251,6 +551,12 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
         infix: None,
         precedence: Precedence::None,
     },
+    // TOKEN_IN
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
     // TOKEN_NIL
     ParseRule {
         prefix: Some(Compiler::literal),
@@ -260,13 +566,13 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
     // TOKEN_OR
     ParseRule {
         prefix: None,
-        infix: None,
-        precedence: Precedence::None,
+        infix: Some(Compiler::or_),
+        precedence: Precedence::Or,
     },
     // TOKEN_PRINT
     ParseRule {
         prefix: None,
-        infix: Some(Compiler::or_),
+        infix: None,
         precedence: Precedence::None,
     },
     // TOKEN_RETURN
@@ -282,6 +588,12 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
         precedence: Precedence::None,
     },
     // TOKEN_THIS
+    ParseRule {
+        prefix: Some(Compiler::this_),
+        infix: None,
+        precedence: Precedence::None,
+    },
+    // TOKEN_THROW
     ParseRule {
         prefix: None,
         infix: None,
@@ -293,6 +605,12 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
         infix: None,
         precedence: Precedence::None,
     },
+    // TOKEN_TRY
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
     // TOKEN_VAR
     ParseRule {
         prefix: None,
@@ -319,6 +637,30 @@ static RULES: [ParseRule; TokenType::Eof as usize + 1] = [
     },
 ];
 
+// what went wrong when an expression starting with `first` is followed
+// by '=' without being a variable
+pub fn invalid_target_message(first: &TokenType) -> &'static str {
+    match first {
+        TokenType::LeftParen => {
+            "Invalid assignment target: can't assign to a parenthesized expression."
+        }
+        TokenType::Number
+        | TokenType::String
+        | TokenType::True
+        | TokenType::False
+        | TokenType::Nil
+        | TokenType::LeftBracket => "Invalid assignment target: can't assign to a literal.",
+        _ => "Invalid assignment target.",
+    }
+}
+
+pub fn is_comparison(token_type: &TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::Less | TokenType::LessEqual | TokenType::Greater | TokenType::GreaterEqual
+    )
+}
+
 impl Parser {
     pub fn new() -> Self {
         Self {
@@ -327,6 +669,7 @@ impl Parser {
                 start: 0,
                 length: 0,
                 line: 0,
+                column: 0,
                 error_msg: None,
             },
             previous: Token {
@@ -334,6 +677,7 @@ impl Parser {
                 start: 0,
                 length: 0,
                 line: 0,
+                column: 0,
                 error_msg: None,
             },
             had_error: false,
@@ -348,9 +692,30 @@ impl Compiler {
             scanner: Scanner::init_scanner(source),
             parser: Parser::new(),
             compiling_chunk: Chunk::init_chunk(),
-            locals: Vec::with_capacity(STACK_MAX),
+            declared_globals: vec![],
+            constant_globals: HashSet::new(),
+            known_globals: HashSet::new(),
+            global_names: GlobalNames::default(),
+            strings: Interner::default(),
+            global_uses: vec![],
+            strict: false,
+            scan_time: Duration::ZERO,
+            optimize: false,
+            operator_methods: false,
+            function_writes: HashSet::new(),
+            late_functions: false,
+            finished_chunks: vec![],
+            can_split: false,
+            constant_overflow: false,
+            locals: vec![],
             local_count: 0,
             scope_depth: 0,
+            function_type: FunctionType::Script,
+            loops: vec![],
+            try_depth: 0,
+            enclosing: vec![],
+            errors: vec![],
+            warnings: vec![],
         }
     }
     /// single pass compilation
@@ -361,7 +726,8 @@ impl Compiler {
     /// Detailed Walkthrough:
     /// 1. Initialization:
     ///    - Source string "2 * 3 + 1" is passed to compiler
-    ///    - self.compiling_chunk gets a fresh chunk to store bytecode
+    ///    - self.compiling_chunk takes over the chunk passed in to store
+    ///      bytecode
     ///
     /// 2. self.advance() is called:
     ///    - Scanner reads first token "2"
@@ -376,18 +742,22 @@ impl Compiler {
     ///       - Gets prefix rule for NUMBER → calls number()
     ///       - number() converts "2" to constant and emits:
     ///         OP_CONSTANT 0 (where 0 is index in constants table)
+    ///
     ///    c) For token "*":
     ///       - Precedence check: Assignment < Factor, continue
     ///       - Advance() consumes "*"
     ///       - Gets infix rule → calls binary()
     ///       - binary() calls parse_precedence(Factor.next())
+    ///
     ///    d) For token "3":
     ///       - Same process as "2"
     ///       - Emits: OP_CONSTANT 1
     ///       - Returns to binary() which emits: OP_MULTIPLY
+    ///
     ///    e) For token "+":
     ///       - Precedence check: Assignment < Term, continue
     ///       - Process similar to "*"
+    ///
     ///    f) For token "1":
     ///       - Same as other numbers
     ///       - Emits: OP_CONSTANT 2
@@ -421,27 +791,159 @@ impl Compiler {
     ///   [7]            // After OP_ADD ((2 * 3) + 1)
     ///   []             // After OP_RETURN
     ///
+    /// Returns every chunk of the program in the order they run, the
+    /// one passed in first. Long programs come out as several, see
+    /// top_level_declaration.
+    ///
     /// Error Handling:
-    /// - Returns false if any parsing errors occurred
+    /// - Returns None if any parsing errors occurred
     /// - Error state tracked in parser.had_error
     /// - Continues compilation after errors to find more issues
     ///
-    pub fn compile(&mut self, source: &str, chunk: &Chunk) -> bool {
-        self.compiling_chunk = chunk.clone();
+    pub fn compile(&mut self, chunk: Chunk) -> Option<Vec<Chunk>> {
+        self.compiling_chunk = chunk;
+        // functions and later chunks copy the file, source and all
+        if let Some(file) = self.compiling_chunk.files.last_mut() {
+            file.source = Some(Rc::from(self.scanner.source.as_str()));
+        }
         self.advance();
+        // the pragma is an ordinary expression statement otherwise
+        if self.parser.current.token_type == TokenType::String
+            && self.lexeme(&self.parser.current) == "\"use strict\""
+        {
+            self.strict = true;
+        }
 
         while !self.match_token(TokenType::Eof) {
-            self.declaration();
+            self.top_level_declaration();
         }
         // self.expression();
         // self.consume(TokenType::Eof, "Expect end of expression.");
         self.end_compiler();
-        return !self.parser.had_error;
+        if self.parser.had_error {
+            return None;
+        }
+        self.check_undefined_globals();
+        if self.parser.had_error {
+            return None;
+        }
+        // found as scopes end, so inner ones came first
+        self.warnings
+            .sort_by_key(|warning| (warning.line, warning.column));
+        let mut chunks = std::mem::take(&mut self.finished_chunks);
+        chunks.push(std::mem::replace(
+            &mut self.compiling_chunk,
+            Chunk::init_chunk(),
+        ));
+        Some(chunks)
+    }
+
+    // compile `source` as a single expression with no trailing ';'.
+    // No OP_RETURN is emitted, so running the chunk leaves the value
+    // on top of the stack for the caller to pick up
+    pub fn compile_expression(&mut self) -> Option<Chunk> {
+        self.advance();
+        self.expression();
+        self.consume(TokenType::Eof, "Expect end of expression.");
+        if self.parser.had_error {
+            return None;
+        }
+        Some(std::mem::replace(
+            &mut self.compiling_chunk,
+            Chunk::init_chunk(),
+        ))
+    }
+
+    // Top-level code that outgrows a chunk continues in a new one, which
+    // the VM runs after it. A declaration whose constants don't fit in
+    // the current chunk is compiled again from its first token into an
+    // empty chunk, so a declaration is never spread over two chunks and
+    // its jumps stay inside one. A single declaration with more than 256
+    // constants loads the rest with OP_CONSTANT_LONG, see emit_constant.
+    // Chunks are also cut once their code passes CHUNK_CODE_LIMIT bytes
+    fn top_level_declaration(&mut self) {
+        if self.compiling_chunk.code.len() >= CHUNK_CODE_LIMIT {
+            self.split_chunk();
+        }
+        let checkpoint = self.checkpoint();
+        self.can_split = !self.compiling_chunk.constants.values.is_empty();
+        self.constant_overflow = false;
+        self.declaration();
+        self.can_split = false;
+
+        // errors have been reported already, compiling again would
+        // only report them twice
+        if self.constant_overflow && !self.parser.had_error {
+            self.restore(checkpoint);
+            self.split_chunk();
+            self.declaration();
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            scanner: (
+                self.scanner.start,
+                self.scanner.current,
+                self.scanner.line,
+                self.scanner.column,
+            ),
+            parser: self.parser.clone(),
+            code_len: self.compiling_chunk.code.len(),
+            constant_count: self.compiling_chunk.constants.values.len(),
+            declared_globals: self.declared_globals.len(),
+            global_uses: self.global_uses.len(),
+            warnings: self.warnings.len(),
+        }
+    }
+
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        (
+            self.scanner.start,
+            self.scanner.current,
+            self.scanner.line,
+            self.scanner.column,
+        ) = checkpoint.scanner;
+        self.parser = checkpoint.parser;
+        let chunk = &mut self.compiling_chunk;
+        chunk.code.truncate(checkpoint.code_len);
+        chunk.lines.truncate(checkpoint.code_len);
+        chunk.columns.truncate(checkpoint.code_len);
+        chunk.constants.values.truncate(checkpoint.constant_count);
+        // a constant declared by the declaration being undone can't
+        // have been one before, redeclaring it is an error
+        for name in self.declared_globals.drain(checkpoint.declared_globals..) {
+            self.constant_globals.remove(&name);
+        }
+        // they will be found again
+        self.global_uses.truncate(checkpoint.global_uses);
+        self.warnings.truncate(checkpoint.warnings);
+    }
+
+    // end the current top-level chunk and carry on in a new one
+    fn split_chunk(&mut self) {
+        self.emit_return();
+        self.finish_chunk();
+        let mut chunk = Chunk::init_chunk();
+        if let Some(file) = self.compiling_chunk.files.last() {
+            chunk.files.push(SourceFile {
+                start: 0,
+                ..file.clone()
+            });
+        }
+        let finished = std::mem::replace(&mut self.compiling_chunk, chunk);
+        self.finished_chunks.push(finished);
     }
 
     pub fn declaration(&mut self) {
-        if self.match_token(TokenType::Var) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
             self.var_declaration();
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration();
         } else {
             self.statement();
         }
@@ -451,6 +953,171 @@ impl Compiler {
         }
     }
 
+    pub fn class_declaration(&mut self) {
+        let global = self.parse_variable("Expect class name.");
+        let class_name = self.parser.previous.clone();
+        let name_constant = self.identifier_constant(class_name.clone());
+
+        self.emit_bytes(OpCode::OP_CLASS as u8, name_constant);
+        self.define_variable(global);
+
+        // the class goes back on the stack while its methods are added
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::OP_POP as u8);
+    }
+
+    pub fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.parser.previous.clone();
+        let constant = self.identifier_constant(name.clone());
+        let lexeme = self.lexeme(&name).to_string();
+        if OPERATOR_METHODS.iter().any(|(_, method)| *method == lexeme) {
+            self.operator_methods = true;
+        }
+
+        let function_type = if lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_bytes(OpCode::OP_METHOD as u8, constant);
+    }
+
+    pub fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // a local function is initialized as soon as it is declared
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    // compiles the parameters and body that follow a function's name
+    // into a chunk of their own and loads the finished function
+    pub fn function(&mut self, function_type: FunctionType) {
+        let name = self.lexeme(&self.parser.previous).to_string();
+        self.begin_function(function_type);
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        let mut arity = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                arity += 1;
+                if arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.".to_string());
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let chunk = self.end_function(&name);
+        let function = ObjFunction {
+            name,
+            arity,
+            chunk: Rc::new(chunk),
+        };
+        self.emit_constant(Value::Object(Obj {
+            obj_type: ObjType::ObjFunction(Rc::new(function)),
+        }));
+    }
+
+    fn begin_function(&mut self, function_type: FunctionType) {
+        let mut chunk = Chunk::init_chunk();
+        if let Some(file) = self.compiling_chunk.files.last() {
+            chunk.files.push(SourceFile {
+                start: 0,
+                ..file.clone()
+            });
+        }
+        let enclosing = FunctionState {
+            chunk: std::mem::replace(&mut self.compiling_chunk, chunk),
+            locals: std::mem::take(&mut self.locals),
+            local_count: self.local_count,
+            scope_depth: self.scope_depth,
+            function_type: self.function_type,
+            loops: std::mem::take(&mut self.loops),
+            try_depth: std::mem::take(&mut self.try_depth),
+        };
+        self.enclosing.push(enclosing);
+        self.function_type = function_type;
+        self.scope_depth = 0;
+        // slot 0 holds the function being called, or `this` in methods.
+        // Its name can't match any identifier
+        self.locals = vec![Local {
+            name: Token {
+                token_type: TokenType::This,
+                start: 0,
+                length: 0,
+                line: self.parser.previous.line,
+                column: 0,
+                error_msg: None,
+            },
+            depth: 0,
+            constant: false,
+            used: true,
+        }];
+        self.local_count = 1;
+    }
+
+    // finishes the current function and returns its chunk
+    fn end_function(&mut self, _name: &str) -> Chunk {
+        self.emit_return();
+        self.finish_chunk();
+        #[cfg(feature = "debug_print_code")]
+        if !self.parser.had_error {
+            self.compiling_chunk.disassemble_chunk(_name);
+        }
+        // the parameters and the body's own locals, slot 0 is hidden
+        for local in 1..self.local_count {
+            self.check_unused(local);
+        }
+        let enclosing = self.enclosing.pop().unwrap();
+        self.locals = enclosing.locals;
+        self.local_count = enclosing.local_count;
+        self.scope_depth = enclosing.scope_depth;
+        self.function_type = enclosing.function_type;
+        self.loops = enclosing.loops;
+        self.try_depth = enclosing.try_depth;
+        std::mem::replace(&mut self.compiling_chunk, enclosing.chunk)
+    }
+
+    // the current chunk has all its code, so its jumps are patched and
+    // the peephole pass can move things around
+    fn finish_chunk(&mut self) {
+        if self.optimize && !self.parser.had_error {
+            peephole::optimize(&mut self.compiling_chunk);
+        }
+    }
+
+    pub fn return_statement(&mut self) {
+        if self.function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.".to_string());
+        }
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+            return;
+        }
+        if self.function_type == FunctionType::Initializer {
+            self.error("Can't return a value from an initializer.".to_string());
+        }
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+        self.emit_byte(OpCode::OP_RETURN as u8);
+    }
+
     pub fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
         if self.match_token(TokenType::Equal) {
@@ -465,24 +1132,74 @@ impl Compiler {
         self.define_variable(global);
     }
 
-    pub fn parse_variable(&mut self, error_msg: &str) -> u8 {
+    // `const name = value;`, a variable that can't be assigned again
+    pub fn const_declaration(&mut self) {
+        let global = self.parse_variable("Expect constant name.");
+        let name = self.parser.previous.clone();
+        if self.scope_depth > 0 {
+            self.locals[self.local_count - 1].constant = true;
+        } else {
+            self.constant_globals.insert(self.identifier_name(&name));
+        }
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
+        self.define_variable(global);
+    }
+
+    pub fn parse_variable(&mut self, error_msg: &str) -> usize {
         self.consume(TokenType::Identifier, error_msg);
 
         self.declare_variable();
-        // Return 0 for locals since they don't need an index in the constants table
+        // Return 0 for locals since they don't need a global slot
         if self.scope_depth > 0 {
             return 0;
         }
 
-        return self.identifier_constant(self.parser.previous.clone());
+        let name = self.parser.previous.clone();
+        let global = self.identifier_name(&name);
+        if self.constant_globals.contains(&global) {
+            self.error(format!(
+                "Can't redeclare constant '{}'.",
+                self.lexeme(&name)
+            ));
+        }
+        self.declared_globals.push(global.clone());
+        return self.global_names.slot(global);
+    }
+
+    pub fn identifier_name(&self, name: &Token) -> ObjType {
+        ObjType::ObjString(ObjString::new(self.lexeme(name).to_string()))
+    }
+
+    pub fn lexeme(&self, token: &Token) -> &str {
+        &self.scanner.source[token.start..token.start + token.length]
     }
 
     pub fn identifier_constant(&mut self, name: Token) -> u8 {
-        self.make_constant(Value::Object(Obj {
-            obj_type: ObjType::ObjString(ObjString::new(
-                self.scanner.source[name.start..name.start + name.length].to_string(),
-            )),
-        }))
+        let value = self.source_string(name.start, name.start + name.length);
+        // a name the chunk already holds reuses its constant, so reading
+        // the same fields over and over doesn't use up the one byte
+        // operands
+        let existing = self.compiling_chunk.constants.values[..]
+            .iter()
+            .take(u8::MAX as usize + 1)
+            .position(|constant| constant.values_equal(&value));
+        match existing {
+            Some(constant) => constant as u8,
+            None => self.make_constant(value),
+        }
+    }
+
+    // source[start..end] as a string from the intern pool
+    fn source_string(&mut self, start: usize, end: usize) -> Value {
+        let id = self.strings.intern_str(&self.scanner.source[start..end]);
+        Value::Object(Obj {
+            obj_type: ObjType::ObjString(self.strings.get(id).clone()),
+        })
     }
 
     pub fn declare_variable(&mut self) {
@@ -505,16 +1222,50 @@ impl Compiler {
             }
         }
 
+        self.check_shadowing(&name);
         self.add_local(name);
     }
 
-    pub fn identifiers_equal(&self, a: &Token, b: &Token) -> bool {
-        println!(
-            "a: {:?} b: {:?}",
-            self.scanner.source[a.start..a.start + a.length].to_string(),
-            self.scanner.source[b.start..b.start + b.length].to_string()
+    // warn about a new local hiding a variable of the same name, a
+    // local of an enclosing block or a global, so the code in its scope
+    // can't get at the other one
+    fn check_shadowing(&mut self, name: &Token) {
+        let outer = self.outer_local(name).cloned();
+        let global = self.identifier_name(name);
+        let shadowed = match outer {
+            Some(_) => "a local variable of an enclosing block",
+            None if self.declared_globals.contains(&global)
+                || self.known_globals.contains(&global) =>
+            {
+                "a global variable"
+            }
+            None => return,
+        };
+        let message = format!(
+            "Local variable '{}' shadows {}.",
+            self.lexeme(name),
+            shadowed
         );
-        println!("a.start: {:?} b.start: {:?}", a.start, b.start);
+        let mut warning = Diagnostic::warning(&message)
+            .at(&self.file_name(), name.line)
+            .at_column(name.column)
+            .with_label(name.start, name.start + name.length, "");
+        if let Some(outer) = outer {
+            warning = warning.with_label(outer.start, outer.start + outer.length, "shadowed");
+        }
+        self.warnings.push(warning);
+    }
+
+    // the local `name` refers to so far, if any
+    fn outer_local(&self, name: &Token) -> Option<&Token> {
+        self.locals[..self.local_count]
+            .iter()
+            .rev()
+            .map(|local| &local.name)
+            .find(|local| self.identifiers_equal(name, local))
+    }
+
+    pub fn identifiers_equal(&self, a: &Token, b: &Token) -> bool {
         a.length == b.length
             && self.scanner.source[a.start..a.start + a.length]
                 == self.scanner.source[b.start..b.start + b.length]
@@ -528,8 +1279,10 @@ impl Compiler {
 
         // Create new local
         let local = Local {
-            name: name,
+            name,
             depth: -1, // Will be set to proper depth when initialized
+            constant: false,
+            used: false,
         };
 
         // If vector is full, push to expand it
@@ -543,12 +1296,12 @@ impl Compiler {
         self.local_count += 1;
     }
 
-    pub fn define_variable(&mut self, global: u8) {
+    pub fn define_variable(&mut self, global: usize) {
         if self.scope_depth > 0 {
             self.mark_initialized();
             return; // Local variables don't need the define instruction
         }
-        self.emit_bytes(OpCode::OP_DEFINE_GLOBAL as u8, global);
+        self.emit_variable(OpCode::OP_DEFINE_GLOBAL_SLOT, global);
     }
 
     pub fn mark_initialized(&mut self) {
@@ -570,10 +1323,14 @@ impl Compiler {
                 TokenType::Class
                 | TokenType::Fun
                 | TokenType::Var
+                | TokenType::Const
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
+                | TokenType::Try
+                | TokenType::Throw
+                | TokenType::Assert
                 | TokenType::Return => return,
                 _ => {}
             }
@@ -589,8 +1346,22 @@ impl Compiler {
             self.for_statement();
         } else if self.match_token(TokenType::If) {
             self.if_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_statement();
         } else if self.match_token(TokenType::While) {
             self.while_statement();
+        } else if self.match_token(TokenType::Do) {
+            self.do_statement();
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement();
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.match_token(TokenType::Assert) {
+            self.assert_statement();
         } else if self.match_token(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -603,9 +1374,15 @@ impl Compiler {
     pub fn for_statement(&mut self) {
         self.begin_scope();
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
-        if self.match_token(TokenType::Semicolon) {
+        let has_var = self.match_token(TokenType::Var);
+        if self.check(TokenType::Identifier) && self.next_is(TokenType::In) {
+            self.for_in_statement();
+            self.end_scope();
+            return;
+        }
+        if !has_var && self.match_token(TokenType::Semicolon) {
             // No initializer
-        } else if self.match_token(TokenType::Var) {
+        } else if has_var {
             // Var declaration
             self.var_declaration();
         } else {
@@ -613,6 +1390,8 @@ impl Compiler {
             self.expression_statement();
         }
         let mut loop_start = self.compiling_chunk.code.len();
+        let loop_top = loop_start;
+        let known_globals = self.declared_globals.len();
         let mut exit_jump = 0; // TODO: probably should somehow set to -1
 
         // Condition
@@ -637,6 +1416,7 @@ impl Compiler {
         }
 
         // Body
+        self.begin_loop(Some(loop_start));
         self.statement();
         self.emit_loop(loop_start);
 
@@ -645,29 +1425,401 @@ impl Compiler {
             self.patch_jump(exit_jump);
             self.emit_byte(OpCode::OP_POP as u8); // Condition
         }
+        self.end_loop();
+        if self.optimize {
+            self.hoist_loop_globals(loop_top, known_globals);
+        }
 
         self.end_scope();
     }
 
+    // for (x in collection) body, with or without `var` before x. x is a
+    // new local of the loop either way. The collection and how far the
+    // loop has got live in two hidden locals right below x, so
+    // OP_FOR_ITER finds all three from the first one's slot
+    fn for_in_statement(&mut self) {
+        self.consume(TokenType::Identifier, "Expect loop variable name.");
+        let name = self.parser.previous.clone();
+        self.consume(TokenType::In, "Expect 'in' after loop variable.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after for-in collection.");
+
+        let slot = self.local_count;
+        // OP_FOR_ITER's slot operand is a single byte
+        if slot + 3 > u8::MAX as usize + 1 {
+            self.error("Too many local variables before for-in loop.".to_string());
+        }
+        self.add_hidden_local();
+        self.emit_constant(Value::Int(0));
+        self.add_hidden_local();
+        self.emit_byte(OpCode::OP_NIL as u8);
+        self.add_local(name);
+        self.mark_initialized();
+
+        let loop_start = self.compiling_chunk.code.len();
+        let known_globals = self.declared_globals.len();
+        self.emit_bytes(OpCode::OP_FOR_ITER as u8, slot as u8);
+        self.emit_bytes(0xff, 0xff);
+        let exit_jump = self.compiling_chunk.code.len() - 2;
+
+        self.begin_loop(Some(loop_start));
+        self.statement();
+        self.emit_loop(loop_start);
+        self.patch_jump(exit_jump);
+        self.end_loop();
+        if self.optimize {
+            self.hoist_loop_globals(loop_start, known_globals);
+        }
+    }
+
+    // a local the compiler uses itself. Its name can't match any
+    // identifier
+    fn add_hidden_local(&mut self) {
+        self.add_local(Token {
+            token_type: TokenType::Identifier,
+            start: 0,
+            length: 0,
+            line: self.parser.previous.line,
+            column: 0,
+            error_msg: None,
+        });
+        self.mark_initialized();
+    }
+
+    // whether the token after the current one is `token_type`. Nothing
+    // is consumed
+    fn next_is(&mut self, token_type: TokenType) -> bool {
+        let saved = (self.scanner.start, self.scanner.current, self.scanner.line);
+        let next = self.scanner.scan_token();
+        (self.scanner.start, self.scanner.current, self.scanner.line) = saved;
+        next.token_type == token_type
+    }
+
     pub fn while_statement(&mut self) {
         let loop_start = self.compiling_chunk.code.len();
+        let known_globals = self.declared_globals.len();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
         let exit_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE as u8);
         self.emit_byte(OpCode::OP_POP as u8);
+        self.begin_loop(Some(loop_start));
         self.statement();
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit_byte(OpCode::OP_POP as u8);
+        self.end_loop();
+        if self.optimize {
+            self.hoist_loop_globals(loop_start, known_globals);
+        }
+    }
+
+    fn begin_loop(&mut self, start: Option<usize>) {
+        self.loops.push(Loop {
+            scope_depth: self.scope_depth,
+            try_depth: self.try_depth,
+            start,
+            breaks: vec![],
+            continues: vec![],
+        });
+    }
+
+    // the loop's breaks land here, in front of the pops of any hidden
+    // locals hoist_loop_globals adds
+    fn end_loop(&mut self) {
+        let finished = self.loops.pop().unwrap();
+        for jump in finished.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    pub fn break_statement(&mut self) {
+        if self.loops.is_empty() {
+            self.error("Can't use 'break' outside of a loop.".to_string());
+            self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+            return;
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+        self.leave_loop_body();
+        let jump = self.emit_jump(OpCode::OP_JUMP as u8);
+        self.loops.last_mut().unwrap().breaks.push(jump);
+    }
+
+    pub fn continue_statement(&mut self) {
+        let Some(start) = self.loops.last().map(|l| l.start) else {
+            self.error("Can't use 'continue' outside of a loop.".to_string());
+            self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+            return;
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+        self.leave_loop_body();
+        match start {
+            Some(start) => self.emit_loop(start),
+            None => {
+                let jump = self.emit_jump(OpCode::OP_JUMP as u8);
+                self.loops.last_mut().unwrap().continues.push(jump);
+            }
+        }
+    }
+
+    // The handlers of try blocks inside the innermost loop's body and
+    // the body's locals are still there when break or continue jumps out.
+    // The locals stay declared for the code after the jump
+    fn leave_loop_body(&mut self) {
+        let innermost = self.loops.last().unwrap();
+        let scope_depth = innermost.scope_depth;
+        for _ in innermost.try_depth..self.try_depth {
+            self.emit_byte(OpCode::OP_END_TRY as u8);
+        }
+        let mut local = self.local_count;
+        while local > 0 && self.locals[local - 1].depth > scope_depth {
+            self.emit_byte(OpCode::OP_POP as u8);
+            local -= 1;
+        }
+    }
+
+    // try { ... } catch (name) { ... }
+    // A runtime error or throw in the try block, or in anything it calls,
+    // unwinds the stack back to where the try started and runs the catch
+    // block with the thrown value, or the error message, in `name`
+    pub fn try_statement(&mut self) {
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+        let handler = self.emit_jump(OpCode::OP_TRY as u8);
+        self.try_depth += 1;
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.try_depth -= 1;
+        self.emit_byte(OpCode::OP_END_TRY as u8);
+        let end_jump = self.emit_jump(OpCode::OP_JUMP as u8);
+
+        // the VM pushes the thrown value, it becomes the catch variable
+        self.patch_jump(handler);
+        self.consume(TokenType::Catch, "Expect 'catch' after try block.");
+        self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+        self.begin_scope();
+        self.parse_variable("Expect exception variable name.");
+        self.mark_initialized();
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after exception variable.",
+        );
+        self.consume(TokenType::LeftBrace, "Expect '{' before catch block.");
+        self.block();
+        self.end_scope();
+        self.patch_jump(end_jump);
+    }
+
+    pub fn throw_statement(&mut self) {
+        let keyword = self.parser.previous.clone();
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        self.emit_byte_at(OpCode::OP_THROW as u8, &keyword);
+    }
+
+    // assert(condition); or assert(condition, message);
+    // The message is only evaluated when the condition is falsey. The
+    // failure is a runtime error, so a try block can catch it; without a
+    // message it shows the condition's source
+    pub fn assert_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'assert'.");
+        let condition_start = self.parser.current.start;
+        self.expression();
+        let condition_end = self.parser.previous.start + self.parser.previous.length;
+        let fail_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE as u8);
+        self.emit_byte(OpCode::OP_POP as u8);
+        let end_jump = self.emit_jump(OpCode::OP_JUMP as u8);
+
+        self.patch_jump(fail_jump);
+        self.emit_byte(OpCode::OP_POP as u8);
+        if self.match_token(TokenType::Comma) {
+            self.expression();
+        } else {
+            let condition = self.source_string(condition_start, condition_end);
+            self.emit_constant(condition);
+        }
+        self.emit_byte(OpCode::OP_ASSERT_FAILED as u8);
+        self.patch_jump(end_jump);
+        self.consume(TokenType::RightParen, "Expect ')' after assertion.");
+        self.consume(TokenType::Semicolon, "Expect ';' after assertion.");
+    }
+
+    // do body while (condition); The body runs once before the
+    // condition is first checked
+    pub fn do_statement(&mut self) {
+        let loop_start = self.compiling_chunk.code.len();
+        let known_globals = self.declared_globals.len();
+        self.begin_loop(None);
+        self.statement();
+
+        for jump in std::mem::take(&mut self.loops.last_mut().unwrap().continues) {
+            self.patch_jump(jump);
+        }
+        self.consume(TokenType::While, "Expect 'while' after do body.");
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        self.consume(TokenType::Semicolon, "Expect ';' after do-while condition.");
+
+        let exit_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE as u8);
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.end_loop();
+        if self.optimize {
+            self.hoist_loop_globals(loop_start, known_globals);
+        }
+    }
+
+    // Loop-invariant global reads. Called once a loop has been compiled:
+    // every global the loop reads but never assigns, and that a top-level
+    // `var` earlier in this program has already defined, is loaded once
+    // into a hidden local in front of the loop, and its
+    // OP_GET_GLOBAL_SLOTs inside the loop become reads of that local.
+    //
+    // The loads are spliced in at `loop_start`. Jumps are relative and
+    // none cross that point backwards, so only local slots need fixing:
+    // locals declared inside the loop move up past the hidden ones.
+    // Jumps of enclosing statements that are still open get patched
+    // later, so they already see the longer code.
+    //
+    // Only the first `known_globals` entries of declared_globals count,
+    // as those were defined before the loop started. That way a read of
+    // a global that might not exist yet still fails where it is written.
+    //
+    // Code the loop calls could assign a global behind its back, and so
+    // could operators that may end up in an operator method. Only
+    // function bodies assign globals from somewhere else, and a top-level
+    // loop can only reach functions compiled before it ends, so there a
+    // call keeps just the globals in function_writes from being hoisted.
+    // Loops inside a function may run after more functions have been
+    // compiled, so those are left alone if they call anything.
+    fn hoist_loop_globals(&mut self, loop_start: usize, known_globals: usize) {
+        let chunk = &self.compiling_chunk;
+        let mut calls = false;
+        let mut offset = loop_start;
+        while offset < chunk.code.len() {
+            let op = chunk.code[offset];
+            calls |= op == OpCode::OP_CALL as u8
+                || op == OpCode::OP_SPREAD_CALL as u8
+                || (self.operator_methods && operator_method(op).is_some());
+            offset += chunk.instruction_len(offset);
+        }
+        if calls && (self.late_functions || !self.enclosing.is_empty()) {
+            return;
+        }
+
+        let first_slot = self.local_count;
+        let mut assigned = HashSet::new();
+        let mut reads: Vec<usize> = vec![];
+        let mut max_slot = first_slot;
+        // the two byte operand of the instruction at `offset`
+        let long_operand = |code: &[u8], offset: usize| {
+            (code[offset + 1] as usize) << 8 | code[offset + 2] as usize
+        };
+
+        let mut offset = loop_start;
+        while offset < chunk.code.len() {
+            let op = chunk.code[offset];
+            if op == OpCode::OP_GET_GLOBAL_SLOT as u8 {
+                let global = long_operand(&chunk.code, offset);
+                if !reads.contains(&global) {
+                    reads.push(global);
+                }
+            } else if op == OpCode::OP_SET_GLOBAL_SLOT as u8 {
+                assigned.insert(long_operand(&chunk.code, offset));
+            } else if op == OpCode::OP_GET_LOCAL as u8 || op == OpCode::OP_SET_LOCAL as u8 {
+                max_slot = max_slot.max(chunk.code[offset + 1] as usize + 1);
+            } else if op == OpCode::OP_GET_LOCAL_LONG as u8 || op == OpCode::OP_SET_LOCAL_LONG as u8
+            {
+                // past 255 means nothing gets hoisted below
+                max_slot = max_slot.max(long_operand(&chunk.code, offset) + 1);
+            } else if op == OpCode::OP_FOR_ITER as u8 {
+                // the collection's slot and the two above it
+                max_slot = max_slot.max(chunk.code[offset + 1] as usize + 3);
+            }
+            offset += chunk.instruction_len(offset);
+        }
+
+        // a call may assign whatever some function assigns
+        if calls {
+            assigned.extend(&self.function_writes);
+        }
+        let hoisted: Vec<usize> = reads
+            .into_iter()
+            .filter(|global| {
+                !assigned.contains(global)
+                    && self
+                        .global_names
+                        .name(*global)
+                        .is_some_and(|name| self.declared_globals[..known_globals].contains(name))
+            })
+            .collect();
+        // every slot still has to fit in a one byte operand
+        if hoisted.is_empty() || max_slot + hoisted.len() > u8::MAX as usize + 1 {
+            return;
+        }
+
+        // a read of a hoisted global becomes OP_GET_LOCAL_LONG, which is
+        // as long as the instruction it replaces. The long form is taken
+        // apart like the short one when an enclosing loop is hoisted
+        let chunk = &mut self.compiling_chunk;
+        let mut offset = loop_start;
+        while offset < chunk.code.len() {
+            let op = chunk.code[offset];
+            if op == OpCode::OP_GET_GLOBAL_SLOT as u8 {
+                let global = long_operand(&chunk.code, offset);
+                if let Some(i) = hoisted.iter().position(|hoisted| *hoisted == global) {
+                    chunk.code[offset] = OpCode::OP_GET_LOCAL_LONG as u8;
+                    chunk.code[offset + 1] = 0;
+                    chunk.code[offset + 2] = (first_slot + i) as u8;
+                }
+            } else if (op == OpCode::OP_GET_LOCAL as u8
+                || op == OpCode::OP_SET_LOCAL as u8
+                || op == OpCode::OP_FOR_ITER as u8)
+                && chunk.code[offset + 1] as usize >= first_slot
+            {
+                chunk.code[offset + 1] += hoisted.len() as u8;
+            } else if (op == OpCode::OP_GET_LOCAL_LONG as u8
+                || op == OpCode::OP_SET_LOCAL_LONG as u8)
+                && long_operand(&chunk.code, offset) >= first_slot
+            {
+                // below 256, see max_slot
+                chunk.code[offset + 2] += hoisted.len() as u8;
+            }
+            offset += chunk.instruction_len(offset);
+        }
+
+        let line = chunk.lines[loop_start];
+        let loads: Vec<u8> = hoisted
+            .iter()
+            .flat_map(|global| {
+                [
+                    OpCode::OP_GET_GLOBAL_SLOT as u8,
+                    (global >> 8) as u8,
+                    *global as u8,
+                ]
+            })
+            .collect();
+        let column = chunk.columns[loop_start];
+        chunk.lines.splice(loop_start..loop_start, vec![line; loads.len()]);
+        chunk.columns.splice(loop_start..loop_start, vec![column; loads.len()]);
+        chunk.code.splice(loop_start..loop_start, loads);
+
+        // the hidden locals go away with the loop
+        for _ in &hoisted {
+            self.emit_byte(OpCode::OP_POP as u8);
+        }
     }
 
     pub fn emit_loop(&mut self, loop_start: usize) {
         self.emit_byte(OpCode::OP_LOOP as u8);
         let offset = self.compiling_chunk.code.len() - loop_start + 2;
-        if offset > std::u16::MAX as usize {
+        if offset > u16::MAX as usize {
             self.error("Loop body too large.".to_string());
         }
         self.emit_byte(((offset >> 8) & 0xff) as u8);
@@ -706,13 +1858,21 @@ impl Compiler {
     }
 
     pub fn or_(&mut self, _can_assign: bool) {
-        let else_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE as u8);
-        let end_jump = self.emit_jump(OpCode::OP_JUMP as u8);
-        self.patch_jump(else_jump);
+        let end_jump = self.emit_jump(OpCode::OP_JUMP_IF_TRUE as u8);
         self.emit_byte(OpCode::OP_POP as u8);
         self.parse_precedence(Precedence::Or);
         self.patch_jump(end_jump);
     }
+
+    // a ?? b is a unless a is nil. Like and/or, b is only evaluated when
+    // it is needed
+    pub fn coalesce(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::OP_JUMP_IF_NOT_NIL as u8);
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.parse_precedence(Precedence::Coalesce);
+        self.patch_jump(end_jump);
+    }
+
     // The first emits a bytecode instruction and writes a placeholder operand for the jump offset.
     // We pass in the opcode as an argument because later we’ll have two different instructions that
     // use this helper. We use two bytes for the jump offset operand. A 16-bit offset lets us jump
@@ -730,6 +1890,9 @@ impl Compiler {
     pub fn patch_jump(&mut self, offset: usize) {
         // -2 to adjust for the bytecode for the jump offset itself.
         let jump = self.compiling_chunk.code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.".to_string());
+        }
         self.compiling_chunk.code[offset] = ((jump >> 8) & 0xff) as u8;
         self.compiling_chunk.code[offset + 1] = (jump & 0xff) as u8;
     }
@@ -750,11 +1913,67 @@ impl Compiler {
 
         // Pop locals from the stack that are going out of scope
         while self.local_count > 0 && self.locals[self.local_count - 1].depth > self.scope_depth {
+            self.check_unused(self.local_count - 1);
             self.emit_byte(OpCode::OP_POP as u8);
             self.local_count -= 1;
         }
     }
 
+    // warn about globals that are read but neither declared by this
+    // program nor there before it, most likely a typo. Code that only
+    // runs after some other program defines the name is warned about
+    // too. In strict mode assigning one is an error
+    fn check_undefined_globals(&mut self) {
+        let declared: HashSet<&ObjType> = self.declared_globals.iter().collect();
+        let mut warnings = vec![];
+        let mut errors = vec![];
+        for (global, name, assigned) in &self.global_uses {
+            if declared.contains(global) || self.known_globals.contains(global) {
+                continue;
+            }
+            if *assigned {
+                if self.strict {
+                    errors.push(name.clone());
+                }
+                continue;
+            }
+            let warning =
+                Diagnostic::warning(&format!("Undefined variable '{}'.", self.lexeme(name)))
+                    .at(&self.file_name(), name.line)
+                    .at_column(name.column)
+                    .with_label(name.start, name.start + name.length, "")
+                    .with_note("no global with this name is declared");
+            warnings.push(warning);
+        }
+        self.warnings.extend(warnings);
+        for name in errors {
+            let message = format!(
+                "Can't assign to undeclared variable '{}' in strict mode.",
+                self.lexeme(&name)
+            );
+            // each is an error of its own, not a cascade of the last
+            self.parser.panic_mode = false;
+            self.error_at(name, message);
+        }
+    }
+
+    // warn about a local going out of scope that nothing read. Names
+    // starting with '_' are unused on purpose, and the compiler's own
+    // locals have no name
+    fn check_unused(&mut self, index: usize) {
+        let local = &self.locals[index];
+        let name = self.lexeme(&local.name);
+        if local.used || name.is_empty() || name.starts_with('_') {
+            return;
+        }
+        let warning = Diagnostic::warning(&format!("Unused local variable '{}'.", name))
+            .at(&self.file_name(), local.name.line)
+            .at_column(local.name.column)
+            .with_label(local.name.start, local.name.start + local.name.length, "")
+            .with_note(&format!("if this is intentional, name it '_{}'", name));
+        self.warnings.push(warning);
+    }
+
     pub fn variable(&mut self, can_assign: bool) {
         self.named_variable(self.parser.previous.clone(), can_assign);
     }
@@ -762,32 +1981,102 @@ impl Compiler {
     pub fn named_variable(&mut self, name: Token, can_assign: bool) {
         let arg = self.resolve_local(&name);
 
-        println!("arg: {}", arg);
+        if arg == -1 && self.is_enclosing_local(&name) {
+            self.error(format!(
+                "Can't use local variable '{}' of an enclosing function; there are no closures.",
+                self.lexeme(&name)
+            ));
+        }
+
+        let constant = if arg != -1 {
+            self.locals[arg as usize].constant
+        } else {
+            !self.constant_globals.is_empty()
+                && self.constant_globals.contains(&self.identifier_name(&name))
+        };
+        let constant_error =
+            constant.then(|| format!("Can't assign to constant '{}'.", self.lexeme(&name)));
 
         let (get_op, set_op, index) = if arg != -1 {
-            (OpCode::OP_GET_LOCAL, OpCode::OP_SET_LOCAL, arg as u8)
+            (OpCode::OP_GET_LOCAL, OpCode::OP_SET_LOCAL, arg as usize)
         } else {
             (
-                OpCode::OP_GET_GLOBAL,
-                OpCode::OP_SET_GLOBAL,
-                self.identifier_constant(name),
+                OpCode::OP_GET_GLOBAL_SLOT,
+                OpCode::OP_SET_GLOBAL_SLOT,
+                self.global_names.slot(self.identifier_name(&name)),
             )
         };
 
         if can_assign && self.match_token(TokenType::Equal) {
+            if let Some(message) = constant_error {
+                self.error(message);
+            }
+            if arg == -1 {
+                self.global_uses
+                    .push((self.identifier_name(&name), name.clone(), true));
+                if !self.enclosing.is_empty() {
+                    self.function_writes.insert(index);
+                }
+            }
             self.expression();
-            self.emit_bytes(set_op as u8, index);
+            self.emit_variable(set_op, index);
         } else {
-            self.emit_bytes(get_op as u8, index);
+            if arg != -1 {
+                self.locals[arg as usize].used = true;
+            } else {
+                self.global_uses
+                    .push((self.identifier_name(&name), name, false));
+            }
+            self.emit_variable(get_op, index);
+        }
+    }
+
+    // `op` with a local's or a global's slot as operand. Local slots
+    // past 255 take the long form of the local instructions, global
+    // slots always have two bytes
+    fn emit_variable(&mut self, op: OpCode, index: usize) {
+        let long_op = match op {
+            OpCode::OP_GET_LOCAL => OpCode::OP_GET_LOCAL_LONG,
+            OpCode::OP_SET_LOCAL => OpCode::OP_SET_LOCAL_LONG,
+            _ => {
+                if index > u16::MAX as usize {
+                    return self.error("Too many global variables.".to_string());
+                }
+                self.emit_byte(op as u8);
+                return self.emit_bytes((index >> 8) as u8, index as u8);
+            }
+        };
+        match u8::try_from(index) {
+            Ok(slot) => self.emit_bytes(op as u8, slot),
+            Err(_) => {
+                self.emit_byte(long_op as u8);
+                self.emit_bytes((index >> 8) as u8, index as u8);
+            }
+        }
+    }
+
+    fn is_enclosing_local(&self, name: &Token) -> bool {
+        self.enclosing.iter().any(|function| {
+            function.locals[..function.local_count]
+                .iter()
+                .any(|local| self.identifiers_equal(name, &local.name))
+        })
+    }
+
+    // `this` always lives in slot 0 of a method
+    pub fn this_(&mut self, _can_assign: bool) {
+        match self.function_type {
+            FunctionType::Method | FunctionType::Initializer => {
+                self.emit_bytes(OpCode::OP_GET_LOCAL as u8, 0);
+            }
+            _ => self.error("Can't use 'this' outside of a method.".to_string()),
         }
     }
 
     pub fn resolve_local(&mut self, name: &Token) -> i32 {
         // Search locals from right to left (most recently declared first)
-        println!("Locals {:?}", self.locals);
         for i in (0..self.local_count).rev() {
             let local = &self.locals[i];
-            println!("name {:?} local.name {:?}", name, local.name);
             if self.identifiers_equal(name, &local.name) {
                 if local.depth == -1 {
                     self.error("Cannot read local variable in its own initializer.".to_string());
@@ -829,7 +2118,9 @@ impl Compiler {
     pub fn advance(&mut self) {
         self.parser.previous = self.parser.current.clone();
         loop {
+            let scan_start = Instant::now();
             self.parser.current = self.scanner.scan_token();
+            self.scan_time += scan_start.elapsed();
             if self.parser.current.token_type != TokenType::Error {
                 break;
             }
@@ -854,6 +2145,14 @@ impl Compiler {
         self.error_at(self.parser.previous.clone(), message);
     }
 
+    // the file being compiled, for errors and warnings
+    fn file_name(&self) -> String {
+        match self.compiling_chunk.files.last() {
+            Some(file) => file.name.clone(),
+            None => "script".to_string(),
+        }
+    }
+
     pub fn error_at(&mut self, token: Token, message: String) {
         // we go ahead and keep compiling as normal as if the error never occurred.
         // The bytecode will never get executed, so it’s harmless to keep on trucking
@@ -861,15 +2160,21 @@ impl Compiler {
             return;
         }
         self.parser.panic_mode = true;
-        println!("[line {}] Error", token.line);
-        if token.token_type == TokenType::Eof {
-            println!(" at end");
-        } else if token.token_type == TokenType::Error {
-            // nothing
-        } else {
-            println!(" at {} '{}'", token.length, token.start);
-        }
-        println!(": {}", message);
+        let file = self.file_name();
+        // an error token's length is that of its message, not of any text
+        let span = match token.token_type {
+            TokenType::Error | TokenType::Eof => token.start..token.start,
+            _ => token.start..token.start + token.length,
+        };
+        self.errors.push(CompileError {
+            message,
+            file,
+            line: token.line,
+            column: token.column,
+            lexeme: self.scanner.source[span.clone()].to_string(),
+            span,
+            at_end: token.token_type == TokenType::Eof,
+        });
         self.parser.had_error = true;
     }
 
@@ -879,9 +2184,18 @@ impl Compiler {
     }
 
     pub fn emit_byte(&mut self, byte: u8) {
+        let previous = &self.parser.previous;
+        self.compiling_chunk
+            .write_chunk_at(byte, previous.line, previous.column);
+    }
+    // an instruction that can fail at runtime, at the operator or
+    // keyword it comes from rather than at the token parsed last, so
+    // the error's caret points at it
+    pub fn emit_byte_at(&mut self, byte: u8, token: &Token) {
         self.compiling_chunk
-            .write_chunk(byte, self.parser.previous.line);
+            .write_chunk_at(byte, token.line, token.column);
     }
+
     // we’ll have enough cases where we need to write an opcode followed by a
     // one-byte operand that it’s worth defining this convenience function.
     pub fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -891,37 +2205,71 @@ impl Compiler {
 
     pub fn end_compiler(&mut self) {
         self.emit_return();
+        self.finish_chunk();
+        #[cfg(feature = "debug_print_code")]
         if !self.parser.had_error {
             self.compiling_chunk.disassemble_chunk("code");
         }
     }
 
     // + - * /
+    // condition ? then : else, with the condition already compiled. Like
+    // an if statement, only one of the two branches runs. The else
+    // branch may be another conditional, so `a ? b : c ? d : e` groups
+    // as `a ? b : (c ? d : e)`
+    pub fn ternary(&mut self, _can_assign: bool) {
+        let then_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE as u8);
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.expression();
+        self.consume(
+            TokenType::Colon,
+            "Expect ':' after then branch of conditional expression.",
+        );
+        let else_jump = self.emit_jump(OpCode::OP_JUMP as u8);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.parse_precedence(Precedence::Ternary);
+        self.patch_jump(else_jump);
+    }
+
     pub fn binary(&mut self, _can_assign: bool) {
         // Remember the operator.
-        let operator_type = self.parser.previous.token_type.clone();
+        let operator = self.parser.previous.clone();
+        let operator_type = operator.token_type.clone();
         // Compile the right operand.
         let rule = self.get_rule(operator_type.clone());
         self.parse_precedence(rule.precedence.clone().next());
+        // `a < b < c` would compare the bool from `a < b` with c and only
+        // fail at runtime with "Operands must be numbers.". Parenthesizing
+        // the first comparison still allows it on purpose
+        if is_comparison(&operator_type) && is_comparison(&self.parser.current.token_type) {
+            self.error_at_current(
+                "Comparison operators can't be chained; write 'a < b and b < c' instead."
+                    .to_string(),
+            );
+        }
         // Emit the operator instruction.
-        match operator_type {
-            TokenType::Plus => self.emit_byte(OpCode::OP_ADD as u8),
-            TokenType::Minus => self.emit_byte(OpCode::OP_SUBTRACT as u8),
-            TokenType::Star => self.emit_byte(OpCode::OP_MULTIPLY as u8),
-            TokenType::Slash => self.emit_byte(OpCode::OP_DIVIDE as u8),
-            TokenType::BangEqual => {
-                self.emit_bytes(OpCode::OP_EQUAL as u8, OpCode::OP_NOT as u8);
-            }
-            TokenType::EqualEqual => self.emit_byte(OpCode::OP_EQUAL as u8),
-            TokenType::Greater => self.emit_byte(OpCode::OP_GREATER as u8),
-            TokenType::GreaterEqual => {
-                self.emit_bytes(OpCode::OP_LESS as u8, OpCode::OP_NOT as u8);
-            }
-            TokenType::Less => self.emit_byte(OpCode::OP_LESS as u8),
-            TokenType::LessEqual => {
-                self.emit_bytes(OpCode::OP_GREATER as u8, OpCode::OP_NOT as u8);
-            }
+        let ops: &[OpCode] = match operator_type {
+            TokenType::Plus => &[OpCode::OP_ADD],
+            TokenType::Minus => &[OpCode::OP_SUBTRACT],
+            TokenType::Star => &[OpCode::OP_MULTIPLY],
+            TokenType::Ampersand => &[OpCode::OP_BIT_AND],
+            TokenType::Pipe => &[OpCode::OP_BIT_OR],
+            TokenType::Caret => &[OpCode::OP_BIT_XOR],
+            TokenType::LessLess => &[OpCode::OP_SHIFT_LEFT],
+            TokenType::GreaterGreater => &[OpCode::OP_SHIFT_RIGHT],
+            TokenType::Slash => &[OpCode::OP_DIVIDE],
+            TokenType::BangEqual => &[OpCode::OP_EQUAL, OpCode::OP_NOT],
+            TokenType::EqualEqual => &[OpCode::OP_EQUAL],
+            TokenType::Greater => &[OpCode::OP_GREATER],
+            TokenType::GreaterEqual => &[OpCode::OP_LESS, OpCode::OP_NOT],
+            TokenType::Less => &[OpCode::OP_LESS],
+            TokenType::LessEqual => &[OpCode::OP_GREATER, OpCode::OP_NOT],
             _ => return,
+        };
+        for op in ops {
+            self.emit_byte_at(*op as u8, &operator);
         }
     }
 
@@ -936,11 +2284,173 @@ impl Compiler {
     }
 
     pub fn grouping(&mut self, _can_assign: bool) {
+        // () is the empty tuple
+        if self.match_token(TokenType::RightParen) {
+            self.emit_bytes(OpCode::OP_BUILD_TUPLE as u8, 0);
+            return;
+        }
         // we assume the initial ( has already been consumed. We recursively call back
         // into expression() to compile the expression between the parentheses, then parse
         // the closing ) at the end.
         self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        if !self.match_token(TokenType::Comma) {
+            self.consume(TokenType::RightParen, "Expect ')' after expression.");
+            return;
+        }
+
+        // a comma makes it a tuple, (1,) for one with a single item
+        let mut item_count: u8 = 1;
+        while !self.check(TokenType::RightParen) {
+            self.expression();
+            if item_count == 255 {
+                self.error("Can't have more than 255 items in a tuple literal.".to_string());
+            } else {
+                item_count += 1;
+            }
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after tuple items.");
+        self.emit_bytes(OpCode::OP_BUILD_TUPLE as u8, item_count);
+    }
+
+    // the callee is already on the stack, the arguments go on top of it
+    pub fn call(&mut self, _can_assign: bool) {
+        let (arg_count, spread) = self.argument_list();
+        if spread {
+            self.emit_bytes(OpCode::OP_SPREAD_CALL as u8, arg_count);
+        } else {
+            self.emit_bytes(OpCode::OP_CALL as u8, arg_count);
+        }
+    }
+
+    pub fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.parser.previous.clone());
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_bytes(OpCode::OP_SET_PROPERTY as u8, name);
+        } else {
+            self.emit_bytes(OpCode::OP_GET_PROPERTY as u8, name);
+        }
+    }
+
+    // a..b counts from a up to but not including b, a..=b includes b.
+    // Binds looser than arithmetic, so 0..n - 1 is 0..(n - 1)
+    pub fn range(&mut self, _can_assign: bool) {
+        let operator_type = self.parser.previous.token_type.clone();
+        self.parse_precedence(Precedence::Range.next());
+        if operator_type == TokenType::DotDotEqual {
+            self.emit_byte(OpCode::OP_RANGE_INCLUSIVE as u8);
+        } else {
+            self.emit_byte(OpCode::OP_RANGE as u8);
+        }
+    }
+
+    // [a, b, c] builds a new list from the items left on the stack
+    pub fn list(&mut self, _can_assign: bool) {
+        let mut item_count: u8 = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                if item_count == 255 {
+                    self.error("Can't have more than 255 items in a list literal.".to_string());
+                } else {
+                    item_count += 1;
+                }
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        self.emit_bytes(OpCode::OP_BUILD_LIST as u8, item_count);
+    }
+
+    // {key: value, ...} builds a new map from the pairs left on the
+    // stack. Only in an expression: a statement starting with '{' is a
+    // block
+    pub fn map(&mut self, _can_assign: bool) {
+        let mut pair_count: u8 = 0;
+        if !self.check(TokenType::RightBrace) {
+            loop {
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after map key.");
+                self.expression();
+                if pair_count == 255 {
+                    self.error("Can't have more than 255 entries in a map literal.".to_string());
+                } else {
+                    pair_count += 1;
+                }
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        self.emit_bytes(OpCode::OP_BUILD_MAP as u8, pair_count);
+    }
+
+    // list[index] and list[index] = value. Like properties, an element is
+    // a valid assignment target
+    pub fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::OP_INDEX_SET as u8);
+        } else {
+            self.emit_byte(OpCode::OP_INDEX_GET as u8);
+        }
+    }
+
+    // Compiles the arguments of a call and returns how many values they
+    // leave on the stack, and whether any of them is spread. Once one is,
+    // every value is a list and the VM passes their items: f(1, 2, ...xs)
+    // pushes [1, 2] and xs, the plain arguments before and between the
+    // spread ones grouped into lists of their own
+    pub fn argument_list(&mut self) -> (u8, bool) {
+        let mut written = 0;
+        let mut values: u8 = 0;
+        // plain arguments since the last spread one
+        let mut plain: u8 = 0;
+        let mut spread = false;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if written == 255 {
+                    self.error("Can't have more than 255 arguments.".to_string());
+                }
+                written += 1;
+                if self.match_token(TokenType::DotDotDot) {
+                    if plain > 0 {
+                        self.emit_bytes(OpCode::OP_BUILD_LIST as u8, plain);
+                        values = values.saturating_add(1);
+                    }
+                    spread = true;
+                    plain = 0;
+                    self.expression();
+                    values = values.saturating_add(1);
+                } else {
+                    self.expression();
+                    plain = plain.saturating_add(1);
+                }
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        if !spread {
+            return (plain, false);
+        }
+        if plain > 0 {
+            self.emit_bytes(OpCode::OP_BUILD_LIST as u8, plain);
+            values = values.saturating_add(1);
+        }
+        (values, true)
     }
 
     pub fn number(&mut self, _can_assign: bool) {
@@ -949,9 +2459,42 @@ impl Compiler {
         let token = &self.parser.previous;
         // we take actual value
         let number_str = &self.scanner.source[token.start..token.start + token.length];
-        // convert to f64
-        let value = number_str.parse::<f64>().unwrap();
-        self.emit_constant(Value::Number(value));
+        let radix = match number_str.get(..2) {
+            Some("0x") => Some(16),
+            Some("0b") => Some(2),
+            Some("0o") => Some(8),
+            _ => None,
+        };
+        if let Some(radix) = radix {
+            match i64::from_str_radix(&number_str[2..], radix) {
+                Ok(value) => self.emit_constant(Value::Int(value)),
+                Err(e) if *e.kind() == IntErrorKind::PosOverflow => {
+                    self.error("Integer literal out of range.".to_string())
+                }
+                Err(_) => self.error(format!("Invalid number literal '{}'.", number_str)),
+            }
+            return;
+        }
+        // without a decimal point it is an Int. One that doesn't fit
+        // isn't quietly rounded to a float, 1.0e19 has to be written
+        // with the point
+        if !number_str.contains('.') {
+            match number_str.parse::<i64>() {
+                Ok(value) => self.emit_constant(Value::Int(value)),
+                Err(e) if *e.kind() == IntErrorKind::PosOverflow => {
+                    self.error("Integer literal out of range.".to_string())
+                }
+                Err(_) => self.error(format!("Invalid number literal '{}'.", number_str)),
+            }
+            return;
+        }
+        // convert to f64. Too many digits for an f64 comes out as
+        // infinity, which is never what the literal meant
+        match number_str.parse::<f64>() {
+            Ok(value) if value.is_finite() => self.emit_constant(Value::Number(value)),
+            Ok(_) => self.error("Number literal is too large.".to_string()),
+            Err(_) => self.error(format!("Invalid number literal '{}'.", number_str)),
+        }
     }
 
     pub fn string(&mut self, _can_assign: bool) {
@@ -959,33 +2502,34 @@ impl Compiler {
         let string_start = self.parser.previous.start + 1;
         let string_length = self.parser.previous.length - 2;
 
-        // Get the actual string value
-        let actual_value = &self.scanner.source[string_start..string_start + string_length];
-
-        // Create object with string data
-        let obj = Obj {
-            obj_type: ObjType::ObjString(ObjString::new(actual_value.to_string())),
-        };
+        // Get the actual string value, the same object as every other
+        // literal with this text
+        let value = self.source_string(string_start, string_start + string_length);
 
         // Emit as constant
-        self.emit_constant(Value::Object(obj));
+        self.emit_constant(value);
     }
 
     pub fn unary(&mut self, _can_assign: bool) {
-        // may be - or !
-        let operator_type = self.parser.previous.token_type.clone();
+        // may be -, ! or ~
+        let operator = self.parser.previous.clone();
         // Compile the operand
         self.parse_precedence(Precedence::Unary);
         // Emit the operator instruction
-        match operator_type {
-            TokenType::Bang => self.emit_byte(OpCode::OP_NOT as u8),
-            TokenType::Minus => self.emit_byte(OpCode::OP_NEGATE as u8),
+        let op = match operator.token_type {
+            TokenType::Bang => OpCode::OP_NOT,
+            TokenType::Minus => OpCode::OP_NEGATE,
+            TokenType::Tilde => OpCode::OP_BIT_NOT,
             _ => return,
-        }
+        };
+        self.emit_byte_at(op as u8, &operator);
     }
 
     pub fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
+        // first token of the expression, in case it turns out to be
+        // the left-hand side of an invalid assignment
+        let target = self.parser.previous.clone();
         let prefix_rule = self
             .get_rule(self.parser.previous.token_type.clone())
             .prefix;
@@ -1006,18 +2550,45 @@ impl Compiler {
             let infix_rule = self.get_rule(self.parser.previous.token_type.clone()).infix;
             infix_rule.unwrap()(self, can_assign);
         }
-        if can_assign && self.match_token(TokenType::Equal) {
-            self.error("Invalid assignment target.".to_string());
+        // A valid target (a variable) consumes the '=' itself, so one
+        // still here means the expression before it can't be assigned to.
+        // Report it over the whole target rather than at the '='
+        if can_assign && self.check(TokenType::Equal) {
+            let end = &self.parser.previous;
+            let span = Token {
+                length: end.start + end.length - target.start,
+                ..target.clone()
+            };
+            self.advance();
+            self.error_at(span, invalid_target_message(&target.token_type).to_string());
         }
     }
 
+    // functions return nil unless they say otherwise, initializers
+    // always return the new instance
     pub fn emit_return(&mut self) {
+        match self.function_type {
+            FunctionType::Script => {}
+            FunctionType::Initializer => self.emit_bytes(OpCode::OP_GET_LOCAL as u8, 0),
+            _ => self.emit_byte(OpCode::OP_NIL as u8),
+        }
         self.emit_byte(OpCode::OP_RETURN as u8);
     }
 
     pub fn make_constant(&mut self, value: Value) -> u8 {
         let constant = self.compiling_chunk.add_constant(value);
-        if constant > std::u8::MAX as usize {
+        self.constant_operand(constant)
+    }
+
+    // the one byte operand for the constant at `constant`
+    fn constant_operand(&mut self, constant: usize) -> u8 {
+        if constant > u8::MAX as usize {
+            // top-level code moves on to a new chunk instead, see
+            // top_level_declaration
+            if self.can_split && self.enclosing.is_empty() {
+                self.constant_overflow = true;
+                return 0;
+            }
             self.error("Too many constants in one chunk.".to_string());
             return 0;
         }
@@ -1026,7 +2597,19 @@ impl Compiler {
 
     pub fn emit_constant(&mut self, value: Value) {
         // add value to constants table
-        let constant = self.make_constant(value);
+        let constant = self.compiling_chunk.add_constant(value);
+        // a chunk that can't move on to a new one, a function's or one a
+        // single top-level declaration fills, loads the rest of its
+        // values with the long form
+        if constant > u8::MAX as usize && !(self.can_split && self.enclosing.is_empty()) {
+            if constant > u16::MAX as usize {
+                self.error("Too many constants in one chunk.".to_string());
+            }
+            self.emit_byte(OpCode::OP_CONSTANT_LONG as u8);
+            self.emit_bytes((constant >> 8) as u8, constant as u8);
+            return;
+        }
+        let constant = self.constant_operand(constant);
         // emit OP_CONSTANT to add value to stack
         self.emit_bytes(OpCode::OP_CONSTANT as u8, constant);
     }
@@ -1035,3 +2618,71 @@ impl Compiler {
         &RULES[token_type as usize]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compiles(source: &str) -> bool {
+        let mut compiler = Compiler::new(source);
+        compiler.compile(Chunk::init_chunk()).is_some()
+    }
+
+    #[test]
+    fn test_invalid_assignment_targets() {
+        assert!(compiles("var a; a = 1;"));
+        assert!(compiles("var a; var b; a = b = 1;"));
+        assert!(compiles("var a; (a = 1);"));
+        for source in [
+            "var a; (a) = 1;",
+            "1 = 2;",
+            "\"s\" = 1;",
+            "var a; var b; a + b = 1;",
+            "var a; -a = 1;",
+            "nil = 1;",
+        ] {
+            assert!(!compiles(source), "{}", source);
+        }
+
+        assert_eq!(
+            invalid_target_message(&TokenType::LeftParen),
+            "Invalid assignment target: can't assign to a parenthesized expression."
+        );
+        assert_eq!(
+            invalid_target_message(&TokenType::True),
+            "Invalid assignment target: can't assign to a literal."
+        );
+        assert_eq!(
+            invalid_target_message(&TokenType::Identifier),
+            "Invalid assignment target."
+        );
+    }
+    #[test]
+    fn test_chained_comparisons() {
+        assert!(!compiles("print 1 < 2 < 3;"));
+        assert!(!compiles("print 3 >= 2 > 1;"));
+        assert!(!compiles("print 1 < 2 <= 3;"));
+        assert!(compiles("print (1 < 2) == true;"));
+        assert!(compiles("print 1 < 2 == 2 > 1;"));
+    }
+    #[test]
+    fn test_number_literals() {
+        assert!(compiles("print 0.25;"));
+        // the largest f64 is about 1.8 followed by 308 digits
+        assert!(compiles(&format!("print 1{}.0;", "0".repeat(308))));
+        assert!(!compiles(&format!("print 1{}.0;", "0".repeat(309))));
+        // too big for an int is an error, not a float
+        assert!(compiles("print 9223372036854775807;"));
+        assert!(compiles("print 9223372036854775808.0;"));
+        let mut compiler = Compiler::new("print 9223372036854775808;");
+        assert!(compiler.compile(Chunk::init_chunk()).is_none());
+        assert_eq!(compiler.errors[0].message, "Integer literal out of range.");
+
+        assert!(compiles(
+            "print 0x7fffffffffffffff + 0b1010 + 0o755 + 0xABC;"
+        ));
+        for bad in ["0x", "0x1g", "0b102", "0o8", "0x8000000000000000", "0x-1"] {
+            assert!(!compiles(&format!("print {};", bad)), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::io::{self, IsTerminal};
+
+// Renders compile errors, runtime errors and warnings the same way:
+//
+//   error: Expect ';' after expression.
+//    --> script:2:9
+//     |
+//   2 | print 1 +
+//     |         ^ expected an expression here
+//
+// The snippet is only shown when the diagnostic has labels and the
+// source is passed in. Color is a property of the renderer, not of the
+// diagnostic, so the same diagnostic can go to a terminal or a file.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// a byte range of the source to underline, with an optional message
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub start: usize,
+    pub end: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: Option<i32>,
+    // from 1, in characters, see Token::column
+    pub column: Option<usize>,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: &str) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message: message.to_string(),
+            file: None,
+            line: None,
+            column: None,
+            labels: vec![],
+            notes: vec![],
+        }
+    }
+
+    pub fn error(message: &str) -> Diagnostic {
+        Diagnostic::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: &str) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, message)
+    }
+
+    pub fn at(mut self, file: &str, line: i32) -> Diagnostic {
+        self.file = Some(file.to_string());
+        self.line = Some(line);
+        self
+    }
+
+    // narrows the location given with at() down to a column. 0 means
+    // the column isn't known and leaves it out
+    pub fn at_column(mut self, column: usize) -> Diagnostic {
+        self.column = (column > 0).then_some(column);
+        self
+    }
+
+    pub fn with_label(mut self, start: usize, end: usize, message: &str) -> Diagnostic {
+        self.labels.push(Label {
+            start,
+            end,
+            message: message.to_string(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: &str) -> Diagnostic {
+        self.notes.push(note.to_string());
+        self
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const GREEN: &str = "\x1b[1;32m";
+const BLUE: &str = "\x1b[1;34m";
+
+#[derive(Debug, Clone, Copy)]
+pub struct Renderer {
+    pub color: bool,
+}
+
+impl Renderer {
+    #[cfg(test)]
+    pub fn plain() -> Renderer {
+        Renderer { color: false }
+    }
+
+    // colored when stdout is a terminal that understands ANSI escapes
+    pub fn stdout() -> Renderer {
+        Renderer {
+            color: supports_color(io::stdout().is_terminal()),
+        }
+    }
+
+    pub fn stderr() -> Renderer {
+        Renderer {
+            color: supports_color(io::stderr().is_terminal()),
+        }
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic, source: Option<&str>) -> String {
+        let (name, color) = match diagnostic.severity {
+            Severity::Error => ("error", RED),
+            Severity::Warning => ("warning", YELLOW),
+        };
+        let mut out = format!(
+            "{}: {}\n",
+            self.paint(color, name),
+            self.paint(BOLD, &diagnostic.message)
+        );
+
+        // every line the labels touch, in order, each with its labels
+        let mut lines: Vec<(usize, usize, &str, Vec<&Label>)> = vec![];
+        if let Some(source) = source {
+            for label in &diagnostic.labels {
+                let (number, start, text) = line_of(source, label.start);
+                match lines.iter_mut().find(|line| line.0 == number) {
+                    Some(line) => line.3.push(label),
+                    None => lines.push((number, start, text, vec![label])),
+                }
+            }
+            lines.sort_by_key(|line| line.0);
+        }
+
+        let widest = lines
+            .iter()
+            .map(|line| line.0)
+            .chain(diagnostic.line.map(|line| line.max(0) as usize))
+            .max()
+            .unwrap_or(0);
+        let gutter = " ".repeat(widest.to_string().len());
+        let bar = self.paint(BLUE, "|");
+
+        if let Some(file) = &diagnostic.file {
+            let location = match (diagnostic.line, diagnostic.column) {
+                (Some(line), Some(column)) => format!("{}:{}:{}", file, line, column),
+                (Some(line), None) => format!("{}:{}", file, line),
+                (None, _) => file.clone(),
+            };
+            out += &format!("{}{} {}\n", gutter, self.paint(BLUE, "-->"), location);
+        }
+
+        if !lines.is_empty() {
+            out += &format!("{} {}\n", gutter, bar);
+        }
+        for &(number, start, text, ref labels) in &lines {
+            let number = format!("{:>1$}", number, gutter.len());
+            out += &format!("{} {} {}\n", self.paint(BLUE, &number), bar, text);
+            for label in labels {
+                // columns are counted in characters so the carets line
+                // up under non-ASCII text too
+                let from = (label.start - start).min(text.len());
+                let to = label.end.saturating_sub(start).clamp(from, text.len());
+                let column = text[..from].chars().count();
+                let width = text[from..to].chars().count().max(1);
+                let mut underline = format!("{}{}", " ".repeat(column), "^".repeat(width));
+                if !label.message.is_empty() {
+                    underline += &format!(" {}", label.message);
+                }
+                out += &format!("{} {} {}\n", gutter, bar, self.paint(color, &underline));
+            }
+        }
+
+        for note in &diagnostic.notes {
+            out += &format!("{} {} {}\n", gutter, self.paint(BLUE, "="), note);
+        }
+        out
+    }
+
+    // one line summing up a batch of results, e.g. a test run
+    pub fn summary(&self, passed: usize, failed: usize) -> String {
+        let status = if failed == 0 {
+            self.paint(GREEN, "ok")
+        } else {
+            self.paint(RED, "FAILED")
+        };
+        format!("result: {}. {} passed; {} failed", status, passed, failed)
+    }
+
+    pub fn paint(&self, color: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+// NO_COLOR (https://no-color.org) always wins. The Windows console only
+// understands escapes when a terminal that enables them says so, which
+// Windows Terminal does with WT_SESSION and mintty and friends with TERM
+pub fn supports_color(is_terminal: bool) -> bool {
+    if !is_terminal || std::env::var_os("NO_COLOR").is_some() {
+        return false;
+    }
+    match std::env::var("TERM") {
+        Ok(term) => term != "dumb",
+        Err(_) => !cfg!(windows) || std::env::var_os("WT_SESSION").is_some(),
+    }
+}
+
+// the byte offset of a 1-based line and column, columns counted in
+// characters like Token::column. None if the source has no such line;
+// a column past the end of the line gives the end of the line
+pub fn offset_of(source: &str, line: i32, column: usize) -> Option<usize> {
+    let start = match line {
+        1 => 0,
+        2.. => source.match_indices('\n').nth(line as usize - 2)?.0 + 1,
+        _ => return None,
+    };
+    let text = source[start..].split('\n').next().unwrap_or("");
+    let within = text
+        .char_indices()
+        .nth(column.saturating_sub(1))
+        .map_or(text.len(), |(i, _)| i);
+    Some(start + within)
+}
+
+// 1-based number, start offset and text (without the newline) of the
+// line containing byte `offset`
+fn line_of(source: &str, offset: usize) -> (usize, usize, &str) {
+    let offset = offset.min(source.len());
+    let start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |i| offset + i);
+    let number = source[..start].matches('\n').count() + 1;
+    (number, start, &source[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_plain() {
+        let diagnostic = Diagnostic::error("Undefined variable 'x'.").at("script", 3);
+        assert_eq!(
+            Renderer::plain().render(&diagnostic, None),
+            "error: Undefined variable 'x'.\n --> script:3\n"
+        );
+        let diagnostic = diagnostic.at_column(7);
+        assert_eq!(
+            Renderer::plain().render(&diagnostic, None),
+            "error: Undefined variable 'x'.\n --> script:3:7\n"
+        );
+    }
+
+    #[test]
+    fn test_render_labels() {
+        let source = "var a = 1;\nprint a < b < c;\n";
+        let diagnostic = Diagnostic::error("Comparison operators can't be chained.")
+            .at("script", 2)
+            .with_label(23, 24, "second comparison")
+            .with_label(19, 20, "")
+            .with_note("write 'a < b and b < c' instead");
+        let expected = [
+            "error: Comparison operators can't be chained.",
+            " --> script:2",
+            "  |",
+            "2 | print a < b < c;",
+            "  |             ^ second comparison",
+            "  |         ^",
+            "  = write 'a < b and b < c' instead",
+        ];
+        assert_eq!(
+            Renderer::plain().render(&diagnostic, Some(source)),
+            expected.join("\n") + "\n"
+        );
+    }
+
+    #[test]
+    fn test_offset_of() {
+        let source = "var a = 1;\nprint é + b;";
+        assert_eq!(offset_of(source, 1, 5), Some(4));
+        assert_eq!(offset_of(source, 2, 7), Some(17));
+        // after the two byte é
+        assert_eq!(offset_of(source, 2, 9), Some(20));
+        assert_eq!(offset_of(source, 2, 99), Some(source.len()));
+        assert_eq!(offset_of(source, 3, 1), None);
+        assert_eq!(offset_of(source, 0, 1), None);
+    }
+
+    #[test]
+    fn test_render_color() {
+        let renderer = Renderer { color: true };
+        let out = renderer.render(&Diagnostic::warning("Unused variable."), None);
+        assert!(out.starts_with("\x1b[1;33mwarning\x1b[0m: "));
+        assert!(!Renderer::plain().summary(2, 0).contains('\x1b'));
+        assert!(renderer.summary(1, 1).contains("FAILED"));
+    }
+}
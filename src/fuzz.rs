@@ -0,0 +1,641 @@
+// Random programs for testing the compiler and VM together. The
+// generator only writes programs that should compile and run without
+// errors, and every one of them is checked for:
+//  - no panics anywhere in the compiler or the VM
+//  - every chunk passing `verify`, with and without -O
+//  - the same output and globals when run twice, and when run with -O
+// A failure names the seed, so it can be replayed with check_program.
+// ELEPHANT_FUZZ_CASES runs more programs than the default.
+//
+// New syntax should get a branch in the generator when it lands, or
+// this stops covering it.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::{
+    chunk::{has_constant_operand, Chunk, OpCode},
+    compiler::Compiler,
+    value::{Obj, ObjType, Value},
+    vm::{CapturedOutput, InterpretResult, VM},
+};
+
+const CASES: u64 = 32;
+
+// xorshift64*, good enough for picking branches and small numbers
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Number,
+    Text,
+    // a list of numbers with at least one item
+    List,
+}
+
+struct Variable {
+    name: String,
+    kind: Kind,
+    // loop counters are read but never assigned, so loops still end.
+    // Constants can't be assigned either
+    assignable: bool,
+}
+
+struct Function {
+    name: String,
+    arity: usize,
+    // makes no calls. Only these are called from loops and other
+    // functions, or the running time of a program could multiply with
+    // every function
+    leaf: bool,
+}
+
+struct Generator {
+    rng: Rng,
+    source: String,
+    indent: usize,
+    // variables in scope, innermost block last. The first scope holds
+    // the globals
+    scopes: Vec<Vec<Variable>>,
+    // functions defined so far. All of them return a number, and a
+    // function can only call the ones defined before it, so there is no
+    // recursion
+    functions: Vec<Function>,
+    // set while writing a function, to whether it calls anything yet
+    calls: Option<bool>,
+    names: usize,
+    // loops around the statement being written, for break and continue
+    loops: usize,
+}
+
+impl Generator {
+    fn program(seed: u64) -> String {
+        let mut generator = Generator {
+            rng: Rng::new(seed),
+            source: String::new(),
+            indent: 0,
+            scopes: vec![vec![]],
+            functions: vec![],
+            calls: None,
+            names: 0,
+            loops: 0,
+        };
+        let declarations = 3 + generator.rng.below(15);
+        // the scanner is slow on long sources, so programs stay short
+        for _ in 0..declarations {
+            if generator.source.len() > 1200 {
+                break;
+            }
+            if generator.rng.chance(15) {
+                generator.function();
+            } else {
+                generator.statement(0);
+            }
+        }
+        generator.source
+    }
+
+    fn line(&mut self, text: &str) {
+        self.source.push_str(&"  ".repeat(self.indent));
+        self.source.push_str(text);
+        self.source.push('\n');
+    }
+
+    fn fresh_name(&mut self, prefix: &str) -> String {
+        self.names += 1;
+        format!("{}{}", prefix, self.names)
+    }
+
+    fn declare(&mut self, name: &str, kind: Kind, assignable: bool) {
+        self.scopes.last_mut().unwrap().push(Variable {
+            name: name.to_string(),
+            kind,
+            assignable,
+        });
+    }
+
+    // a random variable in scope that fits
+    fn pick(&mut self, kind: Kind, assignable: bool) -> Option<String> {
+        let names: Vec<String> = self
+            .scopes
+            .iter()
+            .flatten()
+            .filter(|v| v.kind == kind && (v.assignable || !assignable))
+            .map(|v| v.name.clone())
+            .collect();
+        if names.is_empty() {
+            return None;
+        }
+        Some(names[self.rng.below(names.len())].clone())
+    }
+
+    fn function(&mut self) {
+        let name = self.fresh_name("f");
+        let arity = self.rng.below(4);
+        let params: Vec<String> = (0..arity).map(|_| self.fresh_name("p")).collect();
+        self.line(&format!("fun {}({}) {{", name, params.join(", ")));
+        self.indent += 1;
+        self.scopes.push(vec![]);
+        for param in &params {
+            self.declare(param, Kind::Number, true);
+        }
+        let loops = std::mem::replace(&mut self.loops, 0);
+        self.calls = Some(false);
+        for _ in 0..self.rng.below(4) {
+            self.statement(1);
+        }
+        let result = self.expression(Kind::Number, 0);
+        self.line(&format!("return {};", result));
+        self.loops = loops;
+        let leaf = self.calls.take() == Some(false);
+        self.scopes.pop();
+        self.indent -= 1;
+        self.line("}");
+        self.functions.push(Function { name, arity, leaf });
+    }
+
+    fn statement(&mut self, depth: usize) {
+        let nested = depth < 3;
+        match self.rng.below(100) {
+            0..=24 => self.var_declaration(),
+            25..=44 => self.assignment(),
+            45..=49 => {
+                let value = self.expression(Kind::Number, 0);
+                self.line(&format!("print {};", value));
+            }
+            50..=59 if nested => {
+                let condition = self.condition(0);
+                self.line(&format!("if ({}) {{", condition));
+                self.block(depth);
+                if self.rng.chance(50) {
+                    self.line("} else {");
+                    self.block(depth);
+                }
+                self.line("}");
+            }
+            60..=67 if nested => {
+                // a counted while or do-while loop, in a block of its own
+                let counter = self.fresh_name("c");
+                let times = self.rng.below(4);
+                let condition = format!("{} < {}", counter, times);
+                let do_while = self.rng.chance(30);
+                self.line("{");
+                self.indent += 1;
+                self.line(&format!("var {} = 0;", counter));
+                if do_while {
+                    self.line("do {");
+                } else {
+                    self.line(&format!("while ({}) {{", condition));
+                }
+                self.indent += 1;
+                self.line(&format!("{} = {} + 1;", counter, counter));
+                self.indent -= 1;
+                self.loop_body(depth, &counter, Kind::Number);
+                if do_while {
+                    self.line(&format!("}} while ({});", condition));
+                } else {
+                    self.line("}");
+                }
+                self.indent -= 1;
+                self.line("}");
+            }
+            68..=75 if nested => {
+                let counter = self.fresh_name("i");
+                let times = self.rng.below(4);
+                self.line(&format!(
+                    "for (var {} = 0; {} < {}; {} = {} + 1) {{",
+                    counter, counter, times, counter, counter
+                ));
+                self.loop_body(depth, &counter, Kind::Number);
+                self.line("}");
+            }
+            76..=85 if nested => {
+                let item = self.fresh_name("x");
+                let (collection, kind) = match self.rng.below(3) {
+                    0 => {
+                        let start = self.rng.below(3);
+                        let end = start + self.rng.below(4);
+                        let op = if self.rng.chance(50) { ".." } else { "..=" };
+                        (format!("{}{}{}", start, op, end), Kind::Number)
+                    }
+                    1 => (self.expression(Kind::List, 1), Kind::Number),
+                    _ => (self.expression(Kind::Text, 1), Kind::Text),
+                };
+                self.line(&format!("for ({} in {}) {{", item, collection));
+                self.loop_body(depth, &item, kind);
+                self.line("}");
+            }
+            86..=90 if self.loops > 0 => {
+                let condition = self.condition(0);
+                let jump = if self.rng.chance(50) {
+                    "break"
+                } else {
+                    "continue"
+                };
+                self.line(&format!("if ({}) {};", condition, jump));
+            }
+            91..=93 if nested => {
+                self.line("{");
+                self.block(depth);
+                self.line("}");
+            }
+            94..=95 if nested => {
+                self.line("try {");
+                self.block(depth);
+                if self.rng.chance(50) {
+                    let value = self.expression(Kind::Number, 0);
+                    self.indent += 1;
+                    self.line(&format!("throw {};", value));
+                    self.indent -= 1;
+                }
+                let name = self.fresh_name("e");
+                self.line(&format!("}} catch ({}) {{", name));
+                self.scopes.push(vec![]);
+                self.declare(&name, Kind::Number, true);
+                self.block(depth);
+                self.scopes.pop();
+                self.line("}");
+            }
+            96..=99 => {
+                let Some(list) = self.pick(Kind::List, false) else {
+                    return self.var_declaration();
+                };
+                let value = self.expression(Kind::Number, 0);
+                self.line(&format!("{}[0] = {};", list, value));
+            }
+            _ => self.assignment(),
+        }
+    }
+
+    // the statements of a block, in a new scope. The braces are the
+    // caller's
+    fn block(&mut self, depth: usize) {
+        self.indent += 1;
+        self.scopes.push(vec![]);
+        for _ in 0..1 + self.rng.below(2) {
+            self.statement(depth + 1);
+        }
+        self.scopes.pop();
+        self.indent -= 1;
+    }
+
+    // a loop body that can read the loop variable and break
+    fn loop_body(&mut self, depth: usize, variable: &str, kind: Kind) {
+        self.scopes.push(vec![]);
+        self.declare(variable, kind, false);
+        self.loops += 1;
+        self.block(depth);
+        self.loops -= 1;
+        self.scopes.pop();
+    }
+
+    fn var_declaration(&mut self) {
+        let kind = match self.rng.below(4) {
+            0 => Kind::Text,
+            1 => Kind::List,
+            _ => Kind::Number,
+        };
+        let name = self.fresh_name("v");
+        let value = self.expression(kind, 0);
+        let constant = self.rng.chance(20);
+        let keyword = if constant { "const" } else { "var" };
+        self.line(&format!("{} {} = {};", keyword, name, value));
+        self.declare(&name, kind, !constant);
+    }
+
+    fn assignment(&mut self) {
+        let kind = match self.rng.below(3) {
+            0 => Kind::Text,
+            _ => Kind::Number,
+        };
+        let Some(name) = self.pick(kind, true) else {
+            return self.var_declaration();
+        };
+        let value = self.expression(kind, 0);
+        self.line(&format!("{} = {};", name, value));
+    }
+
+    fn expression(&mut self, kind: Kind, depth: usize) -> String {
+        // leaves only, once the expression is deep enough
+        let choice = if depth > 2 {
+            self.rng.below(2)
+        } else {
+            self.rng.below(6)
+        };
+        if depth <= 2 && self.rng.chance(10) {
+            let condition = self.condition(1);
+            let then = self.expression(kind, depth + 1);
+            let otherwise = self.expression(kind, depth + 1);
+            return format!("({} ? {} : {})", condition, then, otherwise);
+        }
+        if depth <= 2 && self.rng.chance(5) {
+            let left = if self.rng.chance(50) {
+                "nil".to_string()
+            } else {
+                self.expression(kind, depth + 1)
+            };
+            let right = self.expression(kind, depth + 1);
+            return format!("({} ?? {})", left, right);
+        }
+        if choice == 1 {
+            if let Some(name) = self.pick(kind, false) {
+                return name;
+            }
+        }
+        match (kind, choice) {
+            (Kind::Number, 2) => {
+                let op = ["+", "-", "*", "/", "&", "|", "^", "<<", ">>"][self.rng.below(9)];
+                let left = self.expression(Kind::Number, depth + 1);
+                // a shift by anything outside 0..63 is an error
+                let right = if op == "<<" || op == ">>" {
+                    self.rng.below(10).to_string()
+                } else {
+                    self.expression(Kind::Number, depth + 1)
+                };
+                format!("({} {} {})", left, op, right)
+            }
+            (Kind::Number, 3) => {
+                let op = if self.rng.chance(80) { "-" } else { "~" };
+                format!("{}({})", op, self.expression(Kind::Number, depth + 1))
+            }
+            (Kind::Number, 4) if !self.callable().is_empty() => {
+                let callable = self.callable();
+                let (name, arity) = callable[self.rng.below(callable.len())].clone();
+                if let Some(calls) = &mut self.calls {
+                    *calls = true;
+                }
+                let mut args: Vec<String> = (0..arity)
+                    .map(|_| self.expression(Kind::Number, depth + 1))
+                    .collect();
+                // some of the arguments passed in a spread list instead
+                if self.rng.chance(20) {
+                    let start = self.rng.below(arity + 1);
+                    let end = start + self.rng.below(arity + 1 - start);
+                    let spread = format!("...[{}]", args[start..end].join(", "));
+                    args.splice(start..end, [spread]);
+                }
+                format!("{}({})", name, args.join(", "))
+            }
+            (Kind::Number, 5) if self.rng.chance(30) => {
+                let first = self.expression(Kind::Number, depth + 1);
+                let second = self.expression(Kind::Text, depth + 1);
+                format!("({}, {})[0]", first, second)
+            }
+            // in parentheses, a statement starting with '{' is a block
+            (Kind::Number, 5) if self.rng.chance(30) => {
+                let value = self.expression(Kind::Number, depth + 1);
+                let key = self.text_literal();
+                format!("({{{}: {}}}[{}])", key, value, key)
+            }
+            (Kind::Number, 5) => format!("{}[0]", self.expression(Kind::List, depth + 1)),
+            (Kind::Number, _) => {
+                if self.rng.chance(20) {
+                    format!("{}.5", self.rng.below(10))
+                } else {
+                    self.rng.below(10).to_string()
+                }
+            }
+            // appending a literal, so a string assigned in a loop grows
+            // by a little each time around rather than doubling
+            (Kind::Text, 2 | 3) => {
+                let left = self.expression(Kind::Text, depth + 1);
+                format!("({} + {})", left, self.text_literal())
+            }
+            (Kind::Text, 4) => format!("{}[0]", self.expression(Kind::Text, depth + 1)),
+            (Kind::Text, _) => self.text_literal(),
+            (Kind::List, 2) => format!("{}[0..1]", self.expression(Kind::List, depth + 1)),
+            (Kind::List, _) => {
+                let items: Vec<String> = (0..1 + self.rng.below(3))
+                    .map(|_| self.expression(Kind::Number, depth + 1))
+                    .collect();
+                format!("[{}]", items.join(", "))
+            }
+        }
+    }
+
+    fn text_literal(&mut self) -> String {
+        let words = ["\"a\"", "\"elephant\"", "\"xy\"", "\"0\""];
+        words[self.rng.below(words.len())].to_string()
+    }
+
+    fn callable(&self) -> Vec<(String, usize)> {
+        let anything = self.loops == 0 && self.calls.is_none();
+        self.functions
+            .iter()
+            .filter(|f| f.leaf || anything)
+            .map(|f| (f.name.clone(), f.arity))
+            .collect()
+    }
+
+    fn condition(&mut self, depth: usize) -> String {
+        match self.rng.below(6) {
+            0 if depth < 2 => format!("!({})", self.condition(depth + 1)),
+            3 if depth < 2 => {
+                let op = ["and", "or"][self.rng.below(2)];
+                let left = self.condition(depth + 1);
+                let right = self.condition(depth + 1);
+                format!("({}) {} ({})", left, op, right)
+            }
+            1 => ["true", "false"][self.rng.below(2)].to_string(),
+            2 => {
+                let left = self.expression(Kind::Text, 1);
+                let right = self.expression(Kind::Text, 1);
+                format!("{} == {}", left, right)
+            }
+            _ => {
+                let op = ["<", ">", "<=", ">=", "==", "!="][self.rng.below(6)];
+                let left = self.expression(Kind::Number, 1);
+                let right = self.expression(Kind::Number, 1);
+                format!("{} {} {}", left, op, right)
+            }
+        }
+    }
+}
+
+// Structural checks of compiled code: every instruction is a known
+// opcode with all its operands inside the chunk, constant operands index
+// the constant pool, jumps land on the start of an instruction (or just
+// past the end), there is a line for every byte and the chunk ends with
+// OP_RETURN. Functions in the constant pool are checked too
+pub fn verify(chunk: &Chunk) -> Result<(), String> {
+    if chunk.lines.len() != chunk.code.len() {
+        return Err(format!(
+            "{} bytes of code but {} lines",
+            chunk.code.len(),
+            chunk.lines.len()
+        ));
+    }
+    if chunk.code.last() != Some(&(OpCode::OP_RETURN as u8)) {
+        return Err("chunk doesn't end with OP_RETURN".to_string());
+    }
+
+    let mut starts = vec![false; chunk.code.len() + 1];
+    let mut jumps = vec![];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        starts[offset] = true;
+        let op = chunk.code[offset];
+        if OpCode::try_from(op).is_err() {
+            return Err(format!("unknown opcode {} at {}", op, offset));
+        }
+        let len = chunk.instruction_len(offset);
+        if offset + len > chunk.code.len() {
+            return Err(format!("operands of the instruction at {} cut off", offset));
+        }
+        if has_constant_operand(op)
+            && chunk.code[offset + 1] as usize >= chunk.constants.values.len()
+        {
+            return Err(format!("constant out of range at {}", offset));
+        }
+        if op == OpCode::OP_CONSTANT_LONG as u8
+            && ((chunk.code[offset + 1] as usize) << 8 | chunk.code[offset + 2] as usize)
+                >= chunk.constants.values.len()
+        {
+            return Err(format!("constant out of range at {}", offset));
+        }
+        // jump operands are the last two bytes of the instruction, and
+        // count from its end
+        let distance =
+            || (chunk.code[offset + len - 2] as usize) << 8 | chunk.code[offset + len - 1] as usize;
+        if op == OpCode::OP_JUMP as u8
+            || op == OpCode::OP_JUMP_IF_FALSE as u8
+            || op == OpCode::OP_FOR_ITER as u8
+            || op == OpCode::OP_TRY as u8
+            || op == OpCode::OP_JUMP_IF_NOT_NIL as u8
+            || op == OpCode::OP_JUMP_IF_TRUE as u8
+        {
+            jumps.push((offset, offset + len + distance()));
+        } else if op == OpCode::OP_LOOP as u8 {
+            match (offset + len).checked_sub(distance()) {
+                Some(target) => jumps.push((offset, target)),
+                None => return Err(format!("loop at {} jumps before the chunk", offset)),
+            }
+        }
+        offset += len;
+    }
+    starts[chunk.code.len()] = true;
+    for (from, to) in jumps {
+        if !starts.get(to).copied().unwrap_or(false) {
+            return Err(format!("jump at {} lands at {}", from, to));
+        }
+    }
+
+    for constant in &chunk.constants.values {
+        if let Value::Object(Obj {
+            obj_type: ObjType::ObjFunction(function),
+        }) = constant
+        {
+            verify(&function.chunk).map_err(|e| format!("in {}: {}", function.name, e))?;
+        }
+    }
+    Ok(())
+}
+
+fn compile(source: &str, optimize: bool) -> Vec<Chunk> {
+    let mut compiler = Compiler::new(source);
+    compiler.optimize = optimize;
+    compiler
+        .compile(Chunk::init_chunk())
+        .expect("doesn't compile")
+}
+
+// what a program leaves behind: what it printed, then the globals it
+// defined, in order
+fn run(source: &str, optimize: bool) -> Vec<String> {
+    let mut vm = VM::init_vm();
+    vm.optimize = optimize;
+    let output = CapturedOutput::default();
+    vm.set_output(Box::new(output.clone()));
+    let before = vm.globals().len();
+    assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+    let after = vm.globals();
+    let globals = after[before..]
+        .iter()
+        .map(|(name, value)| format!("{} = {}", name.as_obj_string(), value.format_with(None)));
+    std::iter::once(output.text()).chain(globals).collect()
+}
+
+pub fn check_program(source: &str) {
+    for optimize in [false, true] {
+        for (i, chunk) in compile(source, optimize).iter().enumerate() {
+            if let Err(e) = verify(chunk) {
+                panic!("part {} (optimize: {}) fails to verify: {}", i, optimize, e);
+            }
+        }
+    }
+    let globals = run(source, false);
+    assert_eq!(run(source, false), globals, "a second run differs");
+    assert_eq!(run(source, true), globals, "-O changes the result");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_programs() {
+        let cases = std::env::var("ELEPHANT_FUZZ_CASES")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(CASES);
+        for seed in 1..=cases {
+            let source = Generator::program(seed);
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| check_program(&source))) {
+                let message = e
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_default();
+                panic!("seed {}: {}\n{}", seed, message, source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_generator_is_deterministic() {
+        assert_eq!(Generator::program(7), Generator::program(7));
+        assert_ne!(Generator::program(7), Generator::program(8));
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut chunk = compile("var a = 1; while (a < 3) a = a + 1;", false).remove(0);
+        assert_eq!(verify(&chunk), Ok(()));
+
+        // land in the middle of an instruction
+        let jump = chunk
+            .code
+            .iter()
+            .position(|&op| op == OpCode::OP_JUMP_IF_FALSE as u8)
+            .unwrap();
+        chunk.code[jump + 2] -= 1;
+        assert!(verify(&chunk).is_err());
+        chunk.code[jump + 2] += 1;
+
+        chunk.code[1] = 200;
+        assert!(verify(&chunk).is_err());
+        chunk.code.pop();
+        chunk.lines.pop();
+        assert!(verify(&chunk).is_err());
+    }
+}
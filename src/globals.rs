@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use crate::value::{ObjType, Value};
+
+// Globals are numbered. The compiler gives every name a program uses a
+// slot the first time it sees it, and OP_*_GLOBAL_SLOT instructions
+// carry that number, so running them indexes a Vec instead of hashing
+// the name. The names outlive any one program: the VM hands them to the
+// compiler of each program it runs and takes them back afterwards, so a
+// function compiled by one program finds the globals of the next ones
+// where it expects them. Bytecode is only meaningful to the VM whose
+// names it was compiled against.
+//
+// Slots are never given back. A name that is used but never defined
+// keeps one that stays empty, reading it is a runtime error
+#[derive(Debug, Clone, Default)]
+pub struct GlobalNames {
+    names: Vec<ObjType>,
+    slots: HashMap<ObjType, usize>,
+}
+
+impl GlobalNames {
+    // the slot of `name`, given a new one if it has none yet
+    pub fn slot(&mut self, name: ObjType) -> usize {
+        if let Some(&slot) = self.slots.get(&name) {
+            return slot;
+        }
+        let slot = self.names.len();
+        self.names.push(name.clone());
+        self.slots.insert(name, slot);
+        slot
+    }
+
+    pub fn find(&self, name: &ObjType) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    pub fn name(&self, slot: usize) -> Option<&ObjType> {
+        self.names.get(slot)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Globals {
+    pub names: GlobalNames,
+    // by slot, None until the global is defined. Shorter than names
+    // while the newest slots haven't been defined
+    values: Vec<Option<Value>>,
+    // the defined slots in the order they were first defined, which is
+    // the order globals are listed in
+    order: Vec<usize>,
+}
+
+impl Globals {
+    pub fn get(&self, slot: usize) -> Option<&Value> {
+        self.values.get(slot).and_then(Option::as_ref)
+    }
+
+    // `var`: defines the global, or overwrites it if it exists
+    pub fn define(&mut self, slot: usize, value: Value) {
+        if slot >= self.values.len() {
+            self.values.resize(slot + 1, None);
+        }
+        if self.values[slot].is_none() {
+            self.order.push(slot);
+        }
+        self.values[slot] = Some(value);
+    }
+
+    // assignment: false, with nothing changed, if the global isn't
+    // defined
+    pub fn set(&mut self, slot: usize, value: Value) -> bool {
+        match self.values.get_mut(slot) {
+            Some(Some(current)) => {
+                *current = value;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // by name, for natives and hosts
+    pub fn get_named(&self, name: &ObjType) -> Option<Value> {
+        self.names
+            .find(name)
+            .and_then(|slot| self.get(slot))
+            .cloned()
+    }
+
+    pub fn define_named(&mut self, name: ObjType, value: Value) {
+        let slot = self.names.slot(name);
+        self.define(slot, value);
+    }
+
+    // the defined globals with their names, in definition order
+    pub fn defined(&self) -> impl Iterator<Item = (&ObjType, &Value)> {
+        self.order
+            .iter()
+            .filter_map(|&slot| Some((self.names.name(slot)?, self.get(slot)?)))
+    }
+
+    // go back to the values `saved` had, undefining globals defined
+    // since. The names stay, code compiled in between may use them
+    pub fn restore(&mut self, saved: Globals) {
+        self.values = saved.values;
+        self.order = saved.order;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::ObjString;
+
+    fn name(text: &str) -> ObjType {
+        ObjType::ObjString(ObjString::new(text.to_string()))
+    }
+
+    #[test]
+    fn test_slots() {
+        let mut globals = Globals::default();
+        let b = globals.names.slot(name("b"));
+        let a = globals.names.slot(name("a"));
+        assert_eq!((b, a), (0, 1));
+        assert_eq!(globals.names.slot(name("b")), b);
+
+        // a slot with no value yet can't be read or assigned
+        assert!(globals.get(a).is_none());
+        assert!(!globals.set(a, Value::Int(1)));
+        assert!(globals.get(a).is_none());
+
+        globals.define(a, Value::Int(1));
+        globals.define_named(name("b"), Value::Int(2));
+        assert!(globals.set(a, Value::Int(3)));
+        assert_eq!(
+            globals.get_named(&name("a")).unwrap().as_number(),
+            Some(3.0)
+        );
+        let order: Vec<&ObjType> = globals.defined().map(|(name, _)| name).collect();
+        assert_eq!(order, [&name("a"), &name("b")]);
+
+        let saved = globals.clone();
+        let c = globals.names.slot(name("c"));
+        globals.define(c, Value::Nil);
+        globals.set(a, Value::Int(4));
+        globals.restore(saved);
+        assert!(globals.get(c).is_none());
+        assert_eq!(globals.get(a).unwrap().as_number(), Some(3.0));
+        assert_eq!(globals.names.find(&name("c")), Some(c));
+    }
+}
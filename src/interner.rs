@@ -0,0 +1,129 @@
+use crate::value::{hash_string, ObjString};
+
+// A string's handle in the VM's intern pool, its index there. Tables
+// key on handles, so finding a field or method compares two u32s, and
+// a key is copied where it used to be a cloned ObjType
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StringId(u32);
+
+impl StringId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+// every string the VM has interned, by handle. The handle of a text is
+// found the way Table finds a key: open addressing on the string's
+// hash. Nothing is ever taken out, so there are no tombstones
+#[derive(Debug, Clone, Default)]
+pub struct Interner {
+    strings: Vec<ObjString>,
+    buckets: Vec<Option<StringId>>,
+}
+
+// grow once more than 3/4 of the buckets are in use, as Table does
+const INTERNER_MAX_LOAD: f64 = 0.75;
+
+impl Interner {
+    // the handle of `string`'s text, which is added as `string` if the
+    // pool doesn't have it yet
+    pub fn intern(&mut self, string: &ObjString) -> StringId {
+        match self.find(string.as_str(), string.get_hash()) {
+            Some(id) => id,
+            None => self.add(string.clone()),
+        }
+    }
+
+    // the handle of `text`, which is added if the pool doesn't have it
+    pub fn intern_str(&mut self, text: &str) -> StringId {
+        match self.find(text, hash_string(text)) {
+            Some(id) => id,
+            None => self.add(ObjString::new(text.to_string())),
+        }
+    }
+
+    // add a string that isn't in the pool, see find
+    pub fn add(&mut self, string: ObjString) -> StringId {
+        if (self.strings.len() + 1) as f64 > self.buckets.len() as f64 * INTERNER_MAX_LOAD {
+            self.adjust_capacity((self.buckets.len() * 2).max(8));
+        }
+        let id = StringId(u32::try_from(self.strings.len()).expect("too many strings"));
+        let bucket = self.empty_bucket(string.get_hash());
+        self.buckets[bucket] = Some(id);
+        self.strings.push(string);
+        id
+    }
+
+    // the string with this text, without making an ObjString to look it
+    // up by. `hash` has to be the text's, see value::hash_string
+    pub fn find(&self, chars: &str, hash: u64) -> Option<StringId> {
+        if self.strings.is_empty() {
+            return None;
+        }
+        let mask = self.buckets.len() - 1;
+        let mut bucket = hash as usize & mask;
+        while let Some(id) = self.buckets[bucket] {
+            let string = self.get(id);
+            if string.get_hash() == hash && string.as_str() == chars {
+                return Some(id);
+            }
+            bucket = (bucket + 1) & mask;
+        }
+        None
+    }
+
+    pub fn get(&self, id: StringId) -> &ObjString {
+        &self.strings[id.index()]
+    }
+
+    // in the order they were interned
+    pub fn strings(&self) -> &[ObjString] {
+        &self.strings
+    }
+
+    // handles given out before are no good afterwards
+    pub fn free_strings(&mut self) {
+        self.strings.clear();
+        self.buckets.clear();
+    }
+
+    fn empty_bucket(&self, hash: u64) -> usize {
+        let mask = self.buckets.len() - 1;
+        let mut bucket = hash as usize & mask;
+        while self.buckets[bucket].is_some() {
+            bucket = (bucket + 1) & mask;
+        }
+        bucket
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        self.buckets = vec![None; capacity];
+        for (i, string) in self.strings.iter().enumerate() {
+            let bucket = self.empty_bucket(string.get_hash());
+            self.buckets[bucket] = Some(StringId(i as u32));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interning() {
+        let mut strings = Interner::default();
+        let ids: Vec<StringId> = (0..100)
+            .map(|i| strings.intern(&ObjString::new(format!("s{}", i))))
+            .collect();
+        assert_eq!(ids[7].index(), 7);
+        // the same text gets the same handle and keeps the first object
+        let again = strings.intern(&ObjString::new("s7".to_string()));
+        assert_eq!(again, ids[7]);
+        assert_eq!(strings.intern_str("s7"), ids[7]);
+        assert_eq!(strings.strings().len(), 100);
+
+        assert_eq!(strings.find("s42", hash_string("s42")), Some(ids[42]));
+        assert_eq!(strings.find("s100", hash_string("s100")), None);
+        assert_eq!(strings.get(ids[99]).as_str(), "s99");
+    }
+}
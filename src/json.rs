@@ -0,0 +1,155 @@
+use serde_json::{Number, Value as Json};
+
+use crate::{
+    value::{Obj, ObjType, Value},
+    vm::VM,
+};
+
+// json_parse() and json_stringify(). JSON null, booleans, strings,
+// arrays and objects become nil, bools, strings, lists and maps, in the
+// order the text has them. Numbers become ints when they are whole and
+// fit in one, floats otherwise. Going the other way tuples are written
+// as arrays too and instances as objects of their fields. Map keys have
+// to be strings, JSON has no other kind
+
+// deeper than this is most likely a list or map that contains itself,
+// and writing it would overflow the native stack
+const MAX_DEPTH: usize = 128;
+
+pub fn parse(vm: &mut VM, text: &str) -> Result<Value, String> {
+    // serde_json stops at 128 levels too, so from_json can recurse
+    let json: Json = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    Ok(from_json(vm, json))
+}
+
+fn from_json(vm: &mut VM, json: Json) -> Value {
+    match json {
+        Json::Null => Value::Nil,
+        Json::Bool(b) => Value::Boolean(b),
+        Json::Number(n) => match n.as_i64() {
+            Some(n) => Value::Int(n),
+            None => Value::Number(n.as_f64().unwrap_or_default()),
+        },
+        Json::String(s) => vm.intern_string(s),
+        Json::Array(items) => {
+            let items = items.into_iter().map(|item| from_json(vm, item)).collect();
+            Value::list(items)
+        }
+        Json::Object(fields) => {
+            let entries = fields
+                .into_iter()
+                .map(|(key, value)| (vm.intern_string(key), from_json(vm, value)))
+                .collect();
+            Value::map(entries)
+        }
+    }
+}
+
+// `vm` names the fields of instances
+pub fn stringify(vm: &VM, value: &Value) -> Result<String, String> {
+    let json = to_json(vm, value, 0)?;
+    serde_json::to_string(&json).map_err(|e| e.to_string())
+}
+
+fn to_json(vm: &VM, value: &Value, depth: usize) -> Result<Json, String> {
+    if depth > MAX_DEPTH {
+        return Err("it is nested too deeply or contains itself".to_string());
+    }
+    let obj = match value {
+        Value::Nil => return Ok(Json::Null),
+        Value::Boolean(b) => return Ok(Json::Bool(*b)),
+        Value::Int(n) => return Ok(Json::from(*n)),
+        Value::Number(n) => {
+            return Number::from_f64(*n)
+                .map(Json::Number)
+                .ok_or_else(|| format!("JSON has no {}", n))
+        }
+        Value::Object(Obj { obj_type }) => obj_type,
+    };
+    let items = |items: &[Value]| -> Result<Json, String> {
+        let items = items
+            .iter()
+            .map(|item| to_json(vm, item, depth + 1))
+            .collect::<Result<_, _>>()?;
+        Ok(Json::Array(items))
+    };
+    match obj {
+        ObjType::ObjString(string) => Ok(Json::String(string.as_str().to_string())),
+        ObjType::ObjList(list) => items(&list.borrow().items),
+        ObjType::ObjTuple(tuple) => items(tuple),
+        ObjType::ObjMap(map) => {
+            let mut fields = serde_json::Map::new();
+            for (key, value) in &map.borrow().entries {
+                let Value::Object(Obj {
+                    obj_type: ObjType::ObjString(key),
+                }) = key
+                else {
+                    return Err(format!("map keys must be strings, not {}", key.type_name()));
+                };
+                fields.insert(key.as_str().to_string(), to_json(vm, value, depth + 1)?);
+            }
+            Ok(Json::Object(fields))
+        }
+        ObjType::ObjInstance(instance) => {
+            let mut fields = serde_json::Map::new();
+            for entry in &instance.borrow().fields.entries {
+                fields.insert(
+                    vm.string(entry.key).as_str().to_string(),
+                    to_json(vm, &entry.value, depth + 1)?,
+                );
+            }
+            Ok(Json::Object(fields))
+        }
+        _ => Err(format!("JSON has no {}", value.type_name())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut vm = VM::init_vm();
+        let text = r#"{"name":"héllo \"x\"","n":-3,"pi":2.5,"tags":["a",null,true],"nested":{"empty":[]}}"#;
+        let value = parse(&mut vm, text).unwrap();
+        assert_eq!(
+            value.format_with(None),
+            "{\"name\": \"héllo \\\"x\\\"\", \"n\": -3, \"pi\": 2.5, \"tags\": [\"a\", nil, true], \"nested\": {\"empty\": []}}"
+        );
+        assert_eq!(
+            value
+                .as_map()
+                .unwrap()
+                .borrow()
+                .get(&vm.intern_string("n".to_string()))
+                .unwrap()
+                .type_name(),
+            "int"
+        );
+        // keys keep their order
+        assert_eq!(stringify(&vm, &value).unwrap(), text);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut vm = VM::init_vm();
+        for text in ["", "{", "[1,]", "{1: 2}", "nul"] {
+            assert!(parse(&mut vm, text).is_err(), "{}", text);
+        }
+
+        let map = Value::map(vec![(Value::Int(1), Value::Nil)]);
+        let cycle = Value::list(vec![]);
+        cycle
+            .as_list()
+            .unwrap()
+            .borrow_mut()
+            .items
+            .push(cycle.clone());
+        for value in [Value::Number(f64::NAN), map, cycle.clone()] {
+            assert!(stringify(&vm, &value).is_err());
+        }
+        // break the cycle so the test doesn't leak it
+        cycle.as_list().unwrap().borrow_mut().items.clear();
+    }
+}
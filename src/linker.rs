@@ -0,0 +1,256 @@
+use crate::{
+    chunk::{has_constant_operand, SourceFile},
+    Chunk, OpCode,
+};
+
+// Combines separately compiled chunks (several files, or REPL lines
+// compiled one at a time) into a single chunk that runs them in the
+// order they were added.
+//
+// Each chunk's constants are moved into one shared pool, so operands
+// that index the pool get relocated, see relocate_constants. An
+// OP_CONSTANT whose constant ends up past 255 becomes an
+// OP_CONSTANT_LONG, one byte longer, so jump operands are worked out
+// again from where their targets ended up. Global slots are left
+// alone: the chunks have to be compiled against the names of the VM
+// that runs them, see VM::compile, which numbers them the same way in
+// every chunk. Each chunk's trailing OP_RETURN is dropped, and the
+// linked chunk ends with a single one.
+pub struct Linker {
+    chunks: Vec<Chunk>,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker { chunks: vec![] }
+    }
+
+    pub fn add_chunk(&mut self, chunk: Chunk) {
+        self.chunks.push(chunk);
+    }
+
+    pub fn link(&self) -> Result<Chunk, String> {
+        let mut linked = Chunk::init_chunk();
+        let relocations = self.relocate_constants(&mut linked)?;
+        let mut last_line = 0;
+
+        for (chunk, relocated) in self.chunks.iter().zip(&relocations) {
+            let is_last_return = |offset: usize, len: usize| {
+                chunk.code[offset] == OpCode::OP_RETURN as u8 && offset + len == chunk.code.len()
+            };
+            let is_widened = |offset: usize| {
+                chunk.code[offset] == OpCode::OP_CONSTANT as u8
+                    && relocated[chunk.code[offset + 1] as usize] > u8::MAX as usize
+            };
+
+            // where each byte of the chunk ends up, one past the end
+            // too. A jump to the dropped OP_RETURN goes on to whatever
+            // comes after the chunk
+            let mut moved = vec![0; chunk.code.len() + 1];
+            let mut next = linked.code.len();
+            let mut offset = 0;
+            while offset < chunk.code.len() {
+                let len = chunk.instruction_len(offset);
+                moved[offset..offset + len].fill(next);
+                if !is_last_return(offset, len) {
+                    next += len + is_widened(offset) as usize;
+                }
+                offset += len;
+            }
+            moved[chunk.code.len()] = next;
+
+            for file in &chunk.files {
+                linked.files.push(SourceFile {
+                    start: moved[file.start],
+                    ..file.clone()
+                });
+            }
+
+            let mut offset = 0;
+            while offset < chunk.code.len() {
+                let op = chunk.code[offset];
+                let len = chunk.instruction_len(offset);
+                if is_last_return(offset, len) {
+                    break;
+                }
+                let mut bytes = chunk.code[offset..offset + len].to_vec();
+                if op == OpCode::OP_CONSTANT_LONG as u8 {
+                    let constant = relocated[(bytes[1] as usize) << 8 | bytes[2] as usize];
+                    bytes[1] = (constant >> 8) as u8;
+                    bytes[2] = constant as u8;
+                } else if is_widened(offset) {
+                    let constant = relocated[bytes[1] as usize];
+                    bytes = vec![
+                        OpCode::OP_CONSTANT_LONG as u8,
+                        (constant >> 8) as u8,
+                        constant as u8,
+                    ];
+                } else if has_constant_operand(op) {
+                    // names are below 256, see relocate_constants
+                    bytes[1] = relocated[bytes[1] as usize] as u8;
+                } else if let Some(target) = chunk.jump_target(offset) {
+                    let end = moved[offset] + len;
+                    let distance = if op == OpCode::OP_LOOP as u8 {
+                        end - moved[target]
+                    } else {
+                        moved[target] - end
+                    };
+                    if distance > u16::MAX as usize {
+                        return Err("Too much code to jump over in linked chunk.".to_string());
+                    }
+                    bytes[len - 2] = (distance >> 8) as u8;
+                    bytes[len - 1] = distance as u8;
+                }
+                // a widened instruction's extra byte is on the same line
+                for (i, byte) in bytes.into_iter().enumerate() {
+                    let source = offset + i.min(len - 1);
+                    let column = chunk.columns.get(source).copied().unwrap_or(0);
+                    linked.write_chunk_at(byte, chunk.lines[source], column);
+                }
+                offset += len;
+            }
+            last_line = chunk.lines.last().copied().unwrap_or(last_line);
+        }
+
+        linked.write_chunk(OpCode::OP_RETURN as u8, last_line);
+        Ok(linked)
+    }
+
+    // where each chunk's constants go in the linked pool. The names
+    // of classes, fields and methods only have one byte operands, so
+    // they go first, and a name several chunks use is only there once.
+    // The rest follow; an OP_CONSTANT loading one past 255 is widened
+    // by link()
+    fn relocate_constants(&self, linked: &mut Chunk) -> Result<Vec<Vec<usize>>, String> {
+        let mut relocations: Vec<Vec<usize>> = self
+            .chunks
+            .iter()
+            .map(|chunk| vec![usize::MAX; chunk.constants.values.len()])
+            .collect();
+
+        for (chunk, relocated) in self.chunks.iter().zip(&mut relocations) {
+            let mut offset = 0;
+            while offset < chunk.code.len() {
+                let op = chunk.code[offset];
+                if has_constant_operand(op) && op != OpCode::OP_CONSTANT as u8 {
+                    let constant = chunk.code[offset + 1] as usize;
+                    let value = &chunk.constants.values[constant];
+                    let existing = linked
+                        .constants
+                        .values
+                        .iter()
+                        .position(|name| name.values_equal(value));
+                    relocated[constant] = match existing {
+                        Some(index) => index,
+                        None => linked.add_constant(value.clone()),
+                    };
+                }
+                offset += chunk.instruction_len(offset);
+            }
+        }
+        if linked.constants.values.len() > u8::MAX as usize + 1 {
+            return Err("Too many names in linked chunk.".to_string());
+        }
+
+        for (chunk, relocated) in self.chunks.iter().zip(&mut relocations) {
+            for (constant, value) in chunk.constants.values.iter().enumerate() {
+                if relocated[constant] == usize::MAX {
+                    relocated[constant] = linked.add_constant(value.clone());
+                }
+            }
+        }
+        if linked.constants.values.len() > u16::MAX as usize + 1 {
+            return Err("Too many constants in linked chunk.".to_string());
+        }
+        Ok(relocations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{InterpretResult, VM};
+
+    fn compile(vm: &mut VM, source: &str, file: &str) -> Chunk {
+        vm.compile(source, file).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_link_chunks() {
+        let mut elephant_vm = VM::init_vm();
+        let first = compile(&mut elephant_vm, "var a = 1;\nvar s = \"x\";", "a.elx");
+        let second = compile(
+            &mut elephant_vm,
+            "var b = a + 2;\nif (b > 2) b = b * 10; else b = 0;\ns = s + \"y\";",
+            "b.elx",
+        );
+        let constants = first.constants.values.len() + second.constants.values.len();
+        let code = first.code.len() + second.code.len() - 1;
+        let mut linker = Linker::new();
+        linker.add_chunk(first);
+        linker.add_chunk(second);
+        let linked = linker.link().unwrap();
+        assert_eq!(linked.constants.values.len(), constants);
+        assert_eq!(linked.code.len(), code);
+        assert_eq!(linked.file_at(0), Some("a.elx"));
+        assert_eq!(linked.file_at(linked.code.len() - 1), Some("b.elx"));
+        // only the final return is left
+        let returns = (0..linked.code.len())
+            .scan(0, |next, offset| {
+                let at = offset == *next;
+                if at {
+                    *next += linked.instruction_len(offset);
+                }
+                Some(at && linked.code[offset] == OpCode::OP_RETURN as u8)
+            })
+            .filter(|found| *found)
+            .count();
+        assert_eq!(returns, 1);
+
+        assert_eq!(
+            elephant_vm.interpret_chunk(linked),
+            InterpretResult::InterpretOk
+        );
+        assert_eq!(elephant_vm.get_global("b").unwrap().as_number(), Some(30.0));
+        let value = elephant_vm.get_global("s").unwrap();
+        assert_eq!(value.as_obj().unwrap().obj_type.as_obj_string(), "xy");
+    }
+
+    #[test]
+    fn test_link_many_constants() {
+        // about 150 constants each, 300 in all. The second file's loads
+        // past 255 are widened inside the loop, which jumps over them
+        let first: String = (0..150).map(|i| format!("a = a + {};\n", i)).collect();
+        let first = format!("var a = 0;\n{}", first);
+        let second: String = (0..150)
+            .map(|i| format!("p.x = p.x + {}.5;\n", i))
+            .collect();
+        let second = format!(
+            "class P {{}}\nvar p = P();\np.x = 0;\nvar n = 0;\n\
+             while (n < 2) {{\nn = n + 1;\n{}}}\nvar x = p.x;",
+            second
+        );
+
+        let mut elephant_vm = VM::init_vm();
+        let mut linker = Linker::new();
+        for chunk in elephant_vm.compile(&first, "a.elx").unwrap() {
+            linker.add_chunk(chunk);
+        }
+        for chunk in elephant_vm.compile(&second, "b.elx").unwrap() {
+            linker.add_chunk(chunk);
+        }
+        let linked = linker.link().unwrap();
+        assert!(linked.constants.values.len() > u8::MAX as usize + 1);
+        assert!(linked.code.contains(&(OpCode::OP_CONSTANT_LONG as u8)));
+        assert_eq!(linked.file_at(linked.code.len() - 1), Some("b.elx"));
+
+        assert_eq!(
+            elephant_vm.interpret_chunk(linked),
+            InterpretResult::InterpretOk
+        );
+        let a = elephant_vm.get_global("a").unwrap();
+        assert_eq!(a.format_with(None), (0..150).sum::<i64>().to_string());
+        let x: f64 = (0..150).map(|i| i as f64 + 0.5).sum::<f64>() * 2.0;
+        assert_eq!(elephant_vm.get_global("x").unwrap().as_number(), Some(x));
+    }
+}
@@ -1,26 +1,103 @@
+// The VM keeps clox's naming (OP_* opcodes, init_*/free_* helpers,
+// Interpret* results) and its explicit returns. ObjType is used as a
+// hash key even though instances are mutable: they hash by address,
+// which a field assignment doesn't change.
+#![allow(
+    non_camel_case_types,
+    clippy::needless_return,
+    clippy::enum_variant_names,
+    clippy::mutable_key_type
+)]
+
 mod chunk;
 mod compiler;
+mod diagnostics;
+#[cfg(test)]
+mod fuzz;
+mod globals;
+mod interner;
+mod json;
+mod linker;
+mod natives;
+mod net;
+mod peephole;
 mod scanner;
+mod session;
 mod table;
 mod value;
 mod vm;
 use std::io;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
 use crate::chunk::*;
+use crate::linker::Linker;
 use crate::scanner::*;
-use crate::table::*;
 use crate::value::*;
 use crate::vm::*;
 use clap::Parser;
 
 #[derive(Parser, Debug)]
 struct Args {
+    // given more than once, the scripts are compiled one by one and
+    // linked into a single program that runs them in order
     #[arg(short, long)]
-    script: Option<String>,
+    script: Vec<String>,
     #[arg(short, long)]
     repl: bool,
+    // report scan, parse/codegen and run times for each program
+    #[arg(long)]
+    time_passes: bool,
+    // print numbers with this many digits after the decimal point
+    // instead of the shortest round-trip form
+    #[arg(long)]
+    precision: Option<usize>,
+    // list each constant with its index, type and the instructions using it
+    #[arg(long)]
+    dump_constants: bool,
+    // print how many strings are interned and how well interning hits
+    #[arg(long)]
+    dump_interns: bool,
+    // print the bytecode size of each chunk and function of a program
+    #[arg(long)]
+    size_report: bool,
+    // don't run ~/.elephantrc when the REPL starts
+    #[arg(long)]
+    no_rc: bool,
+    // start without the standard prelude definitions
+    #[arg(long)]
+    no_prelude: bool,
+    // optimize the compiled bytecode
+    #[arg(short = 'O', long)]
+    optimize: bool,
+    // stop with a runtime error when arithmetic gives NaN or overflows
+    // to infinity, including division by zero
+    #[arg(long)]
+    checked_arithmetic: bool,
+    // most instructions one REPL input may run, 0 for no limit.
+    // Scripts are never limited
+    #[arg(long, default_value_t = 100_000_000)]
+    repl_budget: usize,
+    // warn about local variables that are never read or that shadow
+    // another variable, and about globals that are never declared
+    #[arg(long)]
+    warn: bool,
+    // treat warnings as errors: the program doesn't run if it has any
+    #[arg(long)]
+    deny_warnings: bool,
+    // compile in strict mode, like a program starting with "use strict";
+    // warnings are errors and so is assigning an undeclared global
+    #[arg(long)]
+    strict: bool,
+    // run the functions the script registers with test() after it and
+    // report which pass. The exit code is 1 if any fail
+    #[arg(long)]
+    test: bool,
+    // everything after the script's path, passed to it as `args`:
+    // elephant -s tool.el input.txt --verbose
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    script_args: Vec<String>,
 }
 
 fn main() {
@@ -28,16 +105,53 @@ fn main() {
 
     // init vm before doing anything else
     let mut elephant_vm = VM::init_vm();
+    elephant_vm.number_precision = args.precision;
+    elephant_vm.optimize = args.optimize;
+    elephant_vm.checked_arithmetic = args.checked_arithmetic;
+    if !args.no_prelude {
+        elephant_vm.load_prelude();
+    }
+    // after the prelude, so only the user's programs are reported
+    elephant_vm.time_passes = args.time_passes;
+    elephant_vm.dump_constants = args.dump_constants;
+    elephant_vm.dump_interns = args.dump_interns;
+    elephant_vm.size_report = args.size_report;
+    elephant_vm.show_warnings = args.warn;
+    elephant_vm.deny_warnings = args.deny_warnings;
+    elephant_vm.strict = args.strict;
+    elephant_vm.set_args(&args.script_args);
 
-    if let Some(script) = args.script {
+    if !args.script.is_empty() {
         // Run the file if script path is provided
-        run_file(&script, &mut elephant_vm);
+        match args.script.as_slice() {
+            [script] => run_file(script, &mut elephant_vm),
+            scripts => run_linked(scripts, &mut elephant_vm),
+        }
+        exit_if_requested(&mut elephant_vm);
+        if args.test {
+            let (_, failed) = elephant_vm.run_tests();
+            exit_if_requested(&mut elephant_vm);
+            if failed > 0 {
+                exit(1);
+            }
+        }
     } else if args.repl {
-        // Run REPL mode if --repl flag is set
+        // Run REPL mode if --repl flag is set. Each input gets a budget
+        // so a runaway loop hands the prompt back instead of hanging
+        println!(
+            "elephant {}. Commands: :type <expr>, :dis <expr>, :vars, :sandbox <code>, :save <path>, :load-session <path>.",
+            env!("CARGO_PKG_VERSION")
+        );
+        if let Some(path) = rc_path().filter(|_| !args.no_rc) {
+            load_rc(&mut elephant_vm, &path);
+        }
+        if args.repl_budget > 0 {
+            elephant_vm.instruction_budget = Some(args.repl_budget);
+        }
         repl(&mut elephant_vm);
     } else {
         // If no arguments provided, print usage and exit
-        println!("Usage: elephant [--script <path>] [--repl]");
+        println!("Usage: elephant [--script <path> [--test]] [--repl]");
         exit(64);
     }
 
@@ -45,6 +159,27 @@ fn main() {
     elephant_vm.free_vm();
 }
 
+// ~/.elephantrc, None without a home directory
+fn rc_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(Path::new(&home).join(".elephantrc"))
+}
+
+// the rc file is an ordinary script the REPL runs before the first
+// prompt, for helpers and settings the user always wants around. A
+// missing one is skipped quietly; errors in it are reported and the
+// REPL starts anyway
+fn load_rc(vm: &mut VM, path: &Path) {
+    let Ok(source) = std::fs::read_to_string(path) else {
+        return;
+    };
+    let name = path.display().to_string();
+    if vm.interpret_file(&source, &name) == InterpretResult::InterpretOk {
+        vm.write_text(&format!("Loaded {}.\n", name));
+        vm.flush_output();
+    }
+}
+
 fn repl(vm: &mut VM) {
     loop {
         print!("<: ");
@@ -54,13 +189,106 @@ fn repl(vm: &mut VM) {
             .read_line(&mut input_text)
             .expect("failed to read from stdin");
         println!("{}", input_text);
-        &vm.interpret(&input_text);
+        if !repl_command(vm, input_text.trim()) {
+            vm.interpret(&input_text);
+            exit_if_requested(vm);
+        }
+    }
+}
+
+// REPL-only introspection commands. Returns false when the line is
+// ordinary source code
+//   :type <expr>  evaluate the expression and show its runtime type
+//   :dis <expr>   show the bytecode the expression compiles to
+//   :vars         list globals in the order they were defined
+//   :sandbox <code>
+//                 run the code, then drop the globals it defined or
+//                 assigned again and list them
+//   :save <path>  write the session's globals to a file
+//   :load-session <path>
+//                 define the globals saved in a file
+fn repl_command(vm: &mut VM, line: &str) -> bool {
+    if let Some(path) = line.strip_prefix(":save ") {
+        match vm.save_session(path.trim()) {
+            Ok((count, skipped)) => {
+                for line in &skipped {
+                    println!("{}", line);
+                }
+                println!("Saved {} globals to {}.", count, path.trim());
+            }
+            Err(error) => println!("Could not save session: {}.", error),
+        }
+    } else if let Some(path) = line.strip_prefix(":load-session ") {
+        match vm.load_session(path.trim()) {
+            Ok(count) => println!("Loaded {} globals from {}.", count, path.trim()),
+            Err(error) => println!("Could not load session: {}.", error),
+        }
+    } else if line == ":vars" {
+        for (name, value) in &vm.globals() {
+            print!("{} = ", name.as_obj_string());
+            value.print_value_with(vm.number_precision);
+            println!();
+        }
+    } else if let Some(source) = line.strip_prefix(":sandbox ") {
+        let (_, defined) = vm.interpret_isolated(source);
+        for (name, value) in &defined {
+            print!("{} = ", name.as_obj_string());
+            value.print_value_with(vm.number_precision);
+            println!();
+        }
+    } else if let Some(expr) = line.strip_prefix(":type ") {
+        if let Some(value) = vm.evaluate(expr) {
+            println!("{}", value.type_name());
+        }
+    } else if let Some(expr) = line.strip_prefix(":dis ") {
+        vm.disassemble_expression(expr);
+    } else if line.starts_with(':') {
+        println!(
+            "Unknown command '{}'. Try :type <expr>, :dis <expr>, :vars, :sandbox <code>, :save <path> or :load-session <path>.",
+            line
+        );
+    } else {
+        return false;
+    }
+    true
+}
+
+// the program called exit(), end the process with its code
+fn exit_if_requested(vm: &mut VM) {
+    if let Some(code) = vm.exit_code {
+        vm.free_vm();
+        exit(code);
+    }
+}
+
+// several scripts, each compiled on its own against the VM's globals
+// and then linked into one chunk, see Linker
+fn run_linked(files: &[String], vm: &mut VM) {
+    let mut linker = Linker::new();
+    for file in files {
+        let file_content = std::fs::read_to_string(file).expect("Failed to read file");
+        let Some(chunks) = vm.compile(&file_content, file) else {
+            exit(65);
+        };
+        for chunk in chunks {
+            linker.add_chunk(chunk);
+        }
+    }
+    let chunk = match linker.link() {
+        Ok(chunk) => chunk,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            exit(65);
+        }
+    };
+    if vm.interpret_chunk(chunk) != InterpretResult::InterpretOk {
+        exit(70);
     }
 }
 
 fn run_file(file: &str, vm: &mut VM) {
     let file_content = std::fs::read_to_string(file).expect("Failed to read file");
-    let result = vm.interpret(&file_content);
+    let result = vm.interpret_file(&file_content, file);
 
     match result {
         InterpretResult::InterpretCompileError => exit(65),
@@ -68,3 +296,27 @@ fn run_file(file: &str, vm: &mut VM) {
         InterpretResult::InterpretOk => (), // Continue execution
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_rc() {
+        let mut elephant_vm = VM::init_vm();
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+        let path = std::env::temp_dir().join(format!("elephant-rc-{}", std::process::id()));
+
+        // no file, nothing to run or announce
+        load_rc(&mut elephant_vm, &path);
+        assert_eq!(output.text(), "");
+        assert!(elephant_vm.get_global("greeting").is_none());
+
+        std::fs::write(&path, "var greeting = \"hi\";\nprint greeting;").unwrap();
+        load_rc(&mut elephant_vm, &path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(output.text(), format!("hi\nLoaded {}.\n", path.display()));
+        assert!(elephant_vm.get_global("greeting").is_some());
+    }
+}
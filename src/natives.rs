@@ -0,0 +1,1938 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use regex::Regex;
+
+use crate::{
+    json, net,
+    table::Table,
+    value::{index_position, Obj, ObjInstance, ObjList, ObjMap, ObjString, ObjType, Value},
+    vm::VM,
+};
+
+// every native the VM defines as a global at startup
+pub fn define_natives(vm: &mut VM) {
+    vm.define_native("clock", Some(0), clock);
+    vm.define_native("globals", Some(0), globals);
+    vm.define_native("fields", Some(1), fields);
+    vm.define_native("arity", Some(1), arity);
+    vm.define_native("name", Some(1), name);
+    vm.define_native("clone", Some(1), clone);
+    vm.define_native("deep_clone", None, deep_clone);
+    vm.define_native("len", Some(1), len);
+    vm.define_native("type", Some(1), type_of);
+    vm.define_native("str", Some(1), str);
+    vm.define_native("num", Some(1), num);
+    vm.define_native("input", None, input);
+    vm.define_native("write", None, write);
+    vm.define_native("format", None, format);
+    vm.define_native("printf", None, printf);
+    vm.define_native("exit", None, exit);
+    vm.define_native("read_file", Some(1), read_file);
+    vm.define_native("write_file", Some(2), write_file);
+    vm.define_native("append_file", Some(2), append_file);
+    vm.define_native("file_exists", Some(1), file_exists);
+    vm.define_native("time", Some(0), time);
+    vm.define_native("sleep", Some(1), sleep);
+    vm.define_native("random", Some(0), random);
+    vm.define_native("random_int", Some(2), random_int);
+    vm.define_native("random_seed", Some(1), random_seed);
+    vm.define_native("json_parse", Some(1), json_parse);
+    vm.define_native("json_stringify", Some(1), json_stringify);
+    vm.define_native("regex_match", Some(2), regex_match);
+    vm.define_native("regex_find_all", Some(2), regex_find_all);
+    vm.define_native("regex_replace", Some(3), regex_replace);
+    vm.define_native("exec", None, exec);
+    vm.define_native("tcp_connect", Some(2), tcp_connect);
+    vm.define_native("tcp_send", Some(2), tcp_send);
+    vm.define_native("tcp_receive", Some(1), tcp_receive);
+    vm.define_native("tcp_close", Some(1), tcp_close);
+    vm.define_native("http_get", Some(1), http_get);
+    vm.define_native("test", Some(2), test);
+    vm.define_native("assert_eq", Some(2), assert_eq);
+    vm.define_native("assert_ne", Some(2), assert_ne);
+    define_string_methods(vm);
+    define_list_methods(vm);
+    define_map_methods(vm);
+}
+
+// methods of strings, "a,b".split(","). Strings never change, so the
+// ones that edit return a new string. Positions count characters like
+// indexing does
+fn define_string_methods(vm: &mut VM) {
+    vm.define_method("string", "len", Some(0), len);
+    vm.define_method("string", "upper", Some(0), upper);
+    vm.define_method("string", "lower", Some(0), lower);
+    vm.define_method("string", "trim", Some(0), trim);
+    vm.define_method("string", "split", Some(1), split);
+    vm.define_method("string", "replace", Some(2), replace);
+    vm.define_method("string", "contains", Some(1), contains);
+    vm.define_method("string", "starts_with", Some(1), starts_with);
+    vm.define_method("string", "substring", None, substring);
+    vm.define_method("string", "char_at", Some(1), char_at);
+    vm.define_method("string", "substr_chars", Some(2), substr_chars);
+    vm.define_method("string", "byte_len", Some(0), byte_len);
+    vm.define_method("string", "find", Some(1), find);
+}
+
+// methods of lists, xs.push(1). Lists are shared, so the ones that
+// change a list change it for every variable holding it
+fn define_list_methods(vm: &mut VM) {
+    vm.define_method("list", "len", Some(0), len);
+    vm.define_method("list", "push", Some(1), push);
+    vm.define_method("list", "pop", Some(0), pop);
+    vm.define_method("list", "insert", Some(2), insert);
+    vm.define_method("list", "remove", Some(1), remove);
+    vm.define_method("list", "index_of", Some(1), index_of);
+    vm.define_method("list", "reverse", Some(0), reverse);
+    vm.define_method("list", "concat", Some(1), concat);
+}
+
+// methods of maps, m.keys(). Maps are shared like lists
+fn define_map_methods(vm: &mut VM) {
+    vm.define_method("map", "len", Some(0), len);
+    vm.define_method("map", "keys", Some(0), keys);
+    vm.define_method("map", "values", Some(0), values);
+    vm.define_method("map", "has", Some(1), has);
+    vm.define_method("map", "remove", Some(1), remove_key);
+    vm.define_method("map", "merge", Some(1), merge);
+}
+
+// seconds since the VM started, for timing scripts. Only the difference
+// between two calls means anything
+fn clock(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(vm.start_time.elapsed().as_secs_f64()))
+}
+
+// the wall clock as seconds since 1970-01-01 UTC, with a fraction. Can
+// jump when the system clock is set, so time differences belong to clock()
+fn time(_vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| "time() found the system clock before 1970.".to_string())?;
+    Ok(Value::Number(now.as_secs_f64()))
+}
+
+// pauses the program for `ms` milliseconds
+fn sleep(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let Some(ms) = args[0]
+        .as_number()
+        .filter(|ms| *ms >= 0.0 && ms.is_finite())
+    else {
+        return Err("sleep() expects a number of milliseconds >= 0.".to_string());
+    };
+    // what was printed so far shows before the pause
+    vm.flush_output();
+    thread::sleep(Duration::from_secs_f64(ms / 1000.0));
+    Ok(Value::Nil)
+}
+
+// reflection. Names come back as a list of strings in definition order
+
+fn globals(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    let names = vm
+        .globals()
+        .into_iter()
+        .map(|(name, _)| Value::Object(Obj { obj_type: name }))
+        .collect();
+    Ok(Value::list(names))
+}
+
+fn fields(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let Some(instance) = args[0].as_instance() else {
+        return Err("fields() expects an instance.".to_string());
+    };
+    let names = key_names(vm, &instance.borrow().fields);
+    Ok(Value::list(names))
+}
+
+// number of arguments a function takes, nil if it takes any number.
+// A class takes what its initializer takes
+fn arity(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let Value::Object(obj) = &args[0] else {
+        return Err("arity() expects a function or class.".to_string());
+    };
+    let arity = match &obj.obj_type {
+        ObjType::ObjNative(native) => {
+            return Ok(native.arity.map_or(Value::Nil, |n| Value::Int(n as i64)))
+        }
+        ObjType::ObjFunction(function) => function.arity,
+        ObjType::ObjBoundMethod(bound) => bound.method.arity,
+        ObjType::ObjBoundNative(bound) => {
+            return Ok(bound
+                .native
+                .arity
+                .map_or(Value::Nil, |n| Value::Int(n as i64)))
+        }
+        ObjType::ObjClass(class) => {
+            let init = vm
+                .string_id("init")
+                .and_then(|init| class.find_method(init));
+            init.map_or(0, |init| init.arity)
+        }
+        _ => return Err("arity() expects a function or class.".to_string()),
+    };
+    Ok(Value::Int(arity as i64))
+}
+
+fn name(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let Value::Object(obj) = &args[0] else {
+        return Err("name() expects a function or class.".to_string());
+    };
+    let name = match &obj.obj_type {
+        ObjType::ObjNative(native) => native.name.clone(),
+        ObjType::ObjFunction(function) => function.name.clone(),
+        ObjType::ObjBoundMethod(bound) => bound.method.name.clone(),
+        ObjType::ObjBoundNative(bound) => bound.native.name.clone(),
+        ObjType::ObjClass(class) => class.name.clone(),
+        _ => return Err("name() expects a function or class.".to_string()),
+    };
+    Ok(vm.intern_string(name))
+}
+
+// the name of the value's type, as error messages show it: "int",
+// "number" (a float), "string", "bool", "nil", "function", "class",
+// "instance", "list", "tuple" or "range"
+fn type_of(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(vm.intern_string(args[0].type_name().to_string()))
+}
+
+// the text print would show for the value, so numbers can be joined
+// onto strings: "n = " + str(3)
+fn str(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if args[0].is_string() {
+        return Ok(args[0].clone());
+    }
+    let text = match vm.number_precision {
+        Some(precision) => format!("{:.*}", precision, args[0]),
+        None => args[0].to_string(),
+    };
+    Ok(vm.intern_string(text))
+}
+
+// parses a string such as " 42 " or "-1.5e3" into a number, or nil when
+// it isn't one, so input can be checked with num(line) == nil. Whole
+// numbers become ints, the same as literals
+fn num(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = match &args[0] {
+        Value::Number(_) | Value::Int(_) => return Ok(args[0].clone()),
+        Value::Object(Obj {
+            obj_type: ObjType::ObjString(string),
+        }) => string.as_str().trim(),
+        other => {
+            return Err(format!(
+                "num() expects a string or number, not {}.",
+                other.type_name()
+            ))
+        }
+    };
+    if let Ok(value) = text.parse::<i64>() {
+        return Ok(Value::Int(value));
+    }
+    // f64 parsing also takes "inf" and "NaN", which aren't numbers a
+    // script could write
+    let numeric = text
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'));
+    match text.parse::<f64>() {
+        Ok(value) if numeric => Ok(Value::Number(value)),
+        _ => Ok(Value::Nil),
+    }
+}
+
+// print without the newline: write(a, b) shows a then b on the current
+// line. Returns nil
+fn write(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    for arg in args {
+        vm.write(arg);
+    }
+    Ok(Value::Nil)
+}
+
+// format("%s is %.2f", name, x): the format string with each specifier
+// replaced by the next argument. %s takes any value as print shows it,
+// %d a whole number, %f a number with 6 decimals or %.Nf with N, and
+// %% is a literal %
+fn format(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = format_args("format", args)?;
+    Ok(vm.intern_string(text))
+}
+
+// format() written out without a newline
+fn printf(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = format_args("printf", args)?;
+    vm.write_text(&text);
+    Ok(Value::Nil)
+}
+
+fn format_args(name: &str, args: &[Value]) -> Result<String, String> {
+    let (template, mut rest) = match args.split_first() {
+        Some((
+            Value::Object(Obj {
+                obj_type: ObjType::ObjString(template),
+            }),
+            rest,
+        )) => (template.as_str(), rest.iter()),
+        Some((other, _)) => {
+            return Err(format!(
+                "{}() expects a format string, not {}.",
+                name,
+                other.type_name()
+            ))
+        }
+        None => return Err(format!("{}() expects a format string.", name)),
+    };
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let mut precision = None;
+        if chars.next_if_eq(&'.').is_some() {
+            let mut digits = String::new();
+            while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                digits.push(digit);
+            }
+            precision = digits.parse::<usize>().ok();
+            if precision.is_none() {
+                return Err(format!("{}() expects digits after '%.'.", name));
+            }
+        }
+        let specifier = chars.next();
+        if specifier == Some('%') && precision.is_none() {
+            out.push('%');
+            continue;
+        }
+        let spec = match specifier {
+            Some(spec @ ('s' | 'd' | 'f')) => spec,
+            Some(other) => {
+                return Err(format!("{}() has an unknown specifier '%{}'.", name, other))
+            }
+            None => return Err(format!("{}() format string ends with '%'.", name)),
+        };
+        if precision.is_some() && spec != 'f' {
+            return Err(format!("{}() only takes a precision for %f.", name));
+        }
+        let Some(arg) = rest.next() else {
+            return Err(format!("{}() has more specifiers than arguments.", name));
+        };
+        match (spec, arg) {
+            ('s', arg) => out.push_str(&arg.to_string()),
+            ('d', Value::Int(n)) => out.push_str(&n.to_string()),
+            ('d', Value::Number(n)) if n.fract() == 0.0 => out.push_str(&(*n as i64).to_string()),
+            ('f', arg) if arg.as_number().is_some() => {
+                let n = arg.as_number().unwrap_or_default();
+                out.push_str(&format!("{:.*}", precision.unwrap_or(6), n));
+            }
+            (_, arg) => {
+                let expected = if spec == 'd' {
+                    "a whole number"
+                } else {
+                    "a number"
+                };
+                // 1.5 says more than "number" when %d gets a fraction
+                let got = match arg {
+                    Value::Number(_) => arg.to_string(),
+                    _ => arg.type_name().to_string(),
+                };
+                return Err(format!(
+                    "{}() %{} expects {}, not {}.",
+                    name, spec, expected, got
+                ));
+            }
+        }
+    }
+    if rest.next().is_some() {
+        return Err(format!("{}() has more arguments than specifiers.", name));
+    }
+    Ok(out)
+}
+
+// exit() or exit(code): stops the program, and the process exits with
+// the code, 0 when there is none. Not an error, so a catch block can't
+// stop it
+fn exit(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let code = match args {
+        [] => 0,
+        [Value::Int(n)] => i32::try_from(*n).map_err(|_| "exit() code is out of range.")?,
+        [Value::Number(n)] if n.fract() == 0.0 && n.abs() <= i32::MAX as f64 => *n as i32,
+        [other] => {
+            return Err(format!(
+                "exit() expects a whole number, not {}.",
+                other.type_name()
+            ))
+        }
+        _ => return Err(format!("Expected 0 or 1 arguments but got {}.", args.len())),
+    };
+    vm.exit_code = Some(code);
+    Ok(Value::Nil)
+}
+
+// input() or input(prompt): a line typed by the user, without its line
+// ending, or nil at the end of input
+fn input(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let prompt = match args {
+        [] => String::new(),
+        [prompt] => prompt.to_string(),
+        _ => return Err(format!("Expected 0 or 1 arguments but got {}.", args.len())),
+    };
+    match vm.read_line(&prompt) {
+        Ok(Some(line)) => Ok(vm.intern_string(line)),
+        Ok(None) => Ok(Value::Nil),
+        Err(e) => Err(format!("input() couldn't read a line: {}.", e)),
+    }
+}
+
+// number of items. Strings count characters, not bytes, the same
+// positions indexing, slicing and for-in use, so len("héllo") is 5
+fn len(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if let Some(list) = args[0].as_list() {
+        return Ok(Value::Int(list.borrow().items.len() as i64));
+    }
+    if let Some(items) = args[0].as_tuple() {
+        return Ok(Value::Int(items.len() as i64));
+    }
+    if let Some(map) = args[0].as_map() {
+        return Ok(Value::Int(map.borrow().len() as i64));
+    }
+    if let Some(range) = args[0].as_range() {
+        let count = (range.end_exclusive() - range.start).ceil().max(0.0);
+        return Ok(Value::Int(count as i64));
+    }
+    match &args[0] {
+        Value::Object(Obj {
+            obj_type: ObjType::ObjString(string),
+        }) => Ok(Value::Int(string.char_len() as i64)),
+        other => Err(format!(
+            "len() expects a string, list, map, tuple or range, not {}.",
+            other.type_name()
+        )),
+    }
+}
+
+// Random numbers, from a xorshift64* generator kept in the VM. Not for
+// anything that has to be unpredictable, like keys or tokens
+
+fn next_random(vm: &mut VM) -> u64 {
+    let state = &mut vm.random_state;
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+// a number from 0 up to but not including 1
+fn random(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    // the top 53 bits, as many as an f64 holds exactly
+    let bits = next_random(vm) >> 11;
+    Ok(Value::Number(bits as f64 / (1u64 << 53) as f64))
+}
+
+// a whole number from lo to hi, both included
+fn random_int(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let (Some(lo), Some(hi)) = (whole_number(&args[0]), whole_number(&args[1])) else {
+        return Err("random_int() expects whole numbers.".to_string());
+    };
+    if lo > hi {
+        return Err(format!("random_int() range {}..={} is empty.", lo, hi));
+    }
+    // i128, as hi - lo + 1 doesn't fit in an i64 for the widest ranges
+    let span = (hi as i128 - lo as i128 + 1) as u128;
+    let offset = next_random(vm) as u128 % span;
+    Ok(Value::Int((lo as i128 + offset as i128) as i64))
+}
+
+fn random_seed(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let Some(seed) = whole_number(&args[0]) else {
+        return Err("random_seed() expects a whole number.".to_string());
+    };
+    vm.seed_random(seed as u64);
+    Ok(Value::Nil)
+}
+
+// see json.rs for how values and JSON map onto each other
+fn json_parse(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = string_arg("json_parse", &args[0])?;
+    json::parse(vm, text).map_err(|e| format!("json_parse() couldn't parse the text: {}.", e))
+}
+
+fn json_stringify(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = json::stringify(vm, &args[0])
+        .map_err(|e| format!("json_stringify() can't write the value: {}.", e))?;
+    Ok(vm.intern_string(text))
+}
+
+// Regular expressions, in the syntax of the regex crate:
+// https://docs.rs/regex/latest/regex/#syntax. Matching takes time
+// linear in the text, there is no backtracking
+
+// patterns kept compiled before the cache starts over
+const REGEX_CACHE_SIZE: usize = 64;
+
+fn compile_regex(vm: &mut VM, function: &str, pattern: &str) -> Result<Regex, String> {
+    if let Some(regex) = vm.regex_cache.get(pattern) {
+        return Ok(regex.clone());
+    }
+    let regex =
+        Regex::new(pattern).map_err(|e| format!("{}() got an invalid pattern: {}", function, e))?;
+    if vm.regex_cache.len() == REGEX_CACHE_SIZE {
+        vm.regex_cache.clear();
+    }
+    vm.regex_cache.insert(pattern.to_string(), regex.clone());
+    Ok(regex)
+}
+
+// the first match as a list, the whole match followed by each group
+// (nil for a group that didn't take part), or nil if nothing matches
+fn regex_match(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let pattern = string_arg("regex_match", &args[0])?;
+    let text = string_arg("regex_match", &args[1])?;
+    let regex = compile_regex(vm, "regex_match", pattern)?;
+    let Some(captures) = regex.captures(text) else {
+        return Ok(Value::Nil);
+    };
+    let groups = captures
+        .iter()
+        .map(|group| {
+            group.map_or(Value::Nil, |group| {
+                vm.intern_string(group.as_str().to_string())
+            })
+        })
+        .collect();
+    Ok(Value::list(groups))
+}
+
+// every match that doesn't overlap the one before, as a list of strings
+fn regex_find_all(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let pattern = string_arg("regex_find_all", &args[0])?;
+    let text = string_arg("regex_find_all", &args[1])?;
+    let regex = compile_regex(vm, "regex_find_all", pattern)?;
+    let found = regex
+        .find_iter(text)
+        .map(|found| vm.intern_string(found.as_str().to_string()))
+        .collect();
+    Ok(Value::list(found))
+}
+
+// every match replaced. $1 or ${name} in the replacement stand for what
+// a group matched, $$ for a $
+fn regex_replace(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let pattern = string_arg("regex_replace", &args[0])?;
+    let text = string_arg("regex_replace", &args[1])?;
+    let replacement = string_arg("regex_replace", &args[2])?;
+    let regex = compile_regex(vm, "regex_replace", pattern)?;
+    let replaced = regex.replace_all(text, replacement).into_owned();
+    Ok(vm.intern_string(replaced))
+}
+
+// exec(cmd) or exec(cmd, args): runs the program with the list of
+// string arguments, without a shell, and waits for it. Returns a map
+// with its "stdout" and "stderr" text and its exit "status", nil when a
+// signal ended it. A program that can't be started is a runtime error
+fn exec(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let (command, arguments) = match args {
+        [command] => (command, vec![]),
+        [command, arguments] => {
+            let list = list_arg("exec", arguments)?;
+            let arguments = list
+                .borrow()
+                .items
+                .iter()
+                .map(|item| string_arg("exec", item).map(String::from))
+                .collect::<Result<Vec<_>, _>>()?;
+            (command, arguments)
+        }
+        _ => return Err(format!("Expected 1 or 2 arguments but got {}.", args.len())),
+    };
+    let command = string_arg("exec", command)?;
+    let output = Command::new(command)
+        .args(&arguments)
+        .output()
+        .map_err(|e| format!("exec() couldn't run '{}': {}.", command, e))?;
+    let stdout = vm.intern_string(String::from_utf8_lossy(&output.stdout).into_owned());
+    let stderr = vm.intern_string(String::from_utf8_lossy(&output.stderr).into_owned());
+    let status = output
+        .status
+        .code()
+        .map_or(Value::Nil, |code| Value::Int(code as i64));
+    let entries = vec![
+        (vm.intern_string("stdout".to_string()), stdout),
+        (vm.intern_string("stderr".to_string()), stderr),
+        (vm.intern_string("status".to_string()), status),
+    ];
+    Ok(Value::map(entries))
+}
+
+// Networking, see net.rs. tcp_connect() returns a number standing for
+// the connection, which the other tcp_* natives take. Failures are
+// runtime errors a script can catch
+
+fn tcp_connect(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let host = string_arg("tcp_connect", &args[0])?;
+    let port = whole_number(&args[1])
+        .and_then(|port| u16::try_from(port).ok())
+        .ok_or_else(|| "tcp_connect() port must be a whole number from 0 to 65535.".to_string())?;
+    let connection = net::Connection::open(host, port).map_err(|e| {
+        format!(
+            "tcp_connect() couldn't connect to {}:{}: {}.",
+            host, port, e
+        )
+    })?;
+    let handle = vm.next_connection;
+    vm.next_connection += 1;
+    vm.connections.insert(handle, connection);
+    Ok(Value::Int(handle))
+}
+
+// the open connection `value` stands for
+fn connection<'a>(
+    vm: &'a mut VM,
+    function: &str,
+    value: &Value,
+) -> Result<&'a mut net::Connection, String> {
+    whole_number(value)
+        .and_then(|handle| vm.connections.get_mut(&handle))
+        .ok_or_else(|| format!("{}() expects an open connection.", function))
+}
+
+fn tcp_send(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = string_arg("tcp_send", &args[1])?;
+    connection(vm, "tcp_send", &args[0])?
+        .send(text)
+        .map_err(|e| format!("tcp_send() failed: {}.", e))?;
+    Ok(Value::Nil)
+}
+
+// the next line received, without its line ending, or nil once the
+// other side has closed the connection
+fn tcp_receive(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let line = connection(vm, "tcp_receive", &args[0])?
+        .receive_line()
+        .map_err(|e| format!("tcp_receive() failed: {}.", e))?;
+    Ok(line.map_or(Value::Nil, |line| vm.intern_string(line)))
+}
+
+fn tcp_close(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let closed = whole_number(&args[0]).and_then(|handle| vm.connections.remove(&handle));
+    if closed.is_none() {
+        return Err("tcp_close() expects an open connection.".to_string());
+    }
+    Ok(Value::Nil)
+}
+
+// fetches an http:// URL. Returns a map with the "status" code and the
+// "body" text
+fn http_get(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let url = string_arg("http_get", &args[0])?;
+    let response =
+        net::http_get(url).map_err(|e| format!("http_get() couldn't get '{}': {}.", url, e))?;
+    let body = vm.intern_string(response.body);
+    let entries = vec![
+        (
+            vm.intern_string("status".to_string()),
+            Value::Int(response.status as i64),
+        ),
+        (vm.intern_string("body".to_string()), body),
+    ];
+    Ok(Value::map(entries))
+}
+
+// Testing. test() only registers the function, `elephant --test` runs
+// them after the script, see VM::run_tests(). A failed assertion is a
+// runtime error, which is what fails the test. assert(condition) is a
+// statement, see Compiler::assert_statement
+
+fn test(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let name = string_arg("test", &args[0])?.to_string();
+    match &args[1] {
+        Value::Object(Obj {
+            obj_type: ObjType::ObjFunction(function),
+        }) if function.arity == 0 => {}
+        Value::Object(Obj {
+            obj_type: ObjType::ObjFunction(_),
+        }) => return Err("test() expects a function without parameters.".to_string()),
+        other => {
+            return Err(format!(
+                "test() expects a function, not {}.",
+                other.type_name()
+            ))
+        }
+    }
+    vm.tests.push((name, args[1].clone()));
+    Ok(Value::Nil)
+}
+
+// compares the way == does, so two lists are only equal when they
+// are the same list
+fn assert_eq(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if args[0].values_equal(&args[1]) {
+        return Ok(Value::Nil);
+    }
+    Err(format!(
+        "assert_eq() failed: got {}, expected {}.",
+        quoted(&args[0]),
+        quoted(&args[1])
+    ))
+}
+
+fn assert_ne(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if !args[0].values_equal(&args[1]) {
+        return Ok(Value::Nil);
+    }
+    Err(format!(
+        "assert_ne() failed: both are {}.",
+        quoted(&args[0])
+    ))
+}
+
+// the value as print writes it, but a string in quotes so "1" and 1
+// can be told apart
+fn quoted(value: &Value) -> String {
+    match value {
+        Value::Object(Obj {
+            obj_type: ObjType::ObjString(string),
+        }) => format!("{:?}", string.as_str()),
+        _ => value.format_with(None),
+    }
+}
+
+// Files. A failure is a runtime error with the reason in it, e.g.
+// "read_file() couldn't read 'in.txt': No such file or directory", so
+// a script can catch it and carry on. Paths are relative to the
+// directory the VM runs in
+
+// the whole file as a string. It has to be UTF-8
+fn read_file(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path = string_arg("read_file", &args[0])?;
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("read_file() couldn't read '{}': {}.", path, e))?;
+    Ok(vm.intern_string(text))
+}
+
+// replaces the file's contents with `text`, creating it if needed
+fn write_file(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path = string_arg("write_file", &args[0])?;
+    let text = string_arg("write_file", &args[1])?;
+    fs::write(path, text).map_err(|e| format!("write_file() couldn't write '{}': {}.", path, e))?;
+    Ok(Value::Nil)
+}
+
+// adds `text` at the end of the file, creating it if needed
+fn append_file(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path = string_arg("append_file", &args[0])?;
+    let text = string_arg("append_file", &args[1])?;
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .map_err(|e| format!("append_file() couldn't write '{}': {}.", path, e))?;
+    Ok(Value::Nil)
+}
+
+fn file_exists(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path = string_arg("file_exists", &args[0])?;
+    Ok(Value::Boolean(Path::new(path).is_file()))
+}
+
+// the text of a string argument of `function`
+fn string_arg<'a>(function: &str, value: &'a Value) -> Result<&'a str, String> {
+    obj_string_arg(function, value).map(ObjString::as_str)
+}
+
+// a string argument of `function`, for what counts characters
+fn obj_string_arg<'a>(function: &str, value: &'a Value) -> Result<&'a ObjString, String> {
+    match value {
+        Value::Object(Obj {
+            obj_type: ObjType::ObjString(string),
+        }) => Ok(string),
+        other => Err(format!(
+            "{}() expects a string, not {}.",
+            function,
+            other.type_name()
+        )),
+    }
+}
+
+// the list a list argument of `function` refers to
+fn list_arg(function: &str, value: &Value) -> Result<Rc<RefCell<ObjList>>, String> {
+    value
+        .as_list()
+        .ok_or_else(|| format!("{}() expects a list, not {}.", function, value.type_name()))
+}
+
+// the map a map argument of `function` refers to
+fn map_arg(function: &str, value: &Value) -> Result<Rc<RefCell<ObjMap>>, String> {
+    value
+        .as_map()
+        .ok_or_else(|| format!("{}() expects a map, not {}.", function, value.type_name()))
+}
+
+// the value as an i64 if it is a whole number, int or float
+fn whole_number(value: &Value) -> Option<i64> {
+    match value {
+        Value::Int(n) => Some(*n),
+        Value::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => Some(*n as i64),
+        _ => None,
+    }
+}
+
+fn upper(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = string_arg("upper", &args[0])?.to_uppercase();
+    Ok(vm.intern_string(text))
+}
+
+fn lower(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = string_arg("lower", &args[0])?.to_lowercase();
+    Ok(vm.intern_string(text))
+}
+
+// without whitespace at either end
+fn trim(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = string_arg("trim", &args[0])?.trim().to_string();
+    Ok(vm.intern_string(text))
+}
+
+// the pieces between each `separator` as a list. An empty separator
+// splits the string into its characters
+fn split(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = string_arg("split", &args[0])?;
+    let separator = string_arg("split", &args[1])?;
+    let pieces: Vec<String> = if separator.is_empty() {
+        text.chars().map(String::from).collect()
+    } else {
+        text.split(separator).map(String::from).collect()
+    };
+    let items = pieces
+        .into_iter()
+        .map(|piece| vm.intern_string(piece))
+        .collect();
+    Ok(Value::list(items))
+}
+
+// every occurrence of `from` replaced with `to`
+fn replace(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = string_arg("replace", &args[0])?;
+    let from = string_arg("replace", &args[1])?;
+    let to = string_arg("replace", &args[2])?;
+    if from.is_empty() {
+        return Err("replace() can't replace an empty string.".to_string());
+    }
+    let text = text.replace(from, to);
+    Ok(vm.intern_string(text))
+}
+
+fn contains(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = string_arg("contains", &args[0])?;
+    let needle = string_arg("contains", &args[1])?;
+    Ok(Value::Boolean(text.contains(needle)))
+}
+
+fn starts_with(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = string_arg("starts_with", &args[0])?;
+    let prefix = string_arg("starts_with", &args[1])?;
+    Ok(Value::Boolean(text.starts_with(prefix)))
+}
+
+// substring(start) or substring(start, end): the characters from start
+// up to but not including end, or to the end of the string. Unlike
+// slicing with a range the end can be left out
+fn substring(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let (
+        Value::Object(Obj {
+            obj_type: ObjType::ObjString(string),
+        }),
+        rest,
+    ) = (&args[0], &args[1..])
+    else {
+        return Err("substring() expects a string.".to_string());
+    };
+    let len = string.char_len();
+    let (start, end) = match rest {
+        [start] => (whole_number(start), Some(len as i64)),
+        [start, end] => (whole_number(start), whole_number(end)),
+        _ => return Err(format!("Expected 1 or 2 arguments but got {}.", rest.len())),
+    };
+    match (start, end) {
+        (Some(start), Some(end)) if 0 <= start && start <= end && end <= len as i64 => {
+            let text = string.substr_chars(start as usize, (end - start) as usize);
+            Ok(vm.intern_string(text))
+        }
+        _ => Err(format!(
+            "substring() expects whole numbers with 0 <= start <= end <= {}.",
+            len
+        )),
+    }
+}
+
+// the character at char index `index`, as a string of its own
+fn char_at(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string = obj_string_arg("char_at", &args[0])?;
+    let character = whole_number(&args[1])
+        .and_then(|index| usize::try_from(index).ok())
+        .and_then(|index| string.char_at(index))
+        .ok_or_else(|| {
+            format!(
+                "char_at() expects a whole number with 0 <= index < {}.",
+                string.char_len()
+            )
+        })?;
+    Ok(vm.intern_string(character))
+}
+
+// substr_chars(start, count): up to count characters from char index
+// start on. Unlike substring it is clipped to the end of the string
+// rather than an error
+fn substr_chars(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string = obj_string_arg("substr_chars", &args[0])?;
+    match (whole_number(&args[1]), whole_number(&args[2])) {
+        (Some(start), Some(count)) if start >= 0 && count >= 0 => {
+            let text = string.substr_chars(start as usize, count as usize);
+            Ok(vm.intern_string(text))
+        }
+        _ => Err("substr_chars() expects whole numbers that aren't negative.".to_string()),
+    }
+}
+
+// the length of the string's UTF-8 encoding, len() counts characters
+fn byte_len(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string = obj_string_arg("byte_len", &args[0])?;
+    Ok(Value::Int(string.byte_len() as i64))
+}
+
+// the char index of the first occurrence of `needle`, nil if there is
+// none
+fn find(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let string = obj_string_arg("find", &args[0])?;
+    let needle = string_arg("find", &args[1])?;
+    Ok(string
+        .find_chars(needle)
+        .map_or(Value::Nil, |index| Value::Int(index as i64)))
+}
+
+// adds the item at the end
+fn push(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    list_arg("push", &args[0])?
+        .borrow_mut()
+        .items
+        .push(args[1].clone());
+    Ok(Value::Nil)
+}
+
+// takes the last item off and returns it
+fn pop(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    list_arg("pop", &args[0])?
+        .borrow_mut()
+        .items
+        .pop()
+        .ok_or_else(|| "pop() on an empty list.".to_string())
+}
+
+// puts the item at `index`, moving the ones from there on up. An index
+// of len() adds it at the end
+fn insert(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let list = list_arg("insert", &args[0])?;
+    let mut list = list.borrow_mut();
+    let len = list.items.len();
+    match whole_number(&args[1]) {
+        Some(index) if 0 <= index && index <= len as i64 => {
+            list.items.insert(index as usize, args[2].clone());
+            Ok(Value::Nil)
+        }
+        _ => Err(format!(
+            "insert() index must be a whole number from 0 to {}.",
+            len
+        )),
+    }
+}
+
+// takes the item at `index` out and returns it
+fn remove(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let list = list_arg("remove", &args[0])?;
+    let mut list = list.borrow_mut();
+    match index_position(&args[1], list.items.len()) {
+        Some(index) => Ok(list.items.remove(index)),
+        None => Err(format!(
+            "remove() index {} is out of bounds for a list of length {}.",
+            args[1],
+            list.items.len()
+        )),
+    }
+}
+
+// the index of the first item equal to `item`, nil if there is none
+fn index_of(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let list = list_arg("index_of", &args[0])?;
+    let position = list
+        .borrow()
+        .items
+        .iter()
+        .position(|item| item.values_equal(&args[1]));
+    Ok(position.map_or(Value::Nil, |index| Value::Int(index as i64)))
+}
+
+// reverses the list in place
+fn reverse(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    list_arg("reverse", &args[0])?.borrow_mut().items.reverse();
+    Ok(Value::Nil)
+}
+
+// a new list with the items of this one followed by those of `other`.
+// Neither list changes
+fn concat(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let mut items = list_arg("concat", &args[0])?.borrow().items.clone();
+    items.extend(list_arg("concat", &args[1])?.borrow().items.iter().cloned());
+    Ok(Value::list(items))
+}
+
+// the keys as a new list, in insertion order
+fn keys(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = map_arg("keys", &args[0])?;
+    let keys = map
+        .borrow()
+        .entries
+        .iter()
+        .map(|(key, _)| key.clone())
+        .collect();
+    Ok(Value::list(keys))
+}
+
+// the values as a new list, in the same order as keys()
+fn values(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = map_arg("values", &args[0])?;
+    let values = map
+        .borrow()
+        .entries
+        .iter()
+        .map(|(_, value)| value.clone())
+        .collect();
+    Ok(Value::list(values))
+}
+
+// whether the map has the key. Unlike map[key] != nil, true for a key
+// whose value is nil
+fn has(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = map_arg("has", &args[0])?;
+    let found = map.borrow().get(&args[1]).is_some();
+    Ok(Value::Boolean(found))
+}
+
+// takes the key out and returns its value, nil if it wasn't there
+fn remove_key(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = map_arg("remove", &args[0])?;
+    let removed = map.borrow_mut().remove(&args[1]);
+    Ok(removed.unwrap_or(Value::Nil))
+}
+
+// a new map with the entries of this one and then those of `other`,
+// whose values win for keys both have. Neither map changes
+fn merge(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let mut entries = map_arg("merge", &args[0])?.borrow().entries.clone();
+    entries.extend(map_arg("merge", &args[1])?.borrow().entries.iter().cloned());
+    Ok(Value::map(entries))
+}
+
+// Copies. Assignment and passing arguments never copy an object, both
+// names refer to the same instance, list or map afterwards. clone()
+// makes a new instance of the same class with the same fields, or a new
+// list or map with the same items, but the field values and items are
+// still shared. deep_clone() copies every instance, list and map it can
+// reach (map values, not keys), so changing the copy never shows
+// through the original. An object reached twice, or through a cycle, is
+// copied only once, so the copy has the same shape. deep_clone(value, depth) stops copying `depth` levels down and
+// shares whatever is below. Strings, numbers, bools, nil, functions and
+// classes can't be changed, they come back as they are
+
+fn clone(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(shallow_copy(&args[0]).unwrap_or_else(|| args[0].clone()))
+}
+
+fn deep_clone(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let depth = match args {
+        [_] => usize::MAX,
+        [_, Value::Int(n)] if *n >= 0 => *n as usize,
+        [_, Value::Number(n)] if *n >= 0.0 && n.fract() == 0.0 => *n as usize,
+        [_, _] => return Err("deep_clone() depth must be a whole number >= 0.".to_string()),
+        _ => return Err(format!("Expected 1 or 2 arguments but got {}.", args.len())),
+    };
+    Ok(deep_copy(&args[0], depth))
+}
+
+// walks the objects with a worklist instead of recursing, so a long
+// chain of them can't overflow the native stack
+fn deep_copy(value: &Value, depth: usize) -> Value {
+    if depth == 0 {
+        return value.clone();
+    }
+    let Some(root_copy) = shallow_copy(value) else {
+        return value.clone();
+    };
+
+    // original object -> its copy
+    let mut copies: HashMap<*const (), Value> = HashMap::new();
+    copies.insert(address(value), root_copy.clone());
+    // copies whose fields or items still point at originals, with how
+    // many more levels below them may be copied
+    let mut pending = vec![(root_copy.clone(), depth - 1)];
+
+    while let Some((copy, depth)) = pending.pop() {
+        if depth == 0 {
+            continue;
+        }
+        let mut copy_child = |child: &Value| -> Option<Value> {
+            if let Some(existing) = copies.get(&address(child)) {
+                return Some(existing.clone());
+            }
+            let child_copy = shallow_copy(child)?;
+            copies.insert(address(child), child_copy.clone());
+            pending.push((child_copy.clone(), depth - 1));
+            Some(child_copy)
+        };
+        if let Some(instance) = copy.as_instance() {
+            let entries = instance.borrow().fields.entries.clone();
+            for entry in entries {
+                if let Some(field_copy) = copy_child(&entry.value) {
+                    instance
+                        .borrow_mut()
+                        .fields
+                        .table_set(entry.key, field_copy);
+                }
+            }
+        } else if let Some(list) = copy.as_list() {
+            let items = list.borrow().items.clone();
+            for (i, item) in items.iter().enumerate() {
+                if let Some(item_copy) = copy_child(item) {
+                    list.borrow_mut().items[i] = item_copy;
+                }
+            }
+        } else if let Some(map) = copy.as_map() {
+            let entries = map.borrow().entries.clone();
+            for (key, value) in entries {
+                if let Some(value_copy) = copy_child(&value) {
+                    map.borrow_mut().set(key, value_copy);
+                }
+            }
+        }
+    }
+
+    root_copy
+}
+
+// a new instance, list or map with the same contents, None for values
+// that are never copied
+fn shallow_copy(value: &Value) -> Option<Value> {
+    if let Some(instance) = value.as_instance() {
+        let instance = instance.borrow();
+        return Some(Value::Object(Obj {
+            obj_type: ObjType::ObjInstance(Rc::new(RefCell::new(ObjInstance {
+                class: instance.class.clone(),
+                fields: instance.fields.clone(),
+            }))),
+        }));
+    }
+    if let Some(map) = value.as_map() {
+        return Some(Value::map(map.borrow().entries.clone()));
+    }
+    value
+        .as_list()
+        .map(|list| Value::list(list.borrow().items.clone()))
+}
+
+// identifies the instance, list or map behind a value
+fn address(value: &Value) -> *const () {
+    match value.as_obj().map(|obj| obj.obj_type) {
+        Some(ObjType::ObjInstance(instance)) => Rc::as_ptr(&instance) as *const (),
+        Some(ObjType::ObjList(list)) => Rc::as_ptr(&list) as *const (),
+        Some(ObjType::ObjMap(map)) => Rc::as_ptr(&map) as *const (),
+        _ => std::ptr::null(),
+    }
+}
+
+fn key_names(vm: &VM, table: &Table) -> Vec<Value> {
+    table
+        .entries
+        .iter()
+        .map(|entry| {
+            Value::Object(Obj {
+                obj_type: ObjType::ObjString(vm.string(entry.key).clone()),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{CapturedOutput, InterpretResult};
+    use std::io::Cursor;
+
+    fn run(vm: &mut VM, source: &str) -> Value {
+        assert_eq!(
+            vm.interpret(&format!("var result = {};", source)),
+            InterpretResult::InterpretOk
+        );
+        vm.get_global("result").unwrap()
+    }
+
+    fn string(value: &Value) -> String {
+        value.as_obj().unwrap().obj_type.as_obj_string().to_string()
+    }
+
+    #[test]
+    fn test_reflection() {
+        let mut vm = VM::init_vm();
+        assert_eq!(run(&mut vm, "arity(clock)").as_number(), Some(0.0));
+        assert_eq!(run(&mut vm, "arity(arity)").as_number(), Some(1.0));
+        assert_eq!(string(&run(&mut vm, "name(clock)")), "clock");
+
+        vm.interpret("class Point {} var p = Point(); p.x = 1; p.y = 2; p.x = 3;");
+        assert_eq!(string(&run(&mut vm, "name(Point)")), "Point");
+        assert_eq!(run(&mut vm, "arity(Point)").as_number(), Some(0.0));
+        assert_eq!(
+            run(&mut vm, "fields(p)").format_with(None),
+            "[\"x\", \"y\"]"
+        );
+        let names = run(&mut vm, "globals()").format_with(None);
+        assert!(names.starts_with("[\"clock\", \"globals\", "));
+        assert!(names.ends_with(", \"result\", \"Point\", \"p\"]"));
+
+        vm.interpret("fun add(a, b) { return a + b; } class Pair { init(a, b) {} sum() {} }");
+        assert_eq!(run(&mut vm, "arity(add)").as_number(), Some(2.0));
+        assert_eq!(run(&mut vm, "arity(Pair)").as_number(), Some(2.0));
+        assert_eq!(string(&run(&mut vm, "name(add)")), "add");
+        assert_eq!(string(&run(&mut vm, "name(Pair(1, 2).sum)")), "sum");
+
+        for source in ["arity(1);", "name(nil);", "fields(Point);"] {
+            assert_eq!(vm.interpret(source), InterpretResult::InterpretRuntimeError);
+        }
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut vm = VM::init_vm();
+        let source = "
+            class Node {}
+            var a = Node();
+            a.value = 1;
+            a.next = Node();
+            a.next.value = 2;
+            a.next.next = a;
+
+            var shallow = clone(a);
+            shallow.value = 10;
+            shallow.next.value = 20;
+
+            var deep = deep_clone(a);
+            deep.next.value = 30;
+            var cycle_kept = deep.next.next == deep;
+
+            var limited = deep_clone(a, 1);
+            limited.value = 40;
+            var shares_below = limited.next == a.next;
+        ";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        // the shallow copy shares a.next, the deep one doesn't
+        assert_eq!(run(&mut vm, "a.value").as_number(), Some(1.0));
+        assert_eq!(run(&mut vm, "a.next.value").as_number(), Some(20.0));
+        assert_eq!(run(&mut vm, "deep.next.value").as_number(), Some(30.0));
+        assert_eq!(run(&mut vm, "cycle_kept").as_bool(), Some(true));
+        assert_eq!(run(&mut vm, "shares_below").as_bool(), Some(true));
+        assert_eq!(run(&mut vm, "clone(3)").as_number(), Some(3.0));
+
+        // lists are copied the same way
+        let source = "
+            var inner = [1];
+            var outer = [inner, inner, a];
+            var shallow_list = clone(outer);
+            shallow_list[0][0] = 2;
+            var deep_list = deep_clone(outer);
+            deep_list[0][0] = 3;
+            var shape_kept = deep_list[0] == deep_list[1];
+            var instance_copied = deep_list[2] != a;
+        ";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(run(&mut vm, "inner[0]").as_number(), Some(2.0));
+        assert_eq!(run(&mut vm, "shallow_list == outer").as_bool(), Some(false));
+        assert_eq!(run(&mut vm, "deep_list[1][0]").as_number(), Some(3.0));
+        assert_eq!(run(&mut vm, "shape_kept").as_bool(), Some(true));
+        assert_eq!(run(&mut vm, "instance_copied").as_bool(), Some(true));
+
+        for source in [
+            "deep_clone();",
+            "deep_clone(a, -1);",
+            "deep_clone(a, 1, 2);",
+        ] {
+            assert_eq!(vm.interpret(source), InterpretResult::InterpretRuntimeError);
+        }
+    }
+
+    #[test]
+    fn test_len() {
+        let mut vm = VM::init_vm();
+        assert_eq!(run(&mut vm, "len(\"\")").as_int(), Some(0));
+        assert_eq!(run(&mut vm, "len(\"héllo\")").as_int(), Some(5));
+        assert_eq!(run(&mut vm, "len(\"日本語\")").as_int(), Some(3));
+        assert_eq!(run(&mut vm, "len([1, nil, \"é\"])").as_int(), Some(3));
+        assert_eq!(run(&mut vm, "len(2..5)").as_int(), Some(3));
+        assert_eq!(run(&mut vm, "len(2..=5)").as_int(), Some(4));
+        assert_eq!(run(&mut vm, "len(5..2)").as_int(), Some(0));
+
+        for source in ["len(1);", "len(nil);", "len(clock);"] {
+            assert_eq!(vm.interpret(source), InterpretResult::InterpretRuntimeError);
+        }
+    }
+
+    #[test]
+    fn test_type() {
+        let mut vm = VM::init_vm();
+        vm.interpret("class Point {} var p = Point(); fun f() {}");
+        let cases = [
+            ("1", "int"),
+            ("1.5", "number"),
+            ("\"a\"", "string"),
+            ("true", "bool"),
+            ("nil", "nil"),
+            ("f", "function"),
+            ("clock", "function"),
+            ("Point", "class"),
+            ("p", "instance"),
+            ("[1]", "list"),
+            ("(1, 2)", "tuple"),
+            ("0..2", "range"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(
+                string(&run(&mut vm, &format!("type({})", source))),
+                expected
+            );
+        }
+        assert_eq!(
+            run(&mut vm, "type(1) == \"int\" ? \"whole\" : \"other\"").format_with(None),
+            "whole"
+        );
+    }
+
+    #[test]
+    fn test_str() {
+        let mut vm = VM::init_vm();
+        let cases = [
+            ("str(3)", "3"),
+            ("str(0.5)", "0.5"),
+            ("str(nil)", "nil"),
+            ("str(true)", "true"),
+            ("str(\"a\")", "a"),
+            ("str([1, \"b\"])", "[1, \"b\"]"),
+            ("str((1,))", "(1,)"),
+            ("str(clock)", "<native fn clock>"),
+            ("\"n = \" + str(2 + 3)", "n = 5"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(string(&run(&mut vm, source)), expected);
+        }
+
+        vm.number_precision = Some(2);
+        assert_eq!(string(&run(&mut vm, "str(1.5)")), "1.50");
+        assert_eq!(string(&run(&mut vm, "str(\"1.5\")")), "1.5");
+    }
+
+    #[test]
+    fn test_num() {
+        let mut vm = VM::init_vm();
+        assert!(matches!(run(&mut vm, "num(\"42\")"), Value::Int(42)));
+        assert!(matches!(run(&mut vm, "num(\" -7 \")"), Value::Int(-7)));
+        assert_eq!(run(&mut vm, "num(\"1.5\")").as_number(), Some(1.5));
+        assert_eq!(run(&mut vm, "num(\"2e3\")").as_number(), Some(2000.0));
+        assert_eq!(run(&mut vm, "num(3)").as_number(), Some(3.0));
+        assert_eq!(run(&mut vm, "num(\"2\") + 1").as_number(), Some(3.0));
+        for bad in ["\"\"", "\"abc\"", "\"1.5x\"", "\"inf\"", "\"NaN\""] {
+            assert!(
+                matches!(run(&mut vm, &format!("num({})", bad)), Value::Nil),
+                "{}",
+                bad
+            );
+        }
+        assert_eq!(
+            vm.interpret("num(nil);"),
+            InterpretResult::InterpretRuntimeError
+        );
+    }
+
+    #[test]
+    fn test_input() {
+        let mut vm = VM::init_vm();
+        let output = CapturedOutput::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.set_input(Box::new(Cursor::new("Ada\r\n42\nlast")));
+        let source = "var name = input(\"name? \"); var n = num(input()); \
+                      var rest = input(); var end = input();";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(output.text(), "name? ");
+        let global = |vm: &VM, name: &str| vm.get_global(name).unwrap();
+        assert_eq!(string(&global(&vm, "name")), "Ada");
+        assert!(matches!(global(&vm, "n"), Value::Int(42)));
+        assert_eq!(string(&global(&vm, "rest")), "last");
+        assert!(matches!(global(&vm, "end"), Value::Nil));
+    }
+
+    #[test]
+    fn test_write() {
+        let mut vm = VM::init_vm();
+        let output = CapturedOutput::default();
+        vm.set_output(Box::new(output.clone()));
+        let source = "for (i in 0..3) write(i, \" \"); print \"done\"; write(); \
+                      write(\"x\", [\"y\"]); print \"\";";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(output.text(), "0 1 2 done\nx[\"y\"]\n");
+    }
+
+    #[test]
+    fn test_format() {
+        let mut vm = VM::init_vm();
+        let cases = [
+            ("format(\"plain\")", "plain"),
+            ("format(\"%s=%d\", \"x\", 42)", "x=42"),
+            ("format(\"%d%%\", 50.0)", "50%"),
+            ("format(\"%.2f|%f\", 3.14159, 2)", "3.14|2.000000"),
+            ("format(\"%.0f\", 2.5)", "2"),
+            ("format(\"%s %s\", [1, \"a\"], nil)", "[1, \"a\"] nil"),
+            ("format(\"é%s\", \"ü\")", "éü"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(string(&run(&mut vm, source)), expected);
+        }
+
+        let output = CapturedOutput::default();
+        vm.set_output(Box::new(output.clone()));
+        let source = "printf(\"%s: %.1f\", \"t\", 1.25); printf(\"!\"); print \"\";";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(output.text(), "t: 1.2!\n");
+
+        for source in [
+            "format();",
+            "format(1);",
+            "format(\"%d\");",
+            "format(\"%d\", 1, 2);",
+            "format(\"%d\", 1.5);",
+            "format(\"%f\", \"1\");",
+            "format(\"%x\", 1);",
+            "format(\"%.2d\", 1);",
+            "format(\"%.f\", 1);",
+            "format(\"50%\");",
+            "printf(\"%s\");",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut vm = VM::init_vm();
+        let output = CapturedOutput::default();
+        vm.set_output(Box::new(output.clone()));
+        let source = "fun stop() { try { exit(3); } catch (e) { print \"caught\"; } } \
+                      print 1; stop(); print 2;";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(vm.exit_code, Some(3));
+        assert_eq!(output.text(), "1\n");
+
+        // the next program runs normally on a clean stack
+        assert_eq!(vm.interpret("print 4;"), InterpretResult::InterpretOk);
+        assert_eq!(vm.exit_code, None);
+        assert_eq!(output.text(), "1\n4\n");
+
+        assert_eq!(vm.interpret("exit(...[]);"), InterpretResult::InterpretOk);
+        assert_eq!(vm.exit_code, Some(0));
+        for source in [
+            "exit(\"1\");",
+            "exit(1.5);",
+            "exit(1, 2);",
+            "exit(3000000000);",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_string_methods() {
+        let mut vm = VM::init_vm();
+        let cases = [
+            ("\"héllo\".len()", "5"),
+            ("\"MiXed\".upper()", "\"MIXED\""),
+            ("\"MiXed\".lower()", "\"mixed\""),
+            ("\"  a b  \".trim()", "\"a b\""),
+            ("\"a,b,,c\".split(\",\")", "[\"a\", \"b\", \"\", \"c\"]"),
+            ("\"héj\".split(\"\")", "[\"h\", \"é\", \"j\"]"),
+            ("\"a-b-c\".replace(\"-\", \"+\")", "\"a+b+c\""),
+            ("\"haystack\".contains(\"st\")", "true"),
+            ("\"haystack\".contains(\"x\")", "false"),
+            ("\"haystack\".starts_with(\"hay\")", "true"),
+            ("\"héllo\".substring(1, 3)", "\"él\""),
+            ("\"héllo\".substring(2)", "\"llo\""),
+            ("\"héllo\".substring(5)", "\"\""),
+            ("\" A,b \".trim().lower().split(\",\")", "[\"a\", \"b\"]"),
+            ("\"abc\".upper", "<native fn upper>"),
+            ("arity(\"abc\".replace)", "2"),
+        ];
+        for (source, expected) in cases {
+            // wrapped in a list so strings show quoted
+            let text = run(&mut vm, &format!("[{}]", source)).format_with(None);
+            assert_eq!(text, format!("[{}]", expected), "{}", source);
+        }
+        // the method keeps the string it was looked up on
+        vm.interpret("var shout = \"xy\".upper;");
+        assert_eq!(string(&run(&mut vm, "shout()")), "XY");
+
+        for source in [
+            "\"a\".reverse();",
+            "\"a\".upper(1);",
+            "\"a\".split(1);",
+            "\"a\".replace(\"\", \"b\");",
+            "\"abc\".substring(2, 1);",
+            "\"abc\".substring(0, 4);",
+            "\"abc\".substring(0.5);",
+            "\"abc\".substring();",
+            "(1).upper();",
+            "\"a\".upper = 1;",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_char_and_byte_methods() {
+        let mut vm = VM::init_vm();
+        // é is two bytes and ü is written as u and a combining mark, so
+        // it is two characters as well
+        let cases = [
+            ("\"héllo\".char_at(1)", "\"é\""),
+            ("\"héllo\".char_at(4)", "\"o\""),
+            ("\"日本語\".char_at(2)", "\"語\""),
+            ("\"héllo\".substr_chars(1, 3)", "\"éll\""),
+            ("\"héllo\".substr_chars(3, 10)", "\"lo\""),
+            ("\"héllo\".substr_chars(9, 1)", "\"\""),
+            ("\"héllo\".byte_len()", "6"),
+            ("\"héllo\".len()", "5"),
+            ("\"日本語\".byte_len()", "9"),
+            ("\"u\u{308}\".len()", "2"),
+            ("\"u\u{308}\".byte_len()", "3"),
+            ("\"héllo wörld\".find(\"wö\")", "6"),
+            ("\"日本語\".find(\"語\")", "2"),
+            ("\"héllo\".find(\"x\")", "nil"),
+            ("\"héllo\".find(\"\")", "0"),
+        ];
+        for (source, expected) in cases {
+            let text = run(&mut vm, &format!("[{}]", source)).format_with(None);
+            assert_eq!(text, format!("[{}]", expected), "{}", source);
+        }
+
+        for source in [
+            "\"héllo\".char_at(5);",
+            "\"héllo\".char_at(-1);",
+            "\"héllo\".char_at(1.5);",
+            "\"héllo\".substr_chars(-1, 2);",
+            "\"héllo\".substr_chars(0, \"2\");",
+            "\"héllo\".find(1);",
+            "\"héllo\".byte_len(1);",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_list_methods() {
+        let mut vm = VM::init_vm();
+        let source = "var xs = [1, 2]; var alias = xs; xs.push(3); xs.insert(0, 0); \
+                      xs.insert(4, 4); var last = xs.pop(); var first = xs.remove(0); \
+                      var found = xs.index_of(2); var missing = xs.index_of(\"2\"); \
+                      var both = xs.concat([\"a\"]); xs.reverse(); var count = xs.len();";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        let shown = |vm: &mut VM, name: &str| run(vm, name).format_with(None);
+        // alias is the same list, so it sees every change
+        assert_eq!(shown(&mut vm, "alias"), "[3, 2, 1]");
+        assert_eq!(shown(&mut vm, "last"), "4");
+        assert_eq!(shown(&mut vm, "first"), "0");
+        assert_eq!(shown(&mut vm, "found"), "1");
+        assert_eq!(shown(&mut vm, "missing"), "nil");
+        assert_eq!(shown(&mut vm, "both"), "[1, 2, 3, \"a\"]");
+        assert_eq!(shown(&mut vm, "count"), "3");
+
+        for source in [
+            "[].pop();",
+            "[1].remove(1);",
+            "[1].remove(-1);",
+            "[1].insert(2, 0);",
+            "[1].insert(0.5, 0);",
+            "[1].concat((2,));",
+            "[1].push();",
+            "[1].upper();",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_map_methods() {
+        let mut vm = VM::init_vm();
+        let source = "var m = {\"a\": 1, \"b\": nil, \"c\": 3}; var keys = m.keys(); \
+                      var values = m.values(); var has_b = m.has(\"b\"); \
+                      var has_z = m.has(\"z\"); var removed = m.remove(\"a\"); \
+                      var gone = m.remove(\"a\"); var merged = m.merge({\"c\": 4, \"d\": 5}); \
+                      var count = m.len(); var copy = clone(m); copy[\"e\"] = 6;";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        let shown = |vm: &mut VM, name: &str| run(vm, name).format_with(None);
+        assert_eq!(shown(&mut vm, "keys"), "[\"a\", \"b\", \"c\"]");
+        assert_eq!(shown(&mut vm, "values"), "[1, nil, 3]");
+        assert_eq!(shown(&mut vm, "has_b"), "true");
+        assert_eq!(shown(&mut vm, "has_z"), "false");
+        assert_eq!(shown(&mut vm, "removed"), "1");
+        assert_eq!(shown(&mut vm, "gone"), "nil");
+        assert_eq!(shown(&mut vm, "m"), "{\"b\": nil, \"c\": 3}");
+        assert_eq!(shown(&mut vm, "merged"), "{\"b\": nil, \"c\": 4, \"d\": 5}");
+        assert_eq!(shown(&mut vm, "count"), "2");
+        assert_eq!(shown(&mut vm, "len(copy)"), "3");
+
+        for source in [
+            "var x = {}.merge([]);",
+            "var x = {}.keys(1);",
+            "var x = {}.push(1);",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = std::env::temp_dir().join(format!("elephant-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt").display().to_string();
+        let mut vm = VM::init_vm();
+        let value = vm.intern_string(path.clone());
+        vm.set_global("path", value);
+        let source = "var before = file_exists(path); write_file(path, \"one\"); \
+                      append_file(path, \",two\"); var text = read_file(path); \
+                      var after = file_exists(path); var dir_is_file = file_exists(\".\"); \
+                      var error = nil; try { read_file(path + \".missing\"); } \
+                      catch (e) { error = e; }";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one,two");
+        let shown = |vm: &mut VM, name: &str| run(vm, name).format_with(None);
+        assert_eq!(shown(&mut vm, "before"), "false");
+        assert_eq!(shown(&mut vm, "text"), "one,two");
+        assert_eq!(shown(&mut vm, "after"), "true");
+        assert_eq!(shown(&mut vm, "dir_is_file"), "false");
+        let error = shown(&mut vm, "error");
+        assert!(
+            error.starts_with(&format!("read_file() couldn't read '{}.missing': ", path)),
+            "{}",
+            error
+        );
+
+        for source in [
+            "read_file(1);",
+            "write_file(path, 1);",
+            "write_file(\".\", \"x\");",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_time() {
+        let mut vm = VM::init_vm();
+        // after 2020-01-01
+        assert!(run(&mut vm, "time()").as_number().unwrap() > 1_577_836_800.0);
+        let source = "var start = clock(); sleep(5); sleep(0.5); var slept = clock() - start;";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        assert!(run(&mut vm, "slept").as_number().unwrap() >= 0.005);
+        for source in ["sleep(-1);", "sleep(\"1\");", "sleep(1 / 0);"] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_random() {
+        let mut vm = VM::init_vm();
+        let source = "var xs = []; for (i in 0..200) xs.push(random()); \
+                      var ints = []; for (i in 0..200) ints.push(random_int(-2, 2));";
+        let mut runs = vec![];
+        for _ in 0..2 {
+            assert_eq!(
+                vm.interpret("random_seed(42);"),
+                InterpretResult::InterpretOk
+            );
+            assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+            let xs = run(&mut vm, "xs").as_list().unwrap().borrow().items.clone();
+            let ints = run(&mut vm, "ints")
+                .as_list()
+                .unwrap()
+                .borrow()
+                .items
+                .clone();
+            assert!(xs
+                .iter()
+                .all(|x| (0.0..1.0).contains(&x.as_number().unwrap())));
+            for n in -2..=2 {
+                assert!(ints.iter().any(|x| matches!(x, Value::Int(m) if *m == n)));
+            }
+            assert!(ints.iter().all(|x| matches!(x, Value::Int(-2..=2))));
+            runs.push(run(&mut vm, "[xs, ints]").format_with(None));
+        }
+        // the same seed, the same numbers
+        assert_eq!(runs[0], runs[1]);
+
+        assert!(matches!(run(&mut vm, "random_int(7, 7)"), Value::Int(7)));
+        for source in [
+            "random_int(2, 1);",
+            "random_int(0.5, 1);",
+            "random_seed(\"a\");",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let mut vm = VM::init_vm();
+        let source = "class Point { init(x, y) { this.x = x; this.y = y; } } \
+                      var text = json_stringify({\"points\": [Point(1, 2.5)], \"pair\": (nil, \"a\")}); \
+                      var back = json_parse(text); var x = back[\"points\"][0][\"x\"]; \
+                      var error = nil; try { json_parse(\"[1,\"); } catch (e) { error = e; }";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(
+            string(&run(&mut vm, "text")),
+            "{\"points\":[{\"x\":1,\"y\":2.5}],\"pair\":[null,\"a\"]}"
+        );
+        assert!(matches!(run(&mut vm, "x"), Value::Int(1)));
+        assert!(
+            string(&run(&mut vm, "error")).starts_with("json_parse() couldn't parse the text: ")
+        );
+
+        for source in [
+            "json_parse(1);",
+            "json_stringify(clock);",
+            "json_stringify(0..2);",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_regex() {
+        let mut vm = VM::init_vm();
+        let date = "\"(\\d+)-(\\d+)(-x)?\"";
+        let cases = [
+            (
+                format!("regex_match({}, \"on 2024-05!\")", date),
+                "[\"2024-05\", \"2024\", \"05\", nil]",
+            ),
+            (format!("regex_match({}, \"none\")", date), "nil"),
+            (
+                "regex_find_all(\"[a-z]+\", \"ab 12 cd é\")".to_string(),
+                "[\"ab\", \"cd\"]",
+            ),
+            ("regex_find_all(\"x\", \"abc\")".to_string(), "[]"),
+            (
+                "regex_replace(\"(\\w+)@(\\w+)\", \"me@host, you@there\", \"$2:$1\")".to_string(),
+                "\"host:me, there:you\"",
+            ),
+        ];
+        for (source, expected) in cases {
+            let text = run(&mut vm, &format!("[{}]", source)).format_with(None);
+            assert_eq!(text, format!("[{}]", expected), "{}", source);
+        }
+        // the date pattern was compiled once for both calls
+        assert_eq!(vm.regex_cache.len(), 4);
+
+        for source in [
+            "regex_match(\"(\", \"a\");",
+            "regex_find_all(1, \"a\");",
+            "regex_replace(\"a\", \"a\", nil);",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_exec() {
+        let mut vm = VM::init_vm();
+        let source = "var out = exec(\"sh\", [\"-c\", \"echo hi; echo oops >&2; exit 3\"]); \
+                      var plain = exec(\"true\");";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(
+            run(&mut vm, "out").format_with(None),
+            "{\"stdout\": \"hi\\n\", \"stderr\": \"oops\\n\", \"status\": 3}"
+        );
+        assert!(matches!(run(&mut vm, "plain[\"status\"]"), Value::Int(0)));
+
+        for source in [
+            "exec(\"elephant-no-such-program\");",
+            "exec(\"echo\", [1]);",
+            "exec(\"echo\", \"a\");",
+            "exec();",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_networking() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        // echoes one line back in upper case, then answers one HTTP request
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            write!(reader.get_mut(), "{}", line.to_uppercase()).unwrap();
+            drop(reader);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            BufReader::new(&stream).read_line(&mut request).unwrap();
+            let body = format!("you asked for {}", request.split(' ').nth(1).unwrap());
+            write!(
+                stream,
+                "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+
+        let mut vm = VM::init_vm();
+        vm.set_global("port", Value::Int(port as i64));
+        let source = "var c = tcp_connect(\"127.0.0.1\", port); tcp_send(c, \"hello\"); \
+                      tcp_send(c, \" there\\r\"); var reply = tcp_receive(c); \
+                      var closed = tcp_receive(c); tcp_close(c); \
+                      var page = http_get(\"http://127.0.0.1:\" + str(port) + \"/a?b\");";
+        // the language has no escapes, a real newline ends the line
+        let source = source.replace("\\r", "\n");
+        assert_eq!(vm.interpret(&source), InterpretResult::InterpretOk);
+        server.join().unwrap();
+        assert_eq!(string(&run(&mut vm, "reply")), "HELLO THERE");
+        assert!(matches!(run(&mut vm, "closed"), Value::Nil));
+        assert_eq!(
+            run(&mut vm, "page").format_with(None),
+            "{\"status\": 200, \"body\": \"you asked for /a?b\"}"
+        );
+        assert!(vm.connections.is_empty());
+
+        for source in [
+            "tcp_send(c, \"x\");",
+            "tcp_close(c);",
+            "tcp_connect(\"127.0.0.1\", 70000);",
+            "http_get(\"https://example.com\");",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_assertions() {
+        let mut vm = VM::init_vm();
+        let source = "fun check() {} test(\"check\", check); \
+                      assert_eq(\"ab\", \"a\" + \"b\"); assert_ne(1, \"1\"); \
+                      var message = nil; \
+                      try { assert_eq(1, \"1\"); } catch (e) { message = e; }";
+        assert_eq!(vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(vm.tests.len(), 1);
+        assert_eq!(
+            string(&run(&mut vm, "message")),
+            "assert_eq() failed: got 1, expected \"1\"."
+        );
+
+        for source in [
+            "assert_eq(1, 2);",
+            "assert_ne(2, 2.0);",
+            "test(\"x\", 1);",
+            "test(1, check);",
+            "fun takes(a) {} test(\"x\", takes);",
+        ] {
+            assert_eq!(
+                vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+}
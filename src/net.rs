@@ -0,0 +1,151 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// what tcp_connect() and http_get() wait for before giving up, so a
+// server that never answers doesn't hang the program
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// a connection opened by tcp_connect(). Reads are buffered so lines can
+// be taken off one at a time
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    pub fn open(host: &str, port: u16) -> io::Result<Connection> {
+        let address = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for the host"))?;
+        let stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(Connection {
+            reader: BufReader::new(stream),
+        })
+    }
+
+    pub fn send(&mut self, text: &str) -> io::Result<()> {
+        self.reader.get_mut().write_all(text.as_bytes())
+    }
+
+    // the next line without its line ending, None once the other side
+    // has closed the connection
+    pub fn receive_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    // everything until the other side closes the connection
+    fn receive_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.reader.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+// GET over plain HTTP. Asks for HTTP/1.0, so the server sends the body
+// as it is and closes the connection after it
+pub fn http_get(url: &str) -> Result<Response, String> {
+    let (host, port, path) = parse_url(url)?;
+    let mut connection = Connection::open(&host, port).map_err(|e| e.to_string())?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: elephant\r\n\r\n",
+        path, host
+    );
+    connection.send(&request).map_err(|e| e.to_string())?;
+    let bytes = connection.receive_all().map_err(|e| e.to_string())?;
+    parse_response(&bytes)
+}
+
+// http://host[:port][/path] into its parts
+fn parse_url(url: &str) -> Result<(String, u16, String), String> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Err("only http:// URLs are supported".to_string());
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| format!("invalid port '{}'", port))?;
+            (host, port)
+        }
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err("the URL has no host".to_string());
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+fn parse_response(bytes: &[u8]) -> Result<Response, String> {
+    let text = String::from_utf8_lossy(bytes);
+    let (head, body) = text
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| "the response has no end of headers".to_string())?;
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| "the response has no status".to_string())?;
+    Ok(Response {
+        status,
+        body: body.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("http://example.com").unwrap(),
+            ("example.com".to_string(), 80, "/".to_string())
+        );
+        assert_eq!(
+            parse_url("http://localhost:8080/a/b?c=d").unwrap(),
+            ("localhost".to_string(), 8080, "/a/b?c=d".to_string())
+        );
+        for url in [
+            "https://example.com",
+            "example.com",
+            "http://:80/",
+            "http://a:x/",
+        ] {
+            assert!(parse_url(url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response =
+            parse_response(b"HTTP/1.0 404 Not Found\r\nContent-Type: text/plain\r\n\r\ngone\r\n")
+                .unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, "gone\r\n");
+        assert!(parse_response(b"HTTP/1.0 200 OK\r\n").is_err());
+        assert!(parse_response(b"garbage\r\n\r\n").is_err());
+    }
+}
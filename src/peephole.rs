@@ -0,0 +1,236 @@
+use crate::{Chunk, OpCode};
+
+// Peephole pass over a finished chunk, run with -O. It removes
+// instructions that can't change what the program does:
+//
+// - a push with no side effects followed by OP_POP, such as the
+//   OP_CONSTANT and OP_POP of an expression statement `1;` or an OP_NIL
+//   that is popped straight away
+// - OP_NOT OP_NOT in front of another OP_NOT, whose result is a bool
+//   either way, or in front of an OP_POP
+// - OP_NOT OP_NOT in front of a conditional jump that only tests the
+//   value: both ways on start with OP_POP, as for an if or a while. The
+//   value is as truthy without them
+//
+// Nothing is removed from the middle of a jump target, an instruction
+// that is jumped to can only go if the instructions after it go too.
+// Removing one pattern can bring two halves of another together, so
+// the pass runs again until it finds nothing.
+//
+// Once instructions are gone, jump operands are worked out again from
+// where their targets ended up, and the lines, columns and file starts
+// follow the bytes they belong to. Jumps only get shorter, so they
+// still fit their operands. Constants that nothing loads any more stay
+// in the pool, their indexes don't change
+pub fn optimize(chunk: &mut Chunk) {
+    while optimize_once(chunk) {}
+}
+
+// one pass, false if there was nothing to remove
+fn optimize_once(chunk: &mut Chunk) -> bool {
+    let mut starts = vec![];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        starts.push(offset);
+        offset += chunk.instruction_len(offset);
+    }
+    // one past the end too, a jump may land there
+    let mut targets = vec![false; chunk.code.len() + 1];
+    for &offset in &starts {
+        if let Some(target) = chunk.jump_target(offset) {
+            targets[target] = true;
+        }
+    }
+
+    let op_at = |i: usize| starts.get(i).map(|&offset| chunk.code[offset]);
+    let is = |i: usize, op: OpCode| op_at(i) == Some(op as u8);
+    let mut removed = vec![false; chunk.code.len()];
+    let mut found = false;
+    let mut i = 0;
+    while i + 1 < starts.len() {
+        let (first, second) = (starts[i], starts[i + 1]);
+        let remove = !targets[second]
+            && if is(i, OpCode::OP_NOT) && is(i + 1, OpCode::OP_NOT) {
+                is(i + 2, OpCode::OP_NOT)
+                    || is(i + 2, OpCode::OP_POP)
+                    || only_tested(chunk, starts.get(i + 2).copied())
+            } else {
+                pushes_only(chunk.code[first]) && is(i + 1, OpCode::OP_POP)
+            };
+        if remove {
+            let end = second + chunk.instruction_len(second);
+            removed[first..end].fill(true);
+            found = true;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    if !found {
+        return false;
+    }
+
+    // where each old offset ends up. A removed byte maps to the next
+    // one that stays, which is where a jump to it should now go
+    let mut moved = Vec::with_capacity(chunk.code.len() + 1);
+    let mut kept = 0;
+    for &gone in &removed {
+        moved.push(kept);
+        if !gone {
+            kept += 1;
+        }
+    }
+    moved.push(kept);
+
+    let mut code = Vec::with_capacity(kept);
+    for &offset in &starts {
+        if removed[offset] {
+            continue;
+        }
+        let len = chunk.instruction_len(offset);
+        let start = code.len();
+        code.extend_from_slice(&chunk.code[offset..offset + len]);
+        if let Some(target) = chunk.jump_target(offset) {
+            let end = moved[offset] + len;
+            let distance = if chunk.code[offset] == OpCode::OP_LOOP as u8 {
+                end - moved[target]
+            } else {
+                moved[target] - end
+            };
+            code[start + len - 2] = (distance >> 8) as u8;
+            code[start + len - 1] = distance as u8;
+        }
+    }
+    keep(&mut chunk.lines, &removed);
+    keep(&mut chunk.columns, &removed);
+    for file in &mut chunk.files {
+        file.start = moved[file.start.min(removed.len())];
+    }
+    chunk.code = code;
+    true
+}
+
+// drops the entries of the bytes that were removed
+fn keep<T>(values: &mut Vec<T>, removed: &[bool]) {
+    let mut gone = removed.iter();
+    values.retain(|_| !gone.next().copied().unwrap_or(false));
+}
+
+// instructions that push one value and do nothing else
+fn pushes_only(op: u8) -> bool {
+    matches!(
+        OpCode::try_from(op),
+        Ok(OpCode::OP_CONSTANT
+            | OpCode::OP_CONSTANT_LONG
+            | OpCode::OP_NIL
+            | OpCode::OP_TRUE
+            | OpCode::OP_FALSE
+            | OpCode::OP_GET_LOCAL
+            | OpCode::OP_GET_LOCAL_LONG)
+    )
+}
+
+// whether the instruction at `offset` is a conditional jump after which
+// the value it tests is popped whichever way it goes
+fn only_tested(chunk: &Chunk, offset: Option<usize>) -> bool {
+    let Some(offset) = offset else {
+        return false;
+    };
+    let op = chunk.code[offset];
+    if op != OpCode::OP_JUMP_IF_FALSE as u8 && op != OpCode::OP_JUMP_IF_TRUE as u8 {
+        return false;
+    }
+    let pop = OpCode::OP_POP as u8;
+    let next = offset + chunk.instruction_len(offset);
+    chunk.code.get(next) == Some(&pop)
+        && chunk
+            .jump_target(offset)
+            .and_then(|target| chunk.code.get(target))
+            == Some(&pop)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::Compiler, Chunk};
+
+    fn disassemble(source: &str, optimize: bool) -> String {
+        let mut compiler = Compiler::new(source);
+        compiler.optimize = optimize;
+        let chunk = compiler.compile(Chunk::init_chunk()).unwrap().remove(0);
+        chunk.disassembly("code")
+    }
+
+    #[test]
+    fn test_removed() {
+        // the local's own OP_POP at the end of the block goes too, once
+        // everything between it and the value is gone
+        assert_eq!(
+            disassemble("{ var b = 1; b; nil; !!b; }", true),
+            "== code ==\n\
+             0000 OP_RETURN\n"
+        );
+        assert_eq!(
+            disassemble("var a; if (!!a) print 1;", true),
+            "== code ==\n\
+             0000 OP_NIL\n\
+             0001 1:6 OP_DEFINE_GLOBAL_SLOT 0\n\
+             0004 1:14 OP_GET_GLOBAL_SLOT 0\n\
+             0007 1:15 OP_JUMP_IF_FALSE 7 -> 17\n\
+             0010 OP_POP\n\
+             0011 1:23 OP_CONSTANT 0 '1'\n\
+             0013 OP_PRINT\n\
+             0014 1:24 OP_JUMP      14 -> 18\n\
+             0017 OP_POP\n\
+             0018 OP_RETURN\n"
+        );
+        // the loop jumps back over less code
+        assert_eq!(
+            disassemble("var i = 0; while (i < 2) { 1; i = i + 1; }", true),
+            "== code ==\n\
+             0000 1:9 OP_CONSTANT 0 '0'\n\
+             0002 1:10 OP_DEFINE_GLOBAL_SLOT 0\n\
+             0005 1:19 OP_GET_GLOBAL_SLOT 0\n\
+             0008 1:23 OP_CONSTANT 1 '2'\n\
+             0010 OP_LESS\n\
+             0011 1:24 OP_JUMP_IF_FALSE 11 -> 28\n\
+             0014 OP_POP\n\
+             0015 1:35 OP_GET_GLOBAL_SLOT 0\n\
+             0018 1:39 OP_CONSTANT 3 '1'\n\
+             0020 OP_ADD\n\
+             0021 1:39 OP_SET_GLOBAL_SLOT 0\n\
+             0024 OP_POP\n\
+             0025 1:42 OP_LOOP       25 -> 5\n\
+             0028 OP_POP\n\
+             0029 OP_RETURN\n"
+        );
+    }
+
+    #[test]
+    fn test_kept() {
+        // !!a is a bool where a may not be, and `or` keeps the value it
+        // tests. Three nots are one
+        assert_eq!(
+            disassemble("var a; var c = !!!a; var d = !!a; a or 1;", true),
+            "== code ==\n\
+             0000 OP_NIL\n\
+             0001 1:6 OP_DEFINE_GLOBAL_SLOT 0\n\
+             0004 1:19 OP_GET_GLOBAL_SLOT 0\n\
+             0007 OP_NOT\n\
+             0008 1:20 OP_DEFINE_GLOBAL_SLOT 1\n\
+             0011 1:32 OP_GET_GLOBAL_SLOT 0\n\
+             0014 OP_NOT\n\
+             0015 OP_NOT\n\
+             0016 1:33 OP_DEFINE_GLOBAL_SLOT 2\n\
+             0019 1:35 OP_GET_GLOBAL_SLOT 0\n\
+             0022 1:37 OP_JUMP_IF_TRUE 22 -> 28\n\
+             0025 OP_POP\n\
+             0026 1:40 OP_CONSTANT 0 '1'\n\
+             0028 OP_POP\n\
+             0029 OP_RETURN\n"
+        );
+        // the else branch's 2 and the OP_POP of the statement look like
+        // a pair, but the then branch jumps to that OP_POP
+        let source = "true ? 1 : 2;";
+        assert_eq!(disassemble(source, true), disassemble(source, false));
+    }
+}
@@ -1,16 +1,26 @@
+#[cfg(test)]
+use std::ops::Range;
+
 pub struct Scanner {
     pub source: String,
     pub start: usize,
     pub current: usize,
     pub line: i32,
+    // column of `current` and of `start`, see Token::column
+    pub column: usize,
+    pub start_column: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
     pub start: usize,
     pub length: usize,
     pub line: i32,
+    // where the token starts on its line, from 1 and counted in
+    // characters, so it matches what an editor shows. 0 for tokens the
+    // compiler makes up
+    pub column: usize,
     pub error_msg: Option<String>,
 }
 
@@ -21,6 +31,8 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -28,6 +40,12 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Question,
+    Colon,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
 
     // One or two character tokens
     Bang,
@@ -36,8 +54,14 @@ pub enum TokenType {
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
+    DotDot,
+    DotDotEqual,
+    DotDotDot,
+    QuestionQuestion,
 
     // Literals
     Identifier,
@@ -46,19 +70,28 @@ pub enum TokenType {
 
     // Keywords
     And,
+    Assert,
+    Break,
+    Catch,
     Class,
+    Const,
+    Continue,
+    Do,
     Else,
     False,
     For,
     Fun,
     If,
+    In,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -73,12 +106,15 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_column: 1,
         }
     }
     pub fn scan_token(&mut self) -> Token {
         // skip any leading whitespace
         self.skip_whitespace();
         self.start = self.current;
+        self.start_column = self.column;
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
         }
@@ -99,12 +135,37 @@ impl Scanner {
             ')' => return self.make_token(TokenType::RightParen),
             '{' => return self.make_token(TokenType::LeftBrace),
             '}' => return self.make_token(TokenType::RightBrace),
+            '[' => return self.make_token(TokenType::LeftBracket),
+            ']' => return self.make_token(TokenType::RightBracket),
             ',' => return self.make_token(TokenType::Comma),
-            '.' => return self.make_token(TokenType::Dot),
+            '.' => {
+                // matching '..' and '..=' ranges, and '...' spreads
+                if self.match_char('.') {
+                    if self.match_char('=') {
+                        return self.make_token(TokenType::DotDotEqual);
+                    }
+                    if self.match_char('.') {
+                        return self.make_token(TokenType::DotDotDot);
+                    }
+                    return self.make_token(TokenType::DotDot);
+                }
+                return self.make_token(TokenType::Dot);
+            }
             '-' => return self.make_token(TokenType::Minus),
             '+' => return self.make_token(TokenType::Plus),
             ';' => return self.make_token(TokenType::Semicolon),
             '*' => return self.make_token(TokenType::Star),
+            '?' => {
+                if self.match_char('?') {
+                    return self.make_token(TokenType::QuestionQuestion);
+                }
+                return self.make_token(TokenType::Question);
+            }
+            ':' => return self.make_token(TokenType::Colon),
+            '&' => return self.make_token(TokenType::Ampersand),
+            '|' => return self.make_token(TokenType::Pipe),
+            '^' => return self.make_token(TokenType::Caret),
+            '~' => return self.make_token(TokenType::Tilde),
             '/' => return self.make_token(TokenType::Slash),
             '!' => {
                 // matching '!=' operator
@@ -128,6 +189,8 @@ impl Scanner {
                 // matching '<=' operator
                 if self.match_char('=') {
                     return self.make_token(TokenType::LessEqual);
+                } else if self.match_char('<') {
+                    return self.make_token(TokenType::LessLess);
                 } else {
                     // matching '<' operator
                     return self.make_token(TokenType::Less);
@@ -137,6 +200,8 @@ impl Scanner {
                 // matching '>=' operator
                 if self.match_char('=') {
                     return self.make_token(TokenType::GreaterEqual);
+                } else if self.match_char('>') {
+                    return self.make_token(TokenType::GreaterGreater);
                 } else {
                     // matching '>' operator
                     return self.make_token(TokenType::Greater);
@@ -145,7 +210,7 @@ impl Scanner {
             '"' => {
                 return self.string();
             }
-            _ => println!("Unexpected character."),
+            _ => {}
         }
         return self.error_token("Unexpected character.");
     }
@@ -169,10 +234,20 @@ impl Scanner {
     }
 
     pub fn is_digit(&self, c: char) -> bool {
-        return c >= '0' && c <= '9';
+        return c.is_ascii_digit();
     }
 
     pub fn number(&mut self) -> Token {
+        // 0x1f, 0b1010 and 0o755. Letters and digits that don't belong to
+        // the base are still part of the token, so the compiler reports
+        // 0x1g as a bad literal instead of it scanning as 0x1 and g
+        if self.char_at(self.start) == '0' && matches!(self.peek(), 'x' | 'b' | 'o') {
+            self.advance();
+            while self.is_alpha(self.peek()) || self.is_digit(self.peek()) {
+                self.advance();
+            }
+            return self.make_token(TokenType::Number);
+        }
         // consume number until the end or fractional part
         while self.is_digit(self.peek()) {
             self.advance();
@@ -192,13 +267,32 @@ impl Scanner {
 
     pub fn advance(&mut self) -> char {
         if !self.is_at_end() {
-            self.current += 1;
-            self.source.chars().nth(self.current - 1).unwrap_or('\0')
+            let c = self.char_at(self.current);
+            self.current += c.len_utf8();
+            if c == '\n' {
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+            c
         } else {
             '\0'
         }
     }
 
+    // `start` and `current` are byte offsets into the source, so tokens
+    // can be sliced straight out of it even when it holds multi-byte
+    // characters. The character starting at `offset`, or '\0' past the
+    // end. Everything but string contents and comments is ASCII, so the
+    // byte is the character; only other bytes are decoded as UTF-8
+    fn char_at(&self, offset: usize) -> char {
+        match self.source.as_bytes().get(offset) {
+            Some(&byte) if byte.is_ascii() => byte as char,
+            Some(_) => self.source[offset..].chars().next().unwrap_or('\0'),
+            None => '\0',
+        }
+    }
+
     pub fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
@@ -208,11 +302,11 @@ impl Scanner {
             return false;
         } else {
             // if next token is not desired one, we return
-            if self.source.chars().nth(self.current).unwrap() != value {
+            if self.char_at(self.current) != value {
                 return false;
             } else {
                 // if it's a desired one, we increase pointer and return true
-                self.current += 1;
+                self.advance();
                 return true;
             }
         }
@@ -223,17 +317,17 @@ impl Scanner {
         if self.is_at_end() {
             '\0' // Return null char if at end
         } else {
-            self.source.chars().nth(self.current).unwrap_or('\0')
+            self.char_at(self.current)
         }
     }
 
     // If the current character and the next one are both /,
     // we consume them and then any other characters until the next newline or the end of the source code.
     pub fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
+        if self.is_at_end() {
             '\0'
         } else {
-            self.source.chars().nth(self.current + 1).unwrap_or('\0')
+            self.char_at(self.current + self.peek().len_utf8())
         }
     }
 
@@ -249,16 +343,12 @@ impl Scanner {
                     self.line += 1;
                     self.advance();
                 }
-                '/' => {
-                    // we consume '/' only if there is a second '/' right after it
-                    if self.peek_next() == '/' {
-                        // A comment goes until the end of the line.
-                        // with peek() we are checking a newline character
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                // we consume '/' only if there is a second '/' right after it
+                '/' if self.peek_next() == '/' => {
+                    // A comment goes until the end of the line.
+                    // with peek() we are checking a newline character
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -268,7 +358,7 @@ impl Scanner {
 
     // check for keywords and identifiers
     pub fn is_alpha(&self, c: char) -> bool {
-        return c >= 'a' && c <= 'z' || c >= 'A' && c <= 'Z' || c == '_';
+        return c.is_ascii_alphabetic() || c == '_';
     }
     // for identifiers we consume both letters and numbers within the identifier
     pub fn identifier(&mut self) -> Token {
@@ -283,11 +373,30 @@ impl Scanner {
     // if the rest of the word is a valid identifier or a keyword
     // instead of storing predefined values in hashmap
     pub fn identifier_type(&self) -> TokenType {
-        match self.source.chars().nth(self.start).unwrap() {
-            'a' => return self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => return self.check_keyword(1, 4, "lass", TokenType::Class),
+        match self.char_at(self.start) {
+            'a' if self.current - self.start > 1 => match self.char_at(self.start + 1) {
+                'n' => return self.check_keyword(2, 1, "d", TokenType::And),
+                's' => return self.check_keyword(2, 4, "sert", TokenType::Assert),
+                _ => return TokenType::Identifier,
+            },
+            'b' => return self.check_keyword(1, 4, "reak", TokenType::Break),
+            'c' if self.current - self.start > 1 => match self.char_at(self.start + 1) {
+                'a' => return self.check_keyword(2, 3, "tch", TokenType::Catch),
+                'l' => return self.check_keyword(2, 3, "ass", TokenType::Class),
+                'o' if self.current - self.start > 3 => match self.char_at(self.start + 3) {
+                    's' => return self.check_keyword(4, 1, "t", TokenType::Const),
+                    't' => return self.check_keyword(4, 4, "inue", TokenType::Continue),
+                    _ => return TokenType::Identifier,
+                },
+                _ => return TokenType::Identifier,
+            },
+            'd' => return self.check_keyword(1, 1, "o", TokenType::Do),
             'e' => return self.check_keyword(1, 3, "lse", TokenType::Else),
-            'i' => return self.check_keyword(1, 1, "f", TokenType::If),
+            'i' if self.current - self.start > 1 => match self.char_at(self.start + 1) {
+                'f' => return self.check_keyword(2, 0, "", TokenType::If),
+                'n' => return self.check_keyword(2, 0, "", TokenType::In),
+                _ => return TokenType::Identifier,
+            },
             'n' => return self.check_keyword(1, 2, "il", TokenType::Nil),
             'o' => return self.check_keyword(1, 1, "r", TokenType::Or),
             'p' => return self.check_keyword(1, 4, "rint", TokenType::Print),
@@ -295,29 +404,25 @@ impl Scanner {
             's' => return self.check_keyword(1, 4, "uper", TokenType::Super),
             'v' => return self.check_keyword(1, 2, "ar", TokenType::Var),
             'w' => return self.check_keyword(1, 4, "hile", TokenType::While),
-            'f' => {
-                if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
-                        'a' => return self.check_keyword(2, 3, "lse", TokenType::False),
-                        'o' => return self.check_keyword(2, 1, "r", TokenType::For),
-                        'u' => return self.check_keyword(2, 1, "n", TokenType::Fun),
-                        _ => return TokenType::Identifier,
-                    }
-                } else {
-                    return TokenType::Identifier;
-                }
-            }
-            't' => {
-                if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
-                        'h' => return self.check_keyword(2, 2, "is", TokenType::This),
-                        'r' => return self.check_keyword(2, 2, "ue", TokenType::True),
-                        _ => return TokenType::Identifier,
-                    }
-                } else {
-                    return TokenType::Identifier;
-                }
-            }
+            'f' if self.current - self.start > 1 => match self.char_at(self.start + 1) {
+                'a' => return self.check_keyword(2, 3, "lse", TokenType::False),
+                'o' => return self.check_keyword(2, 1, "r", TokenType::For),
+                'u' => return self.check_keyword(2, 1, "n", TokenType::Fun),
+                _ => return TokenType::Identifier,
+            },
+            't' if self.current - self.start > 1 => match self.char_at(self.start + 1) {
+                'h' if self.current - self.start > 2 => match self.char_at(self.start + 2) {
+                    'i' => return self.check_keyword(3, 1, "s", TokenType::This),
+                    'r' => return self.check_keyword(3, 2, "ow", TokenType::Throw),
+                    _ => return TokenType::Identifier,
+                },
+                'r' if self.current - self.start > 2 => match self.char_at(self.start + 2) {
+                    'u' => return self.check_keyword(3, 1, "e", TokenType::True),
+                    'y' => return self.check_keyword(3, 0, "", TokenType::Try),
+                    _ => return TokenType::Identifier,
+                },
+                _ => return TokenType::Identifier,
+            },
             _ => return TokenType::Identifier,
         }
     }
@@ -336,20 +441,21 @@ impl Scanner {
         // current should be 5 (end of "false")
         // start would be 0 (beginning of word)
         // 5 - 0 == 2 + 3 checks if total word length matches
-        if self.current - self.start == start + length {
-            if self.source[self.start + start..self.current].eq(rest) {
-                return token_type;
-            }
+        if self.current - self.start == start + length
+            && self.source[self.start + start..self.current].eq(rest)
+        {
+            return token_type;
         }
         return TokenType::Identifier;
     }
 
     pub fn make_token(&self, token_type: TokenType) -> Token {
         Token {
-            token_type: token_type,
+            token_type,
             start: self.start,
             length: self.current - self.start,
             line: self.line,
+            column: self.start_column,
             error_msg: None,
         }
     }
@@ -360,11 +466,128 @@ impl Scanner {
             start: self.start,
             length: message.len(),
             line: self.line,
+            column: self.start_column,
             error_msg: Some(message.to_string()),
         }
     }
 }
 
+// A source file's tokens, kept up to date while the text is edited, for
+// editors that highlight or check code as it is typed. An edit scans
+// again from just before the changed text until the scanner is back in
+// step with the old tokens. The tokens after that are reused, moved by
+// however much the edit grew or shrank the text. Nothing in the
+// interpreter edits source yet, so it is only built for its tests until
+// an editor integration uses it.
+#[cfg(test)]
+pub struct TokenStream {
+    scanner: Scanner,
+    // every token of the source, ending with Eof
+    pub tokens: Vec<Token>,
+}
+
+#[cfg(test)]
+impl TokenStream {
+    pub fn new(source: &str) -> TokenStream {
+        let mut scanner = Scanner::init_scanner(source);
+        let mut tokens = vec![];
+        loop {
+            let token = scanner.scan_token();
+            let eof = token.token_type == TokenType::Eof;
+            tokens.push(token);
+            if eof {
+                return TokenStream { scanner, tokens };
+            }
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.scanner.source
+    }
+
+    // Replace bytes `start..end` of the source with `text`. Returns the
+    // indices of the tokens that were scanned again: the ones before
+    // them are unchanged and the ones after them have only moved.
+    pub fn edit(&mut self, start: usize, end: usize, text: &str) -> Range<usize> {
+        // The scanner looks up to two characters past a token to decide
+        // where it ends ("1.5", "//", "<="), so a token ending closer than
+        // that to the edit may come out differently. Error tokens don't
+        // say how much text they cover, so nothing after one is kept
+        let keep = self
+            .tokens
+            .iter()
+            .take_while(|token| {
+                !matches!(token.token_type, TokenType::Error | TokenType::Eof)
+                    && token.start + token.length + 2 <= start
+            })
+            .count();
+        let (from, line) = match keep {
+            0 => (0, 1),
+            n => {
+                let last = &self.tokens[n - 1];
+                (last.start + last.length, last.line)
+            }
+        };
+
+        self.scanner.source.replace_range(start..end, text);
+        self.scanner.start = from;
+        self.scanner.current = from;
+        self.scanner.line = line;
+        let line_start = self.scanner.source[..from].rfind('\n').map_or(0, |i| i + 1);
+        self.scanner.column = self.scanner.source[line_start..from].chars().count() + 1;
+        let shift = text.len() as isize - (end - start) as isize;
+
+        let mut scanned = vec![];
+        // the first old token that could still be reused
+        let mut old = keep;
+        loop {
+            let token = self.scanner.scan_token();
+            // a token past the edit that matches an old one, moved, means
+            // the rest of the old tokens are still right
+            if token.start >= start + text.len() {
+                while old < self.tokens.len()
+                    && (self.tokens[old].start < end
+                        || (self.tokens[old].start as isize + shift) < token.start as isize)
+                {
+                    old += 1;
+                }
+                if let Some(same) = self.tokens.get(old).filter(|same| {
+                    same.token_type == token.token_type
+                        && same.start as isize + shift == token.start as isize
+                        && same.length == token.length
+                        && same.error_msg == token.error_msg
+                }) {
+                    // only the tokens on the same line move sideways
+                    let line_shift = token.line - same.line;
+                    let column_shift = token.column as isize - same.column as isize;
+                    let same_line = same.line;
+                    let mut rest = self.tokens.split_off(old);
+                    for moved in &mut rest {
+                        if moved.line == same_line {
+                            moved.column = (moved.column as isize + column_shift) as usize;
+                        }
+                        moved.start = (moved.start as isize + shift) as usize;
+                        moved.line += line_shift;
+                    }
+                    self.tokens.truncate(keep);
+                    let count = scanned.len();
+                    self.tokens.extend(scanned);
+                    self.tokens.extend(rest);
+                    return keep..keep + count;
+                }
+            }
+
+            let eof = token.token_type == TokenType::Eof;
+            scanned.push(token);
+            if eof {
+                self.tokens.truncate(keep);
+                self.tokens.extend(scanned);
+                return keep..self.tokens.len();
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -392,7 +615,7 @@ mod tests {
     #[test]
     fn test_scan_lexemes() {
         let mut scanner = Scanner::init_scanner(
-            "and class else if nil or print return super var while false for fun this true",
+            "and class else if in nil or print return super var while false for fun this true",
         );
         let mut token = scanner.scan_token();
         assert_eq!(token.token_type, TokenType::And);
@@ -403,6 +626,8 @@ mod tests {
         token = scanner.scan_token();
         assert_eq!(token.token_type, TokenType::If);
         token = scanner.scan_token();
+        assert_eq!(token.token_type, TokenType::In);
+        token = scanner.scan_token();
         assert_eq!(token.token_type, TokenType::Nil);
         token = scanner.scan_token();
         assert_eq!(token.token_type, TokenType::Or);
@@ -442,9 +667,116 @@ mod tests {
         let mut scanner = Scanner::init_scanner("     \"test string\" \"test string2\"");
         let mut token = scanner.scan_token();
         assert_eq!(token.token_type, TokenType::String);
-        let mut token = scanner.scan_token();
+        token = scanner.scan_token();
         assert_eq!(token.token_type, TokenType::String);
         token = scanner.scan_token();
         assert_eq!(token.token_type, TokenType::Eof);
     }
+
+    #[test]
+    fn test_multibyte_source() {
+        // token offsets are in bytes, so they slice the source directly
+        let source = "\"héllo\" // ü\nvar ß";
+        let mut scanner = Scanner::init_scanner(source);
+        let token = scanner.scan_token();
+        assert_eq!(token.token_type, TokenType::String);
+        assert_eq!(
+            &source[token.start..token.start + token.length],
+            "\"héllo\""
+        );
+        assert_eq!(scanner.scan_token().token_type, TokenType::Var);
+        let token = scanner.scan_token();
+        assert_eq!(token.token_type, TokenType::Error);
+        assert_eq!(token.line, 2);
+        assert_eq!(scanner.scan_token().token_type, TokenType::Eof);
+    }
+
+    #[test]
+    fn test_columns() {
+        // counted in characters from 1. A multi-line string has the
+        // column it starts at, and as before the line it ends on
+        let source = "var a = 1;\n  print \"é\" + a;\n\"x\ny\" a";
+        let mut scanner = Scanner::init_scanner(source);
+        let mut columns = vec![];
+        loop {
+            let token = scanner.scan_token();
+            columns.push((token.line, token.column));
+            if token.token_type == TokenType::Eof {
+                break;
+            }
+        }
+        assert_eq!(
+            columns,
+            [
+                (1, 1),
+                (1, 5),
+                (1, 7),
+                (1, 9),
+                (1, 10),
+                (2, 3),
+                (2, 9),
+                (2, 13),
+                (2, 15),
+                (2, 16),
+                (4, 1),
+                (4, 4),
+                (4, 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_long_source() {
+        // each character is looked at in constant time, a scan that
+        // searched from the start for every one would take minutes here
+        let text = "ab é ".repeat(100_000);
+        let source = format!("\"{}\" // {}\nvar x;", text, text);
+        let mut scanner = Scanner::init_scanner(&source);
+        let token = scanner.scan_token();
+        assert_eq!(token.token_type, TokenType::String);
+        assert_eq!(token.length, text.len() + 2);
+        assert_eq!(scanner.scan_token().token_type, TokenType::Var);
+        assert_eq!(scanner.scan_token().line, 2);
+    }
+
+    #[test]
+    fn test_incremental_rescan() {
+        let source = "var a = 1;\n// note\nprint a + 2.5;\nvar s = \"two\nlines\";\nprint s;\n";
+        // (start, end, replacement), each applied to the previous result
+        let edits = [
+            (8, 9, "12"),                     // a literal grows
+            (4, 5, "ab"),                     // rename
+            (11, 13, ""),                     // uncomment the note
+            (0, 0, "\n\n"),                   // lines move down
+            (14, 14, "\""),                   // an unterminated string swallows the rest
+            (14, 15, ""),                     // and is taken back
+            (27, 27, ".5"),                   // 2.5.5
+            (3, 3, "x"),                      // the keyword becomes an identifier
+            (0, 0, "@"),                      // an unexpected character
+            (0, usize::MAX, "print 1 <= 2;"), // replace everything
+        ];
+        let mut stream = TokenStream::new(source);
+        for (start, end, text) in edits {
+            let end = end.min(stream.source().len());
+            let mut expected = stream.source().to_string();
+            expected.replace_range(start..end, text);
+            stream.edit(start, end, text);
+            assert_eq!(stream.source(), expected);
+            assert_eq!(
+                stream.tokens,
+                TokenStream::new(&expected).tokens,
+                "{:?}",
+                text
+            );
+        }
+
+        // a small edit in a long file only scans a few tokens again
+        let source = "var x = 1;\n".repeat(100);
+        let mut stream = TokenStream::new(&source);
+        // the 1 on line 46, and the '=' before it as it ends too close
+        let changed = stream.edit(503, 504, "42");
+        assert_eq!(changed.len(), 2);
+        assert_eq!(stream.tokens[changed.end - 1].length, 2);
+        assert_eq!(stream.tokens.last().unwrap().start, source.len() + 1);
+    }
 }
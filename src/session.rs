@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, OpCode, SourceFile};
+use crate::globals::GlobalNames;
+use crate::value::{Obj, ObjFunction, ObjRange, ObjString, ObjType, Value};
+
+// REPL sessions are saved as the globals table in a small binary format:
+//
+//   "ELC2"                         magic and format version
+//   u32                            number of globals
+//   per global, in definition order:
+//     u32 + bytes                  name (UTF-8)
+//     u8 tag + payload             value
+//
+// tags: 0 nil, 1 false, 2 true, 3 number (f64), 4 string (u32 +
+// bytes), 5 int (i64), 6 list and 7 tuple (u32 count + values), 8 map
+// (u32 count + key and value per entry), 9 range (f64 start, f64 end,
+// u8 inclusive), 10 function (name, u32 arity, chunk).
+//
+// A chunk is its code (u32 + bytes), an i32 line and u32 column per
+// byte, its constants (u32 count + values), the global slots the code
+// uses (u32 count + u32 slot and name each) and its files (u32 count +
+// u32 start and name each, without the source). Slots are numbered by
+// the VM that saved them, loading gives the names this VM's slots and
+// rewrites the code. Functions have no upvalues, there are no closures,
+// so that is all there is to one.
+//
+// All integers are little endian. Lists and maps shared by several
+// globals come back as copies, and one that contains itself isn't
+// saved. Natives are defined again by every VM at startup, and classes,
+// instances and bound methods would have to be made by the code that
+// made them anyway, so those are left out with a line saying so.
+const MAGIC: &[u8; 4] = b"ELC2";
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_INT: u8 = 5;
+const TAG_LIST: u8 = 6;
+const TAG_TUPLE: u8 = 7;
+const TAG_MAP: u8 = 8;
+const TAG_RANGE: u8 = 9;
+const TAG_FUNCTION: u8 = 10;
+
+// writes the globals that can be saved. Returns how many, and a line
+// for each one that was left out
+pub fn save_globals(
+    globals: &[(ObjType, Value)],
+    names: &GlobalNames,
+    path: &str,
+) -> io::Result<(usize, Vec<String>)> {
+    let mut saved = 0;
+    let mut skipped = vec![];
+    let mut entries = vec![];
+    for (name, value) in globals {
+        let name = name.as_obj_string();
+        if let Value::Object(Obj {
+            obj_type: ObjType::ObjNative(native),
+        }) = value
+        {
+            // defined again at startup
+            if native.name == name {
+                continue;
+            }
+        }
+        let mut writer = Writer {
+            bytes: vec![],
+            names,
+            open: vec![],
+        };
+        match writer.value(value) {
+            Ok(()) => {
+                write_str(&mut entries, name);
+                entries.extend(writer.bytes);
+                saved += 1;
+            }
+            Err(reason) => skipped.push(format!("Skipped '{}', {}.", name, reason)),
+        }
+    }
+    let mut bytes = MAGIC.to_vec();
+    write_u32(&mut bytes, saved);
+    bytes.extend(entries);
+    fs::write(path, bytes)?;
+    Ok((saved, skipped))
+}
+
+// what load_globals read: the globals, and the slots the code of the
+// saved functions assigns, see Compiler::function_writes
+pub struct Loaded {
+    pub globals: Vec<(ObjType, Value)>,
+    pub function_writes: Vec<usize>,
+}
+
+// reads the globals saved in `path`. The global slots their functions
+// use are looked up, or added, in `names`
+pub fn load_globals(path: &str, names: &mut GlobalNames) -> io::Result<Loaded> {
+    let bytes = fs::read(path)?;
+    let mut reader = Reader {
+        bytes: &bytes,
+        at: 0,
+        names,
+        function_writes: vec![],
+    };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("not an elephant session file"));
+    }
+
+    let count = reader.u32()?;
+    let mut globals = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name = ObjType::ObjString(ObjString::new(reader.string()?));
+        globals.push((name, reader.value()?));
+    }
+    Ok(Loaded {
+        globals,
+        function_writes: reader.function_writes,
+    })
+}
+
+// the offsets of the instructions with a global slot as operand
+fn global_operands(chunk: &Chunk) -> Vec<usize> {
+    let mut offsets = vec![];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = chunk.code[offset];
+        if op == OpCode::OP_DEFINE_GLOBAL_SLOT as u8
+            || op == OpCode::OP_GET_GLOBAL_SLOT as u8
+            || op == OpCode::OP_SET_GLOBAL_SLOT as u8
+        {
+            offsets.push(offset);
+        }
+        offset += chunk.instruction_len(offset);
+    }
+    offsets
+}
+
+fn write_u32(bytes: &mut Vec<u8>, n: usize) {
+    bytes.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, s: &str) {
+    write_u32(bytes, s.len());
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+struct Writer<'a> {
+    bytes: Vec<u8>,
+    names: &'a GlobalNames,
+    // the lists and maps being written, by address. Meeting one of them
+    // again inside itself would never end
+    open: Vec<usize>,
+}
+
+impl Writer<'_> {
+    // Err with why the value can't be saved
+    fn value(&mut self, value: &Value) -> Result<(), String> {
+        match value {
+            Value::Nil => self.bytes.push(TAG_NIL),
+            Value::Boolean(false) => self.bytes.push(TAG_FALSE),
+            Value::Boolean(true) => self.bytes.push(TAG_TRUE),
+            Value::Number(n) => {
+                self.bytes.push(TAG_NUMBER);
+                self.bytes.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Int(n) => {
+                self.bytes.push(TAG_INT);
+                self.bytes.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Object(obj) => match &obj.obj_type {
+                ObjType::ObjString(s) => {
+                    self.bytes.push(TAG_STRING);
+                    write_str(&mut self.bytes, s.as_str());
+                }
+                ObjType::ObjList(list) => {
+                    self.enter(Rc::as_ptr(list) as usize)?;
+                    self.bytes.push(TAG_LIST);
+                    let list = list.borrow();
+                    write_u32(&mut self.bytes, list.items.len());
+                    for item in &list.items {
+                        self.value(item)?;
+                    }
+                    self.open.pop();
+                }
+                ObjType::ObjTuple(items) => {
+                    self.bytes.push(TAG_TUPLE);
+                    write_u32(&mut self.bytes, items.len());
+                    for item in items.iter() {
+                        self.value(item)?;
+                    }
+                }
+                ObjType::ObjMap(map) => {
+                    self.enter(Rc::as_ptr(map) as usize)?;
+                    self.bytes.push(TAG_MAP);
+                    let map = map.borrow();
+                    write_u32(&mut self.bytes, map.entries.len());
+                    for (key, value) in &map.entries {
+                        self.value(key)?;
+                        self.value(value)?;
+                    }
+                    self.open.pop();
+                }
+                ObjType::ObjRange(range) => {
+                    self.bytes.push(TAG_RANGE);
+                    self.bytes.extend_from_slice(&range.start.to_le_bytes());
+                    self.bytes.extend_from_slice(&range.end.to_le_bytes());
+                    self.bytes.push(range.inclusive as u8);
+                }
+                ObjType::ObjFunction(function) => {
+                    self.bytes.push(TAG_FUNCTION);
+                    write_str(&mut self.bytes, &function.name);
+                    write_u32(&mut self.bytes, function.arity);
+                    self.chunk(&function.chunk)?;
+                }
+                ObjType::ObjNative(_) | ObjType::ObjBoundNative(_) => {
+                    return Err("natives can't be saved".to_string())
+                }
+                ObjType::ObjBoundMethod(_) => {
+                    return Err("bound methods can't be saved".to_string())
+                }
+                ObjType::ObjClass(_) => return Err("classes can't be saved".to_string()),
+                ObjType::ObjInstance(_) => return Err("instances can't be saved".to_string()),
+            },
+        }
+        Ok(())
+    }
+
+    fn enter(&mut self, address: usize) -> Result<(), String> {
+        if self.open.contains(&address) {
+            return Err("it contains itself".to_string());
+        }
+        self.open.push(address);
+        Ok(())
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) -> Result<(), String> {
+        write_u32(&mut self.bytes, chunk.code.len());
+        self.bytes.extend_from_slice(&chunk.code);
+        for i in 0..chunk.code.len() {
+            self.bytes.extend_from_slice(&chunk.lines[i].to_le_bytes());
+            write_u32(&mut self.bytes, chunk.columns.get(i).copied().unwrap_or(0));
+        }
+        write_u32(&mut self.bytes, chunk.constants.values.len());
+        for constant in &chunk.constants.values {
+            self.value(constant)?;
+        }
+
+        let mut slots = vec![];
+        for offset in global_operands(chunk) {
+            let slot = (chunk.code[offset + 1] as usize) << 8 | chunk.code[offset + 2] as usize;
+            if !slots.contains(&slot) {
+                slots.push(slot);
+            }
+        }
+        write_u32(&mut self.bytes, slots.len());
+        for slot in slots {
+            let Some(name) = self.names.name(slot) else {
+                return Err(format!(
+                    "its code uses global slot {} that has no name",
+                    slot
+                ));
+            };
+            write_u32(&mut self.bytes, slot);
+            write_str(&mut self.bytes, name.as_obj_string());
+        }
+
+        write_u32(&mut self.bytes, chunk.files.len());
+        for file in &chunk.files {
+            write_u32(&mut self.bytes, file.start);
+            write_str(&mut self.bytes, &file.name);
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    names: &'a mut GlobalNames,
+    function_writes: Vec<usize>,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        if self.at + n > self.bytes.len() {
+            return Err(invalid("session file is truncated"));
+        }
+        self.at += n;
+        Ok(&self.bytes[self.at - n..self.at])
+    }
+
+    fn u32(&mut self) -> io::Result<usize> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| invalid("invalid UTF-8 in session file"))
+    }
+
+    fn values(&mut self) -> io::Result<Vec<Value>> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.value()).collect()
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        let obj = |obj_type| Value::Object(Obj { obj_type });
+        Ok(match self.take(1)?[0] {
+            TAG_NIL => Value::Nil,
+            TAG_FALSE => Value::Boolean(false),
+            TAG_TRUE => Value::Boolean(true),
+            TAG_NUMBER => Value::Number(self.f64()?),
+            TAG_INT => Value::Int(i64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            TAG_STRING => obj(ObjType::ObjString(ObjString::new(self.string()?))),
+            TAG_LIST => Value::list(self.values()?),
+            TAG_TUPLE => Value::tuple(self.values()?),
+            TAG_MAP => {
+                let count = self.u32()?;
+                let entries = (0..count)
+                    .map(|_| Ok((self.value()?, self.value()?)))
+                    .collect::<io::Result<_>>()?;
+                Value::map(entries)
+            }
+            TAG_RANGE => obj(ObjType::ObjRange(ObjRange {
+                start: self.f64()?,
+                end: self.f64()?,
+                inclusive: self.take(1)?[0] != 0,
+            })),
+            TAG_FUNCTION => {
+                let name = self.string()?;
+                let arity = self.u32()?;
+                let chunk = Rc::new(self.chunk()?);
+                obj(ObjType::ObjFunction(Rc::new(ObjFunction {
+                    name,
+                    arity,
+                    chunk,
+                })))
+            }
+            tag => return Err(invalid(&format!("unknown value tag {}", tag))),
+        })
+    }
+
+    fn chunk(&mut self) -> io::Result<Chunk> {
+        let mut chunk = Chunk::init_chunk();
+        let len = self.u32()?;
+        let code = self.take(len)?.to_vec();
+        for byte in code {
+            let line = i32::from_le_bytes(self.take(4)?.try_into().unwrap());
+            let column = self.u32()?;
+            chunk.write_chunk_at(byte, line, column);
+        }
+        for constant in self.values()? {
+            chunk.add_constant(constant);
+        }
+
+        // the saving VM's slots to this one's
+        let mut slots = HashMap::new();
+        for _ in 0..self.u32()? {
+            let saved = self.u32()?;
+            let name = ObjType::ObjString(ObjString::new(self.string()?));
+            slots.insert(saved, self.names.slot(name));
+        }
+        for _ in 0..self.u32()? {
+            let start = self.u32()?;
+            let name = self.string()?;
+            chunk.files.push(SourceFile {
+                start,
+                name,
+                source: None,
+            });
+        }
+
+        for offset in global_operands(&chunk) {
+            if offset + 3 > chunk.code.len() {
+                return Err(invalid("instruction cut off in session file"));
+            }
+            let saved = (chunk.code[offset + 1] as usize) << 8 | chunk.code[offset + 2] as usize;
+            let Some(&slot) = slots.get(&saved).filter(|slot| **slot <= u16::MAX as usize) else {
+                return Err(invalid("global slot without a name in session file"));
+            };
+            chunk.code[offset + 1] = (slot >> 8) as u8;
+            chunk.code[offset + 2] = slot as u8;
+            if chunk.code[offset] == OpCode::OP_SET_GLOBAL_SLOT as u8 {
+                self.function_writes.push(slot);
+            }
+        }
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut globals = vec![];
+        let values = [
+            ("n", Value::Nil),
+            ("yes", Value::Boolean(true)),
+            ("no", Value::Boolean(false)),
+            ("pi", Value::Number(3.25)),
+            ("count", Value::Int(-42)),
+            (
+                "greeting",
+                Value::Object(Obj {
+                    obj_type: ObjType::ObjString(ObjString::new("héllo \"there\"\n".to_string())),
+                }),
+            ),
+            (
+                "nested",
+                Value::list(vec![
+                    Value::Int(1),
+                    Value::tuple(vec![Value::Nil, Value::Number(2.5)]),
+                    Value::map(vec![(Value::Int(3), Value::list(vec![]))]),
+                ]),
+            ),
+            (
+                "span",
+                Value::Object(Obj {
+                    obj_type: ObjType::ObjRange(ObjRange {
+                        start: 1.0,
+                        end: 4.0,
+                        inclusive: true,
+                    }),
+                }),
+            ),
+        ];
+        for (name, value) in &values {
+            globals.push((
+                ObjType::ObjString(ObjString::new(name.to_string())),
+                value.clone(),
+            ));
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("elephant-session-{}.elc", std::process::id()));
+        let path = path.to_str().unwrap();
+        let (saved, skipped) = save_globals(&globals, &GlobalNames::default(), path).unwrap();
+        assert_eq!((saved, skipped.len()), (values.len(), 0));
+        let loaded = load_globals(path, &mut GlobalNames::default())
+            .unwrap()
+            .globals;
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.len(), values.len());
+        for ((name, value), (loaded_name, loaded_value)) in values.iter().zip(&loaded) {
+            assert_eq!(loaded_name.as_obj_string(), *name);
+            // lists and maps come back as new objects, so compare the text
+            assert_eq!(value.format_with(None), loaded_value.format_with(None));
+            assert_eq!(value.type_name(), loaded_value.type_name());
+        }
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        let path =
+            std::env::temp_dir().join(format!("elephant-not-session-{}.elc", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, b"print 1;").unwrap();
+        assert!(load_globals(path, &mut GlobalNames::default()).is_err());
+        fs::write(path, b"ELC2\x01\x00\x00\x00").unwrap();
+        assert!(load_globals(path, &mut GlobalNames::default()).is_err());
+        // the format before functions and lists could be saved
+        fs::write(path, b"ELC1\x00\x00\x00\x00").unwrap();
+        assert!(load_globals(path, &mut GlobalNames::default()).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,46 +1,224 @@
-use std::collections::HashMap;
-
-use crate::value::{ObjType, Value};
+use crate::{interner::StringId, value::Value};
 
+// entries are kept in insertion order so anything that walks a table
+// (listing globals, printing a map) gives the same output on every run.
+// Keys are strings, by their handle in the VM's intern pool, see
+// interner.rs. They are found through `buckets`, an open addressing
+// hash table as in clox: a key's hash picks a bucket, and on a collision the next ones
+// are tried in turn until the key or an empty bucket turns up. A full
+// bucket holds the key's position in `entries`
 #[derive(Debug, Clone)]
 pub struct Table {
-    pub entries: HashMap<ObjType, Value>,
+    pub entries: Vec<Entry>,
+    buckets: Vec<Bucket>,
+    // full buckets and tombstones, both make probing longer so both
+    // count towards the load factor
+    count: usize,
 }
 
 #[derive(Debug, Clone)]
 pub struct Entry {
-    pub key: ObjType,
+    pub key: StringId,
     pub value: Value,
 }
 
-// I will not implement HashMap from scratch
-// as it doesn't make much sense and it's not the goal here
+// a deleted key leaves a tombstone rather than an empty bucket, so a
+// probe for a key that collided with it goes on past it. Inserting can
+// reuse one
+#[derive(Debug, Clone, Copy)]
+enum Bucket {
+    Empty,
+    // only table_delete leaves one
+    #[cfg_attr(not(test), allow(dead_code))]
+    Tombstone,
+    Full(usize),
+}
+
+// grow once more than 3/4 of the buckets are in use
+const TABLE_MAX_LOAD: f64 = 0.75;
+
 impl Table {
     pub fn init_table() -> Table {
         Table {
-            entries: HashMap::new(),
+            entries: vec![],
+            buckets: vec![],
+            count: 0,
+        }
+    }
+
+    // overwriting a key keeps its original position
+    pub fn table_set(&mut self, key: StringId, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.buckets.len() as f64 * TABLE_MAX_LOAD {
+            self.adjust_capacity((self.buckets.len() * 2).max(8));
+        }
+        let bucket = self.find_bucket(key);
+        match self.buckets[bucket] {
+            Bucket::Full(i) => {
+                self.entries[i].value = value;
+                false
+            }
+            empty => {
+                if let Bucket::Empty = empty {
+                    self.count += 1;
+                }
+                self.buckets[bucket] = Bucket::Full(self.entries.len());
+                self.entries.push(Entry { key, value });
+                true
+            }
+        }
+    }
+
+    pub fn table_get(&self, key: StringId) -> Option<Value> {
+        self.position(key).map(|i| self.entries[i].value.clone())
+    }
+
+    // where `key` is in `entries`, for the inline caches that remember
+    // it, see Chunk::cache
+    pub fn position(&self, key: StringId) -> Option<usize> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        match self.buckets[self.find_bucket(key)] {
+            Bucket::Full(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    // deleting shifts the later entries down, so their positions
+    // have to be updated as well. Nothing takes a field out of an
+    // instance yet, so only the tests delete
+    #[cfg(test)]
+    pub fn table_delete(&mut self, key: StringId) -> bool {
+        if self.entries.is_empty() {
+            return false;
+        }
+        let bucket = self.find_bucket(key);
+        let Bucket::Full(i) = self.buckets[bucket] else {
+            return false;
+        };
+        self.buckets[bucket] = Bucket::Tombstone;
+        self.entries.remove(i);
+        for bucket in &mut self.buckets {
+            if let Bucket::Full(later) = bucket {
+                if *later > i {
+                    *later -= 1;
+                }
+            }
+        }
+        true
+    }
+
+    // the bucket that holds `key`, or if it's not in the table the one
+    // to put it in: the first tombstone on the way, else the empty
+    // bucket the probe stopped at. The load factor keeps an empty
+    // bucket around, so the probe always stops
+    fn find_bucket(&self, key: StringId) -> usize {
+        // the capacity is a power of two, so masking is the modulo.
+        // Handles are given out one after another, so they make a
+        // good enough hash as they are
+        let mask = self.buckets.len() - 1;
+        let mut bucket = key.index() & mask;
+        let mut tombstone = None;
+        loop {
+            match self.buckets[bucket] {
+                Bucket::Empty => return tombstone.unwrap_or(bucket),
+                Bucket::Tombstone => {
+                    tombstone.get_or_insert(bucket);
+                }
+                Bucket::Full(i) => {
+                    if self.entries[i].key == key {
+                        return bucket;
+                    }
+                }
+            }
+            bucket = (bucket + 1) & mask;
         }
     }
 
-    pub fn free_table(&mut self) {
-        self.entries.clear();
+    // rehash every entry into `capacity` buckets. Tombstones aren't
+    // carried over
+    fn adjust_capacity(&mut self, capacity: usize) {
+        self.buckets = vec![Bucket::Empty; capacity];
+        self.count = 0;
+        for i in 0..self.entries.len() {
+            let bucket = self.find_bucket(self.entries[i].key);
+            self.buckets[bucket] = Bucket::Full(i);
+            self.count += 1;
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interner::Interner, value::ObjString};
 
-    pub fn table_set(&mut self, key: ObjType, value: Value) -> bool {
-        let is_new_key = !self.entries.contains_key(&key);
-        self.entries.insert(key, value);
-        is_new_key
+    fn key(strings: &mut Interner, name: &str) -> StringId {
+        strings.intern(&ObjString::new(name.to_string()))
     }
 
-    pub fn table_get(&self, key: &ObjType) -> Option<Value> {
-        self.entries.get(key).cloned()
+    fn keys(strings: &Interner, table: &Table) -> Vec<String> {
+        table
+            .entries
+            .iter()
+            .map(|entry| strings.get(entry.key).as_str().to_string())
+            .collect()
     }
 
-    pub fn table_delete(&mut self, key: &ObjType) -> bool {
-        self.entries.remove(key).is_some()
+    #[test]
+    fn test_insertion_order() {
+        let mut strings = Interner::default();
+        let [zeta, alpha, mid, beta] =
+            ["zeta", "alpha", "mid", "beta"].map(|name| key(&mut strings, name));
+        let mut table = Table::init_table();
+        for key in [zeta, alpha, mid, beta] {
+            assert!(table.table_set(key, Value::Nil));
+        }
+        // overwriting doesn't move the key
+        assert!(!table.table_set(alpha, Value::Number(1.0)));
+        assert_eq!(keys(&strings, &table), ["zeta", "alpha", "mid", "beta"]);
+
+        assert!(table.table_delete(alpha));
+        assert!(!table.table_delete(alpha));
+        assert_eq!(keys(&strings, &table), ["zeta", "mid", "beta"]);
+        assert_eq!(table.table_get(beta).unwrap().type_name(), "nil");
+
+        table.table_set(alpha, Value::Number(2.0));
+        assert_eq!(keys(&strings, &table), ["zeta", "mid", "beta", "alpha"]);
+        assert_eq!(table.table_get(alpha).unwrap().as_number(), Some(2.0));
     }
 
-    pub fn table_add_all(&mut self, from: &Table) {
-        self.entries.extend(from.entries.clone());
+    #[test]
+    fn test_probing() {
+        let mut strings = Interner::default();
+        let ids: Vec<StringId> = (0..100)
+            .map(|i| key(&mut strings, &format!("k{}", i)))
+            .collect();
+        let mut table = Table::init_table();
+        for (i, &id) in ids.iter().enumerate() {
+            table.table_set(id, Value::Int(i as i64));
+        }
+        // growing kept every key
+        assert_eq!(table.entries.len(), 100);
+        assert!(table.buckets.len() as f64 * TABLE_MAX_LOAD >= 100.0);
+
+        // deleting leaves tombstones that probes go past, and that
+        // inserting fills again
+        for &id in ids.iter().step_by(2) {
+            assert!(table.table_delete(id));
+        }
+        for (i, &id) in ids.iter().enumerate() {
+            assert_eq!(
+                table.table_get(id).map(|value| value.as_number()),
+                (i % 2 == 1).then_some(Some(i as f64))
+            );
+        }
+        let buckets = table.buckets.len();
+        for &id in ids.iter().step_by(2) {
+            assert!(table.table_set(id, Value::Nil));
+        }
+        assert_eq!(table.buckets.len(), buckets);
+        assert_eq!(table.entries[49].key, ids[99]);
+        assert_eq!(table.entries[50].key, ids[0]);
     }
 }
@@ -11,13 +11,28 @@
 // literals in the program. To keep things simpler,
 // we’ll put all constants in there, even simple integers.
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::rc::Rc;
 
+use crate::{interner::StringId, table::Table, vm::VM, Chunk};
+
+// nil, booleans and numbers live inline in the enum, so pushing one
+// never allocates and there is nothing to cache for them. Only
+// objects own heap data.
+//
+// Numbers come in two kinds. A literal without a decimal point is an
+// Int, and arithmetic on two ints stays exact. Once a float is involved,
+// or for division, the result is a float Number. Both are "numbers" to
+// is_number() and as_number(), and 1 == 1.0
 #[derive(Debug, Clone)]
 pub enum Value {
     Boolean(bool),
     Nil,
     Number(f64),
+    Int(i64),
     Object(Obj),
 }
 
@@ -26,17 +41,67 @@ pub struct Obj {
     pub obj_type: ObjType,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+// functions, classes and instances have identity: copies of the value
+// share the object, so a field set through one is seen through all of them
+#[derive(Debug, Clone)]
 pub enum ObjType {
     ObjString(ObjString),
+    ObjNative(ObjNative),
+    ObjFunction(Rc<ObjFunction>),
+    ObjClass(Rc<ObjClass>),
+    ObjInstance(Rc<RefCell<ObjInstance>>),
+    ObjBoundMethod(Rc<ObjBoundMethod>),
+    ObjBoundNative(Rc<ObjBoundNative>),
+    ObjList(Rc<RefCell<ObjList>>),
+    ObjMap(Rc<RefCell<ObjMap>>),
+    ObjRange(ObjRange),
+    // a fixed sequence, (1, "a", true). Nothing changes a tuple once it
+    // is built, so like ranges two with equal items are equal. The lists
+    // or instances inside one can still change
+    ObjTuple(Rc<[Value]>),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+impl PartialEq for ObjType {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ObjType::ObjString(a), ObjType::ObjString(b)) => a == b,
+            (ObjType::ObjNative(a), ObjType::ObjNative(b)) => a == b,
+            (ObjType::ObjFunction(a), ObjType::ObjFunction(b)) => Rc::ptr_eq(a, b),
+            (ObjType::ObjClass(a), ObjType::ObjClass(b)) => Rc::ptr_eq(a, b),
+            (ObjType::ObjInstance(a), ObjType::ObjInstance(b)) => Rc::ptr_eq(a, b),
+            (ObjType::ObjBoundMethod(a), ObjType::ObjBoundMethod(b)) => Rc::ptr_eq(a, b),
+            (ObjType::ObjBoundNative(a), ObjType::ObjBoundNative(b)) => Rc::ptr_eq(a, b),
+            (ObjType::ObjList(a), ObjType::ObjList(b)) => Rc::ptr_eq(a, b),
+            (ObjType::ObjMap(a), ObjType::ObjMap(b)) => Rc::ptr_eq(a, b),
+            (ObjType::ObjRange(a), ObjType::ObjRange(b)) => a == b,
+            (ObjType::ObjTuple(a), ObjType::ObjTuple(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.values_equal(b))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for ObjType {}
+
+// The text is shared, so copying a string value onto the stack, out of
+// the constant pool or out of a table only bumps a reference count.
+// Strings that went through VM::intern_string are the same allocation
+// when their text is equal, and compare by pointer; others, e.g. the
+// compiler's constants, fall back to comparing hash and text
+#[derive(Debug, Clone, Eq)]
 pub struct ObjString {
-    string: String,
+    string: Rc<str>,
     hash: u64,
 }
 
+impl PartialEq for ObjString {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.string, &other.string)
+            || (self.hash == other.hash && self.string == other.string)
+    }
+}
+
 // Manual Hash implementation for ObjString
 impl Hash for ObjString {
     fn hash<H: Hasher>(&self, state: &mut H) {
@@ -49,19 +114,251 @@ impl Hash for ObjType {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match self {
             ObjType::ObjString(s) => s.hash(state),
+            ObjType::ObjNative(n) => n.name.hash(state),
+            ObjType::ObjFunction(f) => Rc::as_ptr(f).hash(state),
+            ObjType::ObjClass(c) => Rc::as_ptr(c).hash(state),
+            ObjType::ObjInstance(i) => Rc::as_ptr(i).hash(state),
+            ObjType::ObjBoundMethod(m) => Rc::as_ptr(m).hash(state),
+            ObjType::ObjBoundNative(m) => Rc::as_ptr(m).hash(state),
+            ObjType::ObjList(l) => Rc::as_ptr(l).hash(state),
+            ObjType::ObjMap(m) => Rc::as_ptr(m).hash(state),
+            ObjType::ObjRange(r) => {
+                r.start.to_bits().hash(state);
+                r.end.to_bits().hash(state);
+                r.inclusive.hash(state);
+            }
+            ObjType::ObjTuple(items) => {
+                items.len().hash(state);
+                for item in items.iter() {
+                    hash_value(item, state);
+                }
+            }
+        }
+    }
+}
+
+// agrees with values_equal, so equal tuples hash the same: 1 and 1.0
+// are equal and hash as the same integer
+fn hash_value<H: Hasher>(value: &Value, state: &mut H) {
+    match value {
+        Value::Nil => state.write_u8(0),
+        Value::Boolean(b) => b.hash(state),
+        Value::Int(n) => n.hash(state),
+        Value::Number(n) => match exact_int(*n) {
+            Some(n) => n.hash(state),
+            None => n.to_bits().hash(state),
+        },
+        Value::Object(obj) => obj.obj_type.hash(state),
+    }
+}
+
+// the i64 a float is exactly, None if it has a fraction or is out of
+// range. An int compares equal to a float only through this: `as f64`
+// rounds big ints, so 2^53 + 1 would equal 2^53 as a float
+fn exact_int(n: f64) -> Option<i64> {
+    let in_range = (i64::MIN as f64..i64::MAX as f64).contains(&n);
+    (in_range && n.fract() == 0.0).then_some(n as i64)
+}
+
+// a function implemented in Rust. It gets the VM (so it can intern
+// strings or read options) and the call's arguments, and either returns
+// the result or a message that is reported as a runtime error
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, String>;
+
+#[derive(Debug, Clone)]
+pub struct ObjNative {
+    pub name: String,
+    // None accepts any number of arguments
+    pub arity: Option<usize>,
+    pub function: NativeFn,
+}
+
+// natives are registered once per name, so the name identifies them
+impl PartialEq for ObjNative {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for ObjNative {}
+
+// a function declared in elephant. Its body was compiled into a chunk
+// of its own, which the VM switches to for the duration of a call
+#[derive(Debug)]
+pub struct ObjFunction {
+    pub name: String,
+    pub arity: usize,
+    pub chunk: Rc<Chunk>,
+}
+
+// methods are added one by one while the class body runs, hence the
+// RefCell
+#[derive(Debug)]
+pub struct ObjClass {
+    pub name: String,
+    pub methods: RefCell<Table>,
+}
+
+impl ObjClass {
+    pub fn find_method(&self, name: StringId) -> Option<Rc<ObjFunction>> {
+        match self.methods.borrow().table_get(name) {
+            Some(Value::Object(Obj {
+                obj_type: ObjType::ObjFunction(method),
+            })) => Some(method),
+            _ => None,
         }
     }
 }
 
+#[derive(Debug)]
+pub struct ObjInstance {
+    pub class: Rc<ObjClass>,
+    pub fields: Table,
+}
+
+// `instance.method` without calling it yet. Calling it later still runs
+// the method with `this` set to that instance
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: Rc<ObjFunction>,
+}
+
+// a method of a built-in type looked up on a value, `"a,b".split`.
+// Calling it runs the native with the receiver as its first argument
+#[derive(Debug)]
+pub struct ObjBoundNative {
+    pub receiver: Value,
+    pub native: ObjNative,
+}
+
+// a growable sequence. Like instances, lists are shared: assigning one
+// to another variable doesn't copy it
+#[derive(Debug, Default)]
+pub struct ObjList {
+    pub items: Vec<Value>,
+}
+
+// keys to values. Any value can be a key, and keys match the way ==
+// compares, so m[1] and m[1.0] are the same entry. Like Table, entries
+// stay in insertion order. Maps are shared like lists
+#[derive(Debug, Default)]
+pub struct ObjMap {
+    pub entries: Vec<(Value, Value)>,
+    index: HashMap<MapKey, usize>,
+}
+
+// a map key hashed and compared like values_equal
+#[derive(Debug, Clone)]
+struct MapKey(Value);
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_value(&self.0, state);
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.values_equal(&other.0)
+    }
+}
+
+impl Eq for MapKey {}
+
+impl ObjMap {
+    pub fn get(&self, key: &Value) -> Option<Value> {
+        self.index
+            .get(&MapKey(key.clone()))
+            .map(|&i| self.entries[i].1.clone())
+    }
+
+    // overwriting a key keeps its original position
+    pub fn set(&mut self, key: Value, value: Value) {
+        match self.index.get(&MapKey(key.clone())) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.index.insert(MapKey(key.clone()), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    // the removed value. The later entries shift down, like
+    // Table::table_delete
+    pub fn remove(&mut self, key: &Value) -> Option<Value> {
+        let i = self.index.remove(&MapKey(key.clone()))?;
+        let (_, value) = self.entries.remove(i);
+        for (key, _) in &self.entries[i..] {
+            if let Some(position) = self.index.get_mut(&MapKey(key.clone())) {
+                *position -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+// where `index` points in a list or string of `len` items, if it is a
+// whole number inside it
+pub fn index_position(index: &Value, len: usize) -> Option<usize> {
+    match index {
+        Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 && (*n as usize) < len => {
+            Some(*n as usize)
+        }
+        Value::Int(n) if *n >= 0 && (*n as u64) < len as u64 => Some(*n as usize),
+        _ => None,
+    }
+}
+
+// start..end or start..=end. The bounds are whole numbers. Ranges are
+// plain values like numbers: two with the same bounds are equal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObjRange {
+    pub start: f64,
+    pub end: f64,
+    pub inclusive: bool,
+}
+
+impl ObjRange {
+    // the end as an exclusive bound
+    pub fn end_exclusive(&self) -> f64 {
+        if self.inclusive {
+            self.end + 1.0
+        } else {
+            self.end
+        }
+    }
+
+    // the part of a list or string of `len` items the range covers, if
+    // it lies inside it
+    pub fn slice(&self, len: usize) -> Option<(usize, usize)> {
+        let end = self.end_exclusive();
+        if self.start < 0.0 || self.start > end || end > len as f64 {
+            return None;
+        }
+        Some((self.start as usize, end as usize))
+    }
+}
+
+// we use FNV-1a algo https://en.wikipedia.org/wiki/Fowler%E2%80%93Noll%E2%80%93Vo_hash_function
+// to hash string value for storing in the hashmap later
+pub fn hash_string(string: &str) -> u64 {
+    let mut hasher = fnv::FnvHasher::default();
+    string.hash(&mut hasher);
+    hasher.finish()
+}
+
 impl ObjString {
     pub fn new(string: String) -> Self {
-        // we use FNV-1a algo https://en.wikipedia.org/wiki/Fowler%E2%80%93Noll%E2%80%93Vo_hash_function
-        // to hash string value for storing in the hashmap later
-        let mut hasher = fnv::FnvHasher::default();
-        string.hash(&mut hasher);
-        let hash = hasher.finish();
-
-        Self { string, hash }
+        let hash = hash_string(&string);
+        Self {
+            string: string.into(),
+            hash,
+        }
     }
 
     pub fn as_str(&self) -> &str {
@@ -71,29 +368,69 @@ impl ObjString {
     pub fn get_hash(&self) -> u64 {
         self.hash
     }
+
+    // Strings are UTF-8. Byte operations count the encoded bytes, char
+    // operations count Unicode scalar values, so "héllo" has a byte_len
+    // of 6 and a char_len of 5. Char indices never split a character.
+    pub fn byte_len(&self) -> usize {
+        self.string.len()
+    }
+
+    pub fn char_len(&self) -> usize {
+        self.string.chars().count()
+    }
+
+    // the character at char index `index`, as a one character string
+    pub fn char_at(&self, index: usize) -> Option<String> {
+        self.string.chars().nth(index).map(String::from)
+    }
+
+    // up to `length` characters starting at char index `start`. The
+    // range is clipped to the end of the string
+    pub fn substr_chars(&self, start: usize, length: usize) -> String {
+        self.string.chars().skip(start).take(length).collect()
+    }
+
+    // char index of the first occurrence of `needle`
+    pub fn find_chars(&self, needle: &str) -> Option<usize> {
+        self.string
+            .find(needle)
+            .map(|byte| self.string[..byte].chars().count())
+    }
 }
 
 impl ObjType {
-    pub fn as_obj_string(&self) -> &String {
-        match self {
-            ObjType::ObjString(s) => &s.string,
-        }
+    pub fn is_string(&self) -> bool {
+        matches!(self, ObjType::ObjString(_))
     }
-    // get hash for lookup in hashmap
-    pub fn get_hash(&self) -> u64 {
+
+    // table keys are always strings, so this only fails on a bug
+    // in the VM
+    pub fn as_obj_string(&self) -> &str {
         match self {
-            ObjType::ObjString(s) => s.get_hash(),
+            ObjType::ObjString(s) => &s.string,
+            _ => unreachable!("{:?} is not a string", self),
         }
     }
 }
 
 impl Value {
+    // ints come back as floats, for code that works on either
     pub fn as_number(&self) -> Option<f64> {
         match self {
             Value::Number(n) => Some(*n),
+            Value::Int(n) => Some(*n as f64),
             _ => None,
         }
     }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+    #[cfg(test)]
     pub fn as_bool(&self) -> Option<bool> {
         match self {
             Value::Boolean(n) => Some(*n),
@@ -107,26 +444,94 @@ impl Value {
             _ => None,
         }
     }
+    // the shared instance behind the value, if it is one
+    pub fn as_instance(&self) -> Option<Rc<RefCell<ObjInstance>>> {
+        match self {
+            Value::Object(Obj {
+                obj_type: ObjType::ObjInstance(instance),
+            }) => Some(instance.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<Rc<RefCell<ObjList>>> {
+        match self {
+            Value::Object(Obj {
+                obj_type: ObjType::ObjList(list),
+            }) => Some(list.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn as_range(&self) -> Option<ObjRange> {
+        match self {
+            Value::Object(Obj {
+                obj_type: ObjType::ObjRange(range),
+            }) => Some(*range),
+            _ => None,
+        }
+    }
+
+    pub fn as_tuple(&self) -> Option<Rc<[Value]>> {
+        match self {
+            Value::Object(Obj {
+                obj_type: ObjType::ObjTuple(items),
+            }) => Some(items.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn tuple(items: Vec<Value>) -> Value {
+        Value::Object(Obj {
+            obj_type: ObjType::ObjTuple(items.into()),
+        })
+    }
+
+    pub fn as_map(&self) -> Option<Rc<RefCell<ObjMap>>> {
+        match self {
+            Value::Object(Obj {
+                obj_type: ObjType::ObjMap(map),
+            }) => Some(map.clone()),
+            _ => None,
+        }
+    }
+
+    // a new map with the entries in order; a later duplicate key
+    // replaces the value of the earlier one
+    pub fn map(entries: Vec<(Value, Value)>) -> Value {
+        let mut map = ObjMap::default();
+        for (key, value) in entries {
+            map.set(key, value);
+        }
+        Value::Object(Obj {
+            obj_type: ObjType::ObjMap(Rc::new(RefCell::new(map))),
+        })
+    }
+
+    pub fn list(items: Vec<Value>) -> Value {
+        Value::Object(Obj {
+            obj_type: ObjType::ObjList(Rc::new(RefCell::new(ObjList { items }))),
+        })
+    }
+
     pub fn values_equal(&self, other: &Value) -> bool {
         match (self, other) {
             (Value::Nil, Value::Nil) => true,
             (Value::Boolean(a), Value::Boolean(b)) => a == b,
             (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Int(a), Value::Number(b)) | (Value::Number(b), Value::Int(a)) => {
+                exact_int(*b) == Some(*a)
+            }
             (Value::Object(a), Value::Object(b)) => match (&a.obj_type, &b.obj_type) {
-                (ObjType::ObjString(str1), ObjType::ObjString(str2)) => {
-                    // First compare hashes, then strings if hashes match
-                    if str1.get_hash() != str2.get_hash() {
-                        false
-                    } else {
-                        str1.as_str() == str2.as_str()
-                    }
-                }
+                (ObjType::ObjString(str1), ObjType::ObjString(str2)) => str1 == str2,
+                (a, b) => a == b,
             },
             _ => false,
         }
     }
     pub fn is_number(&self) -> bool {
-        matches!(self, Value::Number(_))
+        matches!(self, Value::Number(_) | Value::Int(_))
     }
 
     pub fn is_string(&self) -> bool {
@@ -139,26 +544,145 @@ impl Value {
         )
     }
 
-    pub fn is_object(&self) -> bool {
-        matches!(self, Value::Object(_))
+    pub fn print_value(&self) {
+        self.print_value_with(None);
     }
 
-    pub fn print_value(&self) {
+    // `precision` fixes the number of digits printed after the decimal
+    // point for numbers; None prints the shortest form that reads back
+    // as the same f64
+    pub fn print_value_with(&self, precision: Option<usize>) {
+        print!("{}", self.format_with(precision));
+    }
+
+    // the text print shows for the value
+    pub fn format_with(&self, precision: Option<usize>) -> String {
+        let mut out = String::new();
+        self.write_value(precision, &mut vec![], &mut out);
+        out
+    }
+
+    // `open` holds the lists and maps being written further up, so one
+    // that contains itself is written as [...] or {...} instead of forever
+    fn write_value(&self, precision: Option<usize>, open: &mut Vec<*const ()>, out: &mut String) {
         match self {
-            Value::Boolean(b) => print!("{}", b),
-            Value::Nil => print!("nil"),
-            Value::Number(n) => print!("{}", n),
-            Value::Object(obj_string) => {
-                match &obj_string.obj_type {
-                    ObjType::ObjString(obj_str) => {
-                        // let obj_string = obj_string.as_obj_string();
-                        for c in obj_str.string.chars() {
-                            print!("{}", c);
+            Value::Boolean(b) => out.push_str(&b.to_string()),
+            Value::Nil => out.push_str("nil"),
+            Value::Number(n) => out.push_str(&format_number(*n, precision)),
+            Value::Int(n) => out.push_str(&n.to_string()),
+            Value::Object(obj) => match &obj.obj_type {
+                ObjType::ObjString(obj_str) => out.push_str(&obj_str.string),
+                ObjType::ObjNative(native) => out.push_str(&format!("<native fn {}>", native.name)),
+                ObjType::ObjFunction(function) => out.push_str(&format!("<fn {}>", function.name)),
+                ObjType::ObjBoundMethod(bound) => {
+                    out.push_str(&format!("<fn {}>", bound.method.name))
+                }
+                ObjType::ObjBoundNative(bound) => {
+                    out.push_str(&format!("<native fn {}>", bound.native.name))
+                }
+                ObjType::ObjClass(class) => out.push_str(&class.name),
+                ObjType::ObjInstance(instance) => {
+                    out.push_str(&format!("{} instance", instance.borrow().class.name))
+                }
+                ObjType::ObjRange(range) => {
+                    let op = if range.inclusive { "..=" } else { ".." };
+                    out.push_str(&format!(
+                        "{}{}{}",
+                        format_number(range.start, None),
+                        op,
+                        format_number(range.end, None)
+                    ));
+                }
+                ObjType::ObjList(list) => {
+                    let address = Rc::as_ptr(list) as *const ();
+                    if open.contains(&address) {
+                        out.push_str("[...]");
+                        return;
+                    }
+                    open.push(address);
+                    out.push('[');
+                    for (i, item) in list.borrow().items.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(", ");
+                        }
+                        // quoted, so ["a, b"] and ["a", "b"] look different
+                        if item.is_string() {
+                            out.push_str(&format!("{:?}", item.format_with(None)));
+                        } else {
+                            item.write_value(precision, open, out);
                         }
-                        println!();
                     }
+                    out.push(']');
+                    open.pop();
                 }
-            }
+                ObjType::ObjMap(map) => {
+                    let address = Rc::as_ptr(map) as *const ();
+                    if open.contains(&address) {
+                        out.push_str("{...}");
+                        return;
+                    }
+                    open.push(address);
+                    out.push('{');
+                    for (i, (key, value)) in map.borrow().entries.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(", ");
+                        }
+                        for (j, part) in [key, value].into_iter().enumerate() {
+                            if j > 0 {
+                                out.push_str(": ");
+                            }
+                            if part.is_string() {
+                                out.push_str(&format!("{:?}", part.format_with(None)));
+                            } else {
+                                part.write_value(precision, open, out);
+                            }
+                        }
+                    }
+                    out.push('}');
+                    open.pop();
+                }
+                ObjType::ObjTuple(items) => {
+                    out.push('(');
+                    for (i, item) in items.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(", ");
+                        }
+                        if item.is_string() {
+                            out.push_str(&format!("{:?}", item.format_with(None)));
+                        } else {
+                            item.write_value(precision, open, out);
+                        }
+                    }
+                    // (1,) and not (1), which is just 1 in parentheses
+                    if items.len() == 1 {
+                        out.push(',');
+                    }
+                    out.push(')');
+                }
+            },
+        }
+    }
+
+    // name of the value's runtime type as shown to users
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Boolean(_) => "bool",
+            Value::Nil => "nil",
+            Value::Number(_) => "number",
+            Value::Int(_) => "int",
+            Value::Object(obj) => match obj.obj_type {
+                ObjType::ObjString(_) => "string",
+                ObjType::ObjNative(_)
+                | ObjType::ObjFunction(_)
+                | ObjType::ObjBoundMethod(_)
+                | ObjType::ObjBoundNative(_) => "function",
+                ObjType::ObjClass(_) => "class",
+                ObjType::ObjInstance(_) => "instance",
+                ObjType::ObjList(_) => "list",
+                ObjType::ObjMap(_) => "map",
+                ObjType::ObjRange(_) => "range",
+                ObjType::ObjTuple(_) => "tuple",
+            },
         }
     }
 
@@ -171,6 +695,23 @@ impl Value {
     }
 }
 
+// the same text print shows; a precision ({:.2}) fixes the digits
+// printed after the decimal point for numbers, like --precision
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format_with(f.precision()))
+    }
+}
+
+// Rust's float formatting is already locale independent and shortest
+// round-trip, so 0.1 + 0.2 prints as 0.30000000000000004 everywhere
+pub fn format_number(n: f64, precision: Option<usize>) -> String {
+    match precision {
+        Some(digits) if n.is_finite() => format!("{:.*}", digits, n),
+        _ => format!("{}", n),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ValueArray {
     pub values: Vec<Value>,
@@ -184,7 +725,83 @@ impl ValueArray {
     pub fn write_value_array(&mut self, value: Value) {
         self.values.push(value);
     }
-    pub fn free_value_array(&mut self) {
-        self.values.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_indices() {
+        let s = ObjString::new("héllo wörld 🐘".to_string());
+        assert_eq!(s.byte_len(), 18);
+        assert_eq!(s.char_len(), 13);
+        assert_eq!(s.char_at(1).as_deref(), Some("é"));
+        assert_eq!(s.char_at(12).as_deref(), Some("🐘"));
+        assert_eq!(s.char_at(13), None);
+        assert_eq!(s.substr_chars(6, 5), "wörld");
+        assert_eq!(s.substr_chars(10, 100), "d 🐘");
+        assert_eq!(s.substr_chars(20, 1), "");
+        assert_eq!(s.find_chars("wö"), Some(6));
+        assert_eq!(s.find_chars("🐘"), Some(12));
+        assert_eq!(s.find_chars("x"), None);
+
+        let ascii = ObjString::new("abc".to_string());
+        assert_eq!((ascii.byte_len(), ascii.char_len()), (3, 3));
+    }
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(format_number(0.1 + 0.2, None), "0.30000000000000004");
+        assert_eq!(format_number(3.0, None), "3");
+        assert_eq!(format_number(-2.5, None), "-2.5");
+        assert_eq!(format_number(0.1 + 0.2, Some(2)), "0.30");
+        assert_eq!(format_number(2.0 / 3.0, Some(4)), "0.6667");
+        assert_eq!(format_number(7.0, Some(0)), "7");
+        assert_eq!(format_number(f64::INFINITY, Some(2)), "inf");
+    }
+
+    #[test]
+    fn test_tuple_hash_matches_equality() {
+        let hash = |value: &Value| {
+            let mut hasher = fnv::FnvHasher::default();
+            value.as_obj().unwrap().obj_type.hash(&mut hasher);
+            hasher.finish()
+        };
+        let text = Value::Object(Obj {
+            obj_type: ObjType::ObjString(ObjString::new("x".to_string())),
+        });
+        let a = Value::tuple(vec![Value::Int(1), text.clone(), Value::Nil]);
+        let b = Value::tuple(vec![Value::Number(1.0), text.clone(), Value::Nil]);
+        assert!(a.values_equal(&b));
+        assert_eq!(hash(&a), hash(&b));
+
+        let c = Value::tuple(vec![Value::Number(1.5), text, Value::Nil]);
+        assert!(!a.values_equal(&c));
+        assert_ne!(hash(&a), hash(&c));
+        assert_eq!(c.format_with(Some(2)), "(1.50, \"x\", nil)");
+    }
+
+    #[test]
+    fn test_format_list() {
+        let text = Value::Object(Obj {
+            obj_type: ObjType::ObjString(ObjString::new("a, b".to_string())),
+        });
+        let inner = Value::list(vec![Value::Number(0.5), Value::Nil]);
+        let list = Value::list(vec![Value::Number(1.0), text, inner, Value::Boolean(true)]);
+        assert_eq!(list.format_with(None), "[1, \"a, b\", [0.5, nil], true]");
+        assert_eq!(
+            list.format_with(Some(2)),
+            "[1.00, \"a, b\", [0.50, nil], true]"
+        );
+        assert_eq!(list.to_string(), list.format_with(None));
+        assert_eq!(format!("{:.2}", list), list.format_with(Some(2)));
+
+        let cycle = Value::list(vec![]);
+        let items = cycle.as_list().unwrap();
+        items.borrow_mut().items.push(cycle.clone());
+        assert_eq!(cycle.format_with(None), "[[...]]");
+        // break the cycle so the test doesn't leak it
+        items.borrow_mut().items.clear();
     }
 }
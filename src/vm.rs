@@ -1,20 +1,271 @@
-use std::panic::PanicInfo;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufWriter, IsTerminal, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::{
-    compiler::Compiler,
+    chunk::{operator_method, ChunkSize, OPERATOR_METHODS},
+    compiler::{CompileError, Compiler},
+    diagnostics::{offset_of, Diagnostic, Renderer, Severity},
+    globals::Globals,
+    interner::{Interner, StringId},
+    natives, net, session,
     table::Table,
-    value::{Obj, ObjString, ObjType},
-    Chunk, OpCode, Scanner, Value,
+    value::{
+        format_number, hash_string, index_position, NativeFn, Obj, ObjBoundMethod, ObjBoundNative,
+        ObjClass, ObjFunction, ObjInstance, ObjNative, ObjRange, ObjString, ObjType,
+    },
+    Chunk, OpCode, Value,
 };
 
-const STACK_SIZE: u16 = 256;
+// deepest nesting of calls before a runaway recursion is stopped
+const FRAMES_MAX: usize = 64;
+// most values the stack holds, room for FRAMES_MAX calls of 256 slots
+// each as in clox. Both are the defaults, see VM::init_vm_with_limits
+const STACK_SIZE: usize = FRAMES_MAX * 256;
+
+// standard definitions every VM starts with, written in elephant itself
+const PRELUDE: &str = include_str!("prelude.elx");
 
 pub struct VM {
-    chunk: Chunk,
+    // the chunk and position of the function that is running, and where
+    // its slots start on the stack. Callers wait in `frames`
+    chunk: Rc<Chunk>,
     ip: usize, // current instruction pointer
+    slot_base: usize,
+    // the function that chunk belongs to, None in top-level code
+    function: Option<Rc<ObjFunction>>,
+    frames: Vec<CallFrame>,
+    // the try blocks being run, innermost last
+    handlers: Vec<ExceptionHandler>,
+    // a runtime error was caught by one of them, see execute()
+    caught: bool,
     stack: Vec<Value>,
-    strings: Table,
-    globals: Table,
+    // pop() found the stack empty, the instruction doing it faults once
+    // it's done, see dispatch
+    underflow: bool,
+    // every interned string, and the handles tables key on
+    strings: Interner,
+    globals: Globals,
+    // the native methods of built-in types, by type name: "string"
+    // has upper, split and so on
+    builtin_methods: HashMap<&'static str, Table>,
+    // globals declared somewhere in the running program whose `var`
+    // statement hasn't executed yet
+    hoisted_globals: HashSet<ObjType>,
+    // print how long each phase of interpret() took
+    pub time_passes: bool,
+    // what time_passes printed for the last program
+    pub pass_times: Vec<(&'static str, Duration)>,
+    // digits after the decimal point when printing numbers,
+    // None for the shortest round-trip form
+    pub number_precision: Option<usize>,
+    // list the constant pool of each compiled program before running it
+    pub dump_constants: bool,
+    // let the compiler optimize the bytecode it produces
+    pub optimize: bool,
+    // the fault that ended the last run, if it was one
+    pub fault: Option<VmFault>,
+    // the errors the last interpret() or eval_expression() stopped
+    // with, for hosts that handle them themselves. Errors a try block
+    // caught aren't kept
+    pub compile_errors: Vec<CompileError>,
+    pub runtime_error: Option<RuntimeError>,
+    // print errors as they are reported, off for hosts that only look
+    // at compile_errors and runtime_error
+    pub print_errors: bool,
+    // what the compiler warned about in the last program, see
+    // Compiler::warnings. Shown with show_warnings, and deny_warnings
+    // makes them errors that stop the program from running
+    pub compile_warnings: Vec<Diagnostic>,
+    pub show_warnings: bool,
+    pub deny_warnings: bool,
+    // compile every program in strict mode, as if it started with
+    // "use strict";. Warnings are errors and so is assigning a global
+    // that is never declared
+    pub strict: bool,
+    // set when the last run ended by calling exit(), to the code the
+    // process should exit with
+    pub exit_code: Option<i32>,
+    // compiled regex_* patterns, so a pattern used in a loop is only
+    // compiled once
+    pub regex_cache: HashMap<String, regex::Regex>,
+    // connections tcp_connect() opened and tcp_close() hasn't closed, by
+    // the number the script holds for each
+    pub connections: HashMap<i64, net::Connection>,
+    pub next_connection: i64,
+    // functions the script registered with test(), by name, in the order
+    // it did, for run_tests()
+    pub tests: Vec<(String, Value)>,
+    // state of the generator behind random(). Seeded from the clock, see
+    // seed_random() for repeatable runs
+    pub random_state: u64,
+    // most instructions a single run may execute before it is stopped
+    // with a runtime error. None means no limit
+    pub instruction_budget: Option<usize>,
+    // when the VM was created, clock() counts from here
+    pub start_time: Instant,
+    // print the interning statistics after each program
+    pub dump_interns: bool,
+    // make arithmetic that gives NaN or overflows a runtime error
+    pub checked_arithmetic: bool,
+    // print the bytecode size of each chunk and function before running
+    pub size_report: bool,
+    // where print writes. Buffered instead of a write per value; flushed
+    // when a run ends and before any diagnostic, so the program's output
+    // and the errors come out in the order they happened
+    output: BufWriter<Box<dyn Write>>,
+    // flush after each line print writes. Set when the output is a
+    // terminal, so someone watching sees every line as it is printed
+    line_buffered: bool,
+    // where input() reads lines from; None reads stdin, sharing its
+    // buffer with the REPL
+    input: Option<Box<dyn BufRead>>,
+    // intern_string calls that found the string already interned,
+    // and those that had to add it
+    intern_hits: usize,
+    intern_misses: usize,
+    // some class defines an operator method, so operators have to check
+    // for instance operands
+    operator_methods: bool,
+    // globals declared with `const` so far, so later programs can't
+    // assign them either
+    constant_globals: HashSet<ObjType>,
+    // the global slots functions assign, see Compiler::function_writes
+    function_writes: HashSet<usize>,
+    // past these many values on the stack or calls in progress, running
+    // stops with a "Stack overflow." runtime error
+    stack_size: usize,
+    frames_max: usize,
+}
+
+// a caller waiting for the function it called to return
+struct CallFrame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    slot_base: usize,
+    function: Option<Rc<ObjFunction>>,
+}
+
+// a try block being run. A throw goes back to the frame and stack
+// height it started at and carries on at its catch block
+struct ExceptionHandler {
+    frame_count: usize,
+    stack_len: usize,
+    catch_ip: usize,
+}
+
+// where in the source the VM is, see VM::current_location()
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: i32,
+    // None for code that wasn't compiled from source, like a chunk
+    // put together by hand
+    pub column: Option<usize>,
+    // the running function, None in top-level code
+    pub function: Option<String>,
+}
+
+// how the string intern table is doing, see VM::intern_stats()
+#[derive(Debug, Clone, PartialEq)]
+pub struct InternStats {
+    pub strings: usize,
+    // total length of the interned strings in bytes
+    pub bytes: usize,
+    pub hits: usize,
+    pub misses: usize,
+    // the longest interned strings, longest first
+    pub largest: Vec<String>,
+}
+
+impl InternStats {
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+// The bytecode itself is broken: an unknown opcode or an operand that
+// doesn't fit the instruction. That is a bug in whatever produced the
+// chunk rather than in the user's program, so it carries enough to
+// debug the chunk: where it happened, the opcode byte and the bytes
+// around it. Reported as an InterpretRuntimeError so a bad chunk never
+// takes the host process down
+#[derive(Debug, Clone)]
+pub struct VmFault {
+    pub ip: usize,
+    pub opcode: u8,
+    pub message: String,
+    pub context: String,
+}
+
+// an error in the user's program that ended the run, see
+// VM::runtime_error
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    // None if nothing had run yet
+    pub location: Option<SourceLocation>,
+    // the text of the file it happened in, if the chunk has it
+    pub source: Option<Rc<str>>,
+    // the calls that led to it, innermost first: where each caller was
+    // when it made the call
+    pub trace: Vec<SourceLocation>,
+}
+
+// a stack overflow has FRAMES_MAX calls, only the innermost are shown
+const TRACE_SHOWN: usize = 16;
+
+impl RuntimeError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        let diagnostic = Diagnostic::error(&self.message);
+        let Some(location) = &self.location else {
+            return diagnostic;
+        };
+        let diagnostic = diagnostic
+            .at(&location.file, location.line)
+            .at_column(location.column.unwrap_or(0));
+        // a caret under where the failing instruction was compiled from
+        let offset = self
+            .source
+            .as_deref()
+            .zip(location.column)
+            .and_then(|(source, column)| offset_of(source, location.line, column));
+        let mut diagnostic = match offset {
+            Some(offset) => diagnostic.with_label(offset, offset, ""),
+            None => diagnostic,
+        };
+        if let Some(function) = &location.function {
+            diagnostic = diagnostic.with_note(&format!("in {}()", function));
+        }
+        for caller in self.trace.iter().take(TRACE_SHOWN) {
+            let function = match &caller.function {
+                Some(function) => format!("{}()", function),
+                None => "<script>".to_string(),
+            };
+            let mut position = format!("{}:{}", caller.file, caller.line);
+            if let Some(column) = caller.column {
+                position += &format!(":{}", column);
+            }
+            diagnostic = diagnostic.with_note(&format!("called from {} at {}", function, position));
+        }
+        if self.trace.len() > TRACE_SHOWN {
+            diagnostic = diagnostic.with_note(&format!(
+                "... and {} more calls",
+                self.trace.len() - TRACE_SHOWN
+            ));
+        }
+        diagnostic
+    }
+
+    pub fn render(&self, renderer: Renderer) -> String {
+        renderer.render(&self.diagnostic(), self.source.as_deref())
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -24,45 +275,446 @@ pub enum InterpretResult {
     InterpretRuntimeError,
 }
 
+// why eval_expression() has no value to give back. The error itself
+// has been reported like any other, and is in compile_errors or
+// runtime_error
+#[derive(PartialEq, Debug)]
+pub enum EvalError {
+    CompileError,
+    RuntimeError,
+}
+
 impl VM {
     pub fn init_vm() -> VM {
-        VM {
-            chunk: Chunk::init_chunk(),
+        VM::init_vm_with_limits(STACK_SIZE, FRAMES_MAX)
+    }
+
+    // a VM whose stack holds at most `stack_size` values and that runs at
+    // most `frames_max` nested calls, for hosts that want deeper
+    // recursion or a tighter bound on memory
+    pub fn init_vm_with_limits(stack_size: usize, frames_max: usize) -> VM {
+        let mut vm = VM {
+            chunk: Rc::new(Chunk::init_chunk()),
             ip: 0,
-            stack: Vec::with_capacity(STACK_SIZE as usize),
-            strings: Table::init_table(),
-            globals: Table::init_table(),
-        }
+            slot_base: 0,
+            function: None,
+            frames: vec![],
+            handlers: vec![],
+            caught: false,
+            underflow: false,
+            // most programs never get past this, the stack grows up to
+            // stack_size if it has to
+            stack: Vec::with_capacity(stack_size.min(256)),
+            strings: Interner::default(),
+            globals: Globals::default(),
+            builtin_methods: HashMap::new(),
+            hoisted_globals: HashSet::new(),
+            time_passes: false,
+            pass_times: vec![],
+            number_precision: None,
+            dump_constants: false,
+            optimize: false,
+            fault: None,
+            compile_errors: vec![],
+            runtime_error: None,
+            print_errors: true,
+            compile_warnings: vec![],
+            show_warnings: false,
+            deny_warnings: false,
+            strict: false,
+            exit_code: None,
+            random_state: 0,
+            regex_cache: HashMap::new(),
+            connections: HashMap::new(),
+            next_connection: 1,
+            tests: vec![],
+            instruction_budget: None,
+            start_time: Instant::now(),
+            dump_interns: false,
+            checked_arithmetic: false,
+            size_report: false,
+            output: BufWriter::new(Box::new(io::stdout())),
+            line_buffered: io::stdout().is_terminal(),
+            input: None,
+            intern_hits: 0,
+            intern_misses: 0,
+            operator_methods: false,
+            constant_globals: HashSet::new(),
+            function_writes: HashSet::new(),
+            stack_size,
+            frames_max,
+        };
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        vm.seed_random(nanos);
+        natives::define_natives(&mut vm);
+        vm
+    }
+
+    // random() and random_int() give the same numbers every run after
+    // the same seed, what random_seed() calls
+    pub fn seed_random(&mut self, seed: u64) {
+        // xorshift gets stuck at 0, the low bit keeps the state off it
+        self.random_state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    }
+
+    // expose a Rust function to scripts as the global `name`. `arity`
+    // None lets the native check its arguments itself
+    pub fn define_native(&mut self, name: &str, arity: Option<usize>, function: NativeFn) {
+        let native = Value::Object(Obj {
+            obj_type: ObjType::ObjNative(ObjNative {
+                name: name.to_string(),
+                arity,
+                function,
+            }),
+        });
+        self.globals
+            .define_named(ObjType::ObjString(ObjString::new(name.to_string())), native);
+    }
+
+    // a method callable on every value of a built-in type, `value.name()`.
+    // `arity` doesn't count the receiver, which the native gets as its
+    // first argument
+    pub fn define_method(
+        &mut self,
+        type_name: &'static str,
+        name: &str,
+        arity: Option<usize>,
+        function: NativeFn,
+    ) {
+        let native = Value::Object(Obj {
+            obj_type: ObjType::ObjNative(ObjNative {
+                name: name.to_string(),
+                arity,
+                function,
+            }),
+        });
+        let name = self.intern_name(name);
+        self.builtin_methods
+            .entry(type_name)
+            .or_insert_with(Table::init_table)
+            .table_set(name, native);
+    }
+
+    // define or overwrite the global `name`, for hosts passing values in
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals
+            .define_named(ObjType::ObjString(ObjString::new(name.to_string())), value);
+    }
+
+    // the value of the global `name`, None if it isn't defined
+    #[cfg(test)]
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals
+            .get_named(&ObjType::ObjString(ObjString::new(name.to_string())))
+    }
+
+    // the command-line arguments given after the script, as the global
+    // list `args` of strings
+    pub fn set_args(&mut self, args: &[String]) {
+        let items = args
+            .iter()
+            .map(|arg| self.intern_string(arg.clone()))
+            .collect();
+        self.set_global("args", Value::list(items));
     }
 
     pub fn free_vm(&mut self) {
         self.reset_stack();
-        self.strings.free_table();
+        self.strings.free_strings();
     }
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
+        self.interpret_file(source, "script")
+    }
+
+    // same as interpret() but errors name `file` as the origin of the code
+    pub fn interpret_file(&mut self, source: &str, file: &str) -> InterpretResult {
+        self.compile_errors.clear();
+        self.compile_warnings.clear();
+        self.runtime_error = None;
+        let compile_start = Instant::now();
         let mut compiler = Compiler::new(source);
-        self.chunk = Chunk::init_chunk();
+        compiler.optimize = self.optimize;
+        compiler.strict = self.strict;
+        compiler.operator_methods = self.operator_methods;
+        compiler.constant_globals = self.constant_globals.clone();
+        compiler.function_writes = self.function_writes.clone();
+        compiler.known_globals = self
+            .globals
+            .defined()
+            .map(|(name, _)| name.clone())
+            .collect();
+        compiler.global_names = std::mem::take(&mut self.globals.names);
+        compiler.strings = std::mem::take(&mut self.strings);
+        let mut chunk = Chunk::init_chunk();
+        chunk.begin_file(file);
 
         // we pass empty chunk to compiler
-        // which should fill it with a bytecode
-        if !compiler.compile(source, &self.chunk) {
+        // which should fill it with a bytecode. Long scripts come out
+        // as several chunks, see Compiler::top_level_declaration
+        let compiled = compiler.compile(chunk);
+        self.globals.names = std::mem::take(&mut compiler.global_names);
+        self.strings = std::mem::take(&mut compiler.strings);
+        self.function_writes = std::mem::take(&mut compiler.function_writes);
+        let Some(chunks) = compiled else {
+            self.report_compile_errors(compiler.errors, source);
             return InterpretResult::InterpretCompileError;
         };
+        self.constant_globals = std::mem::take(&mut compiler.constant_globals);
+        self.compile_warnings = std::mem::take(&mut compiler.warnings);
+        if self.report_warnings(source, compiler.strict) {
+            return InterpretResult::InterpretCompileError;
+        }
+        let compile_time = compile_start.elapsed();
 
-        self.chunk = compiler.compiling_chunk;
-        self.ip = 0;
-        let result: InterpretResult = self.run();
+        let part_name = |i: usize| match chunks.len() {
+            1 => file.to_string(),
+            n => format!("{} (part {}/{})", file, i + 1, n),
+        };
+        if self.size_report {
+            let mut sizes = vec![];
+            for (i, chunk) in chunks.iter().enumerate() {
+                chunk.sizes(&part_name(i), &mut sizes);
+            }
+            print_size_report(&sizes);
+        }
+        if self.dump_constants {
+            for (i, chunk) in chunks.iter().enumerate() {
+                chunk.dump_constants(&part_name(i));
+            }
+        }
+        self.hoisted_globals = compiler.declared_globals.into_iter().collect();
+        let run_start = Instant::now();
+        let mut result = InterpretResult::InterpretOk;
+        for chunk in chunks {
+            self.chunk = Rc::new(chunk);
+            self.ip = 0;
+            self.fault = None;
+            result = self.run();
+            if result != InterpretResult::InterpretOk || self.exit_code.is_some() {
+                break;
+            }
+        }
+        let run_time = run_start.elapsed();
+        // declarations only count for the program that made them
+        self.hoisted_globals.clear();
+
+        if self.time_passes {
+            self.pass_times = vec![
+                ("scan", compiler.scan_time),
+                ("parse/codegen", compile_time.saturating_sub(compiler.scan_time)),
+                ("run", run_time),
+            ];
+            eprint!("{}", pass_times_report(&self.pass_times));
+        }
+        if self.dump_interns {
+            print_intern_stats(&self.intern_stats(5));
+        }
 
         return result;
     }
 
+    // Run `source` in a namespace of its own, for snippets a host doesn't
+    // trust. The snippet sees the current globals, but the globals it
+    // defines or assigns are taken out again afterwards and returned,
+    // so the shared table is left as it was. Objects stay shared: a
+    // field the snippet sets on an instance that was already global
+    // stays set
+    pub fn interpret_isolated(&mut self, source: &str) -> (InterpretResult, Vec<(ObjType, Value)>) {
+        let saved = self.globals.clone();
+        let saved_constants = self.constant_globals.clone();
+        let result = self.interpret(source);
+        let after = self.globals();
+        self.globals.restore(saved);
+        self.constant_globals = saved_constants;
+
+        let defined = after
+            .into_iter()
+            .filter(|(name, value)| {
+                !self
+                    .globals
+                    .get_named(name)
+                    .is_some_and(|before| before.values_equal(value))
+            })
+            .collect();
+        (result, defined)
+    }
+
+    // compile `source` against this VM's global names without running
+    // it, for code that is put together first, e.g. by the Linker. The
+    // chunks only run on this VM, see globals.rs
+    pub fn compile(&mut self, source: &str, file: &str) -> Option<Vec<Chunk>> {
+        let mut compiler = Compiler::new(source);
+        compiler.optimize = self.optimize;
+        compiler.operator_methods = self.operator_methods;
+        compiler.constant_globals = self.constant_globals.clone();
+        compiler.function_writes = self.function_writes.clone();
+        compiler.late_functions = true;
+        compiler.global_names = std::mem::take(&mut self.globals.names);
+        compiler.strings = std::mem::take(&mut self.strings);
+        let mut chunk = Chunk::init_chunk();
+        chunk.begin_file(file);
+        let compiled = compiler.compile(chunk);
+        self.globals.names = std::mem::take(&mut compiler.global_names);
+        self.strings = std::mem::take(&mut compiler.strings);
+        self.function_writes = std::mem::take(&mut compiler.function_writes);
+        if compiled.is_none() {
+            self.report_compile_errors(compiler.errors, source);
+        }
+        compiled
+    }
+
+    // run an already compiled chunk, e.g. one put together by the Linker
+    pub fn interpret_chunk(&mut self, chunk: Chunk) -> InterpretResult {
+        self.chunk = Rc::new(chunk);
+        self.ip = 0;
+        self.fault = None;
+        self.run()
+    }
+
+    // Run the functions registered with test() so far, each on its own,
+    // and write a line per test and a summary the way `cargo test` does.
+    // A test passes when its function returns and fails with a runtime
+    // error, which is reported as usual. Returns how many passed and how
+    // many failed. exit() in a test ends the run with the rest unrun
+    pub fn run_tests(&mut self) -> (usize, usize) {
+        let tests = std::mem::take(&mut self.tests);
+        self.write_text(&format!("running {} tests\n", tests.len()));
+        let (mut passed, mut failed) = (0, 0);
+        for (name, function) in tests {
+            // name() and a call with no arguments, as the call would
+            // compile
+            let mut chunk = Chunk::init_chunk();
+            chunk.begin_file(&format!("test {}", name));
+            let constant = chunk.add_constant(function);
+            chunk.write_chunk(OpCode::OP_CONSTANT as u8, 0);
+            chunk.write_chunk(constant as u8, 0);
+            chunk.write_chunk(OpCode::OP_CALL as u8, 0);
+            chunk.write_chunk(0, 0);
+            chunk.write_chunk(OpCode::OP_POP as u8, 0);
+            let result = self.interpret_chunk(chunk);
+            if self.exit_code.is_some() {
+                return (passed, failed);
+            }
+            if result == InterpretResult::InterpretOk {
+                passed += 1;
+                self.write_text(&format!("test {} ... ok\n", name));
+            } else {
+                failed += 1;
+                self.write_text(&format!("test {} ... FAILED\n", name));
+            }
+        }
+        let summary = Renderer::stdout().summary(passed, failed);
+        self.write_text(&format!("\ntest {}\n", summary));
+        self.flush_output();
+        (passed, failed)
+    }
+
+    // Compile and run `source` as a single expression, without a
+    // trailing ';', and return its value. Meant for hosts that evaluate
+    // formulas or config values written by users, e.g. "width * 2 + 1"
+    // after defining `width` with set_global(). The expression sees and
+    // can change the VM's globals
+    pub fn eval_expression(&mut self, source: &str) -> Result<Value, EvalError> {
+        self.compile_errors.clear();
+        self.runtime_error = None;
+        let mut compiler = Compiler::new(source);
+        compiler.operator_methods = self.operator_methods;
+        compiler.constant_globals = self.constant_globals.clone();
+        compiler.function_writes = self.function_writes.clone();
+        compiler.global_names = std::mem::take(&mut self.globals.names);
+        compiler.strings = std::mem::take(&mut self.strings);
+        let compiled = compiler.compile_expression();
+        self.globals.names = std::mem::take(&mut compiler.global_names);
+        self.strings = std::mem::take(&mut compiler.strings);
+        self.function_writes = std::mem::take(&mut compiler.function_writes);
+        let Some(chunk) = compiled else {
+            self.report_compile_errors(compiler.errors, source);
+            return Err(EvalError::CompileError);
+        };
+
+        self.chunk = Rc::new(chunk);
+        self.ip = 0;
+        self.fault = None;
+        if self.run() != InterpretResult::InterpretOk {
+            return Err(EvalError::RuntimeError);
+        }
+        self.stack.pop().ok_or(EvalError::RuntimeError)
+    }
+
+    // eval_expression() for callers that only care whether there is a value
+    pub fn evaluate(&mut self, source: &str) -> Option<Value> {
+        self.eval_expression(source).ok()
+    }
+
+    // compile `source` as a lone expression and print its bytecode
+    // without running it
+    pub fn disassemble_expression(&self, source: &str) -> bool {
+        let mut compiler = Compiler::new(source);
+        compiler.global_names = self.globals.names.clone();
+        let Some(chunk) = compiler.compile_expression() else {
+            if self.print_errors {
+                for error in &compiler.errors {
+                    eprint!(
+                        "{}",
+                        Renderer::stderr().render(&error.diagnostic(), Some(source))
+                    );
+                }
+            }
+            return false;
+        };
+        chunk.disassemble_chunk(source);
+        true
+    }
+
+    pub fn load_prelude(&mut self) -> InterpretResult {
+        self.interpret_file(PRELUDE, "prelude")
+    }
+
+    // write all globals to `path`, see session.rs for the format.
+    // Returns how many were saved and a line for each one left out
+    pub fn save_session(&self, path: &str) -> std::io::Result<(usize, Vec<String>)> {
+        session::save_globals(&self.globals(), &self.globals.names, path)
+    }
+
+    // define the globals saved in `path`, replacing ones with the same name
+    pub fn load_session(&mut self, path: &str) -> std::io::Result<usize> {
+        let loaded = session::load_globals(path, &mut self.globals.names)?;
+        self.function_writes.extend(loaded.function_writes);
+        let count = loaded.globals.len();
+        for (name, value) in loaded.globals {
+            self.globals.define_named(name, value);
+        }
+        Ok(count)
+    }
+
+    // the defined globals by name, in the order they were defined
+    pub fn globals(&self) -> Vec<(ObjType, Value)> {
+        self.globals
+            .defined()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
     pub fn push(&mut self, value: Value) {
         self.stack.push(value);
     }
+    // run() checks each instruction has the values it takes, see
+    // instruction_fits, so an empty stack here is a bug in the VM or a
+    // chunk it can't check. nil stands in for the missing value until
+    // the instruction is done and faults
     pub fn pop(&mut self) -> Value {
-        return self.stack.pop().unwrap();
+        match self.stack.pop() {
+            Some(value) => value,
+            None => {
+                self.underflow = true;
+                Value::Nil
+            }
+        }
     }
 
+    #[cfg(feature = "debug_trace_execution")]
     pub fn print_stack(&self) {
         println!("Values in the stack from stack top to bottom");
         for value in &self.stack {
@@ -70,37 +722,122 @@ impl VM {
         }
     }
 
+    // a string made at runtime, e.g. by + or a native. One the pool
+    // already has is shared, found by its text so a hit doesn't
+    // allocate. Any other is not added: the pool is never emptied, and
+    // strings are compared by their text, so only names that tables
+    // key on need a handle, see name_id
     pub fn intern_string(&mut self, string: String) -> Value {
-        // Create a new ObjString
-        let obj_string = ObjString::new(string);
+        let string = match self.strings.find(&string, hash_string(&string)) {
+            Some(id) => {
+                self.intern_hits += 1;
+                self.strings.get(id).clone()
+            }
+            None => {
+                self.intern_misses += 1;
+                ObjString::new(string)
+            }
+        };
+        Value::Object(Obj {
+            obj_type: ObjType::ObjString(string),
+        })
+    }
+
+    // the handle tables know `name` by, for natives and hosts. Names
+    // are interned too, but don't count towards intern_stats
+    pub fn intern_name(&mut self, name: &str) -> StringId {
+        self.strings.intern_str(name)
+    }
 
-        // Check if we already have this string
-        if let Some(existing_value) = self
+    // the handle of `name` if it was ever interned. A name that wasn't
+    // is in no table
+    pub fn string_id(&self, name: &str) -> Option<StringId> {
+        self.strings.find(name, hash_string(name))
+    }
+
+    pub fn string(&self, id: StringId) -> &ObjString {
+        self.strings.get(id)
+    }
+
+    // helper to read chunk's constant string
+    // `largest` is how many of the longest strings to include
+    pub fn intern_stats(&self, largest: usize) -> InternStats {
+        let mut strings: Vec<&str> = self
             .strings
-            .table_get(&ObjType::ObjString(obj_string.clone()))
-        {
-            return existing_value;
+            .strings()
+            .iter()
+            .map(ObjString::as_str)
+            .collect();
+        let bytes = strings.iter().map(|s| s.len()).sum();
+        // stable, so strings of the same length stay in interning order
+        strings.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        InternStats {
+            strings: strings.len(),
+            bytes,
+            hits: self.intern_hits,
+            misses: self.intern_misses,
+            largest: strings
+                .into_iter()
+                .take(largest)
+                .map(str::to_string)
+                .collect(),
         }
+    }
 
-        // If not found, create new string object and store it
-        let value = Value::Object(Obj {
-            obj_type: ObjType::ObjString(obj_string.clone()),
-        });
+    // the two byte operand at ip, of a jump or a long local. Only the
+    // operand is 16 bits, the ip a jump adds it to or takes it from is a
+    // usize, so code past the first 64KB of a chunk jumps correctly too
+    fn read_long_operand(&self) -> usize {
+        let high = self.chunk.code[self.ip] as usize;
+        let low = self.chunk.code[self.ip + 1] as usize;
+        (high << 8) | low
+    }
 
-        // Store in the strings table
-        self.strings
-            .table_set(ObjType::ObjString(obj_string), value.clone());
+    pub fn read_string(&self) -> Option<ObjType> {
+        let constant_index = *self.chunk.code.get(self.ip)?;
+        match self.chunk.constants.values.get(constant_index as usize) {
+            Some(Value::Object(obj)) if obj.obj_type.is_string() => Some(obj.obj_type.clone()),
+            _ => None,
+        }
+    }
 
-        value
+    // the handle of the name operand of the instruction at `offset`,
+    // interned the first time the instruction runs and kept in its
+    // inline cache after that
+    fn name_id(&mut self, offset: usize, name: &ObjType) -> StringId {
+        let ObjType::ObjString(string) = name else {
+            unreachable!("{:?} is not a string", name)
+        };
+        let cache = self.chunk.cache(offset);
+        if let Some(id) = cache.and_then(|cache| cache.name.get()) {
+            return id;
+        }
+        let id = self.strings.intern(string);
+        if let Some(cache) = cache {
+            cache.name.set(Some(id));
+        }
+        id
     }
-    // helper to read chunk's constant string
-    pub fn read_string(&self) -> ObjType {
-        let constant_index = self.chunk.code[self.ip as usize];
-        if let Value::Object(obj) = &self.chunk.constants.values[constant_index as usize] {
-            obj.obj_type.clone()
-        } else {
-            panic!("Expected string constant");
+
+    // where the field `name` is in `fields`, trying the position the
+    // instruction's inline cache remembers before hashing the name
+    fn field_position(&self, fields: &Table, offset: usize, name: StringId) -> Option<usize> {
+        let cache = self.chunk.cache(offset);
+        if let Some(cache) = cache {
+            let cached = cache.position.get();
+            if fields
+                .entries
+                .get(cached)
+                .is_some_and(|entry| entry.key == name)
+            {
+                return Some(cached);
+            }
+        }
+        let position = fields.position(name)?;
+        if let Some(cache) = cache {
+            cache.position.set(position);
         }
+        Some(position)
     }
 
     pub fn concatenate(&mut self) -> InterpretResult {
@@ -128,26 +865,35 @@ impl VM {
             return InterpretResult::InterpretRuntimeError;
         }
 
+        // two ints stay ints, except that division always gives a float
+        if let (Some(a), Some(b)) = (self.peek(1).as_int(), self.peek(0).as_int()) {
+            if op != "/" {
+                self.pop();
+                self.pop();
+                return self.push_int_arithmetic(op, a, b);
+            }
+        }
+
         match op {
             "+" => {
                 let b = self.pop().as_number().unwrap();
                 let a = self.pop().as_number().unwrap();
-                self.push(Value::Number(a + b));
+                return self.push_arithmetic(op, a, b, a + b);
             }
             "-" => {
                 let b = self.pop().as_number().unwrap();
                 let a = self.pop().as_number().unwrap();
-                self.push(Value::Number(a - b));
+                return self.push_arithmetic(op, a, b, a - b);
             }
             "*" => {
                 let b = self.pop().as_number().unwrap();
                 let a = self.pop().as_number().unwrap();
-                self.push(Value::Number(a * b));
+                return self.push_arithmetic(op, a, b, a * b);
             }
             "/" => {
                 let b = self.pop().as_number().unwrap();
                 let a = self.pop().as_number().unwrap();
-                self.push(Value::Number(a / b));
+                return self.push_arithmetic(op, a, b, a / b);
             }
             ">" => {
                 let b = self.pop().as_number().unwrap();
@@ -159,51 +905,320 @@ impl VM {
                 let a = self.pop().as_number().unwrap();
                 self.push(Value::Boolean(a < b));
             }
+            _ => {
+                self.runtime_error(&format!("Unknown binary operation '{}'.", op));
+                return InterpretResult::InterpretRuntimeError;
+            }
+        }
+        InterpretResult::InterpretOk
+    }
+    // An int result that doesn't fit in 64 bits. With checked_arithmetic
+    // on that is an error, like a float overflow; otherwise the operation
+    // is done again on floats, as if one operand had been a float
+    fn push_int_arithmetic(&mut self, op: &str, a: i64, b: i64) -> InterpretResult {
+        let result = match op {
+            "+" => a.checked_add(b),
+            "-" => a.checked_sub(b),
+            "*" => a.checked_mul(b),
+            ">" => {
+                self.push(Value::Boolean(a > b));
+                return InterpretResult::InterpretOk;
+            }
+            "<" => {
+                self.push(Value::Boolean(a < b));
+                return InterpretResult::InterpretOk;
+            }
+            _ => {
+                self.runtime_error(&format!("Unknown binary operation '{}'.", op));
+                return InterpretResult::InterpretRuntimeError;
+            }
+        };
+        if let Some(result) = result {
+            self.push(Value::Int(result));
+            return InterpretResult::InterpretOk;
+        }
+        if self.checked_arithmetic {
+            self.runtime_error(&format!("Integer overflow in {} {} {}.", a, op, b));
+            return InterpretResult::InterpretRuntimeError;
+        }
+        let (a, b) = (a as f64, b as f64);
+        let result = match op {
+            "+" => a + b,
+            "-" => a - b,
+            _ => a * b,
+        };
+        self.push(Value::Number(result));
+        InterpretResult::InterpretOk
+    }
+
+    // The bitwise operators work on 64-bit integers and give an int. A
+    // float is cut down to one the way a Rust `as` cast does: the
+    // fraction is dropped, numbers too big for 64 bits become the largest
+    // (or smallest) one and NaN becomes 0
+    fn bitwise_op(&mut self, op: OpCode) -> InterpretResult {
+        let (Some(a), Some(b)) = (to_integer(self.peek(1)), to_integer(self.peek(0))) else {
+            self.runtime_error("Operands must be numbers.");
+            return InterpretResult::InterpretRuntimeError;
+        };
+        let is_shift = matches!(op, OpCode::OP_SHIFT_LEFT | OpCode::OP_SHIFT_RIGHT);
+        if is_shift && !(0..64).contains(&b) {
+            self.runtime_error(&format!(
+                "Shift amount must be between 0 and 63, not {}.",
+                b
+            ));
+            return InterpretResult::InterpretRuntimeError;
+        }
+        let result = match op {
+            OpCode::OP_BIT_AND => a & b,
+            OpCode::OP_BIT_OR => a | b,
+            OpCode::OP_BIT_XOR => a ^ b,
+            OpCode::OP_SHIFT_LEFT => a << b,
+            _ => a >> b,
+        };
+        self.pop();
+        self.pop();
+        self.push(Value::Int(result));
+        InterpretResult::InterpretOk
+    }
 
-            _ => println!("unknown binary operation"),
+    // With checked_arithmetic on, an operation on ordinary numbers that
+    // gives NaN or overflows to infinity is a runtime error rather than
+    // a value that quietly spreads through the rest of the program. NaN
+    // and infinite operands still go through: the problem started where
+    // they were made, and that is where checked mode reports it
+    fn push_arithmetic(&mut self, op: &str, a: f64, b: f64, result: f64) -> InterpretResult {
+        if self.checked_arithmetic && a.is_finite() && b.is_finite() && !result.is_finite() {
+            let problem = if op == "/" && b == 0.0 {
+                "Division by zero"
+            } else if result.is_nan() {
+                "Arithmetic produced NaN"
+            } else {
+                "Arithmetic overflow"
+            };
+            self.runtime_error(&format!(
+                "{} in {} {} {}.",
+                problem,
+                format_number(a, None),
+                op,
+                format_number(b, None)
+            ));
+            return InterpretResult::InterpretRuntimeError;
         }
+        self.push(Value::Number(result));
         InterpretResult::InterpretOk
     }
+
+    // send program output somewhere other than stdout, e.g. into a
+    // buffer when embedding. Whatever is still buffered goes to the old
+    // one first
+    #[cfg(test)]
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.flush_output();
+        self.output = BufWriter::new(output);
+        self.line_buffered = false;
+    }
+
+    // read input() lines from somewhere other than stdin
+    #[cfg(test)]
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = Some(input);
+    }
+
+    // for input(): writes the prompt where print writes and flushes it,
+    // so it shows before waiting, then reads a line without its line
+    // ending. None once the input has run out
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        let _ = write!(self.output, "{}", prompt);
+        self.flush_output();
+        let mut line = String::new();
+        let read = match &mut self.input {
+            Some(input) => input.read_line(&mut line)?,
+            None => io::stdin().read_line(&mut line)?,
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    pub fn flush_output(&mut self) {
+        // nowhere to report a closed stdout to, the program goes on
+        let _ = self.output.flush();
+    }
+
+    // what `print` shows for a value, then a newline
+    fn print(&mut self, value: &Value) {
+        self.write(value);
+        let _ = writeln!(self.output);
+        if self.line_buffered {
+            self.flush_output();
+        }
+    }
+
+    // the value's text without a newline, for the write() native, so
+    // a line can be built from several writes
+    pub fn write(&mut self, value: &Value) {
+        let text = value.format_with(self.number_precision);
+        self.write_text(&text);
+    }
+
+    pub fn write_text(&mut self, text: &str) {
+        let _ = write!(self.output, "{}", text);
+    }
+
     pub fn run(&mut self) -> InterpretResult {
+        let result = self.execute();
+        self.flush_output();
+        result
+    }
+
+    fn execute(&mut self) -> InterpretResult {
+        // every run starts in top-level code
+        self.function = None;
+        self.runtime_error = None;
+        self.exit_code = None;
+        let mut fuel = self.instruction_budget;
+        loop {
+            let result = self.dispatch(&mut fuel);
+            // the error happened inside a try block and has already
+            // unwound to its catch block, carry on from there
+            if result == InterpretResult::InterpretRuntimeError && std::mem::take(&mut self.caught)
+            {
+                continue;
+            }
+            return result;
+        }
+    }
+
+    fn dispatch(&mut self, fuel: &mut Option<usize>) -> InterpretResult {
+        // where the instruction being executed starts
+        let mut current = self.ip;
         loop {
+            // the last instruction popped more than there was
+            if std::mem::take(&mut self.underflow) {
+                self.ip = current + 1;
+                return self.fault("Stack underflow: popped an empty stack.");
+            }
+
             // First check if we have any instructions to execute
-            if self.ip as usize >= self.chunk.code.len() {
+            if self.ip >= self.chunk.code.len() {
                 return InterpretResult::InterpretOk;
             }
 
-            //self.print_stack();
-            let instruction = self.chunk.code[self.ip as usize];
+            if let Some(remaining) = fuel.as_mut() {
+                if *remaining == 0 {
+                    // report it at the instruction we didn't get to run.
+                    // A try block can't catch it, or it could keep going
+                    self.ip += 1;
+                    self.report_error(&format!(
+                        "Stopped after {} instructions, the evaluation budget ran out.",
+                        self.instruction_budget.unwrap_or(0)
+                    ));
+                    return InterpretResult::InterpretRuntimeError;
+                }
+                *remaining -= 1;
+            }
+
+            #[cfg(feature = "debug_trace_execution")]
+            self.print_stack();
+            current = self.ip;
+            let instruction = self.chunk.code[self.ip];
+            let fits = self.instruction_fits(self.ip);
             self.ip += 1;
+            if !fits {
+                return self.misfit_fault();
+            }
+            // no instruction pushes more than a few values, apart from a
+            // spread call, which checks as it unpacks, so checking between
+            // them keeps the stack within a few values of its limit
+            if self.stack.len() > self.stack_size {
+                self.runtime_error("Stack overflow.");
+                return InterpretResult::InterpretRuntimeError;
+            }
+
+            if self.operator_methods {
+                if let Some(result) = self.operator_call(instruction) {
+                    if result != InterpretResult::InterpretOk {
+                        return result;
+                    }
+                    continue;
+                }
+            }
 
-            match instruction {
-                x if x == OpCode::OP_RETURN as u8 => {
+            let Ok(op) = OpCode::try_from(instruction) else {
+                return self.fault("Unknown instruction.");
+            };
+            match op {
+                OpCode::OP_RETURN => {
+                    // back to the caller, with the result in place of the
+                    // callee and its arguments
+                    if let Some(caller) = self.frames.pop() {
+                        // the callee's slot is below its result, a chunk
+                        // that popped it has nothing left to return
+                        if self.stack.len() <= self.slot_base + 1 {
+                            return self.fault("Stack underflow: no value to return.");
+                        }
+                        // try blocks the return leaves
+                        while self
+                            .handlers
+                            .last()
+                            .is_some_and(|handler| handler.frame_count > self.frames.len())
+                        {
+                            self.handlers.pop();
+                        }
+                        let result = self.pop();
+                        self.stack.truncate(self.slot_base);
+                        self.chunk = caller.chunk;
+                        self.ip = caller.ip;
+                        self.slot_base = caller.slot_base;
+                        self.function = caller.function;
+                        self.push(result);
+                        continue;
+                    }
                     if !self.stack.is_empty() {
                         let result = self.pop();
-                        result.print_value();
-                        println!();
+                        self.print(&result);
                     }
                     return InterpretResult::InterpretOk;
                 }
-                x if x == OpCode::OP_CONSTANT as u8 => {
+                OpCode::OP_CONSTANT => {
                     // get constant index
-                    let constant_index = self.chunk.code[self.ip as usize];
+                    let constant_index = self.chunk.code[self.ip];
+                    // get constant
+                    let Some(constant) = self.chunk.constants.values.get(constant_index as usize)
+                    else {
+                        return self.fault("Constant index out of range.");
+                    };
                     // move past constant index
                     self.ip += 1;
-                    // get constant
-                    let constant = &self.chunk.constants.values[constant_index as usize];
+                    #[cfg(feature = "debug_trace_execution")]
                     println!("constant: {:?}", &constant);
                     self.stack.push(constant.clone());
                 }
-                x if x == OpCode::OP_NIL as u8 => {
+                OpCode::OP_CONSTANT_LONG => {
+                    let Some(constant) = self.chunk.constants.values.get(self.read_long_operand())
+                    else {
+                        return self.fault("Constant index out of range.");
+                    };
+                    self.ip += 2;
+                    self.stack.push(constant.clone());
+                }
+                OpCode::OP_NIL => {
                     self.stack.push(Value::Nil);
                 }
-                x if x == OpCode::OP_TRUE as u8 => {
+                OpCode::OP_TRUE => {
                     self.stack.push(Value::Boolean(true));
                 }
-                x if x == OpCode::OP_FALSE as u8 => {
+                OpCode::OP_FALSE => {
                     self.stack.push(Value::Boolean(false));
                 }
-                x if x == OpCode::OP_NOT as u8 => {
+                OpCode::OP_NOT => {
                     let temp_val = self.pop();
                     self.stack.push(Value::Boolean(temp_val.is_falsey()));
                 }
@@ -211,149 +1226,1106 @@ impl VM {
                 // we pop from the stack, make negative and push back
                 // var a = 1.2;
                 // print -a;
-                x if x == OpCode::OP_NEGATE as u8 => {
+                OpCode::OP_NEGATE => {
                     if !self.peek(0).is_number() {
                         self.runtime_error("Operand must be a number.");
                         return InterpretResult::InterpretRuntimeError;
                     }
-                    let value = self.pop().as_number().unwrap() * -1 as f64;
-                    self.push(Value::Number(value));
+                    let value = self.pop();
+                    match value.as_int().map(i64::checked_neg) {
+                        Some(Some(n)) => self.push(Value::Int(n)),
+                        Some(None) if self.checked_arithmetic => {
+                            self.runtime_error(&format!("Integer overflow in -({}).", i64::MIN));
+                            return InterpretResult::InterpretRuntimeError;
+                        }
+                        _ => self.push(Value::Number(-value.as_number().unwrap())),
+                    }
                 }
-                x if x == OpCode::OP_ADD as u8 => {
+                OpCode::OP_ADD => {
                     // concatenate 2 strings and push result back to stack
-                    if self.peek(0).is_string() && self.peek(1).is_string() {
-                        self.concatenate();
+                    let result = if self.peek(0).is_string() && self.peek(1).is_string() {
+                        self.concatenate()
                     } else if self.peek(0).is_number() && self.peek(1).is_number() {
-                        self.binary_op("+");
+                        self.binary_op("+")
                     } else {
                         self.runtime_error("Operands must be two numbers or two strings.");
+                        InterpretResult::InterpretRuntimeError
+                    };
+                    if result != InterpretResult::InterpretOk {
+                        return result;
+                    }
+                }
+                OpCode::OP_BIT_NOT => {
+                    let Some(value) = to_integer(self.peek(0)) else {
+                        self.runtime_error("Operand must be a number.");
                         return InterpretResult::InterpretRuntimeError;
+                    };
+                    self.pop();
+                    self.push(Value::Int(!value));
+                }
+                OpCode::OP_BIT_AND
+                | OpCode::OP_BIT_OR
+                | OpCode::OP_BIT_XOR
+                | OpCode::OP_SHIFT_LEFT
+                | OpCode::OP_SHIFT_RIGHT => {
+                    let result = self.bitwise_op(op);
+                    if result != InterpretResult::InterpretOk {
+                        return result;
                     }
-                    // self.binary_op("+");
                 }
-                x if x == OpCode::OP_SUBTRACT as u8 => {
-                    self.binary_op("-");
+                OpCode::OP_SUBTRACT => {
+                    let result = self.binary_op("-");
+                    if result != InterpretResult::InterpretOk {
+                        return result;
+                    }
                 }
-                x if x == OpCode::OP_MULTIPLY as u8 => {
-                    self.binary_op("*");
+                OpCode::OP_MULTIPLY => {
+                    let result = self.binary_op("*");
+                    if result != InterpretResult::InterpretOk {
+                        return result;
+                    }
                 }
-                x if x == OpCode::OP_DIVIDE as u8 => {
-                    self.binary_op("/");
+                OpCode::OP_DIVIDE => {
+                    let result = self.binary_op("/");
+                    if result != InterpretResult::InterpretOk {
+                        return result;
+                    }
                 }
-                x if x == OpCode::OP_EQUAL as u8 => {
+                OpCode::OP_EQUAL => {
                     let b = self.pop();
                     let a = self.pop();
                     self.push(Value::Boolean(a.values_equal(&b)));
                 }
-                x if x == OpCode::OP_GREATER as u8 => {
-                    self.binary_op(">");
+                OpCode::OP_GREATER => {
+                    let result = self.binary_op(">");
+                    if result != InterpretResult::InterpretOk {
+                        return result;
+                    }
                 }
-                x if x == OpCode::OP_LESS as u8 => {
-                    self.binary_op("<");
+                OpCode::OP_LESS => {
+                    let result = self.binary_op("<");
+                    if result != InterpretResult::InterpretOk {
+                        return result;
+                    }
                 }
-                x if x == OpCode::OP_PRINT as u8 => {
+                OpCode::OP_PRINT => {
                     let value = self.pop();
-                    value.print_value();
-                    println!();
+                    self.print(&value);
                 }
-                x if x == OpCode::OP_POP as u8 => {
+                OpCode::OP_POP => {
                     self.pop();
                 }
-                x if x == OpCode::OP_DEFINE_GLOBAL as u8 => {
-                    let name = self.read_string();
-                    self.ip += 1; // Move past the constant index
-                    self.globals.table_set(name, self.peek(0).clone());
-                    self.pop();
+                OpCode::OP_DUP => {
+                    self.push(self.peek(0).clone());
                 }
-                x if x == OpCode::OP_GET_GLOBAL as u8 => {
-                    let name = self.read_string();
-                    self.ip += 1;
+                OpCode::OP_SWAP => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(b);
+                    self.push(a);
+                }
+                OpCode::OP_DEFINE_GLOBAL_SLOT => {
+                    let Some(slot) = self.global_slot() else {
+                        return self.fault("Global slot out of range.");
+                    };
+                    self.ip += 2;
+                    let value = self.pop();
+                    self.globals.define(slot, value);
+                }
+                OpCode::OP_GET_GLOBAL_SLOT => {
+                    let Some(slot) = self.global_slot() else {
+                        return self.fault("Global slot out of range.");
+                    };
+                    self.ip += 2;
 
-                    match self.globals.table_get(&name) {
+                    match self.globals.get(slot) {
                         Some(value) => {
-                            self.push(value);
-                        }
-                        None => {
-                            self.runtime_error(&format!(
-                                "Undefined variable '{}'.",
-                                name.as_obj_string()
-                            ));
-                            return InterpretResult::InterpretRuntimeError;
+                            self.push(value.clone());
                         }
+                        None => return self.undefined_global(slot),
                     }
                 }
-                x if x == OpCode::OP_SET_GLOBAL as u8 => {
-                    let name = self.read_string();
-                    self.ip += 1;
+                OpCode::OP_SET_GLOBAL_SLOT => {
+                    let Some(slot) = self.global_slot() else {
+                        return self.fault("Global slot out of range.");
+                    };
+                    self.ip += 2;
 
-                    if self.globals.table_set(name.clone(), self.peek(0).clone()) {
-                        self.globals.table_delete(&name);
-                        self.runtime_error(&format!(
-                            "Undefined variable '{}'.",
-                            name.as_obj_string()
-                        ));
-                        return InterpretResult::InterpretRuntimeError;
+                    let value = self.peek(0).clone();
+                    if !self.globals.set(slot, value) {
+                        return self.undefined_global(slot);
                     }
                 }
 
-                x if x == OpCode::OP_GET_LOCAL as u8 => {
-                    let slot = self.chunk.code[self.ip as usize];
+                OpCode::OP_GET_LOCAL => {
+                    let Some(slot) = self.local_slot(self.chunk.code[self.ip] as usize) else {
+                        return self.fault("Local slot out of range.");
+                    };
                     self.ip += 1;
-                    self.push(self.stack[slot as usize].clone());
+                    self.push(self.stack[slot].clone());
                 }
 
-                x if x == OpCode::OP_SET_LOCAL as u8 => {
-                    let slot = self.chunk.code[self.ip as usize];
+                OpCode::OP_SET_LOCAL => {
+                    let Some(slot) = self.local_slot(self.chunk.code[self.ip] as usize) else {
+                        return self.fault("Local slot out of range.");
+                    };
                     self.ip += 1;
-                    self.stack[slot as usize] = self.peek(0).clone();
+                    self.stack[slot] = self.peek(0).clone();
+                }
+
+                OpCode::OP_GET_LOCAL_LONG => {
+                    let Some(slot) = self.local_slot(self.read_long_operand()) else {
+                        return self.fault("Local slot out of range.");
+                    };
+                    self.ip += 2;
+                    self.push(self.stack[slot].clone());
+                }
+
+                OpCode::OP_SET_LOCAL_LONG => {
+                    let Some(slot) = self.local_slot(self.read_long_operand()) else {
+                        return self.fault("Local slot out of range.");
+                    };
+                    self.ip += 2;
+                    self.stack[slot] = self.peek(0).clone();
                 }
 
-                x if x == OpCode::OP_JUMP_IF_FALSE as u8 => {
+                OpCode::OP_JUMP_IF_FALSE => {
                     // Read the two bytes that make up the jump offset
-                    let high = self.chunk.code[self.ip as usize] as u16;
-                    let low = self.chunk.code[(self.ip + 1) as usize] as u16;
-                    let offset = (high << 8) | low;
+                    let offset = self.read_long_operand();
                     self.ip += 2; // Move past both offset bytes
 
                     if self.peek(0).is_falsey() {
-                        self.ip = (self.ip as u16 + offset) as usize;
+                        self.ip += offset;
                     }
                 }
-                x if x == OpCode::OP_JUMP as u8 => {
+                OpCode::OP_JUMP_IF_TRUE => {
+                    let offset = self.read_long_operand();
+                    self.ip += 2;
+                    if !self.peek(0).is_falsey() {
+                        self.ip += offset;
+                    }
+                }
+                OpCode::OP_JUMP_IF_NOT_NIL => {
+                    let high = self.chunk.code[self.ip] as usize;
+                    let low = self.chunk.code[self.ip + 1] as usize;
+                    self.ip += 2;
+                    if !matches!(self.peek(0), Value::Nil) {
+                        self.ip += (high << 8) | low;
+                    }
+                }
+                OpCode::OP_JUMP => {
                     // Read the two bytes that make up the jump offset
-                    let high = self.chunk.code[self.ip as usize] as u16;
-                    let low = self.chunk.code[(self.ip + 1) as usize] as u16;
-                    let offset = (high << 8) | low;
+                    let offset = self.read_long_operand();
                     self.ip += 2; // Move past both offset bytes
 
-                    self.ip = (self.ip as u16 + offset) as usize;
+                    self.ip += offset;
                 }
-                x if x == OpCode::OP_LOOP as u8 => {
+                OpCode::OP_LOOP => {
                     // Read the two bytes that make up the jump offset
-                    let high = self.chunk.code[self.ip as usize] as u16;
-                    let low = self.chunk.code[(self.ip + 1) as usize] as u16;
-                    let offset = (high << 8) | low;
+                    let offset = self.read_long_operand();
                     self.ip += 2; // Move past both offset bytes
 
-                    self.ip = (self.ip as u16 - offset) as usize;
+                    let Some(target) = self.ip.checked_sub(offset) else {
+                        return self.fault("Loop jumps before the start of the chunk.");
+                    };
+                    self.ip = target;
                 }
-                _ => {
-                    panic!("unknown instruction");
+                OpCode::OP_CLASS => {
+                    let Some(name) = self.read_string() else {
+                        return self.fault("Expected string constant.");
+                    };
+                    self.ip += 1;
+                    self.push(Value::Object(Obj {
+                        obj_type: ObjType::ObjClass(Rc::new(ObjClass {
+                            name: name.as_obj_string().to_string(),
+                            methods: RefCell::new(Table::init_table()),
+                        })),
+                    }));
                 }
-            }
-        }
-    }
+                OpCode::OP_GET_PROPERTY => {
+                    let Some(name) = self.read_string() else {
+                        return self.fault("Expected string constant.");
+                    };
+                    let offset = self.ip - 1;
+                    self.ip += 1;
+                    let id = self.name_id(offset, &name);
+                    let Some(instance) = self.peek(0).as_instance() else {
+                        let type_name = self.peek(0).type_name();
+                        let Some(methods) = self.builtin_methods.get(type_name) else {
+                            self.runtime_error("Only instances have properties.");
+                            return InterpretResult::InterpretRuntimeError;
+                        };
+                        let Some(Value::Object(Obj {
+                            obj_type: ObjType::ObjNative(native),
+                        })) = methods.table_get(id)
+                        else {
+                            self.runtime_error(&format!(
+                                "Undefined method '{}' on {}.",
+                                name.as_obj_string(),
+                                type_name
+                            ));
+                            return InterpretResult::InterpretRuntimeError;
+                        };
+                        let receiver = self.pop();
+                        self.push(Value::Object(Obj {
+                            obj_type: ObjType::ObjBoundNative(Rc::new(ObjBoundNative {
+                                receiver,
+                                native,
+                            })),
+                        }));
+                        continue;
+                    };
+                    let field = {
+                        let fields = &instance.borrow().fields;
+                        self.field_position(fields, offset, id)
+                            .map(|position| fields.entries[position].value.clone())
+                    };
+                    let method = instance.borrow().class.find_method(id);
+                    match (field, method) {
+                        // fields shadow methods
+                        (Some(value), _) => {
+                            self.pop();
+                            self.push(value);
+                        }
+                        (None, Some(method)) => {
+                            let receiver = self.pop();
+                            self.push(Value::Object(Obj {
+                                obj_type: ObjType::ObjBoundMethod(Rc::new(ObjBoundMethod {
+                                    receiver,
+                                    method,
+                                })),
+                            }));
+                        }
+                        (None, None) => {
+                            self.runtime_error(&format!(
+                                "Undefined property '{}'.",
+                                name.as_obj_string()
+                            ));
+                            return InterpretResult::InterpretRuntimeError;
+                        }
+                    }
+                }
+                OpCode::OP_SET_PROPERTY => {
+                    let Some(name) = self.read_string() else {
+                        return self.fault("Expected string constant.");
+                    };
+                    let offset = self.ip - 1;
+                    self.ip += 1;
+                    let id = self.name_id(offset, &name);
+                    let Some(instance) = self.peek(1).as_instance() else {
+                        self.runtime_error("Only instances have fields.");
+                        return InterpretResult::InterpretRuntimeError;
+                    };
+                    // the assignment evaluates to the assigned value
+                    let value = self.pop();
+                    let fields = &mut instance.borrow_mut().fields;
+                    match self.field_position(fields, offset, id) {
+                        Some(position) => fields.entries[position].value = value.clone(),
+                        None => {
+                            fields.table_set(id, value.clone());
+                        }
+                    }
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::OP_METHOD => {
+                    let Some(name) = self.read_string() else {
+                        return self.fault("Expected string constant.");
+                    };
+                    let offset = self.ip - 1;
+                    self.ip += 1;
+                    let id = self.name_id(offset, &name);
+                    let method = self.pop();
+                    let Value::Object(Obj {
+                        obj_type: ObjType::ObjClass(class),
+                    }) = self.peek(0).clone()
+                    else {
+                        return self.fault("Expected a class to add the method to.");
+                    };
+                    if OPERATOR_METHODS
+                        .iter()
+                        .any(|(_, op)| *op == name.as_obj_string())
+                    {
+                        self.operator_methods = true;
+                    }
+                    class.methods.borrow_mut().table_set(id, method);
+                }
+                OpCode::OP_BUILD_LIST => {
+                    let item_count = self.chunk.code[self.ip] as usize;
+                    self.ip += 1;
+                    let items = self.stack.split_off(self.stack.len() - item_count);
+                    self.push(Value::list(items));
+                }
+                OpCode::OP_BUILD_TUPLE => {
+                    let item_count = self.chunk.code[self.ip] as usize;
+                    self.ip += 1;
+                    let items = self.stack.split_off(self.stack.len() - item_count);
+                    self.push(Value::tuple(items));
+                }
+                OpCode::OP_BUILD_MAP => {
+                    let pair_count = self.chunk.code[self.ip] as usize;
+                    self.ip += 1;
+                    let parts = self.stack.split_off(self.stack.len() - 2 * pair_count);
+                    let mut parts = parts.into_iter();
+                    let mut entries = Vec::with_capacity(pair_count);
+                    while let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+                        entries.push((key, value));
+                    }
+                    self.push(Value::map(entries));
+                }
+                OpCode::OP_INDEX_GET => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    match self.get_index(&target, &index) {
+                        Ok(item) => self.push(item),
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return InterpretResult::InterpretRuntimeError;
+                        }
+                    }
+                }
+                OpCode::OP_INDEX_SET => {
+                    // any key can be set on a map, new ones are added
+                    if let Some(map) = self.peek(2).as_map() {
+                        let value = self.pop();
+                        let key = self.pop();
+                        map.borrow_mut().set(key, value.clone());
+                        self.pop();
+                        self.push(value);
+                        continue;
+                    }
+                    let Some(list) = self.peek(2).as_list() else {
+                        if self.peek(2).as_tuple().is_some() {
+                            self.runtime_error("Tuples can't be changed.");
+                            return InterpretResult::InterpretRuntimeError;
+                        }
+                        self.runtime_error("Only list items and map entries can be assigned.");
+                        return InterpretResult::InterpretRuntimeError;
+                    };
+                    let len = list.borrow().items.len();
+                    let Some(position) = index_position(self.peek(1), len) else {
+                        let message = index_message(self.peek(1), len, "list");
+                        self.runtime_error(&message);
+                        return InterpretResult::InterpretRuntimeError;
+                    };
+                    // like a property, the assignment evaluates to the value
+                    let value = self.pop();
+                    list.borrow_mut().items[position] = value.clone();
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::OP_RANGE | OpCode::OP_RANGE_INCLUSIVE => {
+                    let end = self.pop();
+                    let start = self.pop();
+                    let (Some(start), Some(end)) = (start.as_number(), end.as_number()) else {
+                        self.runtime_error("Range bounds must be numbers.");
+                        return InterpretResult::InterpretRuntimeError;
+                    };
+                    if start.fract() != 0.0 || end.fract() != 0.0 {
+                        self.runtime_error("Range bounds must be whole numbers.");
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                    self.push(Value::Object(Obj {
+                        obj_type: ObjType::ObjRange(ObjRange {
+                            start,
+                            end,
+                            inclusive: op == OpCode::OP_RANGE_INCLUSIVE,
+                        }),
+                    }));
+                }
+                OpCode::OP_FOR_ITER => {
+                    // the collection, the position and the loop variable
+                    let Some(last) = self.local_slot(self.chunk.code[self.ip] as usize + 2) else {
+                        return self.fault("Local slot out of range.");
+                    };
+                    let slot = last - 2;
+                    let high = self.chunk.code[self.ip + 1] as usize;
+                    let low = self.chunk.code[self.ip + 2] as usize;
+                    self.ip += 3;
+                    let Value::Int(position) = self.stack[slot + 1] else {
+                        return self.fault("Expected a for-in position.");
+                    };
+                    let collection = self.stack[slot].clone();
+                    match self.next_item(&collection, position as usize) {
+                        Ok(Some((item, next))) => {
+                            self.stack[slot + 1] = Value::Int(next as i64);
+                            self.stack[slot + 2] = item;
+                        }
+                        Ok(None) => self.ip += (high << 8) | low,
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return InterpretResult::InterpretRuntimeError;
+                        }
+                    }
+                }
+                OpCode::OP_TRY => {
+                    let high = self.chunk.code[self.ip] as usize;
+                    let low = self.chunk.code[self.ip + 1] as usize;
+                    self.ip += 2;
+                    self.handlers.push(ExceptionHandler {
+                        frame_count: self.frames.len(),
+                        stack_len: self.stack.len(),
+                        catch_ip: self.ip + ((high << 8) | low),
+                    });
+                }
+                OpCode::OP_END_TRY => {
+                    self.handlers.pop();
+                }
+                OpCode::OP_THROW => {
+                    let value = self.pop();
+                    if !self.unwind(value.clone()) {
+                        self.report_error(&format!(
+                            "Uncaught exception: {}",
+                            value.format_with(self.number_precision)
+                        ));
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                }
+                OpCode::OP_ASSERT_FAILED => {
+                    let message = self.pop().format_with(self.number_precision);
+                    let line = self.chunk.lines[self.ip - 1];
+                    self.runtime_error(&format!("Assertion failed on line {}: {}", line, message));
+                    return InterpretResult::InterpretRuntimeError;
+                }
+                OpCode::OP_CALL => {
+                    let arg_count = self.chunk.code[self.ip] as usize;
+                    self.ip += 1;
+                    let result = self.call_value(arg_count);
+                    if result != InterpretResult::InterpretOk || self.exit_code.is_some() {
+                        return result;
+                    }
+                }
+                OpCode::OP_SPREAD_CALL => {
+                    let list_count = self.chunk.code[self.ip] as usize;
+                    self.ip += 1;
+                    // the items go where the lists were, the arity is
+                    // checked against how many there are in total
+                    let lists = self.stack.split_off(self.stack.len() - list_count);
+                    let mut arg_count = 0;
+                    for value in lists {
+                        let Some(list) = value.as_list() else {
+                            self.runtime_error(&format!(
+                                "Can only spread a list, not {}.",
+                                value.type_name()
+                            ));
+                            return InterpretResult::InterpretRuntimeError;
+                        };
+                        let items = &list.borrow().items;
+                        if self.stack.len() + items.len() > self.stack_size {
+                            self.runtime_error("Stack overflow.");
+                            return InterpretResult::InterpretRuntimeError;
+                        }
+                        arg_count += items.len();
+                        self.stack.extend(items.iter().cloned());
+                    }
+                    let result = self.call_value(arg_count);
+                    if result != InterpretResult::InterpretOk || self.exit_code.is_some() {
+                        return result;
+                    }
+                }
+            }
+        }
+    }
+    // the callee sits below its `arg_count` arguments. A native runs
+    // right away and its result replaces the callee and arguments. A
+    // function gets a new frame and does the same when it returns
+    pub fn call_value(&mut self, arg_count: usize) -> InterpretResult {
+        let callee_slot = self.stack.len() - 1 - arg_count;
+        let (native, receiver) = match self.peek(arg_count) {
+            Value::Object(Obj {
+                obj_type: ObjType::ObjNative(native),
+            }) => (native.clone(), None),
+            Value::Object(Obj {
+                obj_type: ObjType::ObjBoundNative(bound),
+            }) => (bound.native.clone(), Some(bound.receiver.clone())),
+            Value::Object(Obj {
+                obj_type: ObjType::ObjFunction(function),
+            }) => return self.call_function(function.clone(), arg_count),
+            Value::Object(Obj {
+                obj_type: ObjType::ObjBoundMethod(bound),
+            }) => {
+                let bound = bound.clone();
+                // the receiver takes the callee's slot and becomes `this`
+                self.stack[callee_slot] = bound.receiver.clone();
+                return self.call_function(bound.method.clone(), arg_count);
+            }
+            Value::Object(Obj {
+                obj_type: ObjType::ObjClass(class),
+            }) => {
+                let class = class.clone();
+                let instance = ObjInstance {
+                    class: class.clone(),
+                    fields: Table::init_table(),
+                };
+                self.stack[callee_slot] = Value::Object(Obj {
+                    obj_type: ObjType::ObjInstance(Rc::new(RefCell::new(instance))),
+                });
+                let initializer = self
+                    .string_id("init")
+                    .and_then(|init| class.find_method(init));
+                if let Some(initializer) = initializer {
+                    return self.call_function(initializer, arg_count);
+                }
+                if arg_count != 0 {
+                    self.runtime_error(&format!("Expected 0 arguments but got {}.", arg_count));
+                    return InterpretResult::InterpretRuntimeError;
+                }
+                return InterpretResult::InterpretOk;
+            }
+            _ => {
+                self.runtime_error("Can only call functions and classes.");
+                return InterpretResult::InterpretRuntimeError;
+            }
+        };
+        if let Some(arity) = native.arity {
+            if arity != arg_count {
+                self.runtime_error(&format!(
+                    "Expected {} arguments but got {}.",
+                    arity, arg_count
+                ));
+                return InterpretResult::InterpretRuntimeError;
+            }
+        }
+
+        let mut args = self.stack.split_off(self.stack.len() - arg_count);
+        self.pop();
+        if let Some(receiver) = receiver {
+            args.insert(0, receiver);
+        }
+        match (native.function)(self, &args) {
+            // exit() was called, the run stops without unwinding any
+            // try blocks
+            Ok(_) if self.exit_code.is_some() => {
+                self.reset_stack();
+                InterpretResult::InterpretOk
+            }
+            Ok(value) => {
+                self.push(value);
+                InterpretResult::InterpretOk
+            }
+            Err(message) => {
+                self.runtime_error(&message);
+                InterpretResult::InterpretRuntimeError
+            }
+        }
+    }
+
+    // start running `function`. Its callee and arguments are already on
+    // the stack and become slot 0 and the parameters of the new frame
+    fn call_function(&mut self, function: Rc<ObjFunction>, arg_count: usize) -> InterpretResult {
+        if arg_count != function.arity {
+            self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                function.arity, arg_count
+            ));
+            return InterpretResult::InterpretRuntimeError;
+        }
+        if self.frames.len() >= self.frames_max {
+            self.runtime_error("Stack overflow.");
+            return InterpretResult::InterpretRuntimeError;
+        }
+        let caller = CallFrame {
+            chunk: std::mem::replace(&mut self.chunk, function.chunk.clone()),
+            ip: self.ip,
+            slot_base: self.slot_base,
+            function: self.function.replace(function),
+        };
+        self.frames.push(caller);
+        self.ip = 0;
+        self.slot_base = self.stack.len() - 1 - arg_count;
+        InterpretResult::InterpretOk
+    }
+
+    // An instance operand hands the operator to its class, `a + b` runs
+    // `a.plus(b)`. The operands already sit where a method call wants
+    // its receiver and argument. None means the instruction isn't an
+    // overloaded operator and runs as usual
+    fn operator_call(&mut self, instruction: u8) -> Option<InterpretResult> {
+        let method_name = operator_method(instruction)?;
+        let arg_count = if instruction == OpCode::OP_NEGATE as u8 {
+            0
+        } else {
+            1
+        };
+        let instance = self.peek(arg_count).as_instance()?;
+        let class = instance.borrow().class.clone();
+        let method = self
+            .string_id(method_name)
+            .and_then(|name| class.find_method(name));
+        match method {
+            Some(method) => Some(self.call_function(method, arg_count)),
+            // without `eq` instances compare by identity
+            None if instruction == OpCode::OP_EQUAL as u8 => None,
+            None => {
+                self.runtime_error(&format!(
+                    "{} has no '{}' method for this operator.",
+                    class.name, method_name
+                ));
+                Some(InterpretResult::InterpretRuntimeError)
+            }
+        }
+    }
+
+    // reading or assigning a global that has no value yet
+    fn undefined_global(&mut self, slot: usize) -> InterpretResult {
+        let message = match self.globals.names.name(slot) {
+            Some(name) => self.undefined_global_message(name),
+            None => "Undefined variable.".to_string(),
+        };
+        self.runtime_error(&message);
+        InterpretResult::InterpretRuntimeError
+    }
+
+    // a name that is declared later in the program gets a more useful
+    // error than one that is never declared at all
+    pub fn undefined_global_message(&self, name: &ObjType) -> String {
+        if self.hoisted_globals.contains(name) {
+            format!(
+                "Global '{}' used before initialization.",
+                name.as_obj_string()
+            )
+        } else {
+            format!("Undefined variable '{}'.", name.as_obj_string())
+        }
+    }
+
+    // The item of a for-in loop at `position` and the position of the one
+    // after it, or None once there are no more. Lists are read afresh on
+    // every step, so items added by the loop body are visited too. For
+    // strings the position is a byte offset, so each step is O(1)
+    fn next_item(
+        &mut self,
+        collection: &Value,
+        position: usize,
+    ) -> Result<Option<(Value, usize)>, String> {
+        if let Some(list) = collection.as_list() {
+            let item = list.borrow().items.get(position).cloned();
+            return Ok(item.map(|item| (item, position + 1)));
+        }
+        // a map gives its keys, in insertion order
+        if let Some(map) = collection.as_map() {
+            let key = map
+                .borrow()
+                .entries
+                .get(position)
+                .map(|(key, _)| key.clone());
+            return Ok(key.map(|key| (key, position + 1)));
+        }
+        if let Some(range) = collection.as_range() {
+            let n = range.start + position as f64;
+            let item = Value::Int(n as i64);
+            return Ok((n < range.end_exclusive()).then_some((item, position + 1)));
+        }
+        if let Value::Object(Obj {
+            obj_type: ObjType::ObjString(string),
+        }) = collection
+        {
+            let Some(c) = string.as_str()[position..].chars().next() else {
+                return Ok(None);
+            };
+            let item = self.intern_string(c.to_string());
+            return Ok(Some((item, position + c.len_utf8())));
+        }
+        Err(format!(
+            "Can only loop over lists, maps, strings and ranges, not {}.",
+            collection.type_name()
+        ))
+    }
+
+    // list[i] and string[i] give one item, list[a..b] and string[a..b]
+    // a new list or string with the items the range covers. Strings
+    // count characters, not bytes. Tuples work like lists
+    fn get_index(&mut self, target: &Value, index: &Value) -> Result<Value, String> {
+        if let Some(items) = target.as_tuple() {
+            let len = items.len();
+            return match index.as_range() {
+                Some(range) => range
+                    .slice(len)
+                    .map(|(start, end)| Value::tuple(items[start..end].to_vec()))
+                    .ok_or_else(|| slice_message(index, len, "tuple")),
+                None => index_position(index, len)
+                    .map(|position| items[position].clone())
+                    .ok_or_else(|| index_message(index, len, "tuple")),
+            };
+        }
+        if let Some(list) = target.as_list() {
+            let items = &list.borrow().items;
+            let len = items.len();
+            return match index.as_range() {
+                Some(range) => range
+                    .slice(len)
+                    .map(|(start, end)| Value::list(items[start..end].to_vec()))
+                    .ok_or_else(|| slice_message(index, len, "list")),
+                None => index_position(index, len)
+                    .map(|position| items[position].clone())
+                    .ok_or_else(|| index_message(index, len, "list")),
+            };
+        }
+
+        // a key that isn't in the map gives nil, so map[key] ?? default
+        // works
+        if let Some(map) = target.as_map() {
+            return Ok(map.borrow().get(index).unwrap_or(Value::Nil));
+        }
+
+        let Value::Object(Obj {
+            obj_type: ObjType::ObjString(string),
+        }) = target
+        else {
+            return Err(format!(
+                "Only lists, maps, tuples and strings can be indexed, not {}.",
+                target.type_name()
+            ));
+        };
+        let len = string.char_len();
+        let part = match index.as_range() {
+            Some(range) => {
+                let (start, end) = range
+                    .slice(len)
+                    .ok_or_else(|| slice_message(index, len, "string"))?;
+                string.substr_chars(start, end - start)
+            }
+            None => {
+                let position = index_position(index, len)
+                    .ok_or_else(|| index_message(index, len, "string"))?;
+                string.char_at(position).unwrap_or_default()
+            }
+        };
+        Ok(self.intern_string(part))
+    }
+
+    // nil past the bottom of the stack, like pop()
     pub fn peek(&self, distance: usize) -> &Value {
-        return &self.stack[self.stack.len() - 1 - distance];
+        match self.stack.len().checked_sub(distance + 1) {
+            Some(index) => &self.stack[index],
+            None => &Value::Nil,
+        }
+    }
+
+    // the global slot operand of the instruction being run, None if it
+    // isn't one the VM's names have, as in bytecode compiled against
+    // another VM
+    fn global_slot(&self) -> Option<usize> {
+        let slot = self.read_long_operand();
+        (slot < self.globals.names.len()).then_some(slot)
+    }
+
+    // The absolute stack index of local `slot` of the running function,
+    // None if the stack doesn't reach that far
+    fn local_slot(&self, slot: usize) -> Option<usize> {
+        let index = self.slot_base + slot;
+        (index < self.stack.len()).then_some(index)
+    }
+
+    // what run() relies on but a broken chunk may not give it: all of
+    // the instruction's operands, and as many values on the running
+    // function's part of the stack as it takes off
+    #[inline]
+    fn instruction_fits(&self, offset: usize) -> bool {
+        let code_len = self.chunk.code.len();
+        // no instruction is longer than 4 bytes, only the last few can
+        // be cut off
+        (offset + 4 <= code_len || offset + self.chunk.instruction_len(offset) <= code_len)
+            && self.chunk.values_taken(offset) <= self.stack.len().saturating_sub(self.slot_base)
+    }
+
+    // the fault for an instruction that doesn't fit. Called with ip
+    // just past its opcode
+    #[cold]
+    fn misfit_fault(&mut self) -> InterpretResult {
+        let offset = self.ip - 1;
+        if offset + self.chunk.instruction_len(offset) > self.chunk.code.len() {
+            return self.fault("Operands cut off by the end of the chunk.");
+        }
+        let message = format!(
+            "Stack underflow: the instruction takes {} values, there are {}.",
+            self.chunk.values_taken(offset),
+            self.stack.len().saturating_sub(self.slot_base)
+        );
+        self.fault(&message)
     }
 
+    // A runtime error aborts the rest of the program. Statements that
+    // finished before it keep their effects (globals they defined or
+    // assigned stay), while the failing statement leaves no trace: a
+    // global is only written once its value has been computed, and the
+    // value stack is emptied here. The VM is ready for the next
+    // interpret() call afterwards, which is what keeps a REPL session
+    // usable after an error.
+    //
+    // Inside a try block the error is thrown instead, with the message
+    // as the value the catch block gets, and nothing is reported. The
+    // caller still returns InterpretRuntimeError, execute() sees the
+    // error was caught and goes on at the catch block.
     pub fn runtime_error(&mut self, message: &str) {
-        println!("Runtime error: {}", message);
+        if !self.handlers.is_empty() {
+            let value = self.intern_string(message.to_string());
+            self.caught = self.unwind(value);
+            return;
+        }
+        self.report_error(message);
+    }
+
+    // report an error that ends the run, whether or not a try block
+    // is running
+    fn report_error(&mut self, message: &str) {
+        self.flush_output();
+        let error = RuntimeError {
+            message: message.to_string(),
+            location: self.current_location(),
+            source: self.chunk.source_at(self.ip.saturating_sub(1)).cloned(),
+            trace: self.call_trace(),
+        };
+        if self.print_errors {
+            eprint!("{}", error.render(Renderer::stderr()));
+        }
+        self.runtime_error = Some(error);
+        self.reset_stack();
+    }
+
+    // show the last program's warnings if asked to. True if they are
+    // denied, the program has to be fixed before it can run. A strict
+    // program denies them itself
+    fn report_warnings(&mut self, source: &str, strict: bool) -> bool {
+        let deny = self.deny_warnings || strict;
+        if self.compile_warnings.is_empty() || !(self.show_warnings || deny) {
+            return false;
+        }
+        self.flush_output();
+        if self.print_errors {
+            for warning in &self.compile_warnings {
+                let mut diagnostic = warning.clone();
+                if deny {
+                    diagnostic.severity = Severity::Error;
+                    diagnostic = diagnostic.with_note(match strict {
+                        true => "warnings are errors in strict mode",
+                        false => "warnings are denied",
+                    });
+                }
+                eprint!("{}", Renderer::stderr().render(&diagnostic, Some(source)));
+            }
+        }
+        deny
+    }
+
+    fn report_compile_errors(&mut self, errors: Vec<CompileError>, source: &str) {
+        self.flush_output();
+        if self.print_errors {
+            for error in &errors {
+                eprint!(
+                    "{}",
+                    Renderer::stderr().render(&error.diagnostic(), Some(source))
+                );
+            }
+        }
+        self.compile_errors = errors;
+    }
+
+    // Throw `value` to the innermost try block: drop the frames and stack
+    // values above where it started and go to its catch block, with the
+    // value on top of the stack for the catch variable. False if no try
+    // block is running
+    fn unwind(&mut self, value: Value) -> bool {
+        let Some(handler) = self.handlers.pop() else {
+            return false;
+        };
+        if let Some(frame) = self.frames.drain(handler.frame_count..).next() {
+            self.chunk = frame.chunk;
+            self.slot_base = frame.slot_base;
+            self.function = frame.function;
+        }
+        self.stack.truncate(handler.stack_len);
+        self.push(value);
+        self.ip = handler.catch_ip;
+        true
+    }
+
+    // The source position of the instruction being executed, for hosts
+    // that want to attribute a log line or an error of their own to the
+    // script. Natives see the call that is running them. Outside a run
+    // it is where the last one stopped, and None if nothing ran yet
+    pub fn current_location(&self) -> Option<SourceLocation> {
+        location_at(&self.chunk, self.ip, &self.function)
+    }
+
+    // where each function waiting for a call to return made the call,
+    // innermost first
+    fn call_trace(&self) -> Vec<SourceLocation> {
+        self.frames
+            .iter()
+            .rev()
+            .filter_map(|frame| location_at(&frame.chunk, frame.ip, &frame.function))
+            .collect()
+    }
+
+    // stop on broken bytecode. Called with ip just past the opcode of
+    // the instruction being executed
+    pub fn fault(&mut self, message: &str) -> InterpretResult {
+        self.flush_output();
+        let ip = self.ip.saturating_sub(1);
+        let from = ip.saturating_sub(4);
+        let to = (ip + 5).min(self.chunk.code.len());
+        let context = (from..to)
+            .map(|i| {
+                if i == ip {
+                    format!("[{:02x}]", self.chunk.code[i])
+                } else {
+                    format!("{:02x}", self.chunk.code[i])
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+        let fault = VmFault {
+            ip,
+            opcode: self.chunk.code.get(ip).copied().unwrap_or(0),
+            message: message.to_string(),
+            context: format!("{:04}: {}", from, context),
+        };
+        let diagnostic = Diagnostic::error(&format!(
+            "VM fault: {} (opcode {:#04x} at {:04})",
+            fault.message, fault.opcode, fault.ip
+        ))
+        .with_note(&fault.context);
+        eprint!("{}", Renderer::stderr().render(&diagnostic, None));
+        self.fault = Some(fault);
         self.reset_stack();
+        InterpretResult::InterpretRuntimeError
     }
 
     pub fn reset_stack(&mut self) {
         self.stack.clear();
+        self.underflow = false;
+        self.frames.clear();
+        self.handlers.clear();
+        self.slot_base = 0;
+    }
+}
+
+// the instruction before `ip`, which is either the one being executed
+// or, for a caller, the call it is waiting on
+fn location_at(
+    chunk: &Chunk,
+    ip: usize,
+    function: &Option<Rc<ObjFunction>>,
+) -> Option<SourceLocation> {
+    let instruction = ip.saturating_sub(1);
+    let line = *chunk.lines.get(instruction)?;
+    let column = chunk.columns.get(instruction).copied();
+    Some(SourceLocation {
+        file: chunk.file_at(instruction).unwrap_or("script").to_string(),
+        line,
+        column: column.filter(|&column| column > 0),
+        function: function.as_ref().map(|f| f.name.clone()),
+    })
+}
+
+// printed to stderr so it doesn't mix with the program's own output
+fn pass_times_report(passes: &[(&str, Duration)]) -> String {
+    let rows: String = passes
+        .iter()
+        .map(|(name, time)| format!("  {:<14}{:>10.3}ms\n", name, time.as_secs_f64() * 1000.0))
+        .collect();
+    format!("time-passes:\n{}", rows)
+}
+
+// the operand of a bitwise operator as an integer, see bitwise_op
+fn to_integer(value: &Value) -> Option<i64> {
+    match value {
+        Value::Int(n) => Some(*n),
+        Value::Number(n) => Some(*n as i64),
+        _ => None,
+    }
+}
+
+// why `index` doesn't point into a list or string of `len` items
+fn index_message(index: &Value, len: usize, kind: &str) -> String {
+    match index {
+        Value::Number(n) if n.fract() == 0.0 => {
+            format!(
+                "Index {} is out of bounds for a {} of length {}.",
+                n, kind, len
+            )
+        }
+        Value::Int(n) => format!(
+            "Index {} is out of bounds for a {} of length {}.",
+            n, kind, len
+        ),
+        Value::Number(n) => format!("Index must be a whole number, got {}.", n),
+        other => format!(
+            "Index must be a number or range, got {}.",
+            other.type_name()
+        ),
+    }
+}
+
+fn slice_message(range: &Value, len: usize, kind: &str) -> String {
+    format!(
+        "Range {} is out of bounds for a {} of length {}.",
+        range.format_with(None),
+        kind,
+        len
+    )
+}
+
+fn print_size_report(sizes: &[ChunkSize]) {
+    eprintln!("size-report:");
+    let width = sizes.iter().map(|size| size.name.len()).max().unwrap_or(0);
+    for size in sizes {
+        eprintln!(
+            "  {:<width$}  {:>7} bytes  {:>3}/256 constants",
+            size.name,
+            size.code,
+            size.constants,
+            width = width
+        );
+    }
+    eprintln!(
+        "  {:<width$}  {:>7} bytes",
+        "total",
+        sizes.iter().map(|size| size.code).sum::<usize>(),
+        width = width
+    );
+}
+
+fn print_intern_stats(stats: &InternStats) {
+    eprintln!("interned strings:");
+    eprintln!("  {:<14}{:>10}", "strings", stats.strings);
+    eprintln!("  {:<14}{:>10}", "bytes", stats.bytes);
+    eprintln!(
+        "  {:<14}{:>10} ({} hits, {} misses, {:.1}% hit rate)",
+        "lookups",
+        stats.hits + stats.misses,
+        stats.hits,
+        stats.misses,
+        stats.hit_rate() * 100.0
+    );
+    for string in &stats.largest {
+        eprintln!("  {:>6} bytes  {:?}", string.len(), string);
+    }
+}
+
+// an output for VM::set_output() that tests can read back
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct CapturedOutput(Rc<RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl CapturedOutput {
+    pub fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
@@ -364,6 +2336,2467 @@ mod tests {
     #[test]
     fn test_simple() {
         let mut elephant_vm = VM::init_vm();
-        assert_eq!(elephant_vm.interpret("1 + 2"), InterpretResult::InterpretOk);
+        assert_eq!(elephant_vm.interpret("1 + 2;"), InterpretResult::InterpretOk);
+    }
+
+    #[test]
+    fn test_error_names_file() {
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(
+            elephant_vm.interpret_file("print 1;\nprint -nil;", "lib.elx"),
+            InterpretResult::InterpretRuntimeError
+        );
+        let failing = elephant_vm.ip - 1;
+        assert_eq!(elephant_vm.chunk.lines[failing], 2);
+        assert_eq!(elephant_vm.chunk.file_at(failing), Some("lib.elx"));
+    }
+
+    fn global(vm: &VM, name: &str) -> Option<Value> {
+        vm.globals
+            .get_named(&ObjType::ObjString(ObjString::new(name.to_string())))
+    }
+
+    #[test]
+    fn test_hoist_loop_globals() {
+        let source = "
+            var n = 3;
+            var step = 2;
+            var total = 0;
+            {
+                var k = 10;
+                for (var i = 0; i < n; i = i + 1) {
+                    var j = i;
+                    while (j < n) {
+                        var one = 1;
+                        total = total + k + step + one;
+                        j = j + one;
+                    }
+                }
+            }
+            var count = 0;
+            while (count < n) count = count + step;
+        ";
+        let mut plain = VM::init_vm();
+        assert_eq!(plain.interpret(source), InterpretResult::InterpretOk);
+        let mut optimized = VM::init_vm();
+        optimized.optimize = true;
+        assert_eq!(optimized.interpret(source), InterpretResult::InterpretOk);
+        for name in ["total", "count"] {
+            assert_eq!(
+                global(&optimized, name).unwrap().as_number(),
+                global(&plain, name).unwrap().as_number()
+            );
+        }
+        assert_eq!(global(&optimized, "total").unwrap().as_number(), Some(78.0));
+
+        // n and step are loaded once in front of each loop
+        let mut compiler = Compiler::new(source);
+        compiler.optimize = true;
+        let chunk = compiler.compile(Chunk::init_chunk()).unwrap().remove(0);
+        let global_reads = (0..chunk.code.len())
+            .scan(0, |next, offset| {
+                let at = offset == *next;
+                if at {
+                    *next += chunk.instruction_len(offset);
+                }
+                Some(at && chunk.code[offset] == OpCode::OP_GET_GLOBAL_SLOT as u8)
+            })
+            .filter(|read| *read)
+            .count();
+        // total and count are assigned in their loops, so their reads
+        // stay (1 + 2). n and step are loaded in front of the for loop and
+        // the last while loop (2 + 2); the nested while's own loads of them
+        // became reads of the for loop's hidden locals
+        assert_eq!(global_reads, 1 + 2 + 2 + 2);
+    }
+
+    #[test]
+    fn test_hoist_keeps_undefined_global_errors() {
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.optimize = true;
+        // never declared: still only fails if the loop body runs
+        assert_eq!(
+            elephant_vm.interpret("while (false) print missing;"),
+            InterpretResult::InterpretOk
+        );
+        assert_eq!(
+            elephant_vm.interpret("var i = 0; while (i < 1) { i = i + 1; print missing; }"),
+            InterpretResult::InterpretRuntimeError
+        );
+    }
+
+    #[test]
+    fn test_faults_instead_of_panics() {
+        let mut elephant_vm = VM::init_vm();
+        let mut chunk = Chunk::init_chunk();
+        chunk.write_chunk(OpCode::OP_NIL as u8, 1);
+        chunk.write_chunk(0xfe, 1);
+        chunk.write_chunk(OpCode::OP_RETURN as u8, 1);
+        elephant_vm.chunk = Rc::new(chunk);
+        elephant_vm.ip = 0;
+        assert_eq!(elephant_vm.run(), InterpretResult::InterpretRuntimeError);
+        let fault = elephant_vm.fault.clone().unwrap();
+        assert_eq!((fault.ip, fault.opcode), (1, 0xfe));
+        assert_eq!(fault.context, "0000: 07 [fe] 00");
+        assert!(elephant_vm.stack.is_empty());
+
+        // a global slot this VM never gave out
+        let mut chunk = Chunk::init_chunk();
+        chunk.write_chunk(OpCode::OP_GET_GLOBAL_SLOT as u8, 1);
+        chunk.write_chunk(0xff, 1);
+        chunk.write_chunk(0xff, 1);
+        elephant_vm.chunk = Rc::new(chunk);
+        elephant_vm.ip = 0;
+        assert_eq!(elephant_vm.run(), InterpretResult::InterpretRuntimeError);
+        let fault = elephant_vm.fault.clone().unwrap();
+        assert_eq!(fault.message, "Global slot out of range.");
+        assert_eq!(fault.opcode, OpCode::OP_GET_GLOBAL_SLOT as u8);
+
+        let faults = [
+            (
+                vec![OpCode::OP_NIL as u8, OpCode::OP_ADD as u8],
+                "Stack underflow: the instruction takes 2 values, there are 1.",
+            ),
+            (
+                vec![OpCode::OP_CALL as u8, 3],
+                "Stack underflow: the instruction takes 4 values, there are 0.",
+            ),
+            (
+                vec![OpCode::OP_CONSTANT as u8, 9],
+                "Constant index out of range.",
+            ),
+            (
+                vec![OpCode::OP_NIL as u8, OpCode::OP_GET_LOCAL as u8, 1],
+                "Local slot out of range.",
+            ),
+            (
+                vec![OpCode::OP_NIL as u8, OpCode::OP_JUMP as u8, 0],
+                "Operands cut off by the end of the chunk.",
+            ),
+            (
+                vec![OpCode::OP_NIL as u8, OpCode::OP_SWAP as u8],
+                "Stack underflow: the instruction takes 2 values, there are 1.",
+            ),
+        ];
+        for (code, message) in faults {
+            let mut chunk = Chunk::init_chunk();
+            for byte in code {
+                chunk.write_chunk(byte, 1);
+            }
+            elephant_vm.chunk = Rc::new(chunk);
+            elephant_vm.ip = 0;
+            assert_eq!(elephant_vm.run(), InterpretResult::InterpretRuntimeError);
+            assert_eq!(elephant_vm.fault.clone().unwrap().message, message);
+            assert!(elephant_vm.stack.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut elephant_vm = VM::init_vm();
+        // a function whose chunk pops its own slot before returning
+        let mut body = Chunk::init_chunk();
+        body.write_chunk(OpCode::OP_POP as u8, 1);
+        body.write_chunk(OpCode::OP_RETURN as u8, 1);
+        let function = ObjFunction {
+            name: "broken".to_string(),
+            arity: 0,
+            chunk: Rc::new(body),
+        };
+        let mut chunk = Chunk::init_chunk();
+        let constant = chunk.add_constant(Value::Object(Obj {
+            obj_type: ObjType::ObjFunction(Rc::new(function)),
+        })) as u8;
+        for byte in [
+            OpCode::OP_CONSTANT as u8,
+            constant,
+            OpCode::OP_CALL as u8,
+            0,
+            OpCode::OP_RETURN as u8,
+        ] {
+            chunk.write_chunk(byte, 1);
+        }
+        elephant_vm.chunk = Rc::new(chunk);
+        elephant_vm.ip = 0;
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+        assert_eq!(elephant_vm.run(), InterpretResult::InterpretRuntimeError);
+        // it stops in the function, nothing made up is returned
+        let fault = elephant_vm.fault.clone().unwrap();
+        assert_eq!(fault.message, "Stack underflow: no value to return.");
+        assert_eq!((fault.ip, fault.opcode), (1, OpCode::OP_RETURN as u8));
+        assert_eq!(output.text(), "");
+        assert!(elephant_vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_dup_and_swap() {
+        let mut elephant_vm = VM::init_vm();
+        let mut chunk = Chunk::init_chunk();
+        let five = chunk.add_constant(Value::Int(5)) as u8;
+        let two = chunk.add_constant(Value::Int(2)) as u8;
+        // (2 - 5) * (2 - 5)
+        for byte in [
+            OpCode::OP_CONSTANT as u8,
+            five,
+            OpCode::OP_CONSTANT as u8,
+            two,
+            OpCode::OP_SWAP as u8,
+            OpCode::OP_SUBTRACT as u8,
+            OpCode::OP_DUP as u8,
+            OpCode::OP_MULTIPLY as u8,
+            OpCode::OP_RETURN as u8,
+        ] {
+            chunk.write_chunk(byte, 1);
+        }
+        assert!(chunk
+            .disassembly("code")
+            .contains("0004 OP_SWAP\n0005 OP_SUBTRACT\n0006 OP_DUP\n"));
+        elephant_vm.chunk = Rc::new(chunk);
+        elephant_vm.ip = 0;
+        // the value left on the stack is printed when the chunk returns
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+        assert_eq!(elephant_vm.run(), InterpretResult::InterpretOk);
+        assert_eq!(output.text(), "9\n");
+        assert!(elephant_vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_swap_underflow() {
+        let mut elephant_vm = VM::init_vm();
+        let mut chunk = Chunk::init_chunk();
+        chunk.write_chunk(OpCode::OP_TRUE as u8, 1);
+        chunk.write_chunk(OpCode::OP_SWAP as u8, 1);
+        elephant_vm.chunk = Rc::new(chunk);
+        elephant_vm.ip = 0;
+        assert_eq!(elephant_vm.run(), InterpretResult::InterpretRuntimeError);
+        let fault = elephant_vm.fault.clone().unwrap();
+        assert_eq!((fault.ip, fault.opcode), (1, OpCode::OP_SWAP as u8));
+        assert!(elephant_vm.stack.is_empty());
+
+        // in a function, only its own slot is there to swap with. The
+        // caller's values below it are out of reach
+        let mut body = Chunk::init_chunk();
+        body.write_chunk(OpCode::OP_SWAP as u8, 1);
+        body.write_chunk(OpCode::OP_RETURN as u8, 1);
+        let function = ObjFunction {
+            name: "swap".to_string(),
+            arity: 0,
+            chunk: Rc::new(body),
+        };
+        let mut chunk = Chunk::init_chunk();
+        let constant = chunk.add_constant(Value::Object(Obj {
+            obj_type: ObjType::ObjFunction(Rc::new(function)),
+        })) as u8;
+        for byte in [
+            OpCode::OP_TRUE as u8,
+            OpCode::OP_CONSTANT as u8,
+            constant,
+            OpCode::OP_CALL as u8,
+            0,
+        ] {
+            chunk.write_chunk(byte, 1);
+        }
+        elephant_vm.chunk = Rc::new(chunk);
+        elephant_vm.ip = 0;
+        assert_eq!(elephant_vm.run(), InterpretResult::InterpretRuntimeError);
+        let fault = elephant_vm.fault.clone().unwrap();
+        assert_eq!(
+            fault.message,
+            "Stack underflow: the instruction takes 2 values, there are 1."
+        );
+        assert_eq!(fault.ip, 0);
+    }
+
+    #[test]
+    fn test_instruction_budget() {
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.instruction_budget = Some(1000);
+        assert_eq!(
+            elephant_vm.interpret("while (true) {}"),
+            InterpretResult::InterpretRuntimeError
+        );
+        // the budget is per run, so the session goes on
+        assert_eq!(
+            elephant_vm.interpret("var i = 0; while (i < 10) i = i + 1;"),
+            InterpretResult::InterpretOk
+        );
+        assert_eq!(global(&elephant_vm, "i").unwrap().as_number(), Some(10.0));
+        assert_eq!(
+            elephant_vm.interpret("while (i < 1000) i = i + 1;"),
+            InterpretResult::InterpretRuntimeError
+        );
+
+        elephant_vm.instruction_budget = None;
+        assert_eq!(
+            elephant_vm.interpret("while (i < 1000) i = i + 1;"),
+            InterpretResult::InterpretOk
+        );
+    }
+
+    #[test]
+    fn test_prelude() {
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.load_prelude(), InterpretResult::InterpretOk);
+        let pi = ObjType::ObjString(ObjString::new("PI".to_string()));
+        assert_eq!(
+            elephant_vm.globals.get_named(&pi).unwrap().as_number(),
+            Some(std::f64::consts::PI)
+        );
+        assert_eq!(
+            elephant_vm.interpret("var tau = 2 * PI;"),
+            InterpretResult::InterpretOk
+        );
+
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+        let source = "
+            print max(2, 7);
+            print min(2, 7);
+            print range_to_list(1..4);
+            print range_to_list(0..0);
+            print range_to_list(\"hé\");
+        ";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(output.text(), "7\n2\n[1, 2, 3]\n[]\n[\"h\", \"é\"]\n");
+    }
+
+    #[test]
+    fn test_intern_stats() {
+        let mut elephant_vm = VM::init_vm();
+        // the names of the built-in methods are in the pool from the
+        // start, but aren't lookups
+        let before = elephant_vm.intern_stats(0);
+        assert_eq!((before.hits, before.misses), (0, 0));
+        let source = "
+            var a = \"ab\" + \"cd\";
+            var b = \"a\" + \"bcd\";
+            var c = \"abcdefghijklm\" + \"nopqrstuvwxyz\";
+            var d = \"abcd\";
+        ";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        // the literals go into the pool as they are compiled, the
+        // compiler doesn't count as lookups either. What the program
+        // makes stays out of it
+        let stats = elephant_vm.intern_stats(1);
+        assert_eq!(stats.strings - before.strings, 6 + 1);
+        assert_eq!(stats.bytes - before.bytes, (2 + 2 + 1 + 3 + 13 + 13) + 4);
+        // "abcd" was there before the program ran
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert_eq!(stats.largest, ["abcdefghijklm"]);
+        assert!((stats.hit_rate() - 2.0 / 3.0).abs() < 1e-9);
+
+        // an interned string is shared, not copied
+        let again = elephant_vm.intern_string("abcd".to_string());
+        let a = global(&elephant_vm, "a").unwrap();
+        let text = |value: &Value| match value {
+            Value::Object(Obj {
+                obj_type: ObjType::ObjString(string),
+            }) => string.as_str().as_ptr(),
+            _ => panic!("not a string"),
+        };
+        assert_eq!(text(&again), text(&a));
+        // so is a literal with the same text as a string made at runtime
+        let d = global(&elephant_vm, "d").unwrap();
+        assert_eq!(text(&d), text(&a));
+
+        // building a string up doesn't leave every step behind
+        let before = elephant_vm.intern_stats(0);
+        let source = "var s = \"\"; for (i in 0..2000) s = s + \"x\"; var n = len(s);";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(global(&elephant_vm, "n").unwrap().as_number(), Some(2000.0));
+        let stats = elephant_vm.intern_stats(0);
+        assert!(stats.strings - before.strings < 10);
+        assert!(stats.bytes - before.bytes < 100);
+    }
+
+    #[test]
+    fn test_time_passes() {
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(
+            elephant_vm.interpret("var a = 1;"),
+            InterpretResult::InterpretOk
+        );
+        assert!(elephant_vm.pass_times.is_empty());
+
+        elephant_vm.time_passes = true;
+        let source = "var sum = 0; for (i in 0..1000) sum = sum + i;";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let passes: Vec<&str> = elephant_vm
+            .pass_times
+            .iter()
+            .map(|(name, _)| *name)
+            .collect();
+        assert_eq!(passes, ["scan", "parse/codegen", "run"]);
+        assert!(elephant_vm.pass_times[2].1 > Duration::ZERO);
+
+        let report = pass_times_report(&elephant_vm.pass_times);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "time-passes:");
+        for (line, pass) in lines[1..].iter().zip(passes) {
+            assert!(line.starts_with(&format!("  {} ", pass)), "{}", line);
+            assert!(line.ends_with("ms"), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let mut elephant_vm = VM::init_vm();
+        // 1 followed by 308 zeros, close to f64::MAX
+        let big = format!("var big = 1{}.0;", "0".repeat(308));
+        assert_eq!(elephant_vm.interpret(&big), InterpretResult::InterpretOk);
+        let overflowing = ["1 / 0;", "0 / 0;", "big * 10;", "-big - big;"];
+        // by default IEEE results go through as values
+        for source in overflowing {
+            assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        }
+
+        elephant_vm.checked_arithmetic = true;
+        for source in overflowing {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+        assert_eq!(
+            elephant_vm.interpret("var x = big + 1; var y = 0.1 * 3;"),
+            InterpretResult::InterpretOk
+        );
+    }
+
+    #[test]
+    fn test_functions_and_methods() {
+        let mut elephant_vm = VM::init_vm();
+        let source = "
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 1) + fib(n - 2);
+            }
+            var f = fib(10);
+
+            class Counter {
+                init(start) { this.count = start; }
+                add(n) { this.count = this.count + n; return this; }
+            }
+            var c = Counter(5);
+            var add = c.add;
+            add(1);
+            var count = c.add(2).count;
+            var init_result = c.init(0) == c;
+        ";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(global(&elephant_vm, "f").unwrap().as_number(), Some(55.0));
+        assert_eq!(
+            global(&elephant_vm, "count").unwrap().as_number(),
+            Some(8.0)
+        );
+        assert_eq!(
+            global(&elephant_vm, "init_result").unwrap().as_bool(),
+            Some(true)
+        );
+
+        for source in [
+            "fib();",
+            "Counter();",
+            "fun forever() { return forever(); } forever();",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretRuntimeError
+            );
+        }
+        // a runtime error deep in a call leaves the VM usable
+        assert_eq!(
+            elephant_vm.interpret("var again = fib(3);"),
+            InterpretResult::InterpretOk
+        );
+    }
+
+    #[test]
+    fn test_operator_overloading() {
+        let mut elephant_vm = VM::init_vm();
+        let source = "
+            class Vec {
+                init(x, y) { this.x = x; this.y = y; }
+                plus(o) { return Vec(this.x + o.x, this.y + o.y); }
+                minus(o) { return Vec(this.x - o.x, this.y - o.y); }
+                times(k) { return Vec(this.x * k, this.y * k); }
+                divide(k) { return Vec(this.x / k, this.y / k); }
+                negate() { return Vec(-this.x, -this.y); }
+                eq(o) { if (this.x != o.x) return false; return this.y == o.y; }
+                lt(o) { return this.x < o.x; }
+                gt(o) { return this.x > o.x; }
+            }
+            var v = (Vec(1, 2) + Vec(3, 4)) * 2 - Vec(1, 1);
+            var w = -v / 2;
+            var same = Vec(1, 2) == Vec(1, 2);
+            var differ = Vec(1, 2) != Vec(1, 3);
+            var less = Vec(1, 0) <= Vec(2, 0);
+            var greater = Vec(3, 0) >= Vec(2, 0);
+        ";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let field = |vm: &VM, name: &str, field: &str| {
+            let instance = global(vm, name).unwrap().as_instance().unwrap();
+            let key = vm.string_id(field).unwrap();
+            let value = instance.borrow().fields.table_get(key);
+            value.unwrap().as_number()
+        };
+        assert_eq!(field(&elephant_vm, "v", "x"), Some(7.0));
+        assert_eq!(field(&elephant_vm, "v", "y"), Some(11.0));
+        assert_eq!(field(&elephant_vm, "w", "x"), Some(-3.5));
+        for name in ["same", "differ", "less", "greater"] {
+            assert_eq!(global(&elephant_vm, name).unwrap().as_bool(), Some(true));
+        }
+
+        // without an eq method instances compare by identity, other
+        // operators need their method
+        let source = "class Plain {} var p = Plain(); var is_same = p == p;";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(
+            elephant_vm.interpret("p + p;"),
+            InterpretResult::InterpretRuntimeError
+        );
+    }
+
+    #[test]
+    fn test_property_caches() {
+        // the same instructions read and write instances whose fields
+        // were set in different orders, so their caches keep missing
+        let mut elephant_vm = VM::init_vm();
+        let source = "
+            class P { x() { return 100; } }
+            fun five() { return 5; }
+            var a = P(); a.x = 1; a.y = 2;
+            var b = P(); b.y = 3;
+            fun call(o) { return o.x(); }
+            fun sum(o) { return o.x * 10 + o.y; }
+            fun bump(o) { o.y = o.y + 1; }
+            // a field added later shadows the method
+            var calls = call(b);
+            b.x = five;
+            calls = calls + call(b);
+            var total = 0;
+            for (var i = 0; i < 4; i = i + 1) {
+                var o = a;
+                if (i > 1) o = b;
+                bump(o);
+                total = total + o.y;
+            }
+            b.x = 4;
+            total = total + sum(a) + sum(b);
+        ";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(
+            global(&elephant_vm, "calls").unwrap().as_number(),
+            Some(105.0)
+        );
+        // 3 + 4 + 4 + 5, then 1 * 10 + 4 and 4 * 10 + 5
+        assert_eq!(
+            global(&elephant_vm, "total").unwrap().as_number(),
+            Some(75.0)
+        );
+    }
+
+    #[test]
+    fn test_caches_per_instruction() {
+        // both reads of x look up the same interned name, but each
+        // remembers where it found it in its own instance
+        let mut elephant_vm = VM::init_vm();
+        let source = "
+            class P {}
+            var a = P(); a.y = 1; a.x = 2;
+            var b = P(); b.x = 3;
+            fun read(a, b) { return a.x * 10 + b.x; }
+            var total = read(a, b) + read(a, b);
+        ";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(
+            global(&elephant_vm, "total").unwrap().as_number(),
+            Some(46.0)
+        );
+        let Some(Value::Object(Obj {
+            obj_type: ObjType::ObjFunction(read),
+        })) = global(&elephant_vm, "read")
+        else {
+            panic!("read is not a function");
+        };
+        let chunk = &read.chunk;
+        let reads: Vec<usize> = (0..chunk.code.len())
+            .filter(|&offset| chunk.code[offset] == OpCode::OP_GET_PROPERTY as u8)
+            .collect();
+        assert_eq!(reads.len(), 2);
+        let positions: Vec<usize> = reads
+            .iter()
+            .map(|&offset| chunk.cache(offset).unwrap().position.get())
+            .collect();
+        assert_eq!(positions, [1, 0]);
+    }
+
+    #[test]
+    fn test_hoist_skips_loops_that_call() {
+        // bump() assigns n behind the loop's back, so n can't be hoisted
+        let source = "
+            var n = 0;
+            fun bump() { n = n + 1; }
+            var reads = 0;
+            while (n < 3) { bump(); reads = reads + 1; }
+        ";
+        let mut optimized = VM::init_vm();
+        optimized.optimize = true;
+        assert_eq!(optimized.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(global(&optimized, "reads").unwrap().as_number(), Some(3.0));
+        // the VM remembers what functions of earlier programs assign
+        assert_eq!(
+            optimized.interpret("reads = 0; while (n < 5) { bump(); reads = reads + 1; }"),
+            InterpretResult::InterpretOk
+        );
+        assert_eq!(global(&optimized, "reads").unwrap().as_number(), Some(2.0));
+    }
+
+    #[test]
+    fn test_session_functions() {
+        let path =
+            std::env::temp_dir().join(format!("elephant-session-vm-{}.elc", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut first = VM::init_vm();
+        let source = "
+            var base = 10;
+            fun add(x) { return x + base; }
+            fun bump() { base = base + 1; }
+            var table = {\"add\": add, \"xs\": [1, (2, 3), 1..3]};
+            class Point {}
+            var p = Point();
+            var xs = [1];
+            xs.push(xs);
+        ";
+        assert_eq!(first.interpret(source), InterpretResult::InterpretOk);
+        let (saved, skipped) = first.save_session(path).unwrap();
+        assert_eq!(saved, 4);
+        assert_eq!(
+            skipped,
+            [
+                "Skipped 'Point', classes can't be saved.",
+                "Skipped 'p', instances can't be saved.",
+                "Skipped 'xs', it contains itself.",
+            ]
+        );
+
+        // the second VM numbers its global slots differently
+        let mut second = VM::init_vm();
+        second.optimize = true;
+        assert_eq!(
+            second.interpret("var other = 1;"),
+            InterpretResult::InterpretOk
+        );
+        assert_eq!(second.load_session(path).unwrap(), saved);
+        std::fs::remove_file(path).unwrap();
+        let output = CapturedOutput::default();
+        second.set_output(Box::new(output.clone()));
+        let source = "
+            print add(1);
+            bump();
+            print table[\"add\"](1);
+            print table[\"xs\"];
+            // bump() came from the file, base still can't be hoisted
+            var base = 0;
+            var seen = 0;
+            for (var i = 0; i < 3; i = i + 1) { bump(); seen = base; }
+            print seen;
+        ";
+        assert_eq!(second.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(output.text(), "11\n12\n[1, (2, 3), 1..3]\n3\n");
+    }
+
+    #[test]
+    fn test_hoist_across_calls() {
+        // scale() and bump() can only assign what their bodies assign, so
+        // limit, scale and bump are loaded once while n is read each time
+        let source = "
+            var limit = 3;
+            var n = 0;
+            fun bump() { n = n + 1; }
+            fun scale(x) { return x * 2; }
+            var total = 0;
+            var i = 0;
+            while (i < limit) { total = total + scale(i) + n; bump(); i = i + 1; }
+            fun again() {
+                var j = 0;
+                while (j < limit) { bump(); j = j + 1; }
+            }
+        ";
+        let mut optimized = VM::init_vm();
+        optimized.optimize = true;
+        assert_eq!(optimized.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(global(&optimized, "total").unwrap().as_number(), Some(9.0));
+        assert_eq!(global(&optimized, "n").unwrap().as_number(), Some(3.0));
+
+        let mut compiler = Compiler::new(source);
+        compiler.optimize = true;
+        let chunk = compiler.compile(Chunk::init_chunk()).unwrap().remove(0);
+        let hoisted_reads = |chunk: &Chunk| {
+            let mut offset = 0;
+            let mut reads = 0;
+            while offset < chunk.code.len() {
+                if chunk.code[offset] == OpCode::OP_GET_LOCAL_LONG as u8 {
+                    reads += 1;
+                }
+                offset += chunk.instruction_len(offset);
+            }
+            reads
+        };
+        assert_eq!(hoisted_reads(&chunk), 3);
+        // again() may run once more functions exist, so its loop is left alone
+        let again = chunk
+            .constants
+            .values
+            .iter()
+            .find_map(|constant| match constant {
+                Value::Object(Obj {
+                    obj_type: ObjType::ObjFunction(function),
+                }) if function.name == "again" => Some(function.chunk.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(hoisted_reads(&again), 0);
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut elephant_vm = VM::init_vm();
+        let source = "
+            var empty = [];
+            var xs = [1, 2 + 3, \"three\", [4]];
+            var alias = xs;
+            alias[0] = 10;
+            var first = xs[0];
+            var nested = xs[3][0];
+            xs[3][0] = xs[1] = 7;
+            var chained = xs[3][0] + xs[1];
+
+            class Box {}
+            var box = Box();
+            box.items = [1, 2];
+            box.items[1] = 3;
+            var through_field = box.items[1];
+        ";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(
+            global(&elephant_vm, "first").unwrap().as_number(),
+            Some(10.0)
+        );
+        assert_eq!(
+            global(&elephant_vm, "nested").unwrap().as_number(),
+            Some(4.0)
+        );
+        assert_eq!(
+            global(&elephant_vm, "chained").unwrap().as_number(),
+            Some(14.0)
+        );
+        assert_eq!(
+            global(&elephant_vm, "through_field").unwrap().as_number(),
+            Some(3.0)
+        );
+        assert_eq!(
+            global(&elephant_vm, "xs").unwrap().format_with(None),
+            "[10, 7, \"three\", [7]]"
+        );
+        assert_eq!(
+            global(&elephant_vm, "empty").unwrap().format_with(None),
+            "[]"
+        );
+
+        for source in [
+            "xs[4];",
+            "xs[-1];",
+            "xs[0.5];",
+            "xs[\"0\"];",
+            "1[0];",
+            "xs[9] = 1;",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretRuntimeError
+            );
+        }
+        for source in ["[1, 2;", "xs[0;", "[1] = 2;"] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError
+            );
+        }
+    }
+
+    #[test]
+    fn test_interpret_isolated() {
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.interpret("var shared = 1; class Box {} var box = Box();");
+        let (result, defined) = elephant_vm.interpret_isolated(
+            "var own = shared + 1; shared = 10; box.touched = true; fun helper() {}",
+        );
+        assert_eq!(result, InterpretResult::InterpretOk);
+        let names: Vec<&str> = defined
+            .iter()
+            .map(|(name, _)| name.as_obj_string())
+            .collect();
+        assert_eq!(names, ["shared", "own", "helper"]);
+        assert_eq!(defined[1].1.as_number(), Some(2.0));
+
+        // the shared globals are as before, apart from the shared instance
+        assert_eq!(
+            global(&elephant_vm, "shared").unwrap().as_number(),
+            Some(1.0)
+        );
+        assert!(global(&elephant_vm, "own").is_none());
+        assert!(global(&elephant_vm, "helper").is_none());
+        assert_eq!(
+            elephant_vm.evaluate("box.touched").unwrap().as_bool(),
+            Some(true)
+        );
+
+        // a failing snippet is rolled back too
+        let (result, defined) = elephant_vm.interpret_isolated("var a = 1; var b = -nil;");
+        assert_eq!(result, InterpretResult::InterpretRuntimeError);
+        assert_eq!(defined.len(), 1);
+        assert!(global(&elephant_vm, "a").is_none());
+    }
+
+    #[test]
+    fn test_for_in() {
+        let source = "
+            var total = 0;
+            for (i in 1..=4) total = total + i * i;
+            var letters = \"\";
+            for (var c in \"abc\") letters = c + letters;
+            var k = 2;
+            var nested = 0;
+            var n = 0;
+            while (n < 2) {
+                for (x in [10, 20]) for (y in 0..k) nested = nested + x * k + y;
+                n = n + 1;
+            }
+            fun sum(xs) { var s = 0; for (x in xs) s = s + x; return s; }
+            var summed = sum([4, 5, 6]);
+            var empty = 0;
+            for (x in 3..3) empty = 1;
+            // the loop variable is a new local, the global x is untouched
+            var x = \"global\";
+            for (x in [1]) {}
+        ";
+        // hoisting has to move the hidden locals of the inner loops too
+        for optimize in [false, true] {
+            let mut elephant_vm = VM::init_vm();
+            elephant_vm.optimize = optimize;
+            assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+            let number = |name: &str| global(&elephant_vm, name).unwrap().as_number();
+            assert_eq!(number("total"), Some(30.0));
+            assert_eq!(number("nested"), Some(244.0));
+            assert_eq!(number("summed"), Some(15.0));
+            assert_eq!(number("empty"), Some(0.0));
+            let text = |name: &str| global(&elephant_vm, name).unwrap().format_with(None);
+            assert_eq!(text("letters"), "cba");
+            assert_eq!(text("x"), "global");
+        }
+
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(
+            elephant_vm.interpret("for (x in nil) {}"),
+            InterpretResult::InterpretRuntimeError
+        );
+        for source in ["for (x in) {}", "for (x in [1] {}", "for (1 in [1]) {}"] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError
+            );
+        }
+    }
+
+    #[test]
+    fn test_utf8_strings() {
+        // positions count characters, so none of this cuts a character
+        // in half. A non-ASCII string or comment doesn't throw off the
+        // tokens after it either
+        let source = "
+            // ünïcödé comment
+            var word = \"héllo wörld\";
+            var second = word[1];
+            var tail = word[6..len(word)];
+            var size = len(word);
+            var reversed = \"\";
+            for (c in \"日本語\") reversed = c + reversed;
+            var same = \"é\" == word[1..2];
+            var mixed = \"naïve\" + \"→\" + \"ok\";
+        ";
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let text = |name: &str| global(&elephant_vm, name).unwrap().format_with(None);
+        assert_eq!(text("second"), "é");
+        assert_eq!(text("tail"), "wörld");
+        assert_eq!(text("reversed"), "語本日");
+        assert_eq!(text("mixed"), "naïve→ok");
+        assert_eq!(global(&elephant_vm, "size").unwrap().as_int(), Some(11));
+        assert_eq!(global(&elephant_vm, "same").unwrap().as_bool(), Some(true));
+
+        assert_eq!(
+            elephant_vm.interpret("\"日本\"[2];"),
+            InterpretResult::InterpretRuntimeError
+        );
+    }
+
+    #[test]
+    fn test_break() {
+        let source = "
+            var k = 3;
+            var n = 0;
+            while (true) {
+                var a = 1;
+                if (n == 4) { var b = 2; break; }
+                n = n + a;
+            }
+            var total = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                var j = i * k;
+                if (j > 10) break;
+                total = total + j;
+            }
+            var found = nil;
+            for (x in [5, 8, 13, 21]) {
+                if (x > 10) { found = x; break; }
+            }
+            // only the inner loop stops
+            var pairs = 0;
+            for (x in 0..3) for (y in 0..3) { if (y == x) break; pairs = pairs + 1; }
+            fun first_over(xs, limit) {
+                for (x in xs) if (x > limit) return x;
+                return nil;
+            }
+            var over = first_over([1, 4, 9], 2);
+            // the stack is back where it was, locals after the loops work
+            { var after = k * 2; var check = after; k = check; }
+        ";
+        for optimize in [false, true] {
+            let mut elephant_vm = VM::init_vm();
+            elephant_vm.optimize = optimize;
+            assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+            let number = |name: &str| global(&elephant_vm, name).unwrap().as_number();
+            assert_eq!(number("n"), Some(4.0));
+            assert_eq!(number("total"), Some(18.0));
+            assert_eq!(number("found"), Some(13.0));
+            assert_eq!(number("pairs"), Some(3.0));
+            assert_eq!(number("over"), Some(4.0));
+            assert_eq!(number("k"), Some(6.0));
+        }
+
+        let mut elephant_vm = VM::init_vm();
+        for source in [
+            "break;",
+            "{ break; }",
+            "while (true) { fun f() { break; } }",
+            "while (true) break",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError
+            );
+        }
+    }
+
+    #[test]
+    fn test_continue() {
+        let source = "
+            var odd = 0;
+            var even = true;
+            for (var i = 0; i < 10; i = i + 1) {
+                var was_even = even;
+                even = !even;
+                if (was_even) continue;
+                odd = odd + i;
+            }
+            var n = 0;
+            var skipped = 0;
+            while (n < 5) {
+                n = n + 1;
+                { var inner = n; if (inner < 3) continue; }
+                skipped = skipped + n;
+            }
+            var letters = \"\";
+            for (c in \"elephant\") {
+                if (c == \"e\") continue;
+                letters = letters + c;
+            }
+            // the condition of a for loop without an increment runs again
+            var m = 0;
+            for (; m < 3;) { m = m + 1; continue; }
+            var count = 0;
+            for (x in 0..3) for (y in 0..3) { if (y > x) continue; count = count + 1; }
+        ";
+        for optimize in [false, true] {
+            let mut elephant_vm = VM::init_vm();
+            elephant_vm.optimize = optimize;
+            assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+            let number = |name: &str| global(&elephant_vm, name).unwrap().as_number();
+            assert_eq!(number("odd"), Some(25.0));
+            assert_eq!(number("skipped"), Some(12.0));
+            assert_eq!(number("m"), Some(3.0));
+            assert_eq!(number("count"), Some(6.0));
+            let text = global(&elephant_vm, "letters").unwrap().format_with(None);
+            assert_eq!(text, "lphant");
+        }
+
+        let mut elephant_vm = VM::init_vm();
+        for source in [
+            "continue;",
+            "for (x in [1]) { fun f() { continue; } }",
+            "while (false) continue",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError
+            );
+        }
+    }
+
+    #[test]
+    fn test_do_while() {
+        let source = "
+            var k = 3;
+            var once = 0;
+            do once = once + 1; while (false);
+            var n = 0;
+            do { var step = k; n = n + step; } while (n < 10);
+            // continue goes to the condition, not back to the top
+            var tries = 0;
+            var kept = 0;
+            do {
+                tries = tries + 1;
+                if (tries < 3) continue;
+                kept = kept + 1;
+            } while (tries < 5);
+            var stopped = 0;
+            do { stopped = stopped + 1; if (stopped == 2) break; } while (true);
+        ";
+        for optimize in [false, true] {
+            let mut elephant_vm = VM::init_vm();
+            elephant_vm.optimize = optimize;
+            assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+            let number = |name: &str| global(&elephant_vm, name).unwrap().as_number();
+            assert_eq!(number("once"), Some(1.0));
+            assert_eq!(number("n"), Some(12.0));
+            assert_eq!(number("tries"), Some(5.0));
+            assert_eq!(number("kept"), Some(3.0));
+            assert_eq!(number("stopped"), Some(2.0));
+        }
+
+        let mut elephant_vm = VM::init_vm();
+        for source in ["do {} (true);", "do {} while (true)", "do {} while true;"] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError
+            );
+        }
+    }
+
+    #[test]
+    fn test_const() {
+        let source = "
+            const limit = 3;
+            var total = 0;
+            for (var i = 0; i < limit; i = i + 1) {
+                const twice = i * 2;
+                total = total + twice;
+            }
+            fun scaled(x) { const factor = 10; return x * factor; }
+            var big = scaled(limit);
+            // a local can shadow a constant global and be assigned
+            { var limit = 1; limit = 2; total = total + limit; }
+        ";
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let number = |name: &str| global(&elephant_vm, name).unwrap().as_number();
+        assert_eq!(number("total"), Some(8.0));
+        assert_eq!(number("big"), Some(30.0));
+
+        // constants stay constant in later programs too
+        for source in [
+            "limit = 4;",
+            "var limit = 4;",
+            "const limit = 4;",
+            "fun limit() {}",
+            "fun f() { limit = 4; }",
+            "{ const x = 1; x = 2; }",
+            "{ const x = 1; { x = 2; } }",
+            "fun f(a) { const b = a; b = 1; }",
+            "const missing;",
+            "const x 1;",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError,
+                "{}",
+                source
+            );
+        }
+        assert_eq!(
+            global(&elephant_vm, "limit").unwrap().as_number(),
+            Some(3.0)
+        );
+        assert!(elephant_vm.eval_expression("limit = 5").is_err());
+        // a program that fails to compile declares nothing
+        assert_eq!(
+            elephant_vm.interpret("const later = 1; later = 2;"),
+            InterpretResult::InterpretCompileError
+        );
+        assert_eq!(
+            elephant_vm.interpret("var later = 1; later = 2;"),
+            InterpretResult::InterpretOk
+        );
+        // nor does an isolated one
+        elephant_vm.interpret_isolated("const sandboxed = 1;");
+        assert_eq!(
+            elephant_vm.interpret("var sandboxed = 2;"),
+            InterpretResult::InterpretOk
+        );
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let source = "
+            var caught = nil;
+            try { throw \"boom\"; caught = \"not reached\"; } catch (e) { caught = e; }
+            // runtime errors are thrown with their message
+            var message = nil;
+            try { var a = 1; var b = a + nil; } catch (e) { message = e; }
+            // a throw deep in calls unwinds their frames and locals
+            fun fail(n) { var local = n; if (n == 0) throw [local, \"deep\"]; return fail(n - 1); }
+            fun wrapped() { var kept = 1; try { fail(5); } catch (e) { return kept + e[0]; } }
+            var unwound = wrapped();
+            // the innermost try catches, an outer one gets a rethrow
+            var order = \"\";
+            try {
+                try { throw 1; } catch (e) { order = order + \"inner \"; throw e + 1; }
+            } catch (e) {
+                order = order + \"outer\";
+                caught = e;
+            }
+            // break, continue and return leave try blocks behind them
+            var steps = 0;
+            for (var i = 0; i < 5; i = i + 1) {
+                try {
+                    if (i == 1) continue;
+                    if (i == 3) break;
+                    steps = steps + 1;
+                } catch (e) {}
+            }
+            fun early() { try { return 1; } catch (e) {} }
+            early();
+            var after = nil;
+            try { throw \"after\"; } catch (e) { after = e; }
+            var rest = 0;
+            try {} catch (e) { rest = 1; }
+            var depth = nil;
+            fun recurse() { recurse(); }
+            try { recurse(); } catch (e) { depth = e; }
+        ";
+        for optimize in [false, true] {
+            let mut elephant_vm = VM::init_vm();
+            elephant_vm.optimize = optimize;
+            assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+            let text = |name: &str| global(&elephant_vm, name).unwrap().format_with(None);
+            assert_eq!(
+                text("message"),
+                "Operands must be two numbers or two strings."
+            );
+            assert_eq!(text("unwound"), "1");
+            assert_eq!(text("order"), "inner outer");
+            assert_eq!(text("caught"), "2");
+            assert_eq!(text("steps"), "2");
+            assert_eq!(text("after"), "after");
+            assert_eq!(text("rest"), "0");
+            assert_eq!(text("depth"), "Stack overflow.");
+            assert!(elephant_vm.stack.is_empty());
+            assert!(elephant_vm.handlers.is_empty());
+        }
+
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.instruction_budget = Some(1000);
+        // uncaught, and a budget that runs out can't be caught either
+        for source in [
+            "throw \"up\";",
+            "fun f() { throw 1; } f();",
+            "try { while (true) {} } catch (e) {}",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretRuntimeError
+            );
+        }
+        for source in [
+            "try {} catch {}",
+            "try {}",
+            "try print 1; catch (e) {}",
+            "throw;",
+            "catch (e) {}",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError
+            );
+        }
+    }
+
+    #[test]
+    fn test_assert() {
+        let source = "
+            var x = 3;
+            assert(x == 3);
+            assert(x > 1, \"x is too small\");
+            var evaluated = false;
+            fun note() { evaluated = true; return \"message\"; }
+            assert(true, note());
+            var caught = nil;
+            try {
+                assert(x < 0);
+            } catch (e) {
+                caught = e;
+            }
+            var custom = nil;
+            try { assert(nil, [x, 1]); } catch (e) { custom = e; }
+        ";
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let text = |name: &str| global(&elephant_vm, name).unwrap().format_with(None);
+        assert_eq!(text("evaluated"), "false");
+        assert_eq!(text("caught"), "Assertion failed on line 10: x < 0");
+        assert_eq!(text("custom"), "Assertion failed on line 15: [3, 1]");
+
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+        assert_eq!(
+            elephant_vm.interpret("print 1;\nassert(1 > 2, \"math\");\nprint 2;"),
+            InterpretResult::InterpretRuntimeError
+        );
+        assert_eq!(output.text(), "1\n");
+        for source in ["assert 1;", "assert(1)", "assert(1, );", "assert();"] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError
+            );
+        }
+    }
+
+    #[test]
+    fn test_spread_call() {
+        let source = "
+            fun add3(a, b, c) { return a * 100 + b * 10 + c; }
+            var args = [1, 2, 3];
+            var all = add3(...args);
+            var mixed = add3(4, ...[5], 6);
+            var twice = add3(...[7], ...[8, 9]);
+            var empty = add3(...[], 1, 2, 3);
+            class Point { init(x, y) { this.x = x; this.y = y; } sum() { return this.x + this.y; } }
+            var p = Point(...[3, 4]);
+            var method = p.sum(...[]);
+            var native = len(...[\"héllo\"]);
+            // the list is read when the call happens
+            var items = [1];
+            fun count(a, b) { return b; }
+            var late = count(items[0] = 5, ...items);
+        ";
+        for optimize in [false, true] {
+            let mut elephant_vm = VM::init_vm();
+            elephant_vm.optimize = optimize;
+            assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+            let number = |name: &str| global(&elephant_vm, name).unwrap().as_number();
+            assert_eq!(number("all"), Some(123.0));
+            assert_eq!(number("mixed"), Some(456.0));
+            assert_eq!(number("twice"), Some(789.0));
+            assert_eq!(number("empty"), Some(123.0));
+            assert_eq!(number("method"), Some(7.0));
+            assert_eq!(number("native"), Some(5.0));
+            assert_eq!(number("late"), Some(5.0));
+        }
+
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.interpret("fun two(a, b) { return a + b; }");
+        // the arity is checked once the lists are unpacked
+        for source in [
+            "two(...[1]);",
+            "two(1, ...[2, 3]);",
+            "two(...nil);",
+            "two(1, ...2);",
+            "two(...\"ab\");",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+        assert_eq!(
+            elephant_vm.interpret("var error = nil; try { two(...[1]); } catch (e) { error = e; }"),
+            InterpretResult::InterpretOk
+        );
+        assert_eq!(
+            global(&elephant_vm, "error").unwrap().format_with(None),
+            "Expected 2 arguments but got 1."
+        );
+        for source in ["two(...);", "two(.. .[1]);", "var x = ...[1];"] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError
+            );
+        }
+    }
+
+    #[test]
+    fn test_tuples() {
+        let source = "
+            var t = (1, \"a\", true);
+            var first = t[1];
+            var tail = t[1..3];
+            var size = len(t);
+            var one = (5,);
+            var grouped = (5);
+            var empty = ();
+            var nested = ((1, 2), [3]);
+            // equal items make equal tuples, ints and floats included
+            var same = (1, \"a\") == (1.0, \"a\");
+            var different = (1, 2) == (2, 1);
+            var shorter = (1, 2) == (1, 2, 3);
+            var not_a_list = (1, 2) == [1, 2];
+            var inner = nested[1];
+            inner[0] = 4;
+        ";
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let text = |name: &str| global(&elephant_vm, name).unwrap().format_with(None);
+        assert_eq!(text("t"), "(1, \"a\", true)");
+        assert_eq!(text("first"), "a");
+        assert_eq!(text("tail"), "(\"a\", true)");
+        assert_eq!(text("size"), "3");
+        assert_eq!(text("one"), "(5,)");
+        assert_eq!(text("grouped"), "5");
+        assert_eq!(text("empty"), "()");
+        assert_eq!(text("nested"), "((1, 2), [4])");
+        assert_eq!(text("same"), "true");
+        assert_eq!(text("different"), "false");
+        assert_eq!(text("shorter"), "false");
+        assert_eq!(text("not_a_list"), "false");
+        assert_eq!(global(&elephant_vm, "t").unwrap().type_name(), "tuple");
+
+        for source in ["t[0] = 2;", "t[3];", "t[-1];", "t[0..4];"] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+        for source in ["(1, 2;", "(,);", "(1,,);"] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_maps() {
+        let source = "
+            var m = {\"a\": 1, 2: [3], (1, 2): nil};
+            var a = m[\"a\"];
+            var missing = m[\"b\"];
+            // keys match like ==, so 2.0 finds the entry for 2
+            var two = m[2.0];
+            m[\"a\"] = 10;
+            m[\"c\"] = true;
+            var size = len(m);
+            var empty = {};
+            var keys = \"\";
+            for (k in {\"x\": 1, \"y\": 2}) keys = keys + k;
+            var nested = {\"in\": {1: 2}}[\"in\"][1];
+            var alias = m;
+            alias[\"d\"] = 4;
+            var same = alias == m;
+            var different = {} == {};
+            var cycle = {};
+            cycle[1] = cycle;
+            { var block = 1; }
+        ";
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let text = |name: &str| global(&elephant_vm, name).unwrap().format_with(None);
+        // overwriting keeps a key where it was
+        assert_eq!(
+            text("m"),
+            "{\"a\": 10, 2: [3], (1, 2): nil, \"c\": true, \"d\": 4}"
+        );
+        assert_eq!(text("a"), "1");
+        assert_eq!(text("missing"), "nil");
+        assert_eq!(text("two"), "[3]");
+        assert_eq!(text("size"), "4");
+        assert_eq!(text("empty"), "{}");
+        assert_eq!(text("keys"), "xy");
+        assert_eq!(text("nested"), "2");
+        assert_eq!(text("same"), "true");
+        assert_eq!(text("different"), "false");
+        assert_eq!(global(&elephant_vm, "m").unwrap().type_name(), "map");
+
+        assert_eq!(text("cycle"), "{1: {...}}");
+
+        for source in [
+            "{1 2};",
+            "var x = {1};",
+            "var x = {1: 2,};",
+            "var x = {1: 2",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_ternary() {
+        let source = "
+            var a = 3;
+            var bigger = a > 2 ? \"big\" : \"small\";
+            var sign = a < 0 ? -1 : a == 0 ? 0 : 1;
+            var grouped = (false ? 1 : 2) + 10;
+            // only the branch that is picked runs
+            var runs = 0;
+            fun bump() { runs = runs + 1; return runs; }
+            var picked = nil ? bump() : bump() + 100;
+            var x = 0;
+            x = true ? 5 : 6;
+            var nested = true ? false ? 1 : 2 : 3;
+        ";
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let number = |name: &str| global(&elephant_vm, name).unwrap().as_number();
+        assert_eq!(number("sign"), Some(1.0));
+        assert_eq!(number("grouped"), Some(12.0));
+        assert_eq!(number("runs"), Some(1.0));
+        assert_eq!(number("picked"), Some(101.0));
+        assert_eq!(number("x"), Some(5.0));
+        assert_eq!(number("nested"), Some(2.0));
+        let text = global(&elephant_vm, "bigger").unwrap().format_with(None);
+        assert_eq!(text, "big");
+
+        for source in [
+            "var y = true ? 1;",
+            "var y = true ? 1 : ;",
+            "var y = ? 1 : 2;",
+            "var z; true ? z : z = 1;",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError
+            );
+        }
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let source = "
+            var runs = 0;
+            fun bump(value) { runs = runs + 1; return value; }
+            var a = nil or 2;
+            var b = 1 or bump(2);
+            var c = false and bump(3);
+            var d = 1 and 4;
+            // and binds tighter than or
+            var e = false and 1 or 5;
+            var f = true or false and bump(false);
+            var g = 1 < 2 and 3 > 2;
+        ";
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let number = |name: &str| global(&elephant_vm, name).unwrap().as_number();
+        assert_eq!(number("a"), Some(2.0));
+        assert_eq!(number("b"), Some(1.0));
+        assert_eq!(number("d"), Some(4.0));
+        assert_eq!(number("e"), Some(5.0));
+        assert_eq!(number("runs"), Some(0.0));
+        let text = |name: &str| global(&elephant_vm, name).unwrap().format_with(None);
+        assert_eq!(text("c"), "false");
+        assert_eq!(text("f"), "true");
+        assert_eq!(text("g"), "true");
+
+        // or is one conditional jump over the right operand
+        let chunk = Compiler::new("var x; x or 1;")
+            .compile(Chunk::init_chunk())
+            .unwrap()
+            .remove(0);
+        let jumps = chunk
+            .code
+            .iter()
+            .filter(|&&op| op == OpCode::OP_JUMP as u8 || op == OpCode::OP_JUMP_IF_FALSE as u8)
+            .count();
+        assert_eq!(jumps, 0);
+        assert!(chunk.code.contains(&(OpCode::OP_JUMP_IF_TRUE as u8)));
+    }
+
+    #[test]
+    fn test_null_coalescing() {
+        let source = "
+            var missing = nil;
+            var a = missing ?? 1;
+            var b = 2 ?? 3;
+            // only nil is replaced, false and 0 are kept
+            var c = false ?? 4;
+            var d = 0 ?? 5;
+            var chained = nil ?? nil ?? 6;
+            var calls = 0;
+            fun fallback() { calls = calls + 1; return 7; }
+            var e = 8 ?? fallback();
+            var f = nil ?? fallback();
+            // binds looser than comparison and arithmetic, tighter than ?:
+            var g = nil ?? 1 + 2;
+            var h = missing ?? 1 > 0 ? \"yes\" : \"no\";
+        ";
+        for optimize in [false, true] {
+            let mut elephant_vm = VM::init_vm();
+            elephant_vm.optimize = optimize;
+            assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+            let text = |name: &str| global(&elephant_vm, name).unwrap().format_with(None);
+            assert_eq!(text("a"), "1");
+            assert_eq!(text("b"), "2");
+            assert_eq!(text("c"), "false");
+            assert_eq!(text("d"), "0");
+            assert_eq!(text("chained"), "6");
+            assert_eq!(text("e"), "8");
+            assert_eq!(text("f"), "7");
+            assert_eq!(text("calls"), "1");
+            assert_eq!(text("g"), "3");
+            assert_eq!(text("h"), "yes");
+        }
+
+        let mut elephant_vm = VM::init_vm();
+        for source in ["1 ??;", "?? 1;", "1 ?? ?? 2;"] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretCompileError
+            );
+        }
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let mut elephant_vm = VM::init_vm();
+        let cases = [
+            ("12 & 10", 8.0),
+            ("12 | 3", 15.0),
+            ("12 ^ 10", 6.0),
+            ("~5", -6.0),
+            ("1 << 10", 1024.0),
+            ("-16 >> 2", -4.0),
+            // fractions are dropped first
+            ("7.9 & 3.2", 3.0),
+            // shifts bind tighter than &, which binds tighter than ^ and |
+            ("1 | 2 ^ 6 & 3 << 1", 5.0),
+            ("1 + 1 << 2", 8.0),
+        ];
+        for (source, expected) in cases {
+            let value = elephant_vm.eval_expression(source).ok();
+            let value = value.and_then(|value| value.as_number());
+            assert_eq!(value, Some(expected), "{}", source);
+        }
+        // unlike in C, comparing the result needs no parentheses
+        assert_eq!(
+            elephant_vm.eval_expression("6 & 3 == 2").unwrap().as_bool(),
+            Some(true)
+        );
+
+        for source in ["1 << 64", "1 >> -1", "\"a\" & 1", "~nil"] {
+            assert_eq!(
+                elephant_vm.eval_expression(source).err(),
+                Some(EvalError::RuntimeError),
+                "{}",
+                source
+            );
+        }
+        assert_eq!(
+            elephant_vm.eval_expression("1 & & 2").err(),
+            Some(EvalError::CompileError)
+        );
+    }
+
+    #[test]
+    fn test_ints() {
+        let mut elephant_vm = VM::init_vm();
+        let eval = |vm: &mut VM, source: &str| {
+            let value = vm.eval_expression(source).ok().unwrap();
+            (value.type_name(), value.format_with(None))
+        };
+        assert_eq!(eval(&mut elephant_vm, "7 + 2 * 3"), ("int", "13".into()));
+        assert_eq!(eval(&mut elephant_vm, "1 + 0.5"), ("number", "1.5".into()));
+        // division always gives a float
+        assert_eq!(eval(&mut elephant_vm, "7 / 2"), ("number", "3.5".into()));
+        assert_eq!(eval(&mut elephant_vm, "6 / 3"), ("number", "2".into()));
+        // exact where a float would round
+        assert_eq!(
+            eval(&mut elephant_vm, "9007199254740993 + 2"),
+            ("int", "9007199254740995".into())
+        );
+        assert_eq!(eval(&mut elephant_vm, "12 & ~3"), ("int", "12".into()));
+        assert_eq!(
+            eval(&mut elephant_vm, "0xff + 0b11 + 0o17"),
+            ("int", "273".into())
+        );
+        assert_eq!(eval(&mut elephant_vm, "1 == 1.0"), ("bool", "true".into()));
+        // compared exactly, not after rounding the int to a float
+        for (source, equal) in [
+            ("9007199254740993 == 9007199254740992.0", "false"),
+            ("9007199254740992 == 9007199254740992.0", "true"),
+            ("9223372036854775807 == 9223372036854775807.0", "false"),
+            ("-9223372036854775807 - 1 == -9223372036854775808.0", "true"),
+            ("1 != 1.5", "true"),
+        ] {
+            assert_eq!(
+                eval(&mut elephant_vm, source),
+                ("bool", equal.into()),
+                "{}",
+                source
+            );
+        }
+        assert_eq!(eval(&mut elephant_vm, "2 < 2.5"), ("bool", "true".into()));
+        assert_eq!(
+            eval(&mut elephant_vm, "[10, 20][1.0]"),
+            ("int", "20".into())
+        );
+
+        // overflow moves on to floats, or is an error in checked mode
+        let max = "9223372036854775807";
+        let overflows = [
+            format!("{} + 1", max),
+            format!("-{} - 2", max),
+            format!("{} * 2", max),
+            format!("-(-{} - 1)", max),
+        ];
+        for source in &overflows {
+            assert_eq!(eval(&mut elephant_vm, source).0, "number", "{}", source);
+        }
+        elephant_vm.checked_arithmetic = true;
+        for source in &overflows {
+            assert_eq!(
+                elephant_vm.eval_expression(source).err(),
+                Some(EvalError::RuntimeError),
+                "{}",
+                source
+            );
+        }
+        elephant_vm.checked_arithmetic = false;
+
+        let source = "
+            var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) sum = sum + i;
+            var item = nil;
+            for (x in 1..3) item = x;
+        ";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(global(&elephant_vm, "sum").unwrap().as_int(), Some(45));
+        assert_eq!(global(&elephant_vm, "item").unwrap().as_int(), Some(2));
+    }
+
+    #[test]
+    fn test_ranges() {
+        let mut elephant_vm = VM::init_vm();
+        let source = "
+            var n = 4;
+            var r = 1..n - 1;
+            var xs = [10, 20, 30, 40];
+            var middle = xs[1..3];
+            var all = xs[0..=3];
+            var none = xs[4..4];
+            var word = \"elephant\"[2..=4];
+            var letter = \"elephant\"[0];
+            var same = (0..3) == (0..3);
+            var differ = (0..3) != (0..=3);
+        ";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let shown = |vm: &VM, name: &str| global(vm, name).unwrap().format_with(None);
+        assert_eq!(shown(&elephant_vm, "r"), "1..3");
+        assert_eq!(global(&elephant_vm, "r").unwrap().type_name(), "range");
+        assert_eq!(shown(&elephant_vm, "middle"), "[20, 30]");
+        assert_eq!(shown(&elephant_vm, "all"), "[10, 20, 30, 40]");
+        assert_eq!(shown(&elephant_vm, "none"), "[]");
+        assert_eq!(shown(&elephant_vm, "word"), "eph");
+        assert_eq!(shown(&elephant_vm, "letter"), "e");
+        assert_eq!(global(&elephant_vm, "same").unwrap().as_bool(), Some(true));
+        assert_eq!(
+            global(&elephant_vm, "differ").unwrap().as_bool(),
+            Some(true)
+        );
+
+        for source in [
+            "xs[0..5];",
+            "xs[3..1];",
+            "xs[-1..2];",
+            "\"ab\"[0..=2];",
+            "1..nil;",
+            "0.5..2;",
+            "(0..2)[0];",
+            "\"ab\"[0] = \"c\";",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretRuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_splits_long_scripts() {
+        // two constants per declaration, more than one chunk holds
+        let mut source: String = (0..150)
+            .map(|i| format!("var v{} = {}.25 + 0.25;\n", i, i))
+            .collect();
+        source += "var sum = 0; for (var i = 0; i < 150; i = i + 1) { sum = sum + i; }";
+        let mut compiler = Compiler::new(&source);
+        let chunks = compiler.compile(Chunk::init_chunk()).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.constants.values.len() <= 256));
+
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.interpret(&source), InterpretResult::InterpretOk);
+        assert_eq!(global(&elephant_vm, "v0").unwrap().as_number(), Some(0.5));
+        assert_eq!(
+            global(&elephant_vm, "v149").unwrap().as_number(),
+            Some(149.5)
+        );
+        assert_eq!(
+            global(&elephant_vm, "sum").unwrap().as_number(),
+            Some(11175.0)
+        );
+
+        // an error in a later chunk stops the program there
+        let source =
+            source.replace("var v140 = 140.25 + 0.25;", "var v140 = -nil;") + " var after = 1;";
+        assert_eq!(
+            elephant_vm.interpret(&source),
+            InterpretResult::InterpretRuntimeError
+        );
+        assert!(global(&elephant_vm, "after").is_none());
+
+        // a single declaration with more than 256 constants gets a chunk
+        // of its own and loads the rest with OP_CONSTANT_LONG
+        let terms: Vec<String> = (0..300).map(|i| format!("{}.5", i)).collect();
+        let source = format!("var a = 1; var big = {};", terms.join(" + "));
+        assert_eq!(elephant_vm.interpret(&source), InterpretResult::InterpretOk);
+        assert_eq!(
+            global(&elephant_vm, "big").unwrap().as_number(),
+            Some(45000.0)
+        );
+
+        // same for a block at the top level
+        let locals: String = (0..300)
+            .map(|i| format!("var l{} = {}.5; total = total + l{};\n", i, i, i))
+            .collect();
+        let source = format!("var total = 0; if (true) {{ {} }}", locals);
+        assert_eq!(elephant_vm.interpret(&source), InterpretResult::InterpretOk);
+        assert_eq!(
+            global(&elephant_vm, "total").unwrap().as_number(),
+            Some(45000.0)
+        );
+    }
+
+    #[test]
+    fn test_function_with_many_constants() {
+        // a function's chunk can't be split, past 256 constants its values
+        // are loaded with OP_CONSTANT_LONG
+        let body: String = (0..600)
+            .map(|i| format!("total = total + {}.5; point.x = total;\n", i))
+            .collect();
+        let source = format!(
+            "class Point {{}} var point = Point(); var total = 0; \
+             fun long() {{ {} return total; }} var result = long();",
+            body
+        );
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.interpret(&source), InterpretResult::InterpretOk);
+        assert_eq!(
+            global(&elephant_vm, "result").unwrap().as_number(),
+            Some(180000.0)
+        );
+        assert_eq!(
+            elephant_vm.evaluate("point.x").and_then(|x| x.as_number()),
+            Some(180000.0)
+        );
+    }
+
+    #[test]
+    fn test_long_function() {
+        // a function isn't split like top-level code, so its jumps and
+        // loops run past the first 64KB of its chunk. 8 bytes a statement
+        let body = "x = x + y; ".repeat(10_000);
+        let source = format!(
+            "fun long() {{ var x = 0; var y = 1; {} \
+             var i = 0; while (i < 3) {{ if (i > 1) x = x * 2; i = i + 1; }} return x; }} \
+             var result = long();",
+            body
+        );
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.interpret(&source), InterpretResult::InterpretOk);
+        assert_eq!(
+            global(&elephant_vm, "result").unwrap().as_number(),
+            Some(20_000.0)
+        );
+
+        // a jump's offset is still 16 bits
+        let source = format!(
+            "fun long() {{ var x = 0; var y = 1; if (y > 0) {{ {} }} }}",
+            body
+        );
+        assert_eq!(
+            elephant_vm.interpret(&source),
+            InterpretResult::InterpretCompileError
+        );
+    }
+
+    #[test]
+    fn test_many_locals() {
+        // slots past 255 use the long local instructions
+        let locals: String = (0..300).map(|i| format!("var l{}; ", i)).collect();
+        let source = format!(
+            "fun many() {{ {} l0 = 1; l298 = 5; l299 = 7; l299 = l299 + l0; \
+             {{ var inner = l298; l1 = inner; }} return l299 * 1000 + l1; }} \
+             var result = many();",
+            locals
+        );
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.interpret(&source), InterpretResult::InterpretOk);
+        assert_eq!(
+            global(&elephant_vm, "result").unwrap().as_number(),
+            Some(8005.0)
+        );
+
+        // for-in keeps its collection in a one byte slot
+        let source = format!("fun many() {{ {} for (x in [1]) {{}} }}", locals);
+        assert_eq!(
+            elephant_vm.interpret(&source),
+            InterpretResult::InterpretCompileError
+        );
+    }
+
+    #[test]
+    fn test_native_clock() {
+        let mut elephant_vm = VM::init_vm();
+        let source = "var start = clock(); var elapsed = clock() - start;";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let elapsed = global(&elephant_vm, "elapsed")
+            .unwrap()
+            .as_number()
+            .unwrap();
+        assert!(elapsed >= 0.0);
+        assert_eq!(
+            global(&elephant_vm, "clock").unwrap().type_name(),
+            "function"
+        );
+
+        for source in ["clock(1);", "var x = 1; x();", "\"clock\"();"] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretRuntimeError
+            );
+        }
+        assert_eq!(
+            elephant_vm.interpret("print clock(;"),
+            InterpretResult::InterpretCompileError
+        );
+    }
+
+    #[test]
+    fn test_classes() {
+        let mut elephant_vm = VM::init_vm();
+        let source = "
+            class Point {}
+            var p = Point();
+            var q = p;
+            p.x = 1;
+            q.y = p.x + 1;
+            var sum = p.x + p.y;
+            var same = p == q;
+            var other = p == Point();
+            {
+                class Local {}
+                var l = Local();
+                l.name = \"local\";
+                sum = sum + (l.z = 3);
+            }
+        ";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(global(&elephant_vm, "sum").unwrap().as_number(), Some(6.0));
+        assert_eq!(global(&elephant_vm, "same").unwrap().as_bool(), Some(true));
+        assert_eq!(
+            global(&elephant_vm, "other").unwrap().as_bool(),
+            Some(false)
+        );
+        assert_eq!(global(&elephant_vm, "Point").unwrap().type_name(), "class");
+        assert_eq!(global(&elephant_vm, "p").unwrap().type_name(), "instance");
+
+        for source in [
+            "p.missing;",
+            "var n = 1; n.x = 2;",
+            "print n.x;",
+            "Point(1);",
+        ] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretRuntimeError
+            );
+        }
+        assert_eq!(
+            elephant_vm.interpret("class Broken { var x; }"),
+            InterpretResult::InterpretCompileError
+        );
+    }
+
+    #[test]
+    fn test_recovers_after_runtime_error() {
+        let mut elephant_vm = VM::init_vm();
+        let a = ObjType::ObjString(ObjString::new("a".to_string()));
+        let b = ObjType::ObjString(ObjString::new("b".to_string()));
+        // the program stops at the failing statement instead of running
+        // on with an emptied stack
+        assert_eq!(
+            elephant_vm.interpret("var a = 1; a = a - nil; var b = 2;"),
+            InterpretResult::InterpretRuntimeError
+        );
+        assert!(elephant_vm.stack.is_empty());
+        assert_eq!(
+            elephant_vm.globals.get_named(&a).unwrap().as_number(),
+            Some(1.0)
+        );
+        assert!(elephant_vm.globals.get_named(&b).is_none());
+
+        for source in ["1 < \"x\";", "\"x\" * 2;", "-\"x\";", "1 + nil;", "b = 1;"] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretRuntimeError
+            );
+            assert!(elephant_vm.stack.is_empty());
+        }
+        assert!(elephant_vm.globals.get_named(&b).is_none());
+
+        // and the session carries on
+        assert_eq!(
+            elephant_vm.interpret("var b = a + 1; a = b * 2;"),
+            InterpretResult::InterpretOk
+        );
+        assert_eq!(
+            elephant_vm.globals.get_named(&a).unwrap().as_number(),
+            Some(4.0)
+        );
+    }
+
+    #[test]
+    fn test_evaluate_expression() {
+        let mut elephant_vm = VM::init_vm();
+        let value = elephant_vm.evaluate("1 + 2 * 3").unwrap();
+        assert_eq!(value.as_number(), Some(7.0));
+        assert_eq!(value.type_name(), "int");
+        assert_eq!(elephant_vm.evaluate("!nil").unwrap().type_name(), "bool");
+        assert_eq!(elephant_vm.evaluate("\"a\" + \"b\"").unwrap().type_name(), "string");
+        // statements aren't expressions
+        assert!(elephant_vm.evaluate("print 1;").is_none());
+        assert!(elephant_vm.evaluate("-nil").is_none());
+    }
+
+    #[test]
+    fn test_buffered_output() {
+        let mut elephant_vm = VM::init_vm();
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+        let source = "for (i in 0..3) print i; print \"a\"; print [1, \"b\"];";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(output.text(), "0\n1\n2\na\n[1, \"b\"]\n");
+
+        // what ran before an error is out before the error is reported
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+        assert_eq!(
+            elephant_vm.interpret("print 1; print nil + 1;"),
+            InterpretResult::InterpretRuntimeError
+        );
+        assert_eq!(output.text(), "1\n");
+        assert_eq!(
+            elephant_vm.eval_expression("2 * 3").unwrap().as_number(),
+            Some(6.0)
+        );
+        assert_eq!(output.text(), "1\n");
+
+        // on a terminal each line is out as soon as it is printed
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+        elephant_vm.print(&Value::Int(1));
+        assert_eq!(output.text(), "");
+        elephant_vm.line_buffered = true;
+        elephant_vm.write(&Value::Int(2));
+        assert_eq!(output.text(), "");
+        elephant_vm.print(&Value::Int(3));
+        assert_eq!(output.text(), "1\n23\n");
+    }
+
+    #[test]
+    fn test_current_location() {
+        fn location(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+            let location = vm.current_location().unwrap();
+            let text = format!(
+                "{}:{} {}",
+                location.file,
+                location.line,
+                location.function.as_deref().unwrap_or("<script>")
+            );
+            Ok(vm.intern_string(text))
+        }
+
+        let mut elephant_vm = VM::init_vm();
+        assert_eq!(elephant_vm.current_location(), None);
+        elephant_vm.define_native("location", Some(0), location);
+        let source = "var top = location();
+fun helper() {
+    return location();
+}
+class Logger { log() { return helper() + \" \" + location(); } }
+var nested = Logger().log();
+var after = location();";
+        assert_eq!(
+            elephant_vm.interpret_file(source, "main.elx"),
+            InterpretResult::InterpretOk
+        );
+        let text = |name: &str| global(&elephant_vm, name).unwrap().format_with(None);
+        assert_eq!(text("top"), "main.elx:1 <script>");
+        assert_eq!(text("nested"), "main.elx:3 helper main.elx:5 log");
+        // back in top-level code once the calls return
+        assert_eq!(text("after"), "main.elx:7 <script>");
+        let last = elephant_vm.current_location().unwrap();
+        // the end of the source
+        assert_eq!((last.line, last.column, last.function), (7, Some(24), None));
+    }
+
+    #[test]
+    fn test_structured_errors() {
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.print_errors = false;
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+
+        let source = "var a = 1;\nvar b = a +* 2;\nvar c = ;";
+        assert_eq!(
+            elephant_vm.interpret_file(source, "main.elx"),
+            InterpretResult::InterpretCompileError
+        );
+        let errors = &elephant_vm.compile_errors;
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "Expect expression.");
+        assert_eq!((errors[0].file.as_str(), errors[0].line), ("main.elx", 2));
+        assert_eq!(errors[0].column, 12);
+        assert_eq!(errors[0].lexeme, "*");
+        assert_eq!(&source[errors[0].span.clone()], "*");
+        let rendered = Renderer::plain().render(&errors[0].diagnostic(), Some(source));
+        assert!(rendered.contains("2 | var b = a +* 2;\n  |            ^\n"));
+        assert_eq!((errors[1].line, errors[1].lexeme.as_str()), (3, ";"));
+
+        // the end of input has no text to point at
+        assert_eq!(
+            elephant_vm.interpret("print 1 +"),
+            InterpretResult::InterpretCompileError
+        );
+        let error = &elephant_vm.compile_errors[0];
+        assert!(error.at_end && error.lexeme.is_empty() && error.span.is_empty());
+
+        assert_eq!(
+            elephant_vm.interpret("print 1;\nprint -\"x\";"),
+            InterpretResult::InterpretRuntimeError
+        );
+        assert!(elephant_vm.compile_errors.is_empty());
+        let error = elephant_vm.runtime_error.clone().unwrap();
+        assert_eq!(error.message, "Operand must be a number.");
+        let rendered = error.render(Renderer::plain());
+        assert!(rendered.contains(" --> script:2:7\n  |\n2 | print -\"x\";\n  |       ^\n"));
+        let location = error.location.unwrap();
+        assert_eq!((location.line, location.column), (2, Some(7)));
+
+        // under the operator or keyword, not the last operand or the ;
+        for (source, column) in [("nil + 1;", 5), ("1 +\n  nil;", 3), ("throw \"x\";", 1)] {
+            assert_eq!(
+                elephant_vm.interpret(source),
+                InterpretResult::InterpretRuntimeError
+            );
+            let error = elephant_vm.runtime_error.clone().unwrap();
+            let location = error.location.unwrap();
+            assert_eq!(
+                (location.line, location.column),
+                (1, Some(column)),
+                "{}",
+                source
+            );
+        }
+
+        // a caught error isn't the run's error
+        let source = "try { print -\"x\"; } catch (e) { print e; }";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(elephant_vm.runtime_error, None);
+
+        assert_eq!(
+            elephant_vm.eval_expression("1 +").err(),
+            Some(EvalError::CompileError)
+        );
+        assert_eq!(elephant_vm.compile_errors.len(), 1);
+        assert_eq!(output.text(), "1\nOperand must be a number.\n");
+    }
+
+    #[test]
+    fn test_stack_trace() {
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.print_errors = false;
+        let source = "fun inner(x) {
+    return -x;
+}
+fun outer(x) { return inner(x); }
+class A { run() { return outer(\"s\"); } }
+A().run();";
+        assert_eq!(
+            elephant_vm.interpret_file(source, "main.elx"),
+            InterpretResult::InterpretRuntimeError
+        );
+        let error = elephant_vm.runtime_error.clone().unwrap();
+        let trace: Vec<_> = error
+            .trace
+            .iter()
+            .map(|caller| (caller.function.as_deref(), caller.line))
+            .collect();
+        assert_eq!(trace, [(Some("outer"), 4), (Some("run"), 5), (None, 6)]);
+        let notes = error.diagnostic().notes;
+        assert_eq!(notes[0], "in inner()");
+        assert_eq!(notes[1], "called from outer() at main.elx:4:30");
+        assert_eq!(notes[3], "called from <script> at main.elx:6:9");
+
+        // a stack overflow only shows the innermost calls
+        let source = "fun f(n) { return f(n + 1); } f(0);";
+        assert_eq!(
+            elephant_vm.interpret(source),
+            InterpretResult::InterpretRuntimeError
+        );
+        let error = elephant_vm.runtime_error.clone().unwrap();
+        assert_eq!(error.trace.len(), FRAMES_MAX);
+        let notes = error.diagnostic().notes;
+        assert_eq!(notes.len(), 1 + TRACE_SHOWN + 1);
+        assert_eq!(notes.last().unwrap(), "... and 48 more calls");
+    }
+
+    #[test]
+    fn test_stack_limits() {
+        let mut elephant_vm = VM::init_vm_with_limits(32, 8);
+        elephant_vm.print_errors = false;
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+
+        // too many values at once, with no call in sight
+        let items = vec!["1"; 40].join(", ");
+        assert_eq!(
+            elephant_vm.interpret(&format!("var l = [{}];", items)),
+            InterpretResult::InterpretRuntimeError
+        );
+        let error = elephant_vm.runtime_error.clone().unwrap();
+        assert_eq!(error.message, "Stack overflow.");
+
+        // recursion stops at the frame limit, long before the stack's
+        let source = "fun f(n) { return f(n + 1); } f(0);";
+        assert_eq!(
+            elephant_vm.interpret(source),
+            InterpretResult::InterpretRuntimeError
+        );
+        let error = elephant_vm.runtime_error.clone().unwrap();
+        assert_eq!(error.message, "Stack overflow.");
+        assert_eq!(error.trace.len(), 8);
+
+        // it can be caught like any other runtime error, and the VM
+        // runs fine afterwards
+        let source = format!("try {{ var l = [{}]; }} catch (e) {{ print e; }}", items);
+        assert_eq!(elephant_vm.interpret(&source), InterpretResult::InterpretOk);
+        assert_eq!(
+            elephant_vm.interpret("print [1, 2, 3];"),
+            InterpretResult::InterpretOk
+        );
+        assert_eq!(output.text(), "Stack overflow.\n[1, 2, 3]\n");
+
+        // a spread call checks as it unpacks, before the arity is
+        let source = "var big = []; for (i in 0..100) big.push(i); len(...big);";
+        assert_eq!(
+            elephant_vm.interpret(source),
+            InterpretResult::InterpretRuntimeError
+        );
+        let error = elephant_vm.runtime_error.clone().unwrap();
+        assert_eq!(error.message, "Stack overflow.");
+    }
+
+    #[test]
+    fn test_unused_locals() {
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.print_errors = false;
+        assert_eq!(elephant_vm.load_prelude(), InterpretResult::InterpretOk);
+        assert!(elephant_vm.compile_warnings.is_empty());
+
+        let source = "fun f(a, unread, _ignored) {
+    var written = 1;
+    written = 2;
+    { var inner = a; print inner; }
+}
+for (var i in [1]) {}
+try { throw 1; } catch (_e) {}
+{ var later = 1; }";
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let warnings: Vec<_> = elephant_vm
+            .compile_warnings
+            .iter()
+            .map(|warning| (warning.message.as_str(), warning.line, warning.column))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                ("Unused local variable 'unread'.", Some(1), Some(10)),
+                ("Unused local variable 'written'.", Some(2), Some(9)),
+                ("Unused local variable 'i'.", Some(6), Some(10)),
+                ("Unused local variable 'later'.", Some(8), Some(7)),
+            ]
+        );
+
+        // denied warnings stop the program before it runs
+        elephant_vm.deny_warnings = true;
+        assert_eq!(
+            elephant_vm.interpret("print 1; { var x = 1; }"),
+            InterpretResult::InterpretCompileError
+        );
+        assert_eq!(
+            elephant_vm.interpret("print 2; { var x = 1; print x; }"),
+            InterpretResult::InterpretOk
+        );
+        assert_eq!(output.text(), "2\n1\n");
+    }
+
+    #[test]
+    fn test_undefined_globals() {
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.print_errors = false;
+        let source = "fun total() { return count + missing; }
+var count = 1;
+print len([count]);
+print typo;";
+        assert_eq!(
+            elephant_vm.interpret(source),
+            InterpretResult::InterpretRuntimeError
+        );
+        let warnings: Vec<_> = elephant_vm
+            .compile_warnings
+            .iter()
+            .map(|warning| (warning.message.as_str(), warning.line, warning.column))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                ("Undefined variable 'missing'.", Some(1), Some(30)),
+                ("Undefined variable 'typo'.", Some(4), Some(7)),
+            ]
+        );
+
+        // what earlier programs defined is known
+        assert_eq!(
+            elephant_vm.interpret("print total;"),
+            InterpretResult::InterpretOk
+        );
+        assert!(elephant_vm.compile_warnings.is_empty());
+    }
+
+    #[test]
+    fn test_shadowing() {
+        let mut elephant_vm = VM::init_vm();
+        let source = "var total = 0;
+fun f(len) {
+    var x = 1;
+    {
+        var x = 2;
+        var y = x + len;
+        print y;
+    }
+    for (var y in [x]) print y;
+    return total;
+}";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let warnings: Vec<_> = elephant_vm
+            .compile_warnings
+            .iter()
+            .map(|warning| (warning.message.as_str(), warning.line))
+            .collect();
+        // the two y's are in sibling scopes, neither shadows the other
+        assert_eq!(
+            warnings,
+            [
+                ("Local variable 'len' shadows a global variable.", Some(2)),
+                (
+                    "Local variable 'x' shadows a local variable of an enclosing block.",
+                    Some(5)
+                ),
+            ]
+        );
+        // pointing at both declarations
+        assert_eq!(elephant_vm.compile_warnings[1].labels.len(), 2);
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.print_errors = false;
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+
+        // without strict mode the assignment is only an error once it runs
+        let source = "fun reset() { totl = 0; }\nvar total = 1;\nprint total;";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        let strict = format!("\"use strict\";\n{}", source);
+        assert_eq!(
+            elephant_vm.interpret(&strict),
+            InterpretResult::InterpretCompileError
+        );
+        let error = &elephant_vm.compile_errors[0];
+        assert_eq!(
+            error.message,
+            "Can't assign to undeclared variable 'totl' in strict mode."
+        );
+        assert_eq!((error.line, error.lexeme.as_str()), (2, "totl"));
+
+        // warnings are errors too
+        elephant_vm.strict = true;
+        assert_eq!(
+            elephant_vm.interpret("{ var unused = 1; } print 2;"),
+            InterpretResult::InterpretCompileError
+        );
+        assert_eq!(elephant_vm.compile_warnings.len(), 1);
+        assert_eq!(
+            elephant_vm.interpret("total = 3; print total;"),
+            InterpretResult::InterpretOk
+        );
+        assert_eq!(output.text(), "1\n3\n");
+    }
+
+    #[test]
+    fn test_script_args() {
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.set_args(&["in.txt".to_string(), "-v".to_string()]);
+        let source = "var count = len(args); var first = args[0]; var flag = args[1] == \"-v\";";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        assert_eq!(
+            global(&elephant_vm, "count").unwrap().as_number(),
+            Some(2.0)
+        );
+        assert_eq!(
+            global(&elephant_vm, "first").unwrap().format_with(None),
+            "in.txt"
+        );
+        assert_eq!(global(&elephant_vm, "flag").unwrap().as_bool(), Some(true));
+    }
+
+    #[test]
+    fn test_run_tests() {
+        let mut elephant_vm = VM::init_vm();
+        let output = CapturedOutput::default();
+        elephant_vm.set_output(Box::new(output.clone()));
+        let source = "
+            var ran = 0;
+            fun adds() { ran = ran + 1; assert_eq(1 + 1, 2); }
+            fun fails() { ran = ran + 1; assert(1 > 2, \"one is not more\"); }
+            fun catches() {
+                ran = ran + 1;
+                try { assert_eq(\"a\", \"b\"); } catch (e) { assert_ne(e, nil); }
+            }
+            test(\"adds\", adds);
+            test(\"fails\", fails);
+            test(\"catches\", catches);
+        ";
+        assert_eq!(elephant_vm.interpret(source), InterpretResult::InterpretOk);
+        // registering doesn't run anything
+        assert_eq!(global(&elephant_vm, "ran").unwrap().as_number(), Some(0.0));
+
+        assert_eq!(elephant_vm.run_tests(), (2, 1));
+        assert_eq!(global(&elephant_vm, "ran").unwrap().as_number(), Some(3.0));
+        assert_eq!(
+            output.text(),
+            "running 3 tests\ntest adds ... ok\ntest fails ... FAILED\ntest catches ... ok\n\
+             \ntest result: FAILED. 2 passed; 1 failed\n"
+        );
+        // they only run once
+        assert!(elephant_vm.tests.is_empty());
+    }
+
+    #[test]
+    fn test_eval_expression() {
+        let mut elephant_vm = VM::init_vm();
+        elephant_vm.set_global("width", Value::Number(20.0));
+        let value = elephant_vm.eval_expression("width * 2 + 1").unwrap();
+        assert_eq!(value.as_number(), Some(41.0));
+        // whitespace and comments around it are fine, a ';' is not
+        let value = elephant_vm.eval_expression("  width // px\n").unwrap();
+        assert_eq!(value.as_number(), Some(20.0));
+        assert_eq!(
+            elephant_vm.eval_expression("width;").unwrap_err(),
+            EvalError::CompileError
+        );
+        assert_eq!(
+            elephant_vm.eval_expression("var a = 1").unwrap_err(),
+            EvalError::CompileError
+        );
+        assert_eq!(
+            elephant_vm.eval_expression("height * 2").unwrap_err(),
+            EvalError::RuntimeError
+        );
+        // the VM is still usable after an error
+        assert!(elephant_vm.eval_expression("width = 3").is_ok());
+        assert_eq!(
+            global(&elephant_vm, "width").unwrap().as_number(),
+            Some(3.0)
+        );
+    }
+
+    #[test]
+    fn test_global_used_before_initialization() {
+        let mut elephant_vm = VM::init_vm();
+        let x = ObjType::ObjString(ObjString::new("x".to_string()));
+        let y = ObjType::ObjString(ObjString::new("y".to_string()));
+        elephant_vm.hoisted_globals.insert(x.clone());
+        assert_eq!(
+            elephant_vm.undefined_global_message(&x),
+            "Global 'x' used before initialization."
+        );
+        assert_eq!(
+            elephant_vm.undefined_global_message(&y),
+            "Undefined variable 'y'."
+        );
+
+        assert_eq!(
+            elephant_vm.interpret("print x; var x = 1;"),
+            InterpretResult::InterpretRuntimeError
+        );
+        // the declaration doesn't leak into the next program
+        assert!(elephant_vm.hoisted_globals.is_empty());
+        assert_eq!(
+            elephant_vm.interpret("var x = 1; print x;"),
+            InterpretResult::InterpretOk
+        );
     }
 }